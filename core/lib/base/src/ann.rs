@@ -1,22 +1,30 @@
+use roaring::RoaringTreemap;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
 use std::default::Default;
+use std::path::Path;
 
+use crate::diskann_ssd::DiskANNSSDParams;
 use crate::diskannv1::DiskANNParams;
 use crate::flat::FlatParams;
 use crate::metric;
+use crate::product_quantizer;
+use crate::scalar_quantizer;
 use pyo3::prelude::*;
 
 use num::traits::NumAssign;
+use rand::distributions::Distribution;
 
 #[derive(Debug)]
 pub enum ANNParams {
     Flat { params: FlatParams },
     DiskANN { params: DiskANNParams },
+    DiskANNSSD { params: DiskANNSSDParams },
 }
 
 #[pyclass]
-#[derive(Serialize, Deserialize, Clone )]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ANNTypes {
     DiskANN = 1,
     Flat = 2,
@@ -32,7 +40,40 @@ pub trait IntoCopied {
     }
 }
 
-// we support f32s and u8s
+pub use half::{bf16, f16};
+
+// the element types by the tags saved indexes record them under, the rust
+// type names are not stable across compilers or refactors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementType {
+    F32 = 1,
+    F16 = 2,
+    BF16 = 3,
+    U8 = 4,
+    I8 = 5,
+    // packed bits, see ElementVal
+    U64 = 6,
+    F64 = 7,
+}
+
+impl ElementType {
+    pub(crate) fn from_u32(val: u32) -> anyhow::Result<ElementType> {
+        Ok(match val {
+            1 => ElementType::F32,
+            2 => ElementType::F16,
+            3 => ElementType::BF16,
+            4 => ElementType::U8,
+            5 => ElementType::I8,
+            6 => ElementType::U64,
+            7 => ElementType::F64,
+            _ => anyhow::bail!("unknown element type: {}", val),
+        })
+    }
+}
+
+// we support f32s, f16s, bf16s, u8s, i8s and u64s - the latter hold packed
+// binary vectors, 64 bits to a word, and are compared through Hamming or
+// MetricJaccard. 16 bit floats are widened to f32 by the metrics
 pub trait ElementVal:
     num::Num
     + std::marker::Copy
@@ -47,16 +88,72 @@ pub trait ElementVal:
     + std::fmt::Debug
 {
     type Native;
+    const ELEMENT_TYPE: ElementType;
+    // the frozen start point of a DiskANN graph, a random point on the sphere
+    // of the given radius. it has to sit amongst the vectors without being
+    // much closer to all of them than they are to each other, otherwise graph
+    // pruning routes every vector through it
+    fn random_start_point(dim: usize, radius: f32) -> Vec<Self> {
+        let mut rng = rand::thread_rng();
+        let v: Vec<f64> = rand::distributions::Standard
+            .sample_iter(&mut rng)
+            .take(dim)
+            .collect();
+        let norm: f64 = v.iter().map(|r| r * r).sum::<f64>().sqrt();
+        v.iter()
+            .map(|p| {
+                Self::from_f64(*p * (radius as f64) / norm).expect("unexpected cast issue fr: f32")
+            })
+            .collect()
+    }
 }
 impl ElementVal for f32 {
     type Native = f32;
+    const ELEMENT_TYPE: ElementType = ElementType::F32;
 }
 impl ElementVal for f64 {
     type Native = f64;
+    const ELEMENT_TYPE: ElementType = ElementType::F64;
+}
+
+impl ElementVal for f16 {
+    type Native = f16;
+    const ELEMENT_TYPE: ElementType = ElementType::F16;
+}
+impl ElementVal for bf16 {
+    type Native = bf16;
+    const ELEMENT_TYPE: ElementType = ElementType::BF16;
 }
 
+// about half the coordinates of a point on the sphere are negative, which
+// u8 cannot represent. spread the start point over the whole range instead
 impl ElementVal for u8 {
     type Native = u8;
+    const ELEMENT_TYPE: ElementType = ElementType::U8;
+    fn random_start_point(dim: usize, _radius: f32) -> Vec<Self> {
+        rand::distributions::Uniform::from(0..=u8::MAX)
+            .sample_iter(rand::thread_rng())
+            .take(dim)
+            .collect()
+    }
+}
+// a point on the sphere rounds to (nearly) the zero vector, which is the
+// center of signed data. spread the start point over the positive half of
+// the range instead
+impl ElementVal for i8 {
+    type Native = i8;
+    const ELEMENT_TYPE: ElementType = ElementType::I8;
+    fn random_start_point(dim: usize, _radius: f32) -> Vec<Self> {
+        rand::distributions::Uniform::from(0..=i8::MAX)
+            .sample_iter(rand::thread_rng())
+            .take(dim)
+            .collect()
+    }
+}
+
+impl ElementVal for u64 {
+    type Native = u64;
+    const ELEMENT_TYPE: ElementType = ElementType::U64;
 }
 
 pub enum Points<'a, T> {
@@ -64,6 +161,39 @@ pub enum Points<'a, T> {
     Values { vals: &'a [T] },
 }
 
+impl<'a, T> Points<'a, T> {
+    // split a batch of points laid out back to back into the individual
+    // points, each of which is dim long
+    pub fn split(&self, dim: usize) -> anyhow::Result<Vec<Points<'a, T>>> {
+        let len = match self {
+            Points::QuantizerIn { vals } => vals.len(),
+            Points::Values { vals } => vals.len(),
+        };
+        if dim == 0 || len % dim != 0 {
+            anyhow::bail!("points.len: {} is not a multiple of dim: {}", len, dim);
+        }
+        Ok(match self {
+            Points::QuantizerIn { vals } => vals
+                .chunks(dim)
+                .map(|vals| Points::QuantizerIn { vals })
+                .collect(),
+            Points::Values { vals } => vals
+                .chunks(dim)
+                .map(|vals| Points::Values { vals })
+                .collect(),
+        })
+    }
+
+    // the full precision values of QuantizerIn points, which re-ranking
+    // scores the candidates of a search against
+    pub fn full_precision(&self) -> Option<&'a [f32]> {
+        match self {
+            Points::QuantizerIn { vals } => Some(vals),
+            Points::Values { .. } => None,
+        }
+    }
+}
+
 // primary trait that enables an obj to act as an ANNIndex - this
 // allows us to use multiple different backends in the future.
 pub trait ANNIndex: Send + Sync {
@@ -74,13 +204,218 @@ pub trait ANNIndex: Send + Sync {
     fn insert(&self, eids: &[EId], data: Points<Self::Val>) -> anyhow::Result<()>;
     fn delete(&self, eids: &[EId]) -> anyhow::Result<()>;
     fn search(&self, q: Points<Self::Val>, k: usize) -> anyhow::Result<Vec<Node>>;
-    fn save(&self) -> anyhow::Result<()>;
+    // every point within radius of q (as measured by the index's metric),
+    // closest first
+    fn range_search(&self, q: Points<Self::Val>, radius: f32) -> anyhow::Result<Vec<Node>>;
+    // search for many queries at once, q holds the queries back to back and
+    // the results come back in the same order
+    fn search_batch(&self, q: Points<Self::Val>, k: usize) -> anyhow::Result<Vec<Vec<Node>>>;
+    fn stats(&self) -> IndexStats;
+    // the vectors stored under eids (None for eids not in the index) with
+    // the padding stripped back to the configured dim, see stores_normalized()
+    fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<Self::Val>>>>;
+    fn contains(&self, eid: &EId) -> bool;
+    // true when the metric pre-processes (normalizes) inserted points, get()
+    // then returns the normalized form rather than what was inserted
+    fn stores_normalized(&self) -> bool;
+    // every live (eid, vector) pair, the eids are snapshotted up front so
+    // points deleted while iterating are skipped
+    fn iter(&self) -> Box<dyn Iterator<Item = anyhow::Result<(EId, Vec<Self::Val>)>> + '_>;
+    // persist the index under the directory at path, the directory is created
+    // if it does not exist yet and existing index files are overwritten
+    fn save(&self, path: &Path) -> anyhow::Result<()>;
+    // re-open an index previously written out by save(..)
+    fn load(path: &Path) -> anyhow::Result<Self>
+    where
+        Self: Sized;
 }
 
 // we use a 16 byte representation for EIds - this would allow clients to
 // ship us UUIDs as their resource identifiers with no addn work needed
 pub type EId = [u8; 16];
 
+// categorical labels attached to a point (ie. tenant, language, source),
+// clients map their own label values onto these ids
+pub type Label = u32;
+
+// predicate a filtered search must satisfy, labels are matched against the
+// label set every point was inserted with
+#[derive(Debug, Clone)]
+pub enum LabelFilter {
+    // the point carries at least one of the labels
+    Any(Vec<Label>),
+    // the point carries every one of the labels
+    All(Vec<Label>),
+}
+
+impl LabelFilter {
+    pub fn labels(&self) -> &[Label] {
+        match self {
+            LabelFilter::Any(labels) => labels,
+            LabelFilter::All(labels) => labels,
+        }
+    }
+
+    // point_labels must be sorted
+    pub fn matches(&self, point_labels: &[Label]) -> bool {
+        match self {
+            LabelFilter::Any(labels) => labels
+                .iter()
+                .any(|label| point_labels.binary_search(label).is_ok()),
+            LabelFilter::All(labels) => labels
+                .iter()
+                .all(|label| point_labels.binary_search(label).is_ok()),
+        }
+    }
+}
+
+// a set of points, named either by their EIds or by their internal vids
+#[derive(Debug, Clone)]
+pub enum IdSet {
+    EIds(HashSet<EId>),
+    VIds(RoaringTreemap),
+}
+
+impl IdSet {
+    pub fn contains(&self, vid: usize, eid: &EId) -> bool {
+        match self {
+            IdSet::EIds(eids) => eids.contains(eid),
+            IdSet::VIds(vids) => vids.contains(vid as u64),
+        }
+    }
+}
+
+// restricts a search to the points in allow (every point when None) that
+// are not part of deny
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub allow: Option<IdSet>,
+    pub deny: Option<IdSet>,
+}
+
+impl SearchFilter {
+    pub fn passes(&self, vid: usize, eid: &EId) -> bool {
+        self.allow
+            .as_ref()
+            .map_or(true, |ids| ids.contains(vid, eid))
+            && !self
+                .deny
+                .as_ref()
+                .map_or(false, |ids| ids.contains(vid, eid))
+    }
+}
+
+// number of vectors fetched per get(..) call while iterating an index
+const ITER_BATCH_SIZE: usize = 256;
+
+// drives ANNIndex::iter() for indexes that can look points up by eid
+pub(crate) fn iter_by_get<'a, I: ANNIndex>(
+    index: &'a I,
+    eids: Vec<EId>,
+) -> Box<dyn Iterator<Item = anyhow::Result<(EId, Vec<I::Val>)>> + 'a> {
+    Box::new(
+        (0..eids.len())
+            .step_by(ITER_BATCH_SIZE)
+            .flat_map(move |idx_s| {
+                let batch =
+                    eids[idx_s..std::cmp::min(idx_s + ITER_BATCH_SIZE, eids.len())].to_vec();
+                let items: Vec<anyhow::Result<(EId, Vec<I::Val>)>> = match index.get(&batch) {
+                    Ok(vecs) => batch
+                        .into_iter()
+                        .zip(vecs)
+                        .filter_map(|(eid, vec)| vec.map(|vec| Ok((eid, vec))))
+                        .collect(),
+                    Err(err) => vec![Err(err)],
+                };
+                items
+            }),
+    )
+}
+
+// point in time health report of an index, see ANNIndex::stats()
+#[derive(Default, Clone, Debug)]
+pub struct IndexStats {
+    pub num_live: usize,
+    // deleted points that are still awaiting consolidation
+    pub num_deleted: usize,
+    // locations freed up by consolidation that inserts can reuse
+    pub num_empty_slots: usize,
+    // None for indexes that grow without bound
+    pub capacity: Option<usize>,
+    // None for indexes that are not graph based (or that would need a full
+    // pass over their files to gather them)
+    pub graph: Option<GraphStats>,
+    // estimated heap bytes held by each structure of the index
+    pub memory_bytes: BTreeMap<&'static str, usize>,
+    pub quantizers: QuantizerStats,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct GraphStats {
+    // out-degrees over the live points
+    pub min_degree: usize,
+    pub avg_degree: f32,
+    pub max_degree: usize,
+    // degree_histogram[d] is the number of live points with out-degree d
+    pub degree_histogram: Vec<usize>,
+    // live points that are close to being cut off from the graph
+    pub num_degree_lt_2: usize,
+    // number of edges tracked by the reverse (in) graph
+    pub in_graph_edges: usize,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct QuantizerStats {
+    // quantile the scalar quantizer clips QuantizerIn points at
+    pub scalar_quantile: Option<f32>,
+    pub pq: Option<product_quantizer::PQParams>,
+    // the codebooks are trained on the first inserted batch
+    pub pq_trained: bool,
+    // candidates per result re-scored at full precision, see RerankParams
+    pub rerank_factor: Option<usize>,
+    // bits per code of the scalar quantizer and whether its ranges are fit
+    // to every dimension separately
+    pub scalar_bits: Option<usize>,
+    pub scalar_per_dimension: bool,
+    // of the points quantized since the ranges were last fit
+    pub scalar_reconstruction_error: Option<scalar_quantizer::ReconstructionError>,
+    // how far the values have moved from the fitted ranges, see
+    // ScalarQuantizer::drift
+    pub scalar_drift: Option<f32>,
+}
+
+impl GraphStats {
+    // gathers the stats from the out-degree of every live point
+    pub fn from_degrees(degrees: impl Iterator<Item = usize>, in_graph_edges: usize) -> Self {
+        let mut stats = GraphStats {
+            min_degree: usize::MAX,
+            in_graph_edges,
+            ..Default::default()
+        };
+        let mut total: usize = 0;
+        let mut count: usize = 0;
+        degrees.for_each(|degree| {
+            stats.min_degree = std::cmp::min(stats.min_degree, degree);
+            stats.max_degree = std::cmp::max(stats.max_degree, degree);
+            if stats.degree_histogram.len() <= degree {
+                stats.degree_histogram.resize(degree + 1, 0);
+            }
+            stats.degree_histogram[degree] += 1;
+            if degree < 2 {
+                stats.num_degree_lt_2 += 1;
+            }
+            total += degree;
+            count += 1;
+        });
+        if count == 0 {
+            stats.min_degree = 0;
+        } else {
+            stats.avg_degree = total as f32 / count as f32;
+        }
+        stats
+    }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Node {
     pub vid: usize,
@@ -1,13 +1,55 @@
+use memmap2::{MmapMut, MmapOptions};
 use num::Num;
+use std::fs;
+use std::marker::PhantomData;
 use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone)]
 #[repr(align(32))]
 pub struct AlignToThirtyTwo([u8; 32]);
 
+// the memory behind an AlignedDataStore - either an over-aligned heap
+// allocation or a file mapped into our address space. mappings always start
+// on a page boundary so the 32 byte alignment guarantee holds for both.
+#[derive(Debug)]
+pub enum Backing<T> {
+    Heap(Vec<T>),
+    Mapped {
+        mmap: MmapMut,
+        path: PathBuf,
+        len: usize,
+        _marker: PhantomData<T>,
+    },
+}
+
+impl<T> Deref for Backing<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        match self {
+            Backing::Heap(vec) => &vec[..],
+            Backing::Mapped { mmap, len, .. } => unsafe {
+                std::slice::from_raw_parts(mmap.as_ptr() as *const T, *len)
+            },
+        }
+    }
+}
+
+impl<T> DerefMut for Backing<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Backing::Heap(vec) => &mut vec[..],
+            Backing::Mapped { mmap, len, .. } => unsafe {
+                std::slice::from_raw_parts_mut(mmap.as_mut_ptr() as *mut T, *len)
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AlignedDataStore<T> {
-    pub data: Vec<T>,
+    pub data: Backing<T>,
     pub num_vectors: usize,
 }
 
@@ -20,11 +62,11 @@ impl<T: Num + std::marker::Copy> AlignedDataStore<T> {
         let len_units = aligned.len();
         let cap_units = aligned.capacity();
         mem::forget(aligned);
-        Vec::from_raw_parts(
-            ptr as *mut T,
-            len_units * mem::size_of::<AlignToThirtyTwo>(),
-            cap_units * mem::size_of::<AlignToThirtyTwo>(),
-        )
+        // the Vec<T> is measured in Ts, not bytes. every element type we
+        // support is 1, 2, 4 or 8 bytes wide so a unit holds a whole number
+        debug_assert_eq!(mem::size_of::<AlignToThirtyTwo>() % mem::size_of::<T>(), 0);
+        let per_unit = mem::size_of::<AlignToThirtyTwo>() / mem::size_of::<T>();
+        Vec::from_raw_parts(ptr as *mut T, len_units * per_unit, cap_units * per_unit)
     }
     pub fn aligned_insert(&mut self, id: usize, data: &[T]) {
         let ptr = self.data.as_ptr();
@@ -47,11 +89,114 @@ impl<T: Num + std::marker::Copy> AlignedDataStore<T> {
             data_vec.set_len(total_internal_points * aligned_dim);
         }
         return AlignedDataStore {
-            data: data_vec,
+            data: Backing::Heap(data_vec),
             num_vectors: 0,
         };
     }
 
+    // create a store backed by a new file at path, the store starts zeroed
+    // out. an existing file (ie. one written out by a save) is left alone
+    // and reported instead, those are re-opened through open_mmap(..)
+    pub fn new_mmap(
+        path: &Path,
+        total_internal_points: usize,
+        aligned_dim: usize,
+    ) -> anyhow::Result<AlignedDataStore<T>> {
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                anyhow::bail!("mapped file: {:?} already exists", path)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        AlignedDataStore::map_file(file, path, total_internal_points * aligned_dim)
+    }
+
+    // map an existing file (ie. one written out by a save) without reading
+    // it in, pages are faulted in on demand as vectors get touched
+    pub fn open_mmap(
+        path: &Path,
+        total_internal_points: usize,
+        aligned_dim: usize,
+    ) -> anyhow::Result<AlignedDataStore<T>> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let len = total_internal_points * aligned_dim;
+        let found = file.metadata()?.len() as usize;
+        if found != len * mem::size_of::<T>() {
+            anyhow::bail!(
+                "mapped file: {:?} is {} bytes, expected: {}",
+                path,
+                found,
+                len * mem::size_of::<T>()
+            );
+        }
+        AlignedDataStore::map_file(file, path, len)
+    }
+
+    fn map_file(file: fs::File, path: &Path, len: usize) -> anyhow::Result<AlignedDataStore<T>> {
+        let n_bytes = len * mem::size_of::<T>();
+        if (file.metadata()?.len() as usize) < n_bytes {
+            file.set_len(n_bytes as u64)?;
+        }
+        // mapping an empty file is an error on some platforms
+        let mmap = unsafe { MmapOptions::new().len(n_bytes.max(1)).map_mut(&file)? };
+        Ok(AlignedDataStore {
+            data: Backing::Mapped {
+                mmap: mmap,
+                path: path.to_path_buf(),
+                len: len,
+                _marker: PhantomData,
+            },
+            num_vectors: 0,
+        })
+    }
+
+    // grow the store to hold total_internal_points vectors, the existing
+    // vectors keep their position. mapped stores extend their file and are
+    // re-mapped, which leaves the contents of the file intact
+    pub fn grow(&mut self, total_internal_points: usize, aligned_dim: usize) -> anyhow::Result<()> {
+        let len = total_internal_points * aligned_dim;
+        if len <= self.data.len() {
+            return Ok(());
+        }
+        let grown = match &self.data {
+            Backing::Heap(vec) => {
+                let mut grown = AlignedDataStore::<T>::new(total_internal_points, aligned_dim);
+                grown.data[..vec.len()].copy_from_slice(&vec[..]);
+                grown.data
+            }
+            Backing::Mapped { mmap, path, .. } => {
+                mmap.flush()?;
+                let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+                AlignedDataStore::<T>::map_file(file, path, len)?.data
+            }
+        };
+        self.data = grown;
+        Ok(())
+    }
+
+    // the file we are mapped onto, None for heap backed stores
+    pub fn mapped_path(&self) -> Option<&Path> {
+        match &self.data {
+            Backing::Heap(_) => None,
+            Backing::Mapped { path, .. } => Some(path),
+        }
+    }
+
+    // push any dirty pages of a mapped store out to disk, no-op on the heap
+    pub fn flush(&self) -> anyhow::Result<()> {
+        match &self.data {
+            Backing::Heap(_) => {}
+            Backing::Mapped { mmap, .. } => mmap.flush()?,
+        }
+        Ok(())
+    }
+
     // TODO(infrawhispers) - do we still need this??
     // pub fn aligned_add(&self, id: usize, length: usize, data: &[f32]) {
     //     let ptr = self.data.as_ptr();
@@ -0,0 +1,641 @@
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::{Mutex, RwLock};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::ann;
+use crate::ann::{bf16, f16, ANNIndex, ANNParams, ANNTypes, EId, ElementType};
+use crate::diskannv1::{DiskANNV1Index, MaintenanceParams};
+use crate::flat::FlatIndex;
+use crate::maintenance::MaintenanceHandle;
+use crate::metric;
+use crate::metric::MetricType;
+use crate::persist;
+
+const CATALOG_FILE: &str = "catalog";
+const CATALOG_MAGIC: &[u8; 4] = b"ACAT";
+const CATALOG_VERSION: u32 = 1;
+// every collection is saved to a directory of its own under here
+const COLLECTIONS_DIR: &str = "collections";
+
+#[derive(Debug)]
+pub struct CollectionParams {
+    pub metric: MetricType,
+    pub element: ElementType,
+    // the backend (and dim) of the collection follow from the variant, only
+    // Flat and DiskANN can be managed for now
+    pub index: ANNParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub dim: usize,
+    pub metric: MetricType,
+    pub element: ElementType,
+    pub backend: ANNTypes,
+}
+
+// the element type agnostic half of ANNIndex, the typed half is reached
+// through Collection::index()
+trait AnyIndex: Send + Sync {
+    fn delete(&self, eids: &[EId]) -> anyhow::Result<()>;
+    fn contains(&self, eid: &EId) -> bool;
+    fn stats(&self) -> ann::IndexStats;
+    fn save(&self, path: &Path) -> anyhow::Result<()>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<TVal: 'static> AnyIndex for Arc<dyn ANNIndex<Val = TVal>> {
+    fn delete(&self, eids: &[EId]) -> anyhow::Result<()> {
+        self.as_ref().delete(eids)
+    }
+    fn contains(&self, eid: &EId) -> bool {
+        self.as_ref().contains(eid)
+    }
+    fn stats(&self) -> ann::IndexStats {
+        self.as_ref().stats()
+    }
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.as_ref().save(path)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Collection {
+    info: CollectionInfo,
+    index: Box<dyn AnyIndex>,
+    // consolidates the deletes of a DiskANN collection in the background,
+    // stopped once the last handle to the collection is dropped
+    _maintenance: Option<MaintenanceHandle>,
+}
+
+impl Collection {
+    pub fn info(&self) -> &CollectionInfo {
+        &self.info
+    }
+
+    // the index behind the collection, TVal has to match the element type
+    // the collection was created with
+    pub fn index<TVal: ann::ElementVal + 'static>(
+        &self,
+    ) -> anyhow::Result<Arc<dyn ANNIndex<Val = TVal>>> {
+        match self
+            .index
+            .as_any()
+            .downcast_ref::<Arc<dyn ANNIndex<Val = TVal>>>()
+        {
+            Some(index) => Ok(index.clone()),
+            None => bail!(
+                "collection: {} holds {:?} elements, not: {}",
+                self.info.name,
+                self.info.element,
+                std::any::type_name::<TVal>()
+            ),
+        }
+    }
+
+    pub fn delete(&self, eids: &[EId]) -> anyhow::Result<()> {
+        self.index.delete(eids)
+    }
+
+    pub fn contains(&self, eid: &EId) -> bool {
+        self.index.contains(eid)
+    }
+
+    pub fn stats(&self) -> ann::IndexStats {
+        self.index.stats()
+    }
+}
+
+enum Source<'a> {
+    New(&'a ANNParams),
+    Load(&'a Path),
+}
+
+// the maintenance of a DiskANN index is started here, once the index is
+// behind AnyIndex it can no longer be reached
+fn open_with<TMetric, TVal>(info: CollectionInfo, source: Source) -> anyhow::Result<Collection>
+where
+    TVal: ann::ElementVal + 'static,
+    TMetric: metric::Metric<TVal> + 'static,
+{
+    let diskann = |index: DiskANNV1Index<TMetric, TVal>| {
+        let index = Arc::new(index);
+        let maintenance = index.start_maintenance(MaintenanceParams::default());
+        (index as Arc<dyn ANNIndex<Val = TVal>>, Some(maintenance))
+    };
+    let (index, maintenance): (Arc<dyn ANNIndex<Val = TVal>>, Option<MaintenanceHandle>) =
+        match (info.backend, source) {
+            (ANNTypes::Flat, Source::New(params)) => {
+                (Arc::new(FlatIndex::<TMetric, TVal>::new(params)?), None)
+            }
+            (ANNTypes::Flat, Source::Load(path)) => {
+                (Arc::new(FlatIndex::<TMetric, TVal>::load(path)?), None)
+            }
+            (ANNTypes::DiskANN, Source::New(params)) => {
+                diskann(DiskANNV1Index::<TMetric, TVal>::new(params)?)
+            }
+            (ANNTypes::DiskANN, Source::Load(path)) => {
+                diskann(DiskANNV1Index::<TMetric, TVal>::load(path)?)
+            }
+        };
+    Ok(Collection {
+        info,
+        index: Box::new(index),
+        _maintenance: maintenance,
+    })
+}
+
+// picks the concrete index for the (metric, element) pair of info
+fn open(info: CollectionInfo, source: Source) -> anyhow::Result<Collection> {
+    use metric::{Hamming, MetricCosine, MetricInnerProduct, MetricJaccard, MetricL1, MetricL2};
+    match (info.metric, info.element) {
+        (MetricType::L2, ElementType::F32) => open_with::<MetricL2, f32>(info, source),
+        (MetricType::L2, ElementType::F16) => open_with::<MetricL2, f16>(info, source),
+        (MetricType::L2, ElementType::BF16) => open_with::<MetricL2, bf16>(info, source),
+        (MetricType::L2, ElementType::U8) => open_with::<MetricL2, u8>(info, source),
+        (MetricType::L2, ElementType::I8) => open_with::<MetricL2, i8>(info, source),
+        (MetricType::L1, ElementType::F32) => open_with::<MetricL1, f32>(info, source),
+        (MetricType::L1, ElementType::F16) => open_with::<MetricL1, f16>(info, source),
+        (MetricType::L1, ElementType::BF16) => open_with::<MetricL1, bf16>(info, source),
+        (MetricType::L1, ElementType::U8) => open_with::<MetricL1, u8>(info, source),
+        (MetricType::L1, ElementType::I8) => open_with::<MetricL1, i8>(info, source),
+        (MetricType::Cosine, ElementType::F32) => open_with::<MetricCosine, f32>(info, source),
+        (MetricType::Cosine, ElementType::F16) => open_with::<MetricCosine, f16>(info, source),
+        (MetricType::Cosine, ElementType::BF16) => open_with::<MetricCosine, bf16>(info, source),
+        (MetricType::Cosine, ElementType::U8) => open_with::<MetricCosine, u8>(info, source),
+        (MetricType::Cosine, ElementType::I8) => open_with::<MetricCosine, i8>(info, source),
+        (MetricType::InnerProduct, ElementType::F32) => {
+            open_with::<MetricInnerProduct, f32>(info, source)
+        }
+        (MetricType::InnerProduct, ElementType::F16) => {
+            open_with::<MetricInnerProduct, f16>(info, source)
+        }
+        (MetricType::InnerProduct, ElementType::BF16) => {
+            open_with::<MetricInnerProduct, bf16>(info, source)
+        }
+        (MetricType::InnerProduct, ElementType::I8) => {
+            open_with::<MetricInnerProduct, i8>(info, source)
+        }
+        (MetricType::Hamming, ElementType::F32) => open_with::<Hamming, f32>(info, source),
+        (MetricType::Hamming, ElementType::U64) => open_with::<Hamming, u64>(info, source),
+        (MetricType::Jaccard, ElementType::U64) => open_with::<MetricJaccard, u64>(info, source),
+        (metric, element) => bail!(
+            "metric: {:?} is not supported over {:?} elements",
+            metric,
+            element
+        ),
+    }
+}
+
+// names double up as directory names when the collections are saved
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!(
+            "invalid collection name: {:?}, expected [A-Za-z0-9_-]+",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn write_catalog(path: &Path, infos: &[&CollectionInfo]) -> anyhow::Result<()> {
+    persist::write_file(&path.join(CATALOG_FILE), |w| {
+        persist::write_header(w, CATALOG_MAGIC, CATALOG_VERSION)?;
+        persist::write_usize(w, infos.len())?;
+        for info in infos.iter() {
+            persist::write_str(w, &info.name)?;
+            persist::write_usize(w, info.dim)?;
+            w.write_u32::<LittleEndian>(info.metric as u32)?;
+            w.write_u32::<LittleEndian>(info.element as u32)?;
+            w.write_u32::<LittleEndian>(info.backend as u32)?;
+        }
+        Ok(())
+    })
+}
+
+fn read_catalog(path: &Path) -> anyhow::Result<Vec<CollectionInfo>> {
+    let mut r = persist::open_file(&path.join(CATALOG_FILE))?;
+    persist::read_header(&mut r, CATALOG_MAGIC, CATALOG_VERSION)?;
+    let num_collections = persist::read_usize(&mut r)?;
+    let mut infos = Vec::with_capacity(persist::capacity_hint(num_collections));
+    for _ in 0..num_collections {
+        let name = persist::read_str(&mut r)?;
+        let dim = persist::read_usize(&mut r)?;
+        let metric = MetricType::from_u32(r.read_u32::<LittleEndian>()?)?;
+        let element = ElementType::from_u32(r.read_u32::<LittleEndian>()?)?;
+        let backend = match r.read_u32::<LittleEndian>()? {
+            1 => ANNTypes::DiskANN,
+            2 => ANNTypes::Flat,
+            val => bail!("unknown backend: {}", val),
+        };
+        infos.push(CollectionInfo {
+            name,
+            dim,
+            metric,
+            element,
+            backend,
+        });
+    }
+    Ok(infos)
+}
+
+// named collections, each backed by an index of its own - the catalog of
+// collections is written out alongside the indexes on save so that they
+// all re-open together on load
+#[derive(Default)]
+pub struct CollectionManager {
+    collections: RwLock<HashMap<String, Arc<Collection>>>,
+    // saves go one at a time, see save()
+    save_lock: Mutex<()>,
+}
+
+impl CollectionManager {
+    pub fn new() -> CollectionManager {
+        CollectionManager::default()
+    }
+
+    pub fn create(&self, name: &str, params: &CollectionParams) -> anyhow::Result<Arc<Collection>> {
+        check_name(name)?;
+        let (backend, dim) = match &params.index {
+            ANNParams::Flat { params } => (ANNTypes::Flat, params.dim),
+            ANNParams::DiskANN { params } => (ANNTypes::DiskANN, params.dim),
+            ANNParams::DiskANNSSD { .. } => {
+                bail!("DiskANNSSD indexes cannot be managed as collections")
+            }
+        };
+        let mut collections = self.collections.write();
+        if collections.contains_key(name) {
+            bail!("collection: {} already exists", name);
+        }
+        let info = CollectionInfo {
+            name: name.to_string(),
+            dim,
+            metric: params.metric,
+            element: params.element,
+            backend,
+        };
+        let collection = Arc::new(open(info, Source::New(&params.index))?);
+        collections.insert(name.to_string(), collection.clone());
+        Ok(collection)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Collection>> {
+        self.collections.read().get(name).cloned()
+    }
+
+    // handles to the collection that are still around keep working, the
+    // collection just is no longer part of the catalog
+    pub fn drop_collection(&self, name: &str) -> anyhow::Result<()> {
+        match self.collections.write().remove(name) {
+            Some(_) => Ok(()),
+            None => bail!("collection: {} does not exist", name),
+        }
+    }
+
+    // ordered by name
+    pub fn list(&self) -> Vec<CollectionInfo> {
+        let mut infos: Vec<CollectionInfo> = self
+            .collections
+            .read()
+            .values()
+            .map(|collection| collection.info.clone())
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    // persist every collection and the catalog under the directory at path,
+    // collections dropped since an earlier save to path are removed from it
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        // a concurrent save could otherwise remove the directory of a
+        // collection this one has just written out
+        let _save_lock = self.save_lock.lock();
+        fs::create_dir_all(path.join(COLLECTIONS_DIR))?;
+        let stale: Vec<CollectionInfo> = if path.join(CATALOG_FILE).exists() {
+            read_catalog(path)?
+        } else {
+            vec![]
+        };
+        // the indexes are written out from a snapshot of the collections so
+        // lookups, creates and drops carry on while the save is in flight
+        let mut collections: Vec<Arc<Collection>> =
+            self.collections.read().values().cloned().collect();
+        collections.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+        for collection in collections.iter() {
+            collection
+                .index
+                .save(&path.join(COLLECTIONS_DIR).join(&collection.info.name))?;
+        }
+        let infos: Vec<&CollectionInfo> = collections
+            .iter()
+            .map(|collection| &collection.info)
+            .collect();
+        write_catalog(path, &infos)?;
+        for info in stale.iter() {
+            let directory = path.join(COLLECTIONS_DIR).join(&info.name);
+            if !infos.iter().any(|saved| saved.name == info.name) && directory.exists() {
+                fs::remove_dir_all(&directory)?;
+            }
+        }
+        Ok(())
+    }
+
+    // re-open every collection in the catalog previously written out by
+    // save(..)
+    pub fn load(path: &Path) -> anyhow::Result<CollectionManager> {
+        let mut collections = HashMap::new();
+        for info in read_catalog(path)? {
+            check_name(&info.name)?;
+            let directory = path.join(COLLECTIONS_DIR).join(&info.name);
+            let name = info.name.clone();
+            collections.insert(name, Arc::new(open(info, Source::Load(&directory))?));
+        }
+        Ok(CollectionManager {
+            collections: RwLock::new(collections),
+            save_lock: Mutex::new(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskannv1::DiskANNParams;
+    use crate::flat::FlatParams;
+
+    fn eid_for(id: usize) -> EId {
+        let mut eid = [0u8; 16];
+        eid[0..8].copy_from_slice(&id.to_le_bytes());
+        eid
+    }
+
+    fn flat_params(metric: MetricType, element: ElementType, dim: usize) -> CollectionParams {
+        CollectionParams {
+            metric,
+            element,
+            index: ANNParams::Flat {
+                params: FlatParams {
+                    dim,
+                    segment_size_kb: 1,
+                    mmap_dir: None,
+                    pq: None,
+                    rerank: None,
+                    quantizer: None,
+                },
+            },
+        }
+    }
+
+    fn diskann_params(metric: MetricType, element: ElementType, dim: usize) -> CollectionParams {
+        CollectionParams {
+            metric,
+            element,
+            index: ANNParams::DiskANN {
+                params: DiskANNParams {
+                    dim,
+                    max_points: 100,
+                    indexing_threads: None,
+                    indexing_range: 16,
+                    indexing_queue_size: 32,
+                    indexing_maxc: 50,
+                    indexing_alpha: 1.2,
+                    maintenance_period_millis: 500,
+                    mmap_dir: None,
+                    pq: None,
+                    growth_factor: None,
+                    rerank: None,
+                    quantizer: None,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn create_and_drop() {
+        let manager = CollectionManager::new();
+        let docs = manager
+            .create("docs", &flat_params(MetricType::L2, ElementType::F32, 8))
+            .unwrap();
+        manager
+            .create(
+                "images",
+                &diskann_params(MetricType::Cosine, ElementType::U8, 16),
+            )
+            .unwrap();
+        manager
+            .create("docs", &flat_params(MetricType::L2, ElementType::F32, 8))
+            .map(|_| ())
+            .expect_err("names are unique");
+        manager
+            .create("../docs", &flat_params(MetricType::L2, ElementType::F32, 8))
+            .map(|_| ())
+            .expect_err("names must be safe to use as directories");
+        manager
+            .create(
+                "sets",
+                &flat_params(MetricType::Jaccard, ElementType::F32, 8),
+            )
+            .map(|_| ())
+            .expect_err("jaccard needs packed bits");
+        assert_eq!(
+            vec![
+                CollectionInfo {
+                    name: "docs".to_string(),
+                    dim: 8,
+                    metric: MetricType::L2,
+                    element: ElementType::F32,
+                    backend: ANNTypes::Flat,
+                },
+                CollectionInfo {
+                    name: "images".to_string(),
+                    dim: 16,
+                    metric: MetricType::Cosine,
+                    element: ElementType::U8,
+                    backend: ANNTypes::DiskANN,
+                },
+            ],
+            manager.list()
+        );
+
+        let points: Vec<f32> = (0..80).map(|x| x as f32).collect();
+        let eids: Vec<EId> = (0..10).map(eid_for).collect();
+        docs.index::<u8>().map(|_| ()).expect_err("docs holds f32s");
+        let index = manager.get("docs").unwrap().index::<f32>().unwrap();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points })
+            .unwrap();
+        let res = index
+            .search(
+                ann::Points::Values {
+                    vals: &points[8..16],
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(eids[1], res[0].eid);
+        docs.delete(&eids[1..2]).unwrap();
+        assert!(!docs.contains(&eids[1]));
+        assert_eq!(9, docs.stats().num_live);
+
+        manager.drop_collection("docs").unwrap();
+        manager
+            .drop_collection("docs")
+            .expect_err("docs is gone already");
+        assert!(manager.get("docs").is_none());
+        assert_eq!(1, manager.list().len());
+        // the handle outlives the catalog entry
+        assert!(docs.contains(&eids[0]));
+    }
+
+    #[test]
+    fn diskann_maintenance() {
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-collections-maintenance-{}",
+            std::process::id()
+        ));
+        let manager = CollectionManager::new();
+        let eids: Vec<EId> = (0..50).map(eid_for).collect();
+        let points: Vec<f32> = (0..800).map(|x| x as f32).collect();
+        let vectors = manager
+            .create(
+                "vectors",
+                &diskann_params(MetricType::L2, ElementType::F32, 16),
+            )
+            .unwrap();
+        vectors
+            .index::<f32>()
+            .unwrap()
+            .insert(&eids, ann::Points::Values { vals: &points })
+            .unwrap();
+        manager
+            .save(&directory)
+            .expect("unable to save the collections");
+        let loaded = CollectionManager::load(&directory).unwrap();
+        // the deletes are consolidated in the background, for the created
+        // collections and the loaded ones alike
+        for collection in [vectors, loaded.get("vectors").unwrap()] {
+            collection.delete(&eids[0..10]).unwrap();
+            assert_eq!(10, collection.stats().num_deleted);
+            let start = std::time::Instant::now();
+            while collection.stats().num_deleted > 0 {
+                assert!(
+                    start.elapsed() < std::time::Duration::from_secs(30),
+                    "the deletes were never consolidated"
+                );
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            assert_eq!(40, collection.stats().num_live);
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved collections");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let directory =
+            std::env::temp_dir().join(format!("anansi-collections-{}", std::process::id()));
+        let manager = CollectionManager::new();
+        let eids: Vec<EId> = (0..10).map(eid_for).collect();
+        let points: Vec<f32> = (0..80).map(|x| x as f32).collect();
+        let bits: Vec<u64> = (0..10).map(|x| 1u64 << x).collect();
+        manager
+            .create("docs", &flat_params(MetricType::L2, ElementType::F32, 8))
+            .unwrap()
+            .index::<f32>()
+            .unwrap()
+            .insert(&eids, ann::Points::Values { vals: &points })
+            .unwrap();
+        manager
+            .create(
+                "sets",
+                &flat_params(MetricType::Jaccard, ElementType::U64, 1),
+            )
+            .unwrap()
+            .index::<u64>()
+            .unwrap()
+            .insert(&eids, ann::Points::Values { vals: &bits })
+            .unwrap();
+        manager
+            .create("scratch", &flat_params(MetricType::L1, ElementType::F16, 4))
+            .unwrap();
+        manager
+            .save(&directory)
+            .expect("unable to save the collections");
+        manager.drop_collection("scratch").unwrap();
+        manager
+            .save(&directory)
+            .expect("unable to save the collections");
+        assert!(!directory.join(COLLECTIONS_DIR).join("scratch").exists());
+
+        let loaded = CollectionManager::load(&directory).unwrap();
+        assert_eq!(manager.list(), loaded.list());
+        let docs = loaded.get("docs").unwrap().index::<f32>().unwrap();
+        let res = docs
+            .search(
+                ann::Points::Values {
+                    vals: &points[16..24],
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(eids[2], res[0].eid);
+        let sets = loaded.get("sets").unwrap().index::<u64>().unwrap();
+        assert_eq!(Some(vec![bits[3]]), sets.get(&eids[3..4]).unwrap()[0]);
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved collections");
+    }
+
+    #[test]
+    fn concurrent_saves() {
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-collections-concurrent-{}",
+            std::process::id()
+        ));
+        let manager = Arc::new(CollectionManager::new());
+        let eids: Vec<EId> = (0..100).map(eid_for).collect();
+        let points: Vec<f32> = (0..800).map(|x| x as f32).collect();
+        for name in ["a", "b", "c"] {
+            manager
+                .create(name, &flat_params(MetricType::L2, ElementType::F32, 8))
+                .unwrap()
+                .index::<f32>()
+                .unwrap()
+                .insert(&eids, ann::Points::Values { vals: &points })
+                .unwrap();
+        }
+        let savers: Vec<std::thread::JoinHandle<()>> = (0..4)
+            .map(|_| {
+                let manager = manager.clone();
+                let directory = directory.clone();
+                std::thread::spawn(move || {
+                    manager
+                        .save(&directory)
+                        .expect("unable to save the collections")
+                })
+            })
+            .collect();
+        // lookups are not held up by the saves
+        for _ in 0..100 {
+            assert_eq!(3, manager.list().len());
+            assert!(manager.get("a").is_some());
+        }
+        savers.into_iter().for_each(|saver| saver.join().unwrap());
+
+        let loaded = CollectionManager::load(&directory).unwrap();
+        assert_eq!(manager.list(), loaded.list());
+        for info in loaded.list() {
+            assert_eq!(100, loaded.get(&info.name).unwrap().stats().num_live);
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved collections");
+    }
+}
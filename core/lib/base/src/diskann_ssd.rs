@@ -0,0 +1,716 @@
+use crate::ann;
+use crate::ann::EId;
+use crate::av_store::AlignedDataStore;
+use crate::metric;
+use crate::nn_queue;
+use crate::persist;
+
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::RwLock;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct DiskANNSSDParams {
+    // directory holding an index written out by DiskANNV1Index::save_ssd(..)
+    pub path: PathBuf,
+    pub search_list_size: usize, // L
+    pub beam_width: usize,       // W - number of nodes fetched from disk per hop
+}
+
+// on-disk layout of an ssd resident index:
+//   disk.index - sector 0 holds the header (the layout and, from v3, the
+//                search params the index was last saved with), every
+//                following sector holds one or more fixed size node
+//                records: [vector][degree][nbrs; max_degree]
+//   disk.codes - the compressed vectors we keep in memory to guide the search
+//   disk.tags  - location -> tag mappings of the live points
+const SECTOR_LEN: usize = 4096;
+const SSD_MAGIC: &[u8; 4] = b"ASSD";
+const SSD_VERSION: u32 = 3;
+const CODES_MAGIC: &[u8; 4] = b"ASCD";
+const CODES_VERSION: u32 = 1;
+const TAGS_MAGIC: &[u8; 4] = b"ASTG";
+const TAGS_VERSION: u32 = 1;
+const LAYOUT_FILE: &str = "disk.index";
+const CODES_FILE: &str = "disk.codes";
+const TAGS_FILE: &str = "disk.tags";
+
+const DEFAULT_SEARCH_LIST_SIZE: usize = 100;
+const DEFAULT_BEAM_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct SSDLayout {
+    dim: usize,
+    aligned_dim: usize,
+    num_nodes: usize,
+    max_degree: usize,
+    start: usize,
+    node_len: usize,
+    nodes_per_sector: usize,
+    sectors_per_node: usize,
+}
+
+impl SSDLayout {
+    fn new(
+        dim: usize,
+        aligned_dim: usize,
+        num_nodes: usize,
+        max_degree: usize,
+        start: usize,
+        val_size: usize,
+    ) -> SSDLayout {
+        let node_len = aligned_dim * val_size + 4 + 4 * max_degree;
+        // small nodes are packed several to a sector, large ones span sectors
+        let (nodes_per_sector, sectors_per_node) = if node_len <= SECTOR_LEN {
+            (SECTOR_LEN / node_len, 1)
+        } else {
+            (1, (node_len + SECTOR_LEN - 1) / SECTOR_LEN)
+        };
+        SSDLayout {
+            dim,
+            aligned_dim,
+            num_nodes,
+            max_degree,
+            start,
+            node_len,
+            nodes_per_sector,
+            sectors_per_node,
+        }
+    }
+
+    fn node_offset(&self, vid: usize) -> u64 {
+        // sector 0 is reserved for the header
+        let sector = 1 + (vid / self.nodes_per_sector) * self.sectors_per_node;
+        (sector * SECTOR_LEN + (vid % self.nodes_per_sector) * self.node_len) as u64
+    }
+
+    fn num_sectors(&self) -> usize {
+        1 + ((self.num_nodes + self.nodes_per_sector - 1) / self.nodes_per_sector)
+            * self.sectors_per_node
+    }
+
+    // sector 0 of the layout file
+    fn header<TVal: ann::ElementVal>(
+        &self,
+        search_list_size: usize,
+        beam_width: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut header: Vec<u8> = Vec::with_capacity(SECTOR_LEN);
+        persist::write_header(&mut header, SSD_MAGIC, SSD_VERSION)?;
+        persist::write_element_type::<TVal>(&mut header)?;
+        persist::write_usize(&mut header, self.dim)?;
+        persist::write_usize(&mut header, self.aligned_dim)?;
+        persist::write_usize(&mut header, self.num_nodes)?;
+        persist::write_usize(&mut header, self.max_degree)?;
+        persist::write_usize(&mut header, self.start)?;
+        persist::write_usize(&mut header, search_list_size)?;
+        persist::write_usize(&mut header, beam_width)?;
+        if header.len() > SECTOR_LEN {
+            bail!("ssd header does not fit into a single sector");
+        }
+        header.resize(SECTOR_LEN, 0);
+        Ok(header)
+    }
+
+    // the layout along with the (search_list_size, beam_width) it was saved
+    // with, layouts from before v3 fall back to the defaults
+    fn read<TVal: ann::ElementVal>(path: &Path) -> anyhow::Result<(SSDLayout, usize, usize)> {
+        let mut r = persist::open_file(&path.join(LAYOUT_FILE))?;
+        let version = persist::read_header(&mut r, SSD_MAGIC, SSD_VERSION)?;
+        persist::check_element_type::<TVal>(&mut r, version < 2)?;
+        let dim = persist::read_usize(&mut r)?;
+        let aligned_dim = persist::read_usize(&mut r)?;
+        let num_nodes = persist::read_usize(&mut r)?;
+        let max_degree = persist::read_usize(&mut r)?;
+        let start = persist::read_usize(&mut r)?;
+        let layout = SSDLayout::new(
+            dim,
+            aligned_dim,
+            num_nodes,
+            max_degree,
+            start,
+            std::mem::size_of::<TVal>(),
+        );
+        if version < 3 {
+            return Ok((layout, DEFAULT_SEARCH_LIST_SIZE, DEFAULT_BEAM_WIDTH));
+        }
+        let search_list_size = persist::read_usize(&mut r)?;
+        let beam_width = persist::read_usize(&mut r)?;
+        Ok((layout, search_list_size, beam_width))
+    }
+}
+
+// per-dimension 8 bit scalar quantization of every node, this is all we keep
+// of the vectors in memory. the approximate distances it gives us are only
+// used to decide which nodes to fetch next, final distances always come
+// from the full precision vectors on disk.
+struct CompressedVectors {
+    aligned_dim: usize,
+    offsets: Vec<f32>,
+    scales: Vec<f32>,
+    codes: Vec<u8>,
+}
+
+impl CompressedVectors {
+    fn train<TVal: ann::ElementVal>(
+        vectors: &[TVal],
+        aligned_dim: usize,
+        num_nodes: usize,
+        is_active: impl Fn(usize) -> bool,
+    ) -> CompressedVectors {
+        let mut mins = vec![f32::MAX; aligned_dim];
+        let mut maxs = vec![f32::MIN; aligned_dim];
+        for vid in (0..num_nodes).filter(|vid| is_active(*vid)) {
+            let vec = &vectors[vid * aligned_dim..vid * aligned_dim + aligned_dim];
+            for j in 0..aligned_dim {
+                let val = vec[j].to_f32().unwrap_or_default();
+                mins[j] = mins[j].min(val);
+                maxs[j] = maxs[j].max(val);
+            }
+        }
+        let mut offsets = vec![0.0f32; aligned_dim];
+        let mut scales = vec![0.0f32; aligned_dim];
+        for j in 0..aligned_dim {
+            if mins[j] <= maxs[j] {
+                offsets[j] = mins[j];
+                scales[j] = (maxs[j] - mins[j]) / 255.0f32;
+            }
+        }
+        let mut codes = vec![0u8; num_nodes * aligned_dim];
+        for vid in 0..num_nodes {
+            for j in 0..aligned_dim {
+                let val = vectors[vid * aligned_dim + j].to_f32().unwrap_or_default();
+                codes[vid * aligned_dim + j] = if scales[j] > 0.0 {
+                    ((val - offsets[j]) / scales[j]).round() as u8
+                } else {
+                    0
+                };
+            }
+        }
+        CompressedVectors {
+            aligned_dim,
+            offsets,
+            scales,
+            codes,
+        }
+    }
+
+    fn decode_into<TVal: ann::ElementVal>(&self, vid: usize, out: &mut [TVal]) {
+        let codes = &self.codes[vid * self.aligned_dim..vid * self.aligned_dim + self.aligned_dim];
+        for j in 0..self.aligned_dim {
+            let val = self.offsets[j] + (codes[j] as f32) * self.scales[j];
+            out[j] = TVal::from_f32(val).unwrap_or_default();
+        }
+    }
+
+    fn save(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        persist::write_header(w, CODES_MAGIC, CODES_VERSION)?;
+        persist::write_usize(w, self.aligned_dim)?;
+        for j in 0..self.aligned_dim {
+            w.write_f32::<LittleEndian>(self.offsets[j])?;
+            w.write_f32::<LittleEndian>(self.scales[j])?;
+        }
+        persist::write_usize(w, self.codes.len())?;
+        w.write_all(&self.codes)?;
+        Ok(())
+    }
+
+    fn load(r: &mut impl Read) -> anyhow::Result<CompressedVectors> {
+        persist::read_header(r, CODES_MAGIC, CODES_VERSION)?;
+        let aligned_dim = persist::read_usize(r)?;
+        let mut offsets = Vec::with_capacity(aligned_dim);
+        let mut scales = Vec::with_capacity(aligned_dim);
+        for _ in 0..aligned_dim {
+            offsets.push(r.read_f32::<LittleEndian>()?);
+            scales.push(r.read_f32::<LittleEndian>()?);
+        }
+        let mut codes = vec![0u8; persist::read_usize(r)?];
+        r.read_exact(&mut codes)?;
+        Ok(CompressedVectors {
+            aligned_dim,
+            offsets,
+            scales,
+            codes,
+        })
+    }
+}
+
+// positional reads against the layout file - pread lets concurrent searches
+// share the file handle, elsewhere we fall back to seek + read under a lock
+struct SectorReader {
+    #[cfg(unix)]
+    file: fs::File,
+    #[cfg(not(unix))]
+    file: parking_lot::Mutex<fs::File>,
+}
+
+impl SectorReader {
+    fn open(path: &Path) -> anyhow::Result<SectorReader> {
+        let file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(err) => bail!("unable to open: {:?} - {}", path, err),
+        };
+        #[cfg(unix)]
+        return Ok(SectorReader { file });
+        #[cfg(not(unix))]
+        return Ok(SectorReader {
+            file: parking_lot::Mutex::new(file),
+        });
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileExt;
+            self.file.read_exact_at(buf, offset)?;
+        }
+        #[cfg(not(unix))]
+        {
+            use std::io::{Seek, SeekFrom};
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buf)?;
+        }
+        Ok(())
+    }
+}
+
+// lays out an in-memory graph + vectors in the sector format, this is invoked
+// by DiskANNV1Index::save_ssd(..) which owns the graph that gets written
+pub(crate) fn write_ssd_index<TVal: ann::ElementVal>(
+    path: &Path,
+    dim: usize,
+    aligned_dim: usize,
+    start: usize,
+    vectors: &[TVal],
+    nbrs: impl Fn(usize) -> Vec<usize>,
+    tags: &HashMap<usize, EId>,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(path)?;
+    let num_nodes = vectors.len() / aligned_dim;
+    let max_degree = (0..num_nodes).map(|vid| nbrs(vid).len()).max().unwrap_or(0);
+    let layout = SSDLayout::new(
+        dim,
+        aligned_dim,
+        num_nodes,
+        max_degree,
+        start,
+        std::mem::size_of::<TVal>(),
+    );
+    persist::write_file(&path.join(LAYOUT_FILE), |w| {
+        w.write_all(&layout.header::<TVal>(DEFAULT_SEARCH_LIST_SIZE, DEFAULT_BEAM_WIDTH)?)?;
+
+        let mut sector: Vec<u8> = vec![0u8; SECTOR_LEN * layout.sectors_per_node];
+        for vid_s in (0..num_nodes).step_by(layout.nodes_per_sector) {
+            sector.fill(0);
+            let vid_e = std::cmp::min(vid_s + layout.nodes_per_sector, num_nodes);
+            for vid in vid_s..vid_e {
+                let mut node: Vec<u8> = Vec::with_capacity(layout.node_len);
+                persist::write_vals(
+                    &mut node,
+                    &vectors[vid * aligned_dim..vid * aligned_dim + aligned_dim],
+                )?;
+                let node_nbrs = nbrs(vid);
+                node.write_u32::<LittleEndian>(node_nbrs.len() as u32)?;
+                for nbr in node_nbrs.iter() {
+                    node.write_u32::<LittleEndian>((*nbr).try_into()?)?;
+                }
+                let offset = (vid - vid_s) * layout.node_len;
+                sector[offset..offset + node.len()].copy_from_slice(&node);
+            }
+            w.write_all(&sector)?;
+        }
+        Ok(())
+    })?;
+    let compressed = CompressedVectors::train(vectors, aligned_dim, num_nodes, |vid| {
+        vid == start || tags.contains_key(&vid)
+    });
+    persist::write_file(&path.join(CODES_FILE), |w| compressed.save(w))?;
+    write_tags(&path.join(TAGS_FILE), tags)?;
+    Ok(())
+}
+
+fn write_tags(path: &Path, tags: &HashMap<usize, EId>) -> anyhow::Result<()> {
+    persist::write_file(path, |w| {
+        persist::write_header(w, TAGS_MAGIC, TAGS_VERSION)?;
+        persist::write_usize(w, tags.len())?;
+        for (vid, eid) in tags.iter() {
+            persist::write_usize(w, *vid)?;
+            persist::write_eid(w, eid)?;
+        }
+        Ok(())
+    })
+}
+
+// a read-mostly DiskANN index where only the compressed vectors live in
+// memory. the graph and the full precision vectors are fetched from disk
+// during the beam search, deletes are supported as in-memory tombstones
+// that are persisted on save.
+pub struct DiskANNSSDIndex<TMetric, TVal: ann::ElementVal> {
+    params: DiskANNSSDParams,
+    metric: PhantomData<TMetric>,
+    element: PhantomData<TVal>,
+
+    layout: SSDLayout,
+    reader: SectorReader,
+    compressed: CompressedVectors,
+    location_to_tag: RwLock<HashMap<usize, EId>>,
+    tag_to_location: RwLock<HashMap<EId, usize>>,
+}
+
+impl<TMetric, TVal> ann::ANNIndex for DiskANNSSDIndex<TMetric, TVal>
+where
+    TVal: ann::ElementVal,
+    TMetric: metric::Metric<TVal>,
+{
+    type Val = TVal;
+    fn new(params: &ann::ANNParams) -> anyhow::Result<DiskANNSSDIndex<TMetric, TVal>> {
+        let ssd_params: &DiskANNSSDParams = match params {
+            ann::ANNParams::DiskANNSSD { params } => params,
+            _ => {
+                unreachable!("incorrect params passed for construction")
+            }
+        };
+        DiskANNSSDIndex::open(ssd_params)
+    }
+    fn insert(&self, _eids: &[EId], _data: ann::Points<TVal>) -> anyhow::Result<()> {
+        bail!("DiskANNSSDIndex is read-only, build with DiskANNV1Index and export it via save_ssd")
+    }
+    fn delete(&self, eids: &[EId]) -> anyhow::Result<()> {
+        self.delete(eids)
+    }
+    fn search(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<ann::Node>> {
+        self.search(q, k)
+    }
+    fn range_search(&self, q: ann::Points<TVal>, radius: f32) -> anyhow::Result<Vec<ann::Node>> {
+        self.range_search(q, radius)
+    }
+    fn search_batch(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<Vec<ann::Node>>> {
+        q.split(self.layout.dim)?
+            .into_par_iter()
+            .map(|q| self.search(q, k))
+            .collect()
+    }
+    fn stats(&self) -> ann::IndexStats {
+        let location_to_tag = self.location_to_tag.read();
+        let mut memory_bytes: BTreeMap<&'static str, usize> = BTreeMap::new();
+        memory_bytes.insert(
+            "compressed_vectors",
+            self.compressed.codes.capacity()
+                + (self.compressed.offsets.capacity() + self.compressed.scales.capacity())
+                    * std::mem::size_of::<f32>(),
+        );
+        memory_bytes.insert(
+            "tags",
+            (location_to_tag.capacity() + self.tag_to_location.read().capacity())
+                * (std::mem::size_of::<usize>() + std::mem::size_of::<EId>() + 1),
+        );
+        // the last node is the frozen start point
+        let capacity = self.layout.num_nodes - 1;
+        ann::IndexStats {
+            num_live: location_to_tag.len(),
+            num_deleted: 0,
+            num_empty_slots: capacity.saturating_sub(location_to_tag.len()),
+            capacity: Some(capacity),
+            // the graph lives in the sectors on disk
+            graph: None,
+            memory_bytes,
+            quantizers: ann::QuantizerStats::default(),
+        }
+    }
+    fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<TVal>>>> {
+        self.get(eids)
+    }
+    fn contains(&self, eid: &EId) -> bool {
+        self.tag_to_location.read().contains_key(eid)
+    }
+    fn stores_normalized(&self) -> bool {
+        TMetric::uses_preprocessor()
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = anyhow::Result<(EId, Vec<TVal>)>> + '_> {
+        let eids: Vec<EId> = self.tag_to_location.read().keys().copied().collect();
+        ann::iter_by_get(self, eids)
+    }
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.save(path)
+    }
+    // searches go on with the params the index was saved with
+    fn load(path: &Path) -> anyhow::Result<DiskANNSSDIndex<TMetric, TVal>> {
+        let (_, search_list_size, beam_width) = SSDLayout::read::<TVal>(path)?;
+        DiskANNSSDIndex::open(&DiskANNSSDParams {
+            path: path.to_path_buf(),
+            search_list_size,
+            beam_width,
+        })
+    }
+}
+
+impl<TMetric, TVal> DiskANNSSDIndex<TMetric, TVal>
+where
+    TVal: ann::ElementVal,
+    TMetric: metric::Metric<TVal>,
+{
+    fn open(params: &DiskANNSSDParams) -> anyhow::Result<DiskANNSSDIndex<TMetric, TVal>> {
+        let (layout, _, _) = SSDLayout::read::<TVal>(&params.path)?;
+        let reader = SectorReader::open(&params.path.join(LAYOUT_FILE))?;
+        let compressed =
+            CompressedVectors::load(&mut persist::open_file(&params.path.join(CODES_FILE))?)?;
+        if compressed.codes.len() != layout.num_nodes * layout.aligned_dim {
+            bail!("compressed vectors do not match the layout of the index");
+        }
+        let mut location_to_tag: HashMap<usize, EId> = HashMap::new();
+        let mut tag_to_location: HashMap<EId, usize> = HashMap::new();
+        {
+            let mut r = persist::open_file(&params.path.join(TAGS_FILE))?;
+            persist::read_header(&mut r, TAGS_MAGIC, TAGS_VERSION)?;
+            for _ in 0..persist::read_usize(&mut r)? {
+                let vid = persist::read_usize(&mut r)?;
+                let eid = persist::read_eid(&mut r)?;
+                location_to_tag.insert(vid, eid);
+                tag_to_location.insert(eid, vid);
+            }
+        }
+        Ok(DiskANNSSDIndex {
+            params: params.clone(),
+            metric: PhantomData,
+            element: PhantomData,
+            layout,
+            reader,
+            compressed,
+            location_to_tag: RwLock::new(location_to_tag),
+            tag_to_location: RwLock::new(tag_to_location),
+        })
+    }
+
+    // fetch the full precision vector + neighbors of vid from disk
+    fn read_node(
+        &self,
+        vid: usize,
+        buf: &mut [u8],
+        vector: &mut [TVal],
+        nbrs: &mut Vec<usize>,
+    ) -> anyhow::Result<()> {
+        self.reader.read_at(self.layout.node_offset(vid), buf)?;
+        let vec_len = self.layout.aligned_dim * std::mem::size_of::<TVal>();
+        let mut r = &buf[..];
+        persist::read_vals(&mut r, vector)?;
+        let mut r = &buf[vec_len..];
+        let degree = r.read_u32::<LittleEndian>()? as usize;
+        nbrs.clear();
+        for _ in 0..degree {
+            nbrs.push(r.read_u32::<LittleEndian>()? as usize);
+        }
+        Ok(())
+    }
+
+    // full precision vectors come straight from the nodes on disk
+    fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<TVal>>>> {
+        let vids: Vec<Option<usize>> = {
+            let tag_to_location = self.tag_to_location.read();
+            eids.iter()
+                .map(|eid| tag_to_location.get(eid).copied())
+                .collect()
+        };
+        let mut buf: Vec<u8> = vec![0u8; self.layout.node_len];
+        let mut vector: Vec<TVal> = vec![Default::default(); self.layout.aligned_dim];
+        let mut nbrs: Vec<usize> = Vec::with_capacity(self.layout.max_degree);
+        vids.into_iter()
+            .map(|vid| match vid {
+                Some(vid) => {
+                    self.read_node(vid, &mut buf, &mut vector, &mut nbrs)?;
+                    Ok(Some(vector[..self.layout.dim].to_vec()))
+                }
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn prepare_query(&self, q: ann::Points<TVal>) -> anyhow::Result<AlignedDataStore<TVal>> {
+        let data: &[TVal] = match q {
+            ann::Points::QuantizerIn { .. } => {
+                bail!("DiskANNSSDIndex does not support quantized queries")
+            }
+            ann::Points::Values { vals } => vals,
+        };
+        let aligned_dim = self.layout.aligned_dim;
+        if data.len() > aligned_dim {
+            bail!("query dim: {} > aligned_dim: {}", data.len(), aligned_dim);
+        }
+        let mut q_aligned: AlignedDataStore<TVal> = AlignedDataStore::<TVal>::new(1, aligned_dim);
+        match ann::pad_and_preprocess::<TVal, TMetric>(data, data.len(), aligned_dim) {
+            Some(vec) => q_aligned.data[..].copy_from_slice(&vec[..]),
+            None => q_aligned.data[..data.len()].copy_from_slice(data),
+        }
+        Ok(q_aligned)
+    }
+
+    fn search(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<ann::Node>> {
+        let q_aligned = self.prepare_query(q)?;
+        let search_list_size = std::cmp::max(self.params.search_list_size, k);
+        let (full_precision, _) = self.beam_search(&q_aligned, search_list_size)?;
+        Ok(self.to_nodes(full_precision.iter(), k))
+    }
+
+    // every point within radius of q - as with the in-memory index the
+    // search list keeps doubling while it comes back full of in-range nodes,
+    // judged on their full precision distances
+    fn range_search(&self, q: ann::Points<TVal>, radius: f32) -> anyhow::Result<Vec<ann::Node>> {
+        let q_aligned = self.prepare_query(q)?;
+        let mut search_list_size = self.params.search_list_size;
+        loop {
+            let (full_precision, worst) = self.beam_search(&q_aligned, search_list_size)?;
+            let saturated = worst.map_or(false, |worst| worst <= radius);
+            if saturated && search_list_size < self.layout.num_nodes {
+                search_list_size = std::cmp::min(search_list_size * 2, self.layout.num_nodes);
+                continue;
+            }
+            return Ok(self.to_nodes(
+                full_precision.iter().filter(|nn| nn.distance <= radius),
+                usize::MAX,
+            ));
+        }
+    }
+
+    // beam search over the compressed vectors, returns every node fetched
+    // from disk sorted on its full precision distance along with the
+    // furthest full precision distance of the nodes left in the search
+    // list - None when the list never filled up
+    fn beam_search(
+        &self,
+        q_aligned: &AlignedDataStore<TVal>,
+        search_list_size: usize,
+    ) -> anyhow::Result<(Vec<ann::INode>, Option<f32>)> {
+        let aligned_dim = self.layout.aligned_dim;
+        // scratch space - the metric kernels expect aligned inputs so both
+        // the decoded and the fetched vectors go through an aligned store
+        let mut decoded: AlignedDataStore<TVal> = AlignedDataStore::<TVal>::new(1, aligned_dim);
+        let mut full: AlignedDataStore<TVal> = AlignedDataStore::<TVal>::new(1, aligned_dim);
+        let mut buf: Vec<u8> = vec![0u8; self.layout.node_len];
+        let mut nbrs: Vec<usize> = Vec::with_capacity(self.layout.max_degree);
+
+        let mut best_l_nodes = nn_queue::NNPriorityQueue::new(search_list_size);
+        let mut visited: HashSet<usize> = HashSet::with_capacity(search_list_size * 10);
+        let mut full_precision: Vec<ann::INode> = Vec::with_capacity(search_list_size * 2);
+
+        let start = self.layout.start;
+        self.compressed.decode_into(start, &mut decoded.data[..]);
+        best_l_nodes.insert(ann::INode {
+            vid: start,
+            distance: TMetric::compare(&q_aligned.data[..], &decoded.data[..]),
+            flag: false,
+        });
+        visited.insert(start);
+
+        let mut beam: Vec<usize> = Vec::with_capacity(self.params.beam_width);
+        while best_l_nodes.has_unexpanded_node() {
+            beam.clear();
+            while beam.len() < std::cmp::max(self.params.beam_width, 1)
+                && best_l_nodes.has_unexpanded_node()
+            {
+                beam.push(best_l_nodes.closest_unexpanded().vid);
+            }
+            for vid in beam.iter() {
+                self.read_node(*vid, &mut buf, &mut full.data[..], &mut nbrs)?;
+                full_precision.push(ann::INode {
+                    vid: *vid,
+                    distance: TMetric::compare(&q_aligned.data[..], &full.data[..]),
+                    flag: true,
+                });
+                for nbr in nbrs.iter() {
+                    if *nbr >= self.layout.num_nodes || !visited.insert(*nbr) {
+                        continue;
+                    }
+                    self.compressed.decode_into(*nbr, &mut decoded.data[..]);
+                    best_l_nodes.insert(ann::INode {
+                        vid: *nbr,
+                        distance: TMetric::compare(&q_aligned.data[..], &decoded.data[..]),
+                        flag: false,
+                    });
+                }
+            }
+        }
+        // re-rank everything we fetched on its full precision distance
+        full_precision.sort();
+        // every node left in the list has been expanded, so fetched
+        let worst = match best_l_nodes.len() < search_list_size {
+            true => None,
+            false => {
+                let in_list: HashSet<usize> = best_l_nodes.data[..best_l_nodes.len()]
+                    .iter()
+                    .map(|nn| nn.vid)
+                    .collect();
+                full_precision
+                    .iter()
+                    .filter(|nn| in_list.contains(&nn.vid))
+                    .map(|nn| nn.distance)
+                    .reduce(f32::max)
+            }
+        };
+        Ok((full_precision, worst))
+    }
+
+    fn to_nodes<'a>(
+        &self,
+        nodes: impl Iterator<Item = &'a ann::INode>,
+        k: usize,
+    ) -> Vec<ann::Node> {
+        let mapping = self.location_to_tag.read();
+        nodes
+            .filter(|nn| nn.vid != self.layout.start)
+            .filter_map(|nn| {
+                mapping.get(&nn.vid).map(|eid| ann::Node {
+                    vid: nn.vid,
+                    eid: *eid,
+                    distance: nn.distance,
+                })
+            })
+            .take(k)
+            .collect()
+    }
+
+    fn delete(&self, eids: &[EId]) -> anyhow::Result<()> {
+        let mut tag_to_location = self.tag_to_location.write();
+        let mut location_to_tag = self.location_to_tag.write();
+        eids.iter().for_each(|eid| {
+            if let Some(vid) = tag_to_location.remove(eid) {
+                location_to_tag.remove(&vid);
+            }
+        });
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(path)?;
+        if !persist::same_file(&self.params.path, path) {
+            fs::copy(self.params.path.join(LAYOUT_FILE), path.join(LAYOUT_FILE))?;
+            fs::copy(self.params.path.join(CODES_FILE), path.join(CODES_FILE))?;
+        }
+        // the search params go into the header sector, the nodes are left be
+        let header = self
+            .layout
+            .header::<TVal>(self.params.search_list_size, self.params.beam_width)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path.join(LAYOUT_FILE))?
+            .write_all(&header)?;
+        write_tags(&path.join(TAGS_FILE), &self.location_to_tag.read())
+    }
+
+    pub fn params(&self) -> &DiskANNSSDParams {
+        &self.params
+    }
+
+    // bytes held in memory for the compressed vectors vs. the bytes on disk
+    pub fn footprint(&self) -> (usize, usize) {
+        (
+            self.compressed.codes.len() + 8 * self.compressed.aligned_dim,
+            self.layout.num_sectors() * SECTOR_LEN,
+        )
+    }
+}
@@ -2,30 +2,37 @@ use crate::ann;
 use crate::ann::EId;
 use crate::av_store;
 use crate::av_store::AlignedDataStore;
+use crate::diskann_ssd;
 use crate::errors;
+use crate::maintenance::MaintenanceHandle;
 use crate::metric;
 use crate::nn_query_scratch;
 use crate::nn_queue;
+use crate::persist;
+use crate::product_quantizer;
+use crate::rerank;
 use crate::scalar_quantizer;
 
 use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crossbeam_channel::{bounded, Receiver, Sender};
 use parking_lot::RwLock;
-use rand::distributions::{Distribution, Standard};
-use rand::thread_rng;
 use rayon::prelude::*;
 use roaring::RoaringTreemap;
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
 use std::marker::PhantomData;
 use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::thread::available_parallelism;
+use std::time;
 use std::time::Instant;
-use std::{thread, time};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DiskANNParams {
     pub dim: usize,
     pub max_points: usize,
@@ -35,6 +42,41 @@ pub struct DiskANNParams {
     pub indexing_maxc: usize,
     pub indexing_alpha: f32,
     pub maintenance_period_millis: u64,
+    // when set, the vectors live in a memory-mapped file inside this
+    // directory rather than on the heap
+    pub mmap_dir: Option<PathBuf>,
+    // when set, every location also carries a product quantization code and
+    // searches traverse the graph on approximate (ADC) distances. the graph
+    // is still built over the full precision vectors - pair this with
+    // mmap_dir so that those are paged in for indexing only.
+    pub pq: Option<product_quantizer::PQParams>,
+    // when set, an insert that would take the index past max_points grows
+    // it by this factor (> 1.0) rather than failing
+    pub growth_factor: Option<f32>,
+    // when set, the full precision values of QuantizerIn points are retained
+    // and the candidates of a search are re-scored against them
+    pub rerank: Option<rerank::RerankParams>,
+    // how QuantizerIn points are quantized, the defaults when not set
+    pub quantizer: Option<scalar_quantizer::ScalarQuantizerParams>,
+}
+
+// when the background maintenance consolidates deletes - it does so once
+// either threshold is crossed and never while nothing is awaiting removal
+#[derive(Debug, Clone)]
+pub struct MaintenanceParams {
+    // number of deleted points awaiting removal
+    pub min_deletes: usize,
+    // deleted points awaiting removal as a fraction of the live points
+    pub min_delete_fraction: f32,
+}
+
+impl Default for MaintenanceParams {
+    fn default() -> Self {
+        MaintenanceParams {
+            min_deletes: 1000,
+            min_delete_fraction: 0.1,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -45,6 +87,12 @@ pub struct DiskANNParamsInternal {
     pub nd: usize,
     pub start: usize,
     pub saturate_graph: bool,
+    // set by the first insert when it hands us Points::QuantizerIn, the data
+    // then holds the u8 codes of the scalar quantizer rather than the values
+    pub quantized: bool,
+    // whether quantized has been settled, by the first insert or the points
+    // of a loaded index. inserts racing the first one are held to its choice
+    pub quantized_fixed: bool,
 }
 
 #[allow(dead_code)]
@@ -53,8 +101,8 @@ pub struct DiskANNV1Index<TMetric, TVal: ann::ElementVal> {
     metric: PhantomData<TMetric>,
 
     data: Arc<RwLock<av_store::AlignedDataStore<TVal>>>,
-    in_graph: Arc<Vec<RwLock<HashSet<usize>>>>, // all vids going *into* the node: vid -> {vid_1, vid_2, ..}
-    final_graph: Arc<Vec<RwLock<Vec<usize>>>>, // all vids that are closest: vid -> [vid_1, vid_2...]
+    in_graph: Arc<RwLock<Vec<RwLock<HashSet<usize>>>>>, // all vids going *into* the node: vid -> {vid_1, vid_2, ..}
+    final_graph: Arc<RwLock<Vec<RwLock<Vec<usize>>>>>, // all vids that are closest: vid -> [vid_1, vid_2...]
     location_to_tag: Arc<RwLock<HashMap<usize, EId>>>,
     tag_to_location: Arc<RwLock<HashMap<EId, usize>>>,
 
@@ -64,15 +112,64 @@ pub struct DiskANNV1Index<TMetric, TVal: ann::ElementVal> {
     s_scratch: Sender<nn_query_scratch::InMemoryQueryScratch>,
     r_scratch: Receiver<nn_query_scratch::InMemoryQueryScratch>,
     quantizer: Arc<scalar_quantizer::ScalarQuantizer>,
+    // trained on the first batch of inserted vectors
+    pq: Arc<RwLock<Option<product_quantizer::ProductQuantizer>>>,
+    pq_codes: Arc<RwLock<Vec<u8>>>, // location -> code, code_len bytes each
+    labels: Arc<RwLock<Vec<RwLock<Vec<ann::Label>>>>>, // location -> sorted label set
+    label_starts: Arc<RwLock<HashMap<ann::Label, usize>>>, // entry point per label
+    label_counts: Arc<RwLock<HashMap<ann::Label, usize>>>, // number of points per label
+    full_precision: Option<Arc<rerank::FullPrecisionStore>>,
+    // shared by every operation that touches the per location state and
+    // held exclusively by resize(..) while that state is re-allocated
+    resize_lock: Arc<RwLock<()>>,
     // indexing_pool: rayon::ThreadPool,
     // handle: Option<thread::JoinHandle<()>>,
 }
 
 const GRAPH_SLACK_FACTOR: f64 = 1.3;
 const MAX_POINTS_FOR_USING_BITSET: usize = 10_000_000;
+
+// on-disk layout of a saved index, everything lives in a single directory:
+//   meta  - params, internal params and the element / metric types
+//   data  - the raw aligned vectors (including the frozen start point)
+//   graph - final_graph + in_graph adjacency lists for every location
+//   tags  - location <-> tag mappings, delete_set and empty_slots
+//   pq    - the product quantizer and the code of every location
+//   labels - label sets of the labelled locations and the per label starts
+//   quantizer - the state of the ScalarQuantizer of a quantized index
+//   full_precision - the retained vectors re-ranking scores against
+const DISKANN_MAGIC: &[u8; 4] = b"ADKN";
+const DISKANN_VERSION: u32 = 9;
+const META_FILE: &str = "meta";
+const DATA_FILE: &str = "data";
+const GRAPH_FILE: &str = "graph";
+const TAGS_FILE: &str = "tags";
+const PQ_FILE: &str = "pq";
+const LABELS_FILE: &str = "labels";
+const QUANTIZER_FILE: &str = "quantizer";
+
 enum QueryTarget<'a, TVal: ann::ElementVal> {
     VId(usize),
     Vector(&'a [TVal]),
+    Codes(&'a product_quantizer::DistanceTable),
+    Quantized(&'a scalar_quantizer::QuantizedQuery),
+}
+
+// a query readied for the traversal, see prepare_query(..)
+enum PreparedQuery<TVal: ann::ElementVal> {
+    Vector(AlignedDataStore<TVal>),
+    Codes(product_quantizer::DistanceTable),
+    Quantized(scalar_quantizer::QuantizedQuery),
+}
+
+impl<TVal: ann::ElementVal> PreparedQuery<TVal> {
+    fn target(&self) -> QueryTarget<'_, TVal> {
+        match self {
+            PreparedQuery::Vector(q_aligned) => QueryTarget::Vector(&q_aligned.data),
+            PreparedQuery::Codes(table) => QueryTarget::Codes(table),
+            PreparedQuery::Quantized(query) => QueryTarget::Quantized(query),
+        }
+    }
 }
 
 impl<TMetric, TVal> ann::ANNIndex for DiskANNV1Index<TMetric, TVal>
@@ -83,10 +180,10 @@ where
     type Val = TVal;
     fn new(params: &ann::ANNParams) -> anyhow::Result<DiskANNV1Index<TMetric, TVal>> {
         let diskann_params: &DiskANNParams = match params {
-            ann::ANNParams::Flat { params: _ } => {
+            ann::ANNParams::DiskANN { params } => params,
+            _ => {
                 unreachable!("incorrect params passed for construction")
             }
-            ann::ANNParams::DiskANN { params } => params,
         };
         DiskANNV1Index::new(diskann_params)
     }
@@ -99,8 +196,33 @@ where
     fn search(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<ann::Node>> {
         self.search(q, k)
     }
-    fn save(&self) -> anyhow::Result<()> {
-        unimplemented!()
+    fn range_search(&self, q: ann::Points<TVal>, radius: f32) -> anyhow::Result<Vec<ann::Node>> {
+        self.range_search(q, radius)
+    }
+    fn search_batch(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<Vec<ann::Node>>> {
+        self.search_batch(q, k)
+    }
+    fn stats(&self) -> ann::IndexStats {
+        self.stats()
+    }
+    fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<TVal>>>> {
+        Ok(self.get(eids))
+    }
+    fn contains(&self, eid: &EId) -> bool {
+        self.tag_to_location.read().contains_key(eid)
+    }
+    fn stores_normalized(&self) -> bool {
+        TMetric::uses_preprocessor() && !self.params.read().quantized
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = anyhow::Result<(EId, Vec<TVal>)>> + '_> {
+        let eids: Vec<EId> = self.tag_to_location.read().keys().copied().collect();
+        ann::iter_by_get(self, eids)
+    }
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.save(path)
+    }
+    fn load(path: &Path) -> anyhow::Result<DiskANNV1Index<TMetric, TVal>> {
+        DiskANNV1Index::load(path)
     }
 }
 
@@ -111,27 +233,7 @@ where
 {
     fn set_start_point_at_random(&self, radius: f32) {
         let params_r = self.params.read();
-        let mut rng = thread_rng();
-        let v: Vec<f64> = Standard
-            .sample_iter(&mut rng)
-            .take(params_r.aligned_dim)
-            .collect();
-
-        let mut norm_sq: f64 = 0.0f64;
-        let real_vec: Vec<f64> = v
-            .iter()
-            .map(|r| {
-                norm_sq += r * r;
-                *r
-            })
-            .collect();
-        let norm: f64 = f64::sqrt(norm_sq);
-        let start_vec: Vec<TVal> = real_vec
-            .iter()
-            .map(|p| {
-                TVal::from_f64(*p * (radius as f64) / norm).expect("unexpected cast issue fr: f32")
-            })
-            .collect();
+        let start_vec: Vec<TVal> = TVal::random_start_point(params_r.aligned_dim, radius);
         // copy the data into our vector!
         let mut data_w = self.data.write();
         let idx_s: usize = params_r.start * params_r.aligned_dim;
@@ -192,6 +294,7 @@ where
         ann::copy_within_a_slice(&mut data_w.data, idx_f, idx_t, params_r.aligned_dim);
     }
     fn link(&self, visit_order: Vec<usize>, do_prune: bool) {
+        let final_graph = self.final_graph.read();
         let params_r = self.params.read();
         // TODO(infrawhispers) - WASM + Rayon on M1 macs is broken!
         visit_order.par_iter().for_each(|vid| {
@@ -239,7 +342,7 @@ where
             let should_prune: bool;
             let graph_copy: Vec<usize>;
             {
-                let nbrs = self.final_graph[*curr_vid].read();
+                let nbrs = final_graph[*curr_vid].read();
                 should_prune = nbrs.len() > params_r.params_e.indexing_range;
                 graph_copy = nbrs.clone();
             }
@@ -253,15 +356,18 @@ where
                 let mut dummy_visited: HashSet<usize> = HashSet::new();
                 let mut dummy_pool: Vec<ann::INode> = Vec::new();
                 let mut new_out_neighbors: Vec<usize> = Vec::new();
+                let view = self.code_view(&params_r);
                 graph_copy.iter().for_each(|nbr_vid| {
                     if !dummy_visited.contains(nbr_vid) && *nbr_vid != *curr_vid {
-                        let arr_a: &[TVal] = &data.data[*curr_vid * params_r.aligned_dim
-                            ..(*curr_vid * params_r.aligned_dim) + params_r.aligned_dim];
-                        let arr_b: &[TVal] = &data.data[nbr_vid * params_r.aligned_dim
-                            ..(nbr_vid * params_r.aligned_dim) + params_r.aligned_dim];
                         dummy_pool.push(ann::INode {
                             vid: *nbr_vid,
-                            distance: TMetric::compare(arr_a, arr_b),
+                            distance: self.compare_locations(
+                                data,
+                                &params_r,
+                                view.as_ref(),
+                                *curr_vid,
+                                *nbr_vid,
+                            ),
                             flag: false,
                         });
                         dummy_visited.insert(*nbr_vid);
@@ -274,6 +380,7 @@ where
                     &params_r,
                     &mut scratch,
                     data,
+                    view.as_ref(),
                 );
                 self.update_graph_nbrs(*curr_vid, new_out_neighbors, true);
                 // let old_segment: Vec<usize>;
@@ -309,19 +416,54 @@ where
         scratch: &mut nn_query_scratch::InMemoryQueryScratch,
         ret_frozen: bool,
         is_search: bool,
+        filter: Option<&[ann::Label]>,
     ) -> (usize, usize) {
+        let final_graph = self.final_graph.read();
         let data = self.data.read();
+        let pq_codes = self.pq_codes.read();
+        let view = self.code_view(params_r);
         // pull out the slice we are comparing against
         let arr_b: &[TVal];
+        let mut pre_b: f32 = 0.0;
+        let mut table: Option<&product_quantizer::DistanceTable> = None;
+        let mut quantized: Option<&scalar_quantizer::QuantizedQuery> = None;
         match target {
             QueryTarget::VId(vid) => {
                 arr_b = &data.data
                     [vid * params_r.aligned_dim..vid * params_r.aligned_dim + params_r.aligned_dim];
+                if let Some(view) = &view {
+                    pre_b = view.pre_compute(vid, arr_b);
+                }
             }
             QueryTarget::Vector(v) => {
                 arr_b = v;
+                if let Some(view) = &view {
+                    pre_b = view.space().pre_compute(arr_b);
+                }
+            }
+            QueryTarget::Codes(t) => {
+                arr_b = &[];
+                table = Some(t);
+            }
+            QueryTarget::Quantized(q) => {
+                arr_b = &[];
+                quantized = Some(q);
             }
         }
+        let code_len = pq_codes.len() / (params_r.params_e.max_points + params_r.num_frozen_pts);
+        let distance = |vid: usize| -> f32 {
+            let arr_a: &[TVal] = &data.data
+                [vid * params_r.aligned_dim..vid * params_r.aligned_dim + params_r.aligned_dim];
+            match (table, quantized, &view) {
+                (Some(t), _, _) => t.distance(&pq_codes[vid * code_len..vid * code_len + code_len]),
+                (None, Some(q), Some(view)) => q.distance(arr_a, view.pre_compute(vid, arr_a)),
+                (None, _, Some(view)) => {
+                    view.space()
+                        .between(arr_a, view.pre_compute(vid, arr_a), arr_b, pre_b)
+                }
+                (None, _, None) => TMetric::compare(arr_a, arr_b),
+            }
+        };
         let expanded_nodes: &mut Vec<ann::INode> = &mut scratch.pool;
         let best_l_nodes: &mut nn_queue::NNPriorityQueue = &mut scratch.best_l_nodes;
         let inserted_into_pool_hs: &mut HashSet<usize> = &mut scratch.inserted_into_pool_hs;
@@ -352,11 +494,9 @@ where
                 } else {
                     inserted_into_pool_hs.insert(*nn_id);
                 }
-                let arr_a: &[TVal] = &data.data[*nn_id * params_r.aligned_dim
-                    ..(*nn_id * params_r.aligned_dim) + params_r.aligned_dim];
                 let nn = ann::INode {
                     vid: *nn_id,
-                    distance: TMetric::compare(arr_a, arr_b),
+                    distance: distance(*nn_id),
                     flag: false,
                 };
                 best_l_nodes.insert(nn);
@@ -375,7 +515,7 @@ where
             id_scratch.clear();
             dist_scratch.clear();
             {
-                let _final_graph = self.final_graph[nbr_vid].read();
+                let _final_graph = final_graph[nbr_vid].read();
                 for m in 0.._final_graph.len() {
                     debug_assert!(
                         _final_graph[m] <= params_r.params_e.max_points + params_r.num_frozen_pts,
@@ -389,7 +529,9 @@ where
                     } else {
                         !inserted_into_pool_hs.contains(&nn_id)
                     };
-                    if is_not_visited {
+                    // filtered traversals only walk the nodes carrying one
+                    // of the labels we are after
+                    if is_not_visited && filter.map_or(true, |f| self.has_any_label(nn_id, f)) {
                         id_scratch.push(_final_graph[m]);
                     }
                 }
@@ -408,12 +550,9 @@ where
                 // if i + 1 < id_scratch.len() {
                 // // TODO(infrawhispers) there is some pre-fetch funny biz that happens in the original implementation
                 // }
-                let arr_a: &[TVal] = &data.data[*nn * params_r.aligned_dim
-                    ..(*nn * params_r.aligned_dim) + params_r.aligned_dim];
-                let dist: f32 = TMetric::compare(arr_a, arr_b);
                 nbrs_potential.push(ann::INode {
                     vid: *nn,
-                    distance: dist,
+                    distance: distance(*nn),
                     flag: false,
                 })
             });
@@ -425,6 +564,48 @@ where
         (hops, cmps)
     }
 
+    // the distance between the points at two locations, the codes of a
+    // quantized index are compared through the view of the quantizer so
+    // that its offset and the per-vid terms are accounted for
+    #[inline(always)]
+    fn compare_locations(
+        &self,
+        data: &AlignedDataStore<TVal>,
+        params_r: &DiskANNParamsInternal,
+        view: Option<&scalar_quantizer::CodeView>,
+        vid_a: usize,
+        vid_b: usize,
+    ) -> f32 {
+        let aligned_dim = params_r.aligned_dim;
+        let arr_a: &[TVal] = &data.data[vid_a * aligned_dim..vid_a * aligned_dim + aligned_dim];
+        let arr_b: &[TVal] = &data.data[vid_b * aligned_dim..vid_b * aligned_dim + aligned_dim];
+        match view {
+            Some(view) => view.between(vid_a, arr_a, vid_b, arr_b),
+            None => TMetric::compare(arr_a, arr_b),
+        }
+    }
+
+    // None unless the index holds the codes of the scalar quantizer
+    fn code_space(&self, params_r: &DiskANNParamsInternal) -> Option<scalar_quantizer::CodeSpace> {
+        if !params_r.quantized {
+            return None;
+        }
+        TMetric::quantized_distance()
+            .map(|distance| self.quantizer.code_space(distance, params_r.aligned_dim))
+    }
+
+    // taken once per search or prune and passed down to compare_locations(..)
+    fn code_view(
+        &self,
+        params_r: &DiskANNParamsInternal,
+    ) -> Option<scalar_quantizer::CodeView<'_>> {
+        if !params_r.quantized {
+            return None;
+        }
+        TMetric::quantized_distance()
+            .map(|distance| self.quantizer.view(distance, params_r.aligned_dim))
+    }
+
     fn occlude_list(
         &self,
         vid: usize,
@@ -435,7 +616,9 @@ where
         result: &mut Vec<usize>,
         params_r: &DiskANNParamsInternal,
         data: &AlignedDataStore<TVal>,
+        view: Option<&scalar_quantizer::CodeView>,
     ) {
+        let labels = self.labels.read();
         if pool.len() == 0 {
             return;
         }
@@ -452,6 +635,24 @@ where
             );
         }
 
+        // filtered-vamana pruning: a candidate is only occluded by a closer
+        // node that also carries every label the candidate shares with vid,
+        // so each label keeps a navigable neighborhood of its own
+        let vid_labels: Vec<ann::Label> = labels[vid].read().clone();
+        let shared_labels: Vec<Vec<ann::Label>> = if vid_labels.is_empty() {
+            Vec::new()
+        } else {
+            pool.iter()
+                .map(|nn| {
+                    let nn_labels = labels[nn.vid].read();
+                    vid_labels
+                        .iter()
+                        .filter(|label| nn_labels.binary_search(label).is_ok())
+                        .copied()
+                        .collect()
+                })
+                .collect()
+        };
         let mut occlude_factor = vec![0.0; pool.len()];
         let mut curr_alpha = 1.0;
         while curr_alpha <= alpha && result.len() < degree {
@@ -472,16 +673,18 @@ where
                     if occlude_factor[idx2] > alpha {
                         continue;
                     }
-                    let arr_a: &[TVal] = &data.data[nn.vid * params_r.aligned_dim
-                        ..(nn.vid * params_r.aligned_dim) + params_r.aligned_dim];
-                    let arr_b: &[TVal] = &data.data[pool[idx2].vid * params_r.aligned_dim
-                        ..(pool[idx2].vid * params_r.aligned_dim) + params_r.aligned_dim];
-                    let djk: f32 = TMetric::compare(arr_a, arr_b);
-                    occlude_factor[idx2] = if djk == 0.0 {
-                        f32::MAX
-                    } else {
-                        occlude_factor[idx2].max(nn_2.distance / djk)
+                    if !shared_labels.is_empty() && !shared_labels[idx2].is_empty() {
+                        let nn_labels = labels[nn.vid].read();
+                        if shared_labels[idx2]
+                            .iter()
+                            .any(|label| nn_labels.binary_search(label).is_err())
+                        {
+                            continue;
+                        }
                     }
+                    let djk: f32 = self.compare_locations(data, params_r, view, nn.vid, nn_2.vid);
+                    occlude_factor[idx2] =
+                        occlude_factor[idx2].max(TMetric::occlude_factor(nn_2.distance, djk));
                 }
             }
             curr_alpha *= 1.2;
@@ -495,6 +698,7 @@ where
         params_r: &DiskANNParamsInternal,
         scratch: &mut nn_query_scratch::InMemoryQueryScratch,
         data: &AlignedDataStore<TVal>,
+        view: Option<&scalar_quantizer::CodeView>,
     ) {
         let pool = &mut scratch.pool;
         if pool.len() == 0 {
@@ -509,7 +713,17 @@ where
         // println!("pool: {:?} | vid: {:?}", pool, vid);
         pruned_list.clear();
         pruned_list.reserve(range);
-        self.occlude_list(vid, pool, alpha, range, maxc, pruned_list, params_r, data);
+        self.occlude_list(
+            vid,
+            pool,
+            alpha,
+            range,
+            maxc,
+            pruned_list,
+            params_r,
+            data,
+            view,
+        );
         debug_assert!(
             pruned_list.len() <= range,
             "pruned_list: {:?}, range: {:?}",
@@ -537,6 +751,8 @@ where
         scratch: &mut nn_query_scratch::InMemoryQueryScratch,
         data: &AlignedDataStore<TVal>,
     ) {
+        let final_graph = self.final_graph.read();
+        let in_graph = self.in_graph.read();
         debug_assert!(!pruned_list.is_empty(), "inter_insert:: vid: {:?}", vid);
         let range = params_r.params_e.indexing_range;
         for des in pruned_list.iter() {
@@ -545,7 +761,7 @@ where
             let mut prune_needed: bool = false;
             let mut vids_added = Vec::with_capacity(range);
             {
-                let mut node_neighbors_f = self.final_graph[*des].write();
+                let mut node_neighbors_f = final_graph[*des].write();
                 copy_of_neighhbors = node_neighbors_f.clone();
                 if !node_neighbors_f.iter().any(|&i| i == vid) {
                     if node_neighbors_f.len() < ((GRAPH_SLACK_FACTOR * (range as f64)) as usize) {
@@ -563,7 +779,7 @@ where
                 // populate_in_graph - the regexp to find instances this must
                 // be tied to is: "final_graph\[.*\].*write\(\)"
                 vids_added.iter().for_each(|nbr_vid| {
-                    self.in_graph[*nbr_vid].write().insert(vid);
+                    in_graph[*nbr_vid].write().insert(vid);
                 });
             } else {
                 // println!("prune is needed: {}", vid);
@@ -571,13 +787,11 @@ where
                     ((range as f64) * GRAPH_SLACK_FACTOR * 1.05).ceil() as usize;
                 let mut dummy_visited: HashSet<usize> = HashSet::with_capacity(reserve_size);
                 let mut dummy_pool: Vec<ann::INode> = Vec::with_capacity(reserve_size);
+                let view = self.code_view(params_r);
                 for curr_nbr in copy_of_neighhbors.iter() {
                     if !dummy_visited.contains(curr_nbr) && *curr_nbr != *des {
-                        let arr_a: &[TVal] = &data.data[*des * params_r.aligned_dim
-                            ..(*des * params_r.aligned_dim) + params_r.aligned_dim];
-                        let arr_b: &[TVal] = &data.data[*curr_nbr * params_r.aligned_dim
-                            ..(*curr_nbr * params_r.aligned_dim) + params_r.aligned_dim];
-                        let dist: f32 = TMetric::compare(arr_a, arr_b);
+                        let dist: f32 =
+                            self.compare_locations(data, params_r, view.as_ref(), *des, *curr_nbr);
                         dummy_pool.push(ann::INode {
                             vid: *curr_nbr,
                             distance: dist,
@@ -595,6 +809,7 @@ where
                     params_r,
                     scratch,
                     &self.data.read(),
+                    view.as_ref(),
                 );
                 self.update_graph_nbrs(*des, new_out_neighbors, true);
                 // let old_segment: Vec<usize>;
@@ -627,6 +842,7 @@ where
         params_r: &DiskANNParamsInternal,
         scratch: &mut nn_query_scratch::InMemoryQueryScratch,
     ) {
+        let labels = self.labels.read();
         let mut init_ids: Vec<usize> = Vec::new();
         init_ids.push(params_r.start);
         self.iterate_to_fixed_point(
@@ -636,7 +852,31 @@ where
             scratch,
             true,
             false,
+            None,
         );
+        let vid_labels: Vec<ann::Label> = labels[vid].read().clone();
+        if !vid_labels.is_empty() {
+            // labelled points also run a search restricted to their labels so
+            // that they end up linked into the subgraph of each of them
+            let mut init_ids: Vec<usize> = self.label_start_ids(&vid_labels);
+            init_ids.retain(|start| *start != vid);
+            if !init_ids.is_empty() {
+                let mut pool = std::mem::take(&mut scratch.pool);
+                scratch.clear();
+                self.iterate_to_fixed_point(
+                    QueryTarget::VId(vid),
+                    params_r,
+                    &mut init_ids,
+                    scratch,
+                    true,
+                    false,
+                    Some(&vid_labels),
+                );
+                let seen: HashSet<usize> = pool.iter().map(|nn| nn.vid).collect();
+                pool.extend(scratch.pool.iter().filter(|nn| !seen.contains(&nn.vid)));
+                scratch.pool = pool;
+            }
+        }
         {
             let pool = &mut scratch.pool;
             let delete_set = self.delete_set.read();
@@ -644,7 +884,15 @@ where
             pool.retain(|&nn| !delete_set.contains(&nn.vid));
         }
         debug_assert!(pruned_list.len() == 0);
-        self.prune_neighbors(vid, pruned_list, params_r, scratch, &self.data.read());
+        let view = self.code_view(params_r);
+        self.prune_neighbors(
+            vid,
+            pruned_list,
+            params_r,
+            scratch,
+            &self.data.read(),
+            view.as_ref(),
+        );
         debug_assert!(
             pruned_list.len() != 0,
             "vid: {:?}, pool is of : {:?}, pruned_list is of: {:?}",
@@ -655,22 +903,199 @@ where
     }
 
     fn search(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<ann::Node>> {
-        let mut init_ids: Vec<usize> = Vec::new();
+        self.search_core(q, k, None)
+    }
+
+    // search restricted to the points matching filter, the traversal itself
+    // only walks nodes that carry one of the labels of the filter and starts
+    // from the entry points of those labels
+    pub fn search_filtered(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+        filter: &ann::LabelFilter,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        self.search_core(q, k, Some(filter))
+    }
+
+    fn search_core(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+        filter: Option<&ann::LabelFilter>,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        let _resize_r = self.resize_lock.read();
+        let params_r = self.params.read();
+        let mut scratch: nn_query_scratch::InMemoryQueryScratch =
+            nn_query_scratch::InMemoryQueryScratch::with_search_list_size(
+                &params_r,
+                self.search_list_size(&q, k, &params_r),
+            );
+        self.search_with_scratch(q, k, filter, &params_r, &mut scratch)
+    }
+
+    // queries are spread over rayon, each one borrowing a scratch space from
+    // the pool shared with indexing rather than allocating its own
+    pub fn search_batch(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+    ) -> anyhow::Result<Vec<Vec<ann::Node>>> {
+        let _resize_r = self.resize_lock.read();
         let params_r = self.params.read();
-        if init_ids.len() == 0 {
-            init_ids.push(params_r.start);
+        let queries = q.split(params_r.params_e.dim)?;
+        queries
+            .into_par_iter()
+            .map(|q| {
+                let search_list_size = self.search_list_size(&q, k, &params_r);
+                if search_list_size > params_r.params_e.indexing_queue_size {
+                    // the pooled scratch spaces cannot hold enough candidates
+                    let mut scratch = nn_query_scratch::InMemoryQueryScratch::with_search_list_size(
+                        &params_r,
+                        search_list_size,
+                    );
+                    return self.search_with_scratch(q, k, None, &params_r, &mut scratch);
+                }
+                // blocking on the pool here would park the rayon worker, so
+                // with the pooled scratch spaces all taken we make our own
+                let mut scratch: nn_query_scratch::InMemoryQueryScratch =
+                    match self.r_scratch.try_recv() {
+                        Ok(scratch) => scratch,
+                        Err(_) => {
+                            let mut scratch =
+                                nn_query_scratch::InMemoryQueryScratch::new(&params_r);
+                            return self.search_with_scratch(q, k, None, &params_r, &mut scratch);
+                        }
+                    };
+                scratch.clear();
+                let res = self.search_with_scratch(q, k, None, &params_r, &mut scratch);
+                self.s_scratch.send(scratch).unwrap();
+                res
+            })
+            .collect()
+    }
+
+    fn search_with_scratch(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+        filter: Option<&ann::LabelFilter>,
+        params_r: &DiskANNParamsInternal,
+        scratch: &mut nn_query_scratch::InMemoryQueryScratch,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        let mut init_ids: Vec<usize> = Vec::new();
+        match filter {
+            Some(filter) => {
+                init_ids = self.label_start_ids(&self.navigation_labels(filter));
+                if init_ids.is_empty() {
+                    // none of the labels have been seen by the index
+                    return Ok(Vec::new());
+                }
+            }
+            None => init_ids.push(params_r.start),
         }
+        let nav_labels: Option<Vec<ann::Label>> = filter.map(|f| self.navigation_labels(f));
+        let reranker = self.reranker(&q);
+        let num_candidates = match reranker {
+            Some((store, _)) => store.num_candidates(k),
+            None => k,
+        };
+        let prepared = self.prepare_query(q, params_r)?;
+        self.iterate_to_fixed_point(
+            prepared.target(),
+            params_r,
+            &mut init_ids,
+            scratch,
+            true,
+            true,
+            nav_labels.as_deref(),
+        );
+        let mut filtered: Vec<ann::Node> = Vec::with_capacity(num_candidates + 1);
+        let mapping = self.location_to_tag.read();
+
+        scratch.best_l_nodes.data.iter().try_for_each(|nn| {
+            if filtered.len() >= num_candidates {
+                return ControlFlow::Break(nn);
+            }
+            let eid;
+            match mapping.get(&nn.vid) {
+                Some(val) => eid = *val,
+                None => return ControlFlow::Continue(()),
+            }
+            if filter.map_or(false, |f| !f.matches(&self.labels.read()[nn.vid].read())) {
+                return ControlFlow::Continue(());
+            }
+            if nn.vid != params_r.start && !(nn.distance.is_infinite() || nn.distance == f32::MAX) {
+                filtered.push(ann::Node {
+                    vid: nn.vid,
+                    distance: nn.distance,
+                    eid: eid,
+                });
+            }
+            ControlFlow::Continue(())
+        });
+        Ok(match reranker {
+            Some((store, vals)) => store.rerank(vals, filtered, k),
+            None => filtered,
+        })
+    }
+
+    // the store to re-rank the candidates of q with along with the full
+    // precision values of q, only QuantizerIn queries can be re-ranked
+    fn reranker<'q>(
+        &self,
+        q: &ann::Points<'q, TVal>,
+    ) -> Option<(&rerank::FullPrecisionStore, &'q [f32])> {
+        match (&self.full_precision, q.full_precision()) {
+            (Some(store), Some(vals)) => Some((store.as_ref(), vals)),
+            _ => None,
+        }
+    }
+
+    // re-ranking needs more candidates out of the graph than k
+    fn search_list_size(
+        &self,
+        q: &ann::Points<TVal>,
+        k: usize,
+        params_r: &DiskANNParamsInternal,
+    ) -> usize {
+        match self.reranker(q) {
+            Some((store, _)) => cmp::max(
+                params_r.params_e.indexing_queue_size,
+                store.num_candidates(k),
+            ),
+            None => params_r.params_e.indexing_queue_size,
+        }
+    }
 
+    // pads and pre-processes q into an aligned buffer the metric can consume,
+    // the distance table of an index using product quantization is built
+    // from that buffer. QuantizerIn queries stay at full precision and are
+    // compared against the codes of a quantized index directly
+    fn prepare_query(
+        &self,
+        q: ann::Points<TVal>,
+        params_r: &DiskANNParamsInternal,
+    ) -> anyhow::Result<PreparedQuery<TVal>> {
         let data: &[TVal];
-        let quantize_result: Vec<TVal>;
         match q {
             ann::Points::QuantizerIn { vals } => {
-                let (res, _) = self.quantizer.quantize_arr(vals);
-                quantize_result = res
-                    .iter()
-                    .map(|x| TVal::from_u8(*x).expect("unable to coerce to u8"))
-                    .collect();
-                data = &quantize_result[..]
+                let space = match self.code_space(params_r) {
+                    Some(space) => space,
+                    None => {
+                        bail!("QuantizerIn queries need an index built from QuantizerIn points")
+                    }
+                };
+                if vals.len() > params_r.aligned_dim {
+                    bail!(
+                        "query dim: {} > aligned_dim: {}",
+                        vals.len(),
+                        params_r.aligned_dim
+                    );
+                }
+                let mut padded: Vec<f32> = vec![0.0; params_r.aligned_dim];
+                padded[0..vals.len()].copy_from_slice(vals);
+                return Ok(PreparedQuery::Quantized(space.query(&padded)));
             }
             ann::Points::Values { vals } => data = vals,
         }
@@ -694,7 +1119,8 @@ where
         let mut q_aligned: AlignedDataStore<TVal> =
             AlignedDataStore::<TVal>::new(params_r.aligned_dim, 1);
         q_aligned.data[0..padded_points.len()].clone_from_slice(padded_points);
-        if TMetric::uses_preprocessor() {
+        // the values a quantized index is queried with are codes already
+        if TMetric::uses_preprocessor() && !params_r.quantized {
             match TMetric::pre_process(&q_aligned.data[0..q_aligned.data.len()]) {
                 Some(vec) => {
                     q_aligned.data[0..padded_points.len()].copy_from_slice(&vec[0..vec.len()]);
@@ -702,39 +1128,105 @@ where
                 None => {}
             }
         }
+        let table = self
+            .pq
+            .read()
+            .as_ref()
+            .map(|pq| pq.distance_table(&product_quantizer::to_f32(&q_aligned.data)));
+        Ok(match table {
+            Some(table) => PreparedQuery::Codes(table),
+            None => PreparedQuery::Vector(q_aligned),
+        })
+    }
 
-        let mut scratch: nn_query_scratch::InMemoryQueryScratch =
-            nn_query_scratch::InMemoryQueryScratch::new(&params_r);
-        self.iterate_to_fixed_point(
-            QueryTarget::Vector(&q_aligned.data),
-            &params_r,
-            &mut init_ids,
-            &mut scratch,
-            true,
-            true,
-        );
-        let mut filtered: Vec<ann::Node> = Vec::with_capacity(k + 1);
-        let mapping = self.location_to_tag.read();
-
-        scratch.best_l_nodes.data.iter().try_for_each(|nn| {
-            if filtered.len() >= k {
-                return ControlFlow::Break(nn);
-            }
-            let eid;
-            match mapping.get(&nn.vid) {
-                Some(val) => eid = *val,
-                None => return ControlFlow::Continue(()),
+    // every point within radius of q - the search list is doubled for as
+    // long as it comes back saturated with in-range candidates, as there
+    // might be more of them the search did not get to keep
+    pub fn range_search(
+        &self,
+        q: ann::Points<TVal>,
+        radius: f32,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        let _resize_r = self.resize_lock.read();
+        let params_r = self.params.read();
+        let prepared = self.prepare_query(q, &params_r)?;
+        let max_search_list_size = params_r.params_e.max_points + params_r.num_frozen_pts;
+        let mut search_list_size = params_r.params_e.indexing_queue_size;
+        loop {
+            let mut scratch = nn_query_scratch::InMemoryQueryScratch::with_search_list_size(
+                &params_r,
+                search_list_size,
+            );
+            let mut init_ids: Vec<usize> = vec![params_r.start];
+            self.iterate_to_fixed_point(
+                prepared.target(),
+                &params_r,
+                &mut init_ids,
+                &mut scratch,
+                true,
+                true,
+                None,
+            );
+            let saturated = scratch
+                .best_l_nodes
+                .data
+                .last()
+                .map_or(false, |nn| nn.distance <= radius);
+            if saturated && search_list_size < max_search_list_size {
+                search_list_size = cmp::min(search_list_size * 2, max_search_list_size);
+                continue;
             }
-            if nn.vid != params_r.start && !(nn.distance.is_infinite() || nn.distance == f32::MAX) {
-                filtered.push(ann::Node {
-                    vid: nn.vid,
-                    distance: nn.distance,
-                    eid: eid,
-                });
+            let mapping = self.location_to_tag.read();
+            return Ok(scratch.best_l_nodes.data[..scratch.best_l_nodes.len()]
+                .iter()
+                .take_while(|nn| nn.distance <= radius)
+                .filter(|nn| nn.vid != params_r.start)
+                .filter_map(|nn| {
+                    mapping.get(&nn.vid).map(|eid| ann::Node {
+                        vid: nn.vid,
+                        eid: *eid,
+                        distance: nn.distance,
+                    })
+                })
+                .collect());
+        }
+    }
+
+    #[inline(always)]
+    fn has_any_label(&self, vid: usize, labels: &[ann::Label]) -> bool {
+        let all_labels = self.labels.read();
+        let vid_labels = all_labels[vid].read();
+        labels
+            .iter()
+            .any(|label| vid_labels.binary_search(label).is_ok())
+    }
+
+    // the labels whose subgraphs a filtered search walks - every label for
+    // Any, while All only needs the subgraph of its rarest label as every
+    // match is guaranteed to be part of it
+    fn navigation_labels(&self, filter: &ann::LabelFilter) -> Vec<ann::Label> {
+        match filter {
+            ann::LabelFilter::Any(labels) => labels.clone(),
+            ann::LabelFilter::All(labels) => {
+                let label_counts = self.label_counts.read();
+                labels
+                    .iter()
+                    .min_by_key(|label| label_counts.get(label).copied().unwrap_or(0))
+                    .map(|label| vec![*label])
+                    .unwrap_or_default()
             }
-            ControlFlow::Continue(())
-        });
-        Ok(filtered)
+        }
+    }
+
+    fn label_start_ids(&self, labels: &[ann::Label]) -> Vec<usize> {
+        let label_starts = self.label_starts.read();
+        let mut init_ids: Vec<usize> = labels
+            .iter()
+            .filter_map(|label| label_starts.get(label).copied())
+            .collect();
+        init_ids.sort();
+        init_ids.dedup();
+        init_ids
     }
 
     #[allow(dead_code)]
@@ -756,27 +1248,20 @@ where
         self.generate_frozen_point();
         let start = Instant::now();
         self.link(visit_order, true);
-        println!("link time: {:?}", start.elapsed());
-        let mut max: usize = 0;
-        let mut min: usize = usize::MAX;
-        let mut total: usize = 0;
-        let mut cnt: usize = 0;
-        for idx in 0..self.params.read().nd {
-            let fg_nbrs = self.final_graph[idx].read();
-            max = cmp::max(max, fg_nbrs.len());
-            min = cmp::min(min, fg_nbrs.len());
-            total += fg_nbrs.len();
-            if fg_nbrs.len() < 2 {
-                cnt += 1;
-            }
-        }
-        println!(
-            "Index Build Stats\nmax: {} | avg: {} | nmin: {} | count(deg<2): {}",
-            max,
-            total / (self.params.read().nd + 1),
-            min,
-            cnt,
-        );
+        log::debug!("link time: {:?}", start.elapsed());
+        // the degree stats walk the whole graph, only worth it when logged
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        if let Some(graph) = self.stats().graph {
+            log::debug!(
+                "index build stats - max: {} | avg: {} | nmin: {} | count(deg<2): {}",
+                graph.max_degree,
+                graph.avg_degree,
+                graph.min_degree,
+                graph.num_degree_lt_2,
+            );
+        }
     }
 
     fn reserve_locations(&self, count: usize) -> anyhow::Result<Vec<usize>> {
@@ -784,7 +1269,7 @@ where
         let mut empty_slots_w = self.empty_slots.write();
         if !empty_slots_w.is_empty() {
             empty_slots_w.iter().try_for_each(|vid| {
-                if vids.len() > count {
+                if vids.len() >= count {
                     return ControlFlow::Break(vid);
                 }
                 vids.push(*vid);
@@ -809,7 +1294,7 @@ where
                 empty_slots_w.insert(*vid);
             });
             bail!(
-                "reservation ({}) would lead to > max_points ({}) in index, resizing not supported as yet",
+                "reservation ({}) would lead to > max_points ({}) in index, see resize(..) or growth_factor",
                 count,
                 params_r.params_e.max_points,
             );
@@ -824,6 +1309,118 @@ where
         Ok(vids)
     }
 
+    // grows the index to hold new_max_points points without a rebuild, see
+    // grow_to(..) for how existing locations are carried over
+    pub fn resize(&self, new_max_points: usize) -> anyhow::Result<()> {
+        let max_points = self.params.read().params_e.max_points;
+        if new_max_points < max_points {
+            bail!(
+                "new_max_points: {} < max_points: {}, shrinking is not supported",
+                new_max_points,
+                max_points
+            );
+        }
+        self.grow_to(new_max_points)
+    }
+
+    // called when a reservation for count points failed - grows the index by
+    // growth_factor (or to fit count if that is not enough), returns false
+    // when automatic growth is not switched on
+    fn grow_for(&self, count: usize) -> anyhow::Result<bool> {
+        let (growth_factor, max_points) = {
+            let params_r = self.params.read();
+            (
+                params_r.params_e.growth_factor,
+                params_r.params_e.max_points,
+            )
+        };
+        let growth_factor = match growth_factor {
+            Some(factor) => factor,
+            None => return Ok(false),
+        };
+        let grown = ((max_points as f64) * (growth_factor as f64)).ceil() as usize;
+        let needed = self.id_increment.load(std::sync::atomic::Ordering::SeqCst) + count;
+        self.grow_to(cmp::max(grown, needed))?;
+        Ok(true)
+    }
+
+    // existing vids keep their location, the per location state is extended
+    // and the frozen start point moves over to the new last location. the
+    // location it leaves behind is handed out like any other once the index
+    // fills up to it. a no-op if the index already holds new_max_points
+    fn grow_to(&self, new_max_points: usize) -> anyhow::Result<()> {
+        let _resize_w = self.resize_lock.write();
+        let mut params_w = self.params.write();
+        let old_max_points = params_w.params_e.max_points;
+        if new_max_points <= old_max_points {
+            return Ok(());
+        }
+        let old_start = params_w.start;
+        let new_start = new_max_points;
+        let aligned_dim = params_w.aligned_dim;
+        let total_internal_points = new_max_points + params_w.num_frozen_pts;
+        {
+            let mut data_w = self.data.write();
+            data_w.grow(total_internal_points, aligned_dim)?;
+            ann::copy_within_a_slice(
+                &mut data_w.data,
+                old_start * aligned_dim,
+                new_start * aligned_dim,
+                aligned_dim,
+            );
+            data_w.data[old_start * aligned_dim..old_start * aligned_dim + aligned_dim]
+                .fill(Default::default());
+        }
+        if let Some(pq_params) = &params_w.params_e.pq {
+            let code_len = pq_params.code_len();
+            let mut pq_codes = self.pq_codes.write();
+            pq_codes.resize(total_internal_points * code_len, 0);
+            pq_codes.copy_within(
+                old_start * code_len..old_start * code_len + code_len,
+                new_start * code_len,
+            );
+        }
+        {
+            let reserve_size =
+                ((params_w.params_e.indexing_range as f64) * GRAPH_SLACK_FACTOR * 1.05).ceil()
+                    as usize;
+            let mut final_graph = self.final_graph.write();
+            let mut in_graph = self.in_graph.write();
+            let mut labels = self.labels.write();
+            final_graph.resize_with(total_internal_points, || {
+                RwLock::new(Vec::with_capacity(reserve_size))
+            });
+            in_graph.resize_with(total_internal_points, || {
+                RwLock::new(HashSet::with_capacity(reserve_size))
+            });
+            labels.resize_with(total_internal_points, || RwLock::new(Vec::new()));
+            final_graph.swap(old_start, new_start);
+            in_graph.swap(old_start, new_start);
+            labels.swap(old_start, new_start);
+            // re-point every edge that touches the start point
+            for nbr_vid in final_graph[new_start].read().iter() {
+                let mut nbr_in = in_graph[*nbr_vid].write();
+                if nbr_in.remove(&old_start) {
+                    nbr_in.insert(new_start);
+                }
+            }
+            for nbr_vid in in_graph[new_start].read().iter() {
+                final_graph[*nbr_vid]
+                    .write()
+                    .iter_mut()
+                    .filter(|vid| **vid == old_start)
+                    .for_each(|vid| *vid = new_start);
+            }
+        }
+        // outside of batch builds nd tracks max_points
+        if params_w.nd == old_max_points {
+            params_w.nd = new_max_points;
+        }
+        params_w.params_e.max_points = new_max_points;
+        params_w.start = new_start;
+        Ok(())
+    }
+
     #[inline(always)]
     fn update_graph_nbrs(
         &self,
@@ -831,9 +1428,11 @@ where
         new_nbrs: impl IntoIterator<Item = usize>,
         update_in_graph: bool,
     ) {
+        let final_graph = self.final_graph.read();
+        let in_graph = self.in_graph.read();
         let mut old_segment: Option<(Vec<usize>, Vec<usize>)> = None;
         {
-            let mut segment = self.final_graph[vid].write();
+            let mut segment = final_graph[vid].write();
             if update_in_graph {
                 let mut nbrs_clone = Vec::new();
                 let segment_clone = segment.clone();
@@ -855,10 +1454,10 @@ where
                 // populate_in_graph - the regexp to find instances this must
                 // be tied to is: "final_graph\[.*\].*write\(\)"
                 old_vids.iter().for_each(|nbr_vid| {
-                    self.in_graph[*nbr_vid].write().remove(&vid);
+                    in_graph[*nbr_vid].write().remove(&vid);
                 });
                 nbrs_copy.into_iter().for_each(|nbr_vid| {
-                    self.in_graph[nbr_vid].write().insert(vid);
+                    in_graph[nbr_vid].write().insert(vid);
                 });
             }
             None => {}
@@ -888,12 +1487,13 @@ where
         params_r: &DiskANNParamsInternal,
         data: &AlignedDataStore<TVal>,
     ) {
+        let final_graph = self.final_graph.read();
         // TODO(infrawhispers) - what should this be set as?
         let mut expanded_nodes_set: Vec<usize> = Vec::with_capacity(10);
 
         // first pool all the items that we care about
         {
-            self.final_graph[vid].read().iter().for_each(|nbr_vid| {
+            final_graph[vid].read().iter().for_each(|nbr_vid| {
                 if !delete_set.contains(nbr_vid) && *nbr_vid != vid {
                     expanded_nodes_set.push(*nbr_vid);
                 }
@@ -904,14 +1504,11 @@ where
             return;
         }
         let mut expanded_nbrs_vec: Vec<ann::INode> = Vec::with_capacity(expanded_nodes_set.len());
+        let view = self.code_view(params_r);
         expanded_nodes_set.iter().for_each(|nbr_vid| {
-            let arr_a: &[TVal] = &data.data[*nbr_vid * params_r.aligned_dim
-                ..(*nbr_vid * params_r.aligned_dim) + params_r.aligned_dim];
-            let arr_b: &[TVal] = &data.data
-                [vid * params_r.aligned_dim..(vid * params_r.aligned_dim) + params_r.aligned_dim];
             expanded_nbrs_vec.push(ann::INode {
                 vid: *nbr_vid,
-                distance: TMetric::compare(arr_a, arr_b),
+                distance: self.compare_locations(data, params_r, view.as_ref(), *nbr_vid, vid),
                 flag: false,
             })
         });
@@ -926,46 +1523,50 @@ where
             &mut pruned_list,
             params_r,
             data,
+            view.as_ref(),
         );
         self.update_graph_nbrs(vid, pruned_list, true);
     }
 
     fn consolidate_deletes(&self) {
+        let _resize_r = self.resize_lock.read();
+        let in_graph = self.in_graph.read();
+        let labels = self.labels.read();
         let old_delete_set: HashSet<usize>;
         {
             old_delete_set = self.delete_set.read().clone();
         }
+        if old_delete_set.is_empty() {
+            return;
+        }
         let start = Instant::now();
         let params_r = self.params.read();
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(1)
-            .build()
-            .unwrap();
         let data = self.data.read();
-        pool.install(|| {
-            // build a map of all vids we care about, these are vids that _link into_
-            // the nodes that we are about to remove!
-            let mut vids_to_visit: HashSet<usize> =
-                HashSet::with_capacity(params_r.params_e.indexing_range * old_delete_set.len());
-            old_delete_set.iter().for_each(|vid| {
-                let nbrs_linked = self.in_graph[*vid].read();
-                nbrs_linked
-                    .iter()
-                    .for_each(|nbr_vid| match old_delete_set.get(nbr_vid) {
-                        Some(_) => {}
-                        None => {
-                            vids_to_visit.insert(*nbr_vid);
-                        }
-                    });
-            });
-            vids_to_visit.par_iter().for_each(|vid| {
-                self.process_delete(*vid, &old_delete_set, &params_r, &data);
-            });
+        // build a map of all vids we care about, these are vids that _link into_
+        // the nodes that we are about to remove!
+        let mut vids_to_visit: HashSet<usize> =
+            HashSet::with_capacity(params_r.params_e.indexing_range * old_delete_set.len());
+        old_delete_set.iter().for_each(|vid| {
+            let nbrs_linked = in_graph[*vid].read();
+            nbrs_linked
+                .iter()
+                .for_each(|nbr_vid| match old_delete_set.get(nbr_vid) {
+                    Some(_) => {}
+                    None => {
+                        vids_to_visit.insert(*nbr_vid);
+                    }
+                });
+        });
+        vids_to_visit.par_iter().for_each(|vid| {
+            self.process_delete(*vid, &old_delete_set, &params_r, &data);
         });
         let mut delete_set = self.delete_set.write();
         let mut empty_slots = self.empty_slots.write();
         let mut tag_to_location = self.tag_to_location.write();
         let mut location_to_tag = self.location_to_tag.write();
+        // label_starts before label_counts, in the order inserts take them
+        let mut label_starts = self.label_starts.write();
+        let mut label_counts = self.label_counts.write();
 
         // let empty_slots = self.
         old_delete_set.iter().for_each(|vid| {
@@ -977,40 +1578,117 @@ where
                 }
                 None => {}
             }
+            let mut vid_labels = labels[*vid].write();
+            vid_labels.iter().for_each(|label| {
+                if let Some(count) = label_counts.get_mut(label) {
+                    *count = count.saturating_sub(1);
+                }
+            });
+            vid_labels.clear();
         });
-        println!("consolidate_delete time: {:?}", start.elapsed());
+        // labels whose entry point was just freed move to another live point
+        let orphaned: Vec<ann::Label> = label_starts
+            .iter()
+            .filter(|(_, start)| old_delete_set.contains(start))
+            .map(|(label, _)| *label)
+            .collect();
+        if !orphaned.is_empty() {
+            orphaned.iter().for_each(|label| {
+                label_starts.remove(label);
+            });
+            for vid in location_to_tag.keys() {
+                for label in labels[*vid].read().iter() {
+                    if orphaned.contains(label) {
+                        label_starts.entry(*label).or_insert(*vid);
+                    }
+                }
+            }
+        }
+        log::debug!("consolidate_delete time: {:?}", start.elapsed());
     }
 
     fn insert(&self, eids: &[EId], p: ann::Points<TVal>) -> anyhow::Result<()> {
-        let data: &[TVal];
-        match p {
-            ann::Points::QuantizerIn { .. } => {
-                unreachable!("incorrect params passed for construction")
-            }
-            ann::Points::Values { vals } => data = vals,
+        self.insert_with_labels(eids, p, &[])
+    }
+
+    // labels[i] is the label set of eids[i], an empty labels slice inserts
+    // the points without any labels
+    pub fn insert_with_labels(
+        &self,
+        eids: &[EId],
+        p: ann::Points<TVal>,
+        labels: &[Vec<ann::Label>],
+    ) -> anyhow::Result<()> {
+        if !labels.is_empty() && labels.len() != eids.len() {
+            bail!("labels.len: {} != eids.len: {}", labels.len(), eids.len());
         }
+        let quantize = matches!(p, ann::Points::QuantizerIn { .. });
+        let points_len = match p {
+            ann::Points::QuantizerIn { vals } => vals.len(),
+            ann::Points::Values { vals } => vals.len(),
+        };
 
         // we assume everything is good!
         {
             let params_r = self.params.read();
             let expected_len = eids.len() * params_r.aligned_dim;
-            if data.len() != expected_len {
+            if points_len != expected_len {
                 bail!(
                     "points.len: {} !=  aligned_dim * eids.len: {}",
-                    data.len(),
+                    points_len,
                     expected_len
                 );
             }
         }
-        let vids = self.reserve_locations(eids.len())?;
+        if let Some(pq_params) = self.params.read().params_e.pq {
+            product_quantizer::check_first_batch(&pq_params, self.pq.read().is_some(), eids.len())?;
+        }
+        self.set_quantized(quantize)?;
+        let vids = loop {
+            match self.reserve_locations(eids.len()) {
+                Ok(vids) => break vids,
+                Err(err) => {
+                    if !self.grow_for(eids.len())? {
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        let _resize_r = self.resize_lock.read();
         debug_assert!(
             vids.len() == eids.len(),
             "could not get enough vids to map to the eid database",
         );
+        let data: &[TVal];
+        let quantize_result: Vec<TVal>;
+        match p {
+            ann::Points::QuantizerIn { vals } => {
+                if let Some(store) = &self.full_precision {
+                    store.insert(&vids, vals)?;
+                }
+                // rows keep their width, the packed codes of a point fill
+                // the front of its row
+                let aligned_dim = self.params.read().aligned_dim;
+                let codes = self.quantizer.quantize(&vids, vals, None);
+                quantize_result = codes
+                    .chunks(self.quantizer.code_len(aligned_dim))
+                    .flat_map(|point| {
+                        point
+                            .iter()
+                            .map(|x| TVal::from_u8(*x).expect("unable to coerce to u8"))
+                            .chain(std::iter::repeat(TVal::default()))
+                            .take(aligned_dim)
+                    })
+                    .collect();
+                data = &quantize_result[..]
+            }
+            ann::Points::Values { vals } => data = vals,
+        }
         let data_processed;
         let mut preprocess_scratch: Vec<TVal>;
 
-        if TMetric::uses_preprocessor() {
+        // codes are compared through the quantizer, which needs no preprocessing
+        if TMetric::uses_preprocessor() && !quantize {
             let params_r = self.params.read();
             preprocess_scratch = vec![Default::default(); data.len()];
             for idx in 0..vids.len() {
@@ -1047,12 +1725,41 @@ where
                     .copy_from_slice(&data_processed[idx_s_fr..idx_e_fr]);
             }
         }
+        let pq_params = self.params.read().params_e.pq;
+        if let Some(pq_params) = pq_params {
+            self.encode_pq(&pq_params, &vids, data_processed)?;
+        }
         {
-            // insert items into the tag_to_location + location_to_tag
+            // reused slots must not keep the labels of their previous point
+            let mut label_starts = self.label_starts.write();
+            let mut label_counts = self.label_counts.write();
+            for (idx, vid) in vids.iter().enumerate() {
+                let mut vid_labels: Vec<ann::Label> = match labels.get(idx) {
+                    Some(l) => l.clone(),
+                    None => Vec::new(),
+                };
+                vid_labels.sort();
+                vid_labels.dedup();
+                vid_labels.iter().for_each(|label| {
+                    label_starts.entry(*label).or_insert(*vid);
+                    *label_counts.entry(*label).or_insert(0) += 1;
+                });
+                *self.labels.read()[*vid].write() = vid_labels;
+            }
+        }
+        {
+            // insert items into the tag_to_location + location_to_tag, an eid
+            // that is already present is upserted - its old location is
+            // untagged right away (so searches stop returning it) and left in
+            // the delete_set for consolidation to unlink
+            let mut delete_set = self.delete_set.write();
             let mut tl = self.tag_to_location.write();
             let mut lt = self.location_to_tag.write();
             for idx in 0..eids.len() {
-                tl.insert(eids[idx], vids[idx]);
+                if let Some(old_vid) = tl.insert(eids[idx], vids[idx]) {
+                    lt.remove(&old_vid);
+                    delete_set.insert(old_vid);
+                }
                 lt.insert(vids[idx], eids[idx]);
             }
         }
@@ -1061,6 +1768,93 @@ where
         self.link(vids, false);
         Ok(())
     }
+    // the first insert decides whether the index holds the codes of the
+    // scalar quantizer or plain values, the two cannot be mixed afterwards.
+    // once settled a read lock does - the write lock is only taken while the
+    // choice is still open, and the choice is checked again under it so that
+    // of two racing first inserts the second sees the choice of the first
+    fn set_quantized(&self, quantize: bool) -> anyhow::Result<()> {
+        if self.full_precision.is_some() && !quantize {
+            bail!("re-ranking needs the full precision values of QuantizerIn points");
+        }
+        {
+            let params_r = self.params.read();
+            if params_r.quantized_fixed {
+                return Self::check_quantized(&params_r, quantize);
+            }
+        }
+        let mut params_w = self.params.write();
+        if params_w.quantized_fixed {
+            return Self::check_quantized(&params_w, quantize);
+        }
+        if quantize && params_w.params_e.pq.is_some() {
+            bail!("product quantization cannot be combined with QuantizerIn points");
+        }
+        if quantize && TMetric::quantized_distance().is_none() {
+            bail!(
+                "metric: {} cannot be used with QuantizerIn points",
+                std::any::type_name::<TMetric>()
+            );
+        }
+        params_w.quantized = quantize;
+        params_w.quantized_fixed = true;
+        Ok(())
+    }
+
+    fn check_quantized(params: &DiskANNParamsInternal, quantize: bool) -> anyhow::Result<()> {
+        match (params.quantized, quantize) {
+            (true, false) => bail!("index holds quantized points, insert through QuantizerIn"),
+            (false, true) => {
+                bail!("index holds plain values, QuantizerIn points cannot be mixed in")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn encode_pq(
+        &self,
+        pq_params: &product_quantizer::PQParams,
+        vids: &[usize],
+        data: &[TVal],
+    ) -> anyhow::Result<()> {
+        let params_r = self.params.read();
+        let aligned_dim = params_r.aligned_dim;
+        let points = product_quantizer::to_f32(data);
+        let start = params_r.start;
+        let start_vec = product_quantizer::to_f32(
+            &self.data.read().data[start * aligned_dim..start * aligned_dim + aligned_dim],
+        );
+        let mut pq_w = self.pq.write();
+        let mut pq_codes = self.pq_codes.write();
+        let code_len = pq_params.code_len();
+        if pq_w.is_none() {
+            // the codebooks are trained on the first batch we are handed, at
+            // which point the frozen start point gets its code as well
+            let pq = product_quantizer::ProductQuantizer::train(
+                pq_params,
+                aligned_dim,
+                product_quantizer::distance_for::<TVal, TMetric>()?,
+                &points,
+            )?;
+            pq.encode(
+                &start_vec,
+                &mut pq_codes[start * code_len..start * code_len + code_len],
+            );
+            *pq_w = Some(pq);
+        }
+        let pq = match pq_w.as_ref() {
+            Some(pq) => pq,
+            None => bail!("the product quantizer is unexpectedly untrained"),
+        };
+        for (idx, vid) in vids.iter().enumerate() {
+            pq.encode(
+                &points[idx * aligned_dim..idx * aligned_dim + aligned_dim],
+                &mut pq_codes[vid * code_len..vid * code_len + code_len],
+            );
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn batch_insert(&self, eids: &[EId], data: &[TVal]) -> Result<(), Box<dyn std::error::Error>> {
         {
@@ -1095,18 +1889,495 @@ where
         Ok(())
     }
 
-    pub fn maintain(&self) {
-        let maintenance_period: u64;
+    pub fn get(&self, eids: &[EId]) -> Vec<Option<Vec<TVal>>> {
+        let _resize_r = self.resize_lock.read();
+        let vids: Vec<Option<usize>> = {
+            let tag_to_location = self.tag_to_location.read();
+            eids.iter()
+                .map(|eid| tag_to_location.get(eid).copied())
+                .collect()
+        };
+        let (dim, aligned_dim) = {
+            let params_r = self.params.read();
+            (params_r.params_e.dim, params_r.aligned_dim)
+        };
+        let data = self.data.read();
+        vids.into_iter()
+            .map(|vid| {
+                vid.map(|vid| data.data[vid * aligned_dim..vid * aligned_dim + dim].to_vec())
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> ann::IndexStats {
+        // writers lock delete_set -> empty_slots -> tag_to_location ->
+        // location_to_tag, so snapshot the former before any lock is held
+        // and take location_to_tag last
+        let num_deleted = self.delete_set.read().len();
+        let num_empty_slots = self.empty_slots.read().len();
+        let tag_to_location_capacity = self.tag_to_location.read().capacity();
+        let _resize_r = self.resize_lock.read();
+        let final_graph = self.final_graph.read();
+        let in_graph = self.in_graph.read();
+        let labels = self.labels.read();
+        let params_r = self.params.read();
+
+        let mut memory_bytes: BTreeMap<&'static str, usize> = BTreeMap::new();
+        memory_bytes.insert(
+            "vectors",
+            self.data.read().data.len() * std::mem::size_of::<TVal>(),
+        );
+        memory_bytes.insert(
+            "final_graph",
+            final_graph
+                .iter()
+                .map(|nbrs| {
+                    std::mem::size_of::<RwLock<Vec<usize>>>()
+                        + nbrs.read().capacity() * std::mem::size_of::<usize>()
+                })
+                .sum(),
+        );
+        // hashbrown keeps a control byte per bucket
+        memory_bytes.insert(
+            "in_graph",
+            in_graph
+                .iter()
+                .map(|nbrs| {
+                    std::mem::size_of::<RwLock<HashSet<usize>>>()
+                        + nbrs.read().capacity() * (std::mem::size_of::<usize>() + 1)
+                })
+                .sum(),
+        );
+        memory_bytes.insert(
+            "labels",
+            labels
+                .iter()
+                .map(|vid_labels| {
+                    std::mem::size_of::<RwLock<Vec<ann::Label>>>()
+                        + vid_labels.read().capacity() * std::mem::size_of::<ann::Label>()
+                })
+                .sum(),
+        );
+        memory_bytes.insert("pq_codes", self.pq_codes.read().capacity());
+        memory_bytes.insert(
+            "full_precision",
+            self.full_precision
+                .as_ref()
+                .map_or(0, |store| store.memory_bytes()),
+        );
+
+        let location_to_tag = self.location_to_tag.read();
+        memory_bytes.insert(
+            "tags",
+            (location_to_tag.capacity() + tag_to_location_capacity)
+                * (std::mem::size_of::<usize>() + std::mem::size_of::<EId>() + 1),
+        );
+
+        let graph = ann::GraphStats::from_degrees(
+            location_to_tag
+                .keys()
+                .map(|vid| final_graph[*vid].read().len()),
+            in_graph.iter().map(|nbrs| nbrs.read().len()).sum(),
+        );
+        ann::IndexStats {
+            num_live: location_to_tag.len(),
+            num_deleted,
+            num_empty_slots,
+            capacity: Some(params_r.params_e.max_points),
+            graph: Some(graph),
+            memory_bytes,
+            quantizers: ann::QuantizerStats {
+                scalar_quantile: Some(self.quantizer.quantile()),
+                pq: params_r.params_e.pq,
+                pq_trained: self.pq.read().is_some(),
+                rerank_factor: self.full_precision.as_ref().map(|store| store.factor()),
+                scalar_bits: Some(self.quantizer.params().bits),
+                scalar_per_dimension: self.quantizer.params().per_dimension,
+                scalar_reconstruction_error: self.quantizer.reconstruction_error(),
+                scalar_drift: Some(self.quantizer.drift()),
+            },
+        }
+    }
+
+    // spawns the background maintenance, every maintenance_period_millis it
+    // checks the deletes awaiting removal against params and consolidates
+    // them once a threshold is crossed
+    pub fn start_maintenance(self: &Arc<Self>, params: MaintenanceParams) -> MaintenanceHandle
+    where
+        TMetric: 'static,
+        TVal: 'static,
+    {
+        let maintenance_period =
+            time::Duration::from_millis(self.params.read().params_e.maintenance_period_millis);
+        let index: Weak<Self> = Arc::downgrade(self);
+        MaintenanceHandle::spawn(maintenance_period, move || match index.upgrade() {
+            Some(index) => {
+                if index.should_consolidate(&params) {
+                    index.consolidate_deletes();
+                }
+                true
+            }
+            None => false,
+        })
+    }
+
+    // removes every point deleted so far from the graph and frees up their
+    // locations, regardless of the maintenance thresholds
+    pub fn consolidate_now(&self) {
+        self.consolidate_deletes();
+    }
+
+    fn should_consolidate(&self, params: &MaintenanceParams) -> bool {
+        let num_deletes = self.delete_set.read().len();
+        if num_deletes == 0 {
+            return false;
+        }
+        let num_live = self.location_to_tag.read().len();
+        num_deletes >= params.min_deletes
+            || num_deletes as f32 >= params.min_delete_fraction * num_live as f32
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(path)?;
+        // we hold the read locks for the duration of the save so the files
+        // we write reflect a single consistent view of the index
+        let _resize_r = self.resize_lock.read();
+        let final_graph = self.final_graph.read();
+        let in_graph = self.in_graph.read();
+        let labels = self.labels.read();
+        let delete_set = self.delete_set.read();
+        let empty_slots = self.empty_slots.read();
+        let location_to_tag = self.location_to_tag.read();
+        let params_r = self.params.read();
+        let data = self.data.read();
+
+        persist::write_file(&path.join(META_FILE), |w| {
+            persist::write_header(w, DISKANN_MAGIC, DISKANN_VERSION)?;
+            persist::write_element_type::<TVal>(w)?;
+            persist::write_metric_type::<TMetric>(w)?;
+            let params_e = &params_r.params_e;
+            persist::write_usize(w, params_e.dim)?;
+            persist::write_usize(w, params_e.max_points)?;
+            persist::write_usize(w, params_e.indexing_threads.unwrap_or(0))?;
+            persist::write_usize(w, params_e.indexing_range)?;
+            persist::write_usize(w, params_e.indexing_queue_size)?;
+            persist::write_usize(w, params_e.indexing_maxc)?;
+            w.write_f32::<LittleEndian>(params_e.indexing_alpha)?;
+            w.write_u64::<LittleEndian>(params_e.maintenance_period_millis)?;
+
+            persist::write_usize(w, params_r.aligned_dim)?;
+            persist::write_usize(w, params_r.num_frozen_pts)?;
+            persist::write_usize(w, params_r.nd)?;
+            persist::write_usize(w, params_r.start)?;
+            w.write_u8(params_r.saturate_graph as u8)?;
+
+            persist::write_usize(
+                w,
+                self.id_increment.load(std::sync::atomic::Ordering::SeqCst),
+            )?;
+            persist::write_usize(w, data.num_vectors)?;
+            persist::write_usize(w, data.data.len())?;
+            // v2: whether the vectors should be mapped rather than read in
+            w.write_u8(data.mapped_path().is_some() as u8)?;
+            // v3: the product quantization settings
+            w.write_u8(params_e.pq.is_some() as u8)?;
+            if let Some(pq_params) = &params_e.pq {
+                persist::write_usize(w, pq_params.num_subspaces)?;
+                persist::write_usize(w, pq_params.bits)?;
+            }
+            // v5: automatic growth
+            w.write_u8(params_e.growth_factor.is_some() as u8)?;
+            if let Some(growth_factor) = params_e.growth_factor {
+                w.write_f32::<LittleEndian>(growth_factor)?;
+            }
+            // v6: whether data holds the codes of the scalar quantizer
+            w.write_u8(params_r.quantized as u8)?;
+            // v7: the re-ranking settings
+            rerank::RerankParams::save(&params_e.rerank, w)?;
+            // v8: the scalar quantizer settings
+            scalar_quantizer::ScalarQuantizerParams::save(&params_e.quantizer, w)?;
+            Ok(())
+        })?;
+        let data_path = path.join(DATA_FILE);
+        match data.mapped_path() {
+            // renaming over the file we are mapped onto would detach the
+            // mapping from it - so just flush our dirty pages instead
+            Some(mapped) if persist::same_file(mapped, &data_path) => data.flush()?,
+            _ => persist::write_file(&data_path, |w| persist::write_vals(w, &data.data[..]))?,
+        }
+        persist::write_file(&path.join(GRAPH_FILE), |w| {
+            persist::write_usize(w, final_graph.len())?;
+            for vid in 0..final_graph.len() {
+                let nbrs = final_graph[vid].read();
+                persist::write_vids(w, nbrs.len(), nbrs.iter())?;
+                let in_nbrs = in_graph[vid].read();
+                persist::write_vids(w, in_nbrs.len(), in_nbrs.iter())?;
+            }
+            Ok(())
+        })?;
+        persist::write_file(&path.join(TAGS_FILE), |w| {
+            persist::write_usize(w, location_to_tag.len())?;
+            for (vid, eid) in location_to_tag.iter() {
+                persist::write_usize(w, *vid)?;
+                persist::write_eid(w, eid)?;
+            }
+            persist::write_vids(w, delete_set.len(), delete_set.iter())?;
+            persist::write_vids(w, empty_slots.len(), empty_slots.iter())?;
+            Ok(())
+        })?;
+        let label_starts = self.label_starts.read();
+        persist::write_file(&path.join(LABELS_FILE), |w| {
+            let labelled: Vec<usize> = (0..labels.len())
+                .filter(|vid| !labels[*vid].read().is_empty())
+                .collect();
+            persist::write_usize(w, labelled.len())?;
+            for vid in labelled {
+                let vid_labels = labels[vid].read();
+                persist::write_usize(w, vid)?;
+                persist::write_usize(w, vid_labels.len())?;
+                for label in vid_labels.iter() {
+                    w.write_u32::<LittleEndian>(*label)?;
+                }
+            }
+            persist::write_usize(w, label_starts.len())?;
+            for (label, start) in label_starts.iter() {
+                w.write_u32::<LittleEndian>(*label)?;
+                persist::write_usize(w, *start)?;
+            }
+            Ok(())
+        })?;
+        if params_r.params_e.pq.is_some() {
+            let pq = self.pq.read();
+            let pq_codes = self.pq_codes.read();
+            persist::write_file(&path.join(PQ_FILE), |w| {
+                w.write_u8(pq.is_some() as u8)?;
+                if let Some(pq) = pq.as_ref() {
+                    pq.save(w)?;
+                }
+                persist::write_usize(w, pq_codes.len())?;
+                w.write_all(&pq_codes)?;
+                Ok(())
+            })?;
+        }
+        if params_r.quantized {
+            persist::write_file(&path.join(QUANTIZER_FILE), |w| self.quantizer.save(w))?;
+        }
+        if let Some(store) = &self.full_precision {
+            store.save(path)?;
+        }
+        Ok(())
+    }
+
+    // write the graph out in the sector layout read by DiskANNSSDIndex, only
+    // the compressed vectors of the index will be kept in memory from there on
+    pub fn save_ssd(&self, path: &Path) -> anyhow::Result<()> {
+        let _resize_r = self.resize_lock.read();
+        let final_graph = self.final_graph.read();
+        let location_to_tag = self.location_to_tag.read();
+        let params_r = self.params.read();
+        let data = self.data.read();
+        diskann_ssd::write_ssd_index(
+            path,
+            params_r.params_e.dim,
+            params_r.aligned_dim,
+            params_r.start,
+            &data.data[..],
+            |vid| final_graph[vid].read().clone(),
+            &location_to_tag,
+        )
+    }
+
+    fn load(path: &Path) -> anyhow::Result<DiskANNV1Index<TMetric, TVal>> {
+        let mut r = persist::open_file(&path.join(META_FILE))?;
+        let version = persist::read_header(&mut r, DISKANN_MAGIC, DISKANN_VERSION)?;
+        persist::check_element_type::<TVal>(&mut r, version < 9)?;
+        persist::check_metric_type::<TMetric>(&mut r, version < 9)?;
+        let mut params = DiskANNParams {
+            dim: persist::read_usize(&mut r)?,
+            max_points: persist::read_usize(&mut r)?,
+            indexing_threads: match persist::read_usize(&mut r)? {
+                0 => None,
+                cnt => Some(cnt),
+            },
+            indexing_range: persist::read_usize(&mut r)?,
+            indexing_queue_size: persist::read_usize(&mut r)?,
+            indexing_maxc: persist::read_usize(&mut r)?,
+            indexing_alpha: r.read_f32::<LittleEndian>()?,
+            maintenance_period_millis: r.read_u64::<LittleEndian>()?,
+            mmap_dir: None,
+            pq: None,
+            growth_factor: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let aligned_dim = persist::read_usize(&mut r)?;
+        let num_frozen_pts = persist::read_usize(&mut r)?;
+        let nd = persist::read_usize(&mut r)?;
+        let start = persist::read_usize(&mut r)?;
+        let saturate_graph = r.read_u8()? != 0;
+        let id_increment = persist::read_usize(&mut r)?;
+        let num_vectors = persist::read_usize(&mut r)?;
+        let data_len = persist::read_usize(&mut r)?;
+        let mapped = version >= 2 && r.read_u8()? != 0;
+        if version >= 3 && r.read_u8()? != 0 {
+            params.pq = Some(product_quantizer::PQParams {
+                num_subspaces: persist::read_usize(&mut r)?,
+                bits: persist::read_usize(&mut r)?,
+            });
+        }
+        if version >= 5 && r.read_u8()? != 0 {
+            params.growth_factor = Some(r.read_f32::<LittleEndian>()?);
+        }
+        let quantized = version >= 6 && r.read_u8()? != 0;
+        // kept out of new_core, which would map over the saved store
+        let rerank_params = match version >= 7 {
+            true => rerank::RerankParams::load(&mut r, path)?,
+            false => None,
+        };
+        if version >= 8 {
+            params.quantizer = scalar_quantizer::ScalarQuantizerParams::load(&mut r)?;
+        }
+
+        let mut data_store: Option<AlignedDataStore<TVal>> = None;
+        if mapped {
+            // cold start - map the saved vectors in place of reading them
+            params.mmap_dir = Some(path.to_path_buf());
+            data_store = Some(AlignedDataStore::open_mmap(
+                &path.join(DATA_FILE),
+                params.max_points + num_frozen_pts,
+                aligned_dim,
+            )?);
+        }
+        let mut obj = DiskANNV1Index::<TMetric, TVal>::new_core(&params, data_store)?;
+        if quantized {
+            let mut r = persist::open_file(&path.join(QUANTIZER_FILE))?;
+            obj.quantizer = Arc::new(scalar_quantizer::ScalarQuantizer::load(&mut r)?);
+        }
         {
-            maintenance_period = self.params.read().params_e.maintenance_period_millis;
+            let mut params_w = obj.params.write();
+            if aligned_dim != params_w.aligned_dim {
+                bail!("aligned_dim of the saved index does not match the params");
+            }
+            params_w.num_frozen_pts = num_frozen_pts;
+            params_w.nd = nd;
+            params_w.start = start;
+            params_w.saturate_graph = saturate_graph;
+            params_w.quantized = quantized;
+            params_w.quantized_fixed = id_increment > 0;
+            params_w.params_e.rerank = rerank_params.clone();
         }
-        loop {
-            thread::sleep(time::Duration::from_millis(maintenance_period));
-            self.consolidate_deletes();
+        if let Some(rerank_params) = &rerank_params {
+            obj.full_precision = Some(Arc::new(rerank::FullPrecisionStore::load(
+                rerank_params,
+                rerank::distance_for::<TVal, TMetric>()?,
+                aligned_dim,
+                path,
+            )?));
+        }
+        obj.id_increment
+            .store(id_increment, std::sync::atomic::Ordering::SeqCst);
+        {
+            let mut data_w = obj.data.write();
+            if data_len != data_w.data.len() {
+                bail!(
+                    "saved data len: {} != expected len: {}",
+                    data_len,
+                    data_w.data.len()
+                );
+            }
+            if !mapped {
+                let mut r = persist::open_file(&path.join(DATA_FILE))?;
+                persist::read_vals(&mut r, &mut data_w.data[..])?;
+            }
+            data_w.num_vectors = num_vectors;
+        }
+        {
+            let mut r = persist::open_file(&path.join(GRAPH_FILE))?;
+            let num_locations = persist::read_usize(&mut r)?;
+            let final_graph = obj.final_graph.read();
+            let in_graph = obj.in_graph.read();
+            if num_locations != final_graph.len() {
+                bail!(
+                    "saved graph has: {} locations, expected: {}",
+                    num_locations,
+                    final_graph.len()
+                );
+            }
+            for vid in 0..num_locations {
+                *final_graph[vid].write() = persist::read_vids(&mut r)?;
+                let mut in_nbrs = in_graph[vid].write();
+                in_nbrs.clear();
+                in_nbrs.extend(persist::read_vids(&mut r)?);
+            }
+        }
+        {
+            let mut r = persist::open_file(&path.join(TAGS_FILE))?;
+            let mut lt = obj.location_to_tag.write();
+            let mut tl = obj.tag_to_location.write();
+            for _ in 0..persist::read_usize(&mut r)? {
+                let vid = persist::read_usize(&mut r)?;
+                let eid = persist::read_eid(&mut r)?;
+                lt.insert(vid, eid);
+                tl.insert(eid, vid);
+            }
+            obj.delete_set.write().extend(persist::read_vids(&mut r)?);
+            obj.empty_slots.write().extend(persist::read_vids(&mut r)?);
+        }
+        if params.pq.is_some() {
+            let mut r = persist::open_file(&path.join(PQ_FILE))?;
+            if r.read_u8()? != 0 {
+                *obj.pq.write() = Some(product_quantizer::ProductQuantizer::load(&mut r)?);
+            }
+            let mut pq_codes = obj.pq_codes.write();
+            if persist::read_usize(&mut r)? != pq_codes.len() {
+                bail!("saved pq codes do not match the params of the index");
+            }
+            r.read_exact(&mut pq_codes[..])?;
+        }
+        if version >= 4 {
+            let mut r = persist::open_file(&path.join(LABELS_FILE))?;
+            let labels = obj.labels.read();
+            for _ in 0..persist::read_usize(&mut r)? {
+                let vid = persist::read_usize(&mut r)?;
+                if vid >= labels.len() {
+                    bail!("labelled location: {} is out of range", vid);
+                }
+                let mut vid_labels: Vec<ann::Label> = Vec::new();
+                for _ in 0..persist::read_usize(&mut r)? {
+                    vid_labels.push(r.read_u32::<LittleEndian>()?);
+                }
+                let mut label_counts = obj.label_counts.write();
+                vid_labels.iter().for_each(|label| {
+                    *label_counts.entry(*label).or_insert(0) += 1;
+                });
+                *labels[vid].write() = vid_labels;
+            }
+            let mut label_starts = obj.label_starts.write();
+            for _ in 0..persist::read_usize(&mut r)? {
+                let label = r.read_u32::<LittleEndian>()?;
+                label_starts.insert(label, persist::read_usize(&mut r)?);
+            }
         }
+        Ok(obj)
     }
 
     fn new(params: &DiskANNParams) -> anyhow::Result<DiskANNV1Index<TMetric, TVal>> {
+        let obj = DiskANNV1Index::new_core(params, None)?;
+        // any additional setup that we need to do _on the instance_
+        obj.set_start_point_at_random(5.0);
+        Ok(obj)
+    }
+
+    // allocates all the structures of the index, data_store lets callers hand
+    // over vectors that already exist (ie. a mapped file) instead of
+    // allocating a fresh store for them
+    fn new_core(
+        params: &DiskANNParams,
+        data_store: Option<AlignedDataStore<TVal>>,
+    ) -> anyhow::Result<DiskANNV1Index<TMetric, TVal>> {
+        if params.growth_factor.map_or(false, |factor| factor <= 1.0) {
+            bail!("growth_factor must be > 1.0");
+        }
         let num_frozen_pts: usize = 1;
         let total_internal_points: usize = params.max_points + num_frozen_pts;
         let aligned_dim: usize = ann::round_up(params.dim.try_into().unwrap()) as usize;
@@ -1147,6 +2418,8 @@ where
                 saturate_graph: false,
                 num_frozen_pts: num_frozen_pts,
                 start: params.max_points,
+                quantized: false,
+                quantized_fixed: false,
             }));
 
         let reserve_size =
@@ -1161,8 +2434,11 @@ where
                 .take(total_internal_points)
                 .collect();
 
-        let final_graph = Arc::new(shared);
-        let in_graph = Arc::new(shared_in);
+        let final_graph = Arc::new(RwLock::new(shared));
+        let in_graph = Arc::new(RwLock::new(shared_in));
+        let labels: Vec<_> = std::iter::repeat_with(|| RwLock::new(Vec::new()))
+            .take(total_internal_points)
+            .collect();
         let empty_slots: Arc<RwLock<HashSet<usize>>> = Arc::new(RwLock::new(HashSet::new()));
         let delete_set: Arc<RwLock<HashSet<usize>>> = Arc::new(RwLock::new(HashSet::new()));
 
@@ -1179,10 +2455,21 @@ where
             lt.reserve(total_internal_points);
             let mut tl = tag_to_location.write();
             tl.reserve(total_internal_points);
-            data = Arc::new(RwLock::new(AlignedDataStore::new(
-                params.params_e.max_points + 1,
-                params.aligned_dim,
-            )));
+            let store = match (data_store, &params.params_e.mmap_dir) {
+                (Some(store), _) => store,
+                (None, Some(dir)) => {
+                    fs::create_dir_all(dir)?;
+                    AlignedDataStore::new_mmap(
+                        &dir.join(DATA_FILE),
+                        params.params_e.max_points + 1,
+                        params.aligned_dim,
+                    )?
+                }
+                (None, None) => {
+                    AlignedDataStore::new(params.params_e.max_points + 1, params.aligned_dim)
+                }
+            };
+            data = Arc::new(RwLock::new(store));
         }
         let num_scratch_spaces = 12;
         let (s, r) = bounded(num_scratch_spaces);
@@ -1191,6 +2478,29 @@ where
             s.send(scratch).unwrap();
         }
 
+        let pq_codes: Vec<u8> = match &params.pq {
+            Some(pq_params) => {
+                pq_params.validate(aligned_dim)?;
+                product_quantizer::distance_for::<TVal, TMetric>()?;
+                vec![0u8; total_internal_points * pq_params.code_len()]
+            }
+            None => Vec::new(),
+        };
+
+        let full_precision = match &params.rerank {
+            Some(rerank_params) => {
+                if params.pq.is_some() {
+                    bail!("re-ranking cannot be combined with product quantization");
+                }
+                Some(Arc::new(rerank::FullPrecisionStore::new(
+                    rerank_params,
+                    rerank::distance_for::<TVal, TMetric>()?,
+                    aligned_dim,
+                )?))
+            }
+            None => None,
+        };
+
         let id_increment = Arc::new(AtomicUsize::new(0));
         let obj: DiskANNV1Index<TMetric, TVal> = DiskANNV1Index::<TMetric, TVal> {
             params: paramsi,
@@ -1206,10 +2516,17 @@ where
             // indexing_pool: indexing_pool,
             s_scratch: s,
             r_scratch: r,
-            quantizer: Arc::new(scalar_quantizer::ScalarQuantizer::new(0.99)?),
+            quantizer: Arc::new(scalar_quantizer::ScalarQuantizer::with_params(
+                &params.quantizer.unwrap_or_default(),
+            )?),
+            pq: Arc::new(RwLock::new(None)),
+            pq_codes: Arc::new(RwLock::new(pq_codes)),
+            labels: Arc::new(RwLock::new(labels)),
+            label_starts: Arc::new(RwLock::new(HashMap::new())),
+            label_counts: Arc::new(RwLock::new(HashMap::new())),
+            full_precision,
+            resize_lock: Arc::new(RwLock::new(())),
         };
-        // any additional setup that we need to do _on the instance_
-        obj.set_start_point_at_random(5.0);
         Ok(obj)
     }
 }
@@ -1,20 +1,77 @@
 use anyhow::bail;
-use parking_lot::RwLock;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use parking_lot::{RwLock, RwLockWriteGuard};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time;
 
 use super::av_store;
 use crate::ann;
 use crate::ann::EId;
+use crate::maintenance::MaintenanceHandle;
 use crate::metric;
+use crate::persist;
+use crate::product_quantizer;
+use crate::rerank;
 use crate::scalar_quantizer;
 
-#[derive(Debug, Clone, Copy)]
+// on-disk layout of a saved index, everything lives in a single directory:
+//   meta          - params, types, id_increment and the list of segments
+//   segment_{id}  - the raw aligned vectors of a single segment
+//   tags          - vid <-> eid mappings and the delete_set
+//   quantizer     - the state of the ScalarQuantizer
+//   pq            - the product quantizer and the codes of every segment
+//   full_precision - the retained vectors re-ranking scores against
+const FLAT_MAGIC: &[u8; 4] = b"AFLT";
+const FLAT_VERSION: u32 = 6;
+const META_FILE: &str = "meta";
+const TAGS_FILE: &str = "tags";
+const QUANTIZER_FILE: &str = "quantizer";
+const PQ_FILE: &str = "pq";
+
+// number of queries search_batch(..) scores together in one pass over the
+// segments
+const QUERY_BLOCK_SIZE: usize = 32;
+
+fn segment_file(segment_id: usize) -> String {
+    format!("segment_{}", segment_id)
+}
+
+#[derive(Debug, Clone)]
 pub struct FlatParams {
     pub dim: usize,
     pub segment_size_kb: usize,
+    // when set, each segment lives in a memory-mapped file inside this
+    // directory rather than on the heap
+    pub mmap_dir: Option<PathBuf>,
+    // when set, vectors are stored as product quantization codes only and
+    // searches are answered with approximate (ADC) distances
+    pub pq: Option<product_quantizer::PQParams>,
+    // when set, the full precision values of QuantizerIn points are retained
+    // and the candidates of a search are re-scored against them
+    pub rerank: Option<rerank::RerankParams>,
+    // how QuantizerIn points are quantized, the defaults when not set
+    pub quantizer: Option<scalar_quantizer::ScalarQuantizerParams>,
+}
+
+// when the background pass re-encodes the stored codes, see requantize()
+#[derive(Debug, Clone)]
+pub struct RequantizeParams {
+    pub period_millis: u64,
+    // drift of the scalar quantizer past which the pass runs
+    pub max_drift: f32,
+}
+
+#[derive(Debug)]
+struct PQSegment {
+    codes: Vec<u8>,
+    num_vectors: usize,
 }
 
 #[derive(Debug)]
@@ -32,6 +89,13 @@ pub struct FlatIndex<TMetric, TVal: ann::ElementVal> {
     aligned_dim: usize,
 
     quantizer: Arc<scalar_quantizer::ScalarQuantizer>,
+    // trained on the first batch of inserted vectors
+    pq: Arc<RwLock<Option<product_quantizer::ProductQuantizer>>>,
+    pq_segments: Arc<RwLock<HashMap<usize, RwLock<PQSegment>>>>,
+    full_precision: Option<Arc<rerank::FullPrecisionStore>>,
+    // shared by inserts and searches and held exclusively while
+    // requantize() re-encodes the segments
+    requantize_lock: Arc<RwLock<()>>,
 }
 
 impl<TMetric, TVal> ann::ANNIndex for FlatIndex<TMetric, TVal>
@@ -61,8 +125,41 @@ where
         self.search(q, k)
     }
 
-    fn save(&self) -> anyhow::Result<()> {
-        Ok(())
+    fn range_search(&self, q: ann::Points<TVal>, radius: f32) -> anyhow::Result<Vec<ann::Node>> {
+        self.range_search(q, radius)
+    }
+
+    fn search_batch(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<Vec<ann::Node>>> {
+        self.search_batch(q, k)
+    }
+
+    fn stats(&self) -> ann::IndexStats {
+        self.stats()
+    }
+
+    fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<TVal>>>> {
+        self.get(eids)
+    }
+
+    fn contains(&self, eid: &EId) -> bool {
+        self.eid_to_vid.read().contains_key(eid)
+    }
+
+    fn stores_normalized(&self) -> bool {
+        TMetric::uses_preprocessor()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = anyhow::Result<(EId, Vec<TVal>)>> + '_> {
+        let eids: Vec<EId> = self.eid_to_vid.read().keys().copied().collect();
+        ann::iter_by_get(self, eids)
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.save(path)
+    }
+
+    fn load(path: &Path) -> anyhow::Result<FlatIndex<TMetric, TVal>> {
+        FlatIndex::load(path)
     }
 }
 
@@ -79,14 +176,36 @@ where
         }
         let id_increment = Arc::new(AtomicUsize::new(0));
         let delete_set: Arc<RwLock<HashSet<usize>>> = Arc::new(RwLock::new(HashSet::new()));
-        let segement_0 = RwLock::new(av_store::AlignedDataStore::new(
-            v_per_segment,
-            aligned_dim.try_into().unwrap(),
-        ));
         let datastore = Arc::new(RwLock::new(HashMap::new()));
-        {
-            datastore.write().insert(0, segement_0);
+        match &params.pq {
+            Some(pq_params) => {
+                // no full precision segments are kept around in this mode
+                pq_params.validate(aligned_dim)?;
+                product_quantizer::distance_for::<TVal, TMetric>()?;
+            }
+            None => {
+                let segement_0 = RwLock::new(FlatIndex::<TMetric, TVal>::new_segment(
+                    params,
+                    0,
+                    v_per_segment,
+                    aligned_dim,
+                )?);
+                datastore.write().insert(0, segement_0);
+            }
         }
+        let full_precision = match &params.rerank {
+            Some(rerank_params) => {
+                if params.pq.is_some() {
+                    bail!("re-ranking cannot be combined with product quantization");
+                }
+                Some(Arc::new(rerank::FullPrecisionStore::new(
+                    rerank_params,
+                    rerank::distance_for::<TVal, TMetric>()?,
+                    aligned_dim,
+                )?))
+            }
+            None => None,
+        };
         let location_to_tag: Arc<RwLock<HashMap<usize, ann::EId>>> =
             Arc::new(RwLock::new(HashMap::with_capacity(v_per_segment * 2)));
         let tag_to_location: Arc<RwLock<HashMap<ann::EId, usize>>> =
@@ -104,10 +223,247 @@ where
             v_per_segment: v_per_segment,
             aligned_dim: aligned_dim,
 
-            quantizer: Arc::new(scalar_quantizer::ScalarQuantizer::new(0.99)?),
+            quantizer: Arc::new(scalar_quantizer::ScalarQuantizer::with_params(
+                &params.quantizer.unwrap_or_default(),
+            )?),
+            pq: Arc::new(RwLock::new(None)),
+            pq_segments: Arc::new(RwLock::new(HashMap::new())),
+            full_precision,
+            requantize_lock: Arc::new(RwLock::new(())),
         })
     }
+    fn new_segment(
+        params: &FlatParams,
+        segment_id: usize,
+        v_per_segment: usize,
+        aligned_dim: usize,
+    ) -> anyhow::Result<av_store::AlignedDataStore<TVal>> {
+        match &params.mmap_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                av_store::AlignedDataStore::new_mmap(
+                    &dir.join(segment_file(segment_id)),
+                    v_per_segment,
+                    aligned_dim,
+                )
+            }
+            None => Ok(av_store::AlignedDataStore::new(v_per_segment, aligned_dim)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(path)?;
+        // hold the read locks for the duration of the save so that the
+        // segments and the mappings agree with one another
+        let datastore = self.datastore.read();
+        let delete_set = self.delete_set.read();
+        let vid_to_eid = self.vid_to_eid.read();
+
+        let mut segment_ids: Vec<usize> = datastore.keys().copied().collect();
+        segment_ids.sort();
+        persist::write_file(&path.join(META_FILE), |w| {
+            persist::write_header(w, FLAT_MAGIC, FLAT_VERSION)?;
+            persist::write_element_type::<TVal>(w)?;
+            persist::write_metric_type::<TMetric>(w)?;
+            persist::write_usize(w, self.params.dim)?;
+            persist::write_usize(w, self.params.segment_size_kb)?;
+            persist::write_usize(w, self.v_per_segment)?;
+            persist::write_usize(w, self.aligned_dim)?;
+            persist::write_usize(
+                w,
+                self.id_increment.load(std::sync::atomic::Ordering::SeqCst),
+            )?;
+            persist::write_usize(w, segment_ids.len())?;
+            for segment_id in segment_ids.iter() {
+                persist::write_usize(w, *segment_id)?;
+                persist::write_usize(w, datastore[segment_id].read().num_vectors)?;
+            }
+            // v2: whether the segments should be mapped rather than read in
+            w.write_u8(self.params.mmap_dir.is_some() as u8)?;
+            // v3: the product quantization settings
+            w.write_u8(self.params.pq.is_some() as u8)?;
+            if let Some(pq_params) = &self.params.pq {
+                persist::write_usize(w, pq_params.num_subspaces)?;
+                persist::write_usize(w, pq_params.bits)?;
+            }
+            // v4: the re-ranking settings
+            rerank::RerankParams::save(&self.params.rerank, w)?;
+            // v5: the scalar quantizer settings
+            scalar_quantizer::ScalarQuantizerParams::save(&self.params.quantizer, w)?;
+            Ok(())
+        })?;
+        for segment_id in segment_ids.iter() {
+            let segment = datastore[segment_id].read();
+            let segment_path = path.join(segment_file(*segment_id));
+            match segment.mapped_path() {
+                // renaming over the file we are mapped onto would detach the
+                // mapping from it - so just flush our dirty pages instead
+                Some(mapped) if persist::same_file(mapped, &segment_path) => segment.flush()?,
+                _ => persist::write_file(&segment_path, |w| {
+                    persist::write_vals(w, &segment.data[..])
+                })?,
+            }
+        }
+        persist::write_file(&path.join(TAGS_FILE), |w| {
+            persist::write_usize(w, vid_to_eid.len())?;
+            for (vid, eid) in vid_to_eid.iter() {
+                persist::write_usize(w, *vid)?;
+                persist::write_eid(w, eid)?;
+            }
+            persist::write_vids(w, delete_set.len(), delete_set.iter())?;
+            Ok(())
+        })?;
+        persist::write_file(&path.join(QUANTIZER_FILE), |w| self.quantizer.save(w))?;
+        if self.params.pq.is_some() {
+            let pq = self.pq.read();
+            let pq_segments = self.pq_segments.read();
+            let mut pq_segment_ids: Vec<usize> = pq_segments.keys().copied().collect();
+            pq_segment_ids.sort();
+            persist::write_file(&path.join(PQ_FILE), |w| {
+                w.write_u8(pq.is_some() as u8)?;
+                if let Some(pq) = pq.as_ref() {
+                    pq.save(w)?;
+                }
+                persist::write_usize(w, pq_segment_ids.len())?;
+                for segment_id in pq_segment_ids.iter() {
+                    let segment = pq_segments[segment_id].read();
+                    persist::write_usize(w, *segment_id)?;
+                    persist::write_usize(w, segment.num_vectors)?;
+                    persist::write_usize(w, segment.codes.len())?;
+                    w.write_all(&segment.codes)?;
+                }
+                Ok(())
+            })?;
+        }
+        if let Some(store) = &self.full_precision {
+            store.save(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<FlatIndex<TMetric, TVal>> {
+        let mut r = persist::open_file(&path.join(META_FILE))?;
+        let version = persist::read_header(&mut r, FLAT_MAGIC, FLAT_VERSION)?;
+        persist::check_element_type::<TVal>(&mut r, version < 6)?;
+        persist::check_metric_type::<TMetric>(&mut r, version < 6)?;
+        let mut params = FlatParams {
+            dim: persist::read_usize(&mut r)?,
+            segment_size_kb: persist::read_usize(&mut r)?,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let mut obj = FlatIndex::<TMetric, TVal>::new_core(&params)?;
+        let v_per_segment = persist::read_usize(&mut r)?;
+        let aligned_dim = persist::read_usize(&mut r)?;
+        if v_per_segment != obj.v_per_segment || aligned_dim != obj.aligned_dim {
+            bail!(
+                "segment layout mismatch, saved: ({}, {}) expected: ({}, {})",
+                v_per_segment,
+                aligned_dim,
+                obj.v_per_segment,
+                obj.aligned_dim
+            );
+        }
+        obj.id_increment.store(
+            persist::read_usize(&mut r)?,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        let mut segments: Vec<(usize, usize)> = Vec::new();
+        for _ in 0..persist::read_usize(&mut r)? {
+            let segment_id = persist::read_usize(&mut r)?;
+            segments.push((segment_id, persist::read_usize(&mut r)?));
+        }
+        let mapped = version >= 2 && r.read_u8()? != 0;
+        if mapped {
+            // cold start - map the saved segments in place of reading them
+            params.mmap_dir = Some(path.to_path_buf());
+        }
+        if version >= 3 && r.read_u8()? != 0 {
+            params.pq = Some(product_quantizer::PQParams {
+                num_subspaces: persist::read_usize(&mut r)?,
+                bits: persist::read_usize(&mut r)?,
+            });
+        }
+        if version >= 4 {
+            params.rerank = rerank::RerankParams::load(&mut r, path)?;
+        }
+        if version >= 5 {
+            params.quantizer = scalar_quantizer::ScalarQuantizerParams::load(&mut r)?;
+        }
+        if let Some(rerank_params) = &params.rerank {
+            // new_core never saw the params, so the mapped file is intact
+            obj.full_precision = Some(Arc::new(rerank::FullPrecisionStore::load(
+                rerank_params,
+                rerank::distance_for::<TVal, TMetric>()?,
+                aligned_dim,
+                path,
+            )?));
+        }
+        obj.params = Arc::new(params);
+        {
+            let mut datastore = obj.datastore.write();
+            datastore.clear();
+            for (segment_id, num_vectors) in segments {
+                let segment_path = path.join(segment_file(segment_id));
+                let mut segment: av_store::AlignedDataStore<TVal>;
+                if mapped {
+                    segment = av_store::AlignedDataStore::open_mmap(
+                        &segment_path,
+                        v_per_segment,
+                        aligned_dim,
+                    )?;
+                } else {
+                    segment = av_store::AlignedDataStore::new(v_per_segment, aligned_dim);
+                    let mut r_segment = persist::open_file(&segment_path)?;
+                    persist::read_vals(&mut r_segment, &mut segment.data[..])?;
+                }
+                segment.num_vectors = num_vectors;
+                datastore.insert(segment_id, RwLock::new(segment));
+            }
+        }
+        {
+            let mut r = persist::open_file(&path.join(TAGS_FILE))?;
+            let mut eid_to_vid = obj.eid_to_vid.write();
+            let mut vid_to_eid = obj.vid_to_eid.write();
+            for _ in 0..persist::read_usize(&mut r)? {
+                let vid = persist::read_usize(&mut r)?;
+                let eid = persist::read_eid(&mut r)?;
+                eid_to_vid.insert(eid, vid);
+                vid_to_eid.insert(vid, eid);
+            }
+            obj.delete_set.write().extend(persist::read_vids(&mut r)?);
+        }
+        {
+            let mut r = persist::open_file(&path.join(QUANTIZER_FILE))?;
+            obj.quantizer = Arc::new(scalar_quantizer::ScalarQuantizer::load(&mut r)?);
+        }
+        if obj.params.pq.is_some() {
+            let mut r = persist::open_file(&path.join(PQ_FILE))?;
+            if r.read_u8()? != 0 {
+                *obj.pq.write() = Some(product_quantizer::ProductQuantizer::load(&mut r)?);
+            }
+            let mut pq_segments = obj.pq_segments.write();
+            for _ in 0..persist::read_usize(&mut r)? {
+                let segment_id = persist::read_usize(&mut r)?;
+                let num_vectors = persist::read_usize(&mut r)?;
+                let mut codes = vec![0u8; persist::read_usize(&mut r)?];
+                r.read_exact(&mut codes)?;
+                pq_segments.insert(segment_id, RwLock::new(PQSegment { codes, num_vectors }));
+            }
+        }
+        Ok(obj)
+    }
+
     pub fn insert(&self, eids: &[ann::EId], points: ann::Points<TVal>) -> anyhow::Result<()> {
+        if self.full_precision.is_some() && points.full_precision().is_none() {
+            bail!("re-ranking needs the full precision values of QuantizerIn points");
+        }
+        if let Some(pq_params) = &self.params.pq {
+            product_quantizer::check_first_batch(pq_params, self.pq.read().is_some(), eids.len())?;
+        }
+        let _requantize_r = self.requantize_lock.read();
         let mut idx_by_vid: HashMap<usize, usize> = HashMap::new();
         let mut vids: Vec<usize> = Vec::with_capacity(eids.len());
         {
@@ -130,9 +486,16 @@ where
         let quantize_result: Vec<TVal>;
         match points {
             ann::Points::QuantizerIn { vals } => {
+                if let Some(store) = &self.full_precision {
+                    store.insert(&vids, vals)?;
+                }
+                // the codes are compared by the metric of the index, so they
+                // are held one per element rather than packed
+                let dim = vals.len() / eids.len();
+                let codes = self.quantizer.quantize(&vids, vals, None);
                 quantize_result = self
                     .quantizer
-                    .quantize(&vids, vals, None)
+                    .unpack(&codes, dim)
                     .iter()
                     .map(|x| TVal::from_u8(*x).expect("unable to coerce to u8"))
                     .collect();
@@ -180,6 +543,27 @@ where
                 vid_by_segment_id.insert(segment_id, new_list);
             }
         });
+        match &self.params.pq {
+            Some(pq_params) => {
+                self.insert_pq(pq_params, vid_by_segment_id, &idx_by_vid, padded_points)?
+            }
+            None => self.insert_segments(vid_by_segment_id, &idx_by_vid, padded_points)?,
+        }
+        let mut eid_to_vid = self.eid_to_vid.write();
+        let mut vid_to_eid = self.vid_to_eid.write();
+        for (idx, vid) in vids.iter().enumerate() {
+            eid_to_vid.insert(eids[idx], *vid);
+            vid_to_eid.insert(*vid, eids[idx]);
+        }
+        Ok(())
+    }
+
+    fn insert_segments(
+        &self,
+        vid_by_segment_id: HashMap<usize, Vec<usize>>,
+        idx_by_vid: &HashMap<usize, usize>,
+        padded_points: &[TVal],
+    ) -> anyhow::Result<()> {
         let mut new_segment_ids: Vec<usize> = Vec::with_capacity(2);
         vid_by_segment_id.iter().for_each(|(segment_id, _)| {
             match self.datastore.read().get(&segment_id) {
@@ -187,13 +571,15 @@ where
                 None => new_segment_ids.push(*segment_id),
             }
         });
-        new_segment_ids.iter().for_each(|segment_id| {
-            let new_segment = RwLock::new(av_store::AlignedDataStore::new(
-                self.v_per_segment.try_into().unwrap(),
-                self.aligned_dim.try_into().unwrap(),
-            ));
+        for segment_id in new_segment_ids.iter() {
+            let new_segment = RwLock::new(FlatIndex::<TMetric, TVal>::new_segment(
+                &self.params,
+                *segment_id,
+                self.v_per_segment,
+                self.aligned_dim,
+            )?);
             self.datastore.write().insert(*segment_id, new_segment);
-        });
+        }
         let datastore = self.datastore.read();
         for (segment_id, vids) in vid_by_segment_id {
             match datastore.get(&segment_id) {
@@ -219,11 +605,64 @@ where
                 }
             }
         }
-        let mut eid_to_vid = self.eid_to_vid.write();
-        let mut vid_to_eid = self.vid_to_eid.write();
-        for (idx, vid) in vids.iter().enumerate() {
-            eid_to_vid.insert(eids[idx], *vid);
-            vid_to_eid.insert(*vid, eids[idx]);
+        Ok(())
+    }
+
+    fn insert_pq(
+        &self,
+        pq_params: &product_quantizer::PQParams,
+        vid_by_segment_id: HashMap<usize, Vec<usize>>,
+        idx_by_vid: &HashMap<usize, usize>,
+        padded_points: &[TVal],
+    ) -> anyhow::Result<()> {
+        let points = product_quantizer::to_f32(padded_points);
+        let mut pq_w = self.pq.write();
+        if pq_w.is_none() {
+            // the codebooks are trained on the first batch we are handed
+            *pq_w = Some(product_quantizer::ProductQuantizer::train(
+                pq_params,
+                self.aligned_dim,
+                product_quantizer::distance_for::<TVal, TMetric>()?,
+                &points,
+            )?);
+        }
+        let pq_r = RwLockWriteGuard::downgrade(pq_w);
+        let pq = match pq_r.as_ref() {
+            Some(pq) => pq,
+            None => bail!("the product quantizer is unexpectedly untrained"),
+        };
+        let code_len = pq.code_len();
+        {
+            let mut pq_segments = self.pq_segments.write();
+            for segment_id in vid_by_segment_id.keys() {
+                pq_segments.entry(*segment_id).or_insert_with(|| {
+                    RwLock::new(PQSegment {
+                        codes: vec![0u8; self.v_per_segment * code_len],
+                        num_vectors: 0,
+                    })
+                });
+            }
+        }
+        let pq_segments = self.pq_segments.read();
+        for (segment_id, vids) in vid_by_segment_id {
+            let mut segment_w = pq_segments[&segment_id].write();
+            for vid in vids {
+                let idx: usize;
+                match idx_by_vid.get(&vid) {
+                    Some(index) => idx = *index,
+                    None => {
+                        bail!(
+                            "every vid should have an associated index - vid: {vid} is missing one"
+                        )
+                    }
+                }
+                let offset = vid % self.v_per_segment;
+                pq.encode(
+                    &points[idx * self.aligned_dim..idx * self.aligned_dim + self.aligned_dim],
+                    &mut segment_w.codes[offset * code_len..offset * code_len + code_len],
+                );
+                segment_w.num_vectors = segment_w.num_vectors.max(offset + 1);
+            }
         }
         Ok(())
     }
@@ -254,13 +693,379 @@ where
 
         Ok(())
     }
+
+    // fits the scalar quantizer to everything it has seen so far and
+    // re-encodes the codes of the live QuantizerIn points with the fresh
+    // ranges - from the values re-ranking retains when there are any and
+    // from the old codes otherwise. inserts and searches wait on the pass,
+    // so neither sees codes of the old ranges next to codes of the new ones
+    pub fn requantize(&self) -> anyhow::Result<usize> {
+        self.check_requantize()?;
+        let _requantize_w = self.requantize_lock.write();
+        let mut vids_by_segment_id: HashMap<usize, Vec<usize>> = HashMap::new();
+        {
+            let vid_to_eid = self.vid_to_eid.read();
+            for vid in self.quantizer.quantized_vids() {
+                if vid_to_eid.contains_key(&vid) {
+                    vids_by_segment_id
+                        .entry(vid / self.v_per_segment)
+                        .or_default()
+                        .push(vid);
+                }
+            }
+        }
+        let dim = self.params.dim;
+        let datastore = self.datastore.read();
+        // the segments are all locked before the ranges move and released
+        // once they are all re-encoded
+        let mut segment_ids: Vec<usize> = vids_by_segment_id.keys().copied().collect();
+        segment_ids.sort();
+        let mut segments = Vec::with_capacity(segment_ids.len());
+        for segment_id in segment_ids.iter() {
+            match datastore.get(segment_id) {
+                Some(segment) => segments.push(segment.write()),
+                None => bail!("unexpectedly, segment: {segment_id} is missing - bailing"),
+            }
+        }
+        let recoder = self.quantizer.refit();
+        let mut num_recoded: usize = 0;
+        for (segment_id, segment) in segment_ids.iter().zip(segments.iter_mut()) {
+            for vid in vids_by_segment_id[segment_id].iter() {
+                let idx_s = (vid % self.v_per_segment) * self.aligned_dim;
+                let codes: Vec<u8> = match &self.full_precision {
+                    Some(store) => match store.vector(*vid) {
+                        Some(vals) => recoder.encode(&vals[..dim]),
+                        None => continue,
+                    },
+                    None => recoder.recode(
+                        &self.quantizer.pack(
+                            &segment.data[idx_s..idx_s + dim]
+                                .iter()
+                                .map(|x| x.to_u8().expect("unable to coerce to u8"))
+                                .collect::<Vec<u8>>(),
+                            dim,
+                        ),
+                        dim,
+                    ),
+                };
+                self.quantizer.recoded(*vid, &codes, dim);
+                let vals: Vec<TVal> = self
+                    .quantizer
+                    .unpack(&codes, dim)
+                    .iter()
+                    .map(|x| TVal::from_u8(*x).expect("unable to coerce to u8"))
+                    .collect();
+                match ann::pad_and_preprocess::<TVal, TMetric>(&vals, dim, self.aligned_dim) {
+                    Some(padded) => {
+                        segment.data[idx_s..idx_s + self.aligned_dim].copy_from_slice(&padded[..])
+                    }
+                    None => segment.data[idx_s..idx_s + dim].copy_from_slice(&vals[..]),
+                }
+                num_recoded += 1;
+            }
+        }
+        Ok(num_recoded)
+    }
+
+    fn check_requantize(&self) -> anyhow::Result<()> {
+        if self.params.pq.is_some() {
+            bail!("FlatIndex in pq mode only keeps the pq codes of its vectors");
+        }
+        // codes that were pre-processed no longer decode to the values
+        if TMetric::uses_preprocessor() && self.full_precision.is_none() {
+            bail!(
+                "metric: {} pre-processes the codes, requantizing needs re-ranking",
+                std::any::type_name::<TMetric>()
+            );
+        }
+        Ok(())
+    }
+
+    // spawns the background pass, every period_millis it checks the drift
+    // of the scalar quantizer and requantizes once it crosses max_drift
+    pub fn start_requantize(
+        self: &Arc<Self>,
+        params: RequantizeParams,
+    ) -> anyhow::Result<MaintenanceHandle>
+    where
+        TMetric: 'static,
+        TVal: 'static,
+    {
+        self.check_requantize()?;
+        let index: Weak<Self> = Arc::downgrade(self);
+        Ok(MaintenanceHandle::spawn(
+            time::Duration::from_millis(params.period_millis),
+            move || match index.upgrade() {
+                Some(index) => {
+                    if index.quantizer.drift() > params.max_drift {
+                        // past check_requantize a failure means the segments
+                        // are inconsistent, retrying every period won't help
+                        if let Err(err) = index.requantize() {
+                            log::error!("stopping the background requantization: {}", err);
+                            return false;
+                        }
+                    }
+                    true
+                }
+                None => false,
+            },
+        ))
+    }
+
     pub fn search(&self, q: ann::Points<TVal>, k: usize) -> anyhow::Result<Vec<ann::Node>> {
+        self.search_core(q, k, None, None)
+    }
+
+    // exact scan for every point within radius of q, in pq mode radius is
+    // compared against the approximate distances
+    pub fn range_search(
+        &self,
+        q: ann::Points<TVal>,
+        radius: f32,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        self.search_core(q, usize::MAX, None, Some(radius))
+    }
+
+    // exact search over the points that pass filter, only those points are
+    // scored so this stays cheap for selective allow lists
+    pub fn search_filtered(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+        filter: &ann::SearchFilter,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        self.search_core(q, k, Some(filter), None)
+    }
+
+    fn search_core(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+        filter: Option<&ann::SearchFilter>,
+        radius: Option<f32>,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        // a radius is given in terms of the stored codes, so range searches
+        // are left as they are
+        let reranker = match (&self.full_precision, q.full_precision(), radius) {
+            (Some(store), Some(vals), None) => Some((store, vals)),
+            _ => None,
+        };
+        // the query is quantized with the ranges the segments hold codes of
+        let _requantize_r = self.requantize_lock.read();
+        let padded_points = self.prepare_query(q)?;
+        if self.params.pq.is_some() {
+            return self.search_pq(&padded_points, k, filter, radius);
+        }
+        let num_candidates = match reranker {
+            Some((store, _)) => store.num_candidates(k),
+            None => k,
+        };
+
+        // maybe we want to keep around a bunch of these in a pool we can pull from?
+        let mut q_aligned: av_store::AlignedDataStore<TVal> =
+            av_store::AlignedDataStore::<TVal>::new(1, self.aligned_dim);
+        q_aligned.data[..padded_points.len()].copy_from_slice(&padded_points[..]);
+        // we should probably use rayon over segments and have multiple vectors
+        // in a given segment
+        let res = self.scan(
+            &self.datastore.read(),
+            |segment| segment.num_vectors,
+            |segment, i| {
+                let arr_a: &[TVal] = &q_aligned.data[..];
+                let arr_b: &[TVal] =
+                    &segment.data[i * self.aligned_dim..(i * self.aligned_dim) + self.aligned_dim];
+                TMetric::compare(arr_a, arr_b)
+            },
+            num_candidates,
+            filter,
+            radius,
+        );
+        Ok(match reranker {
+            Some((store, vals)) => store.rerank(vals, res, k),
+            None => res,
+        })
+    }
+
+    // vectors are only kept as codes in pq mode so there is nothing exact to
+    // hand back
+    pub fn get(&self, eids: &[EId]) -> anyhow::Result<Vec<Option<Vec<TVal>>>> {
+        if self.params.pq.is_some() {
+            bail!("FlatIndex in pq mode only keeps the pq codes of its vectors")
+        }
+        let eid_to_vid = self.eid_to_vid.read();
+        let datastore = self.datastore.read();
+        let mut vecs: Vec<Option<Vec<TVal>>> = Vec::with_capacity(eids.len());
+        for eid in eids.iter() {
+            let vid = match eid_to_vid.get(eid) {
+                Some(vid) => *vid,
+                None => {
+                    vecs.push(None);
+                    continue;
+                }
+            };
+            let segment = match datastore.get(&(vid / self.v_per_segment)) {
+                Some(segment) => segment.read(),
+                None => bail!("unexpectedly, the segment of vid: {vid} is missing - bailing"),
+            };
+            let idx_s = (vid % self.v_per_segment) * self.aligned_dim;
+            vecs.push(Some(segment.data[idx_s..idx_s + self.params.dim].to_vec()));
+        }
+        Ok(vecs)
+    }
+
+    pub fn stats(&self) -> ann::IndexStats {
+        let vid_to_eid = self.vid_to_eid.read();
+        let mut memory_bytes: BTreeMap<&'static str, usize> = BTreeMap::new();
+        memory_bytes.insert(
+            "vectors",
+            self.datastore
+                .read()
+                .values()
+                .map(|segment| segment.read().data.len() * std::mem::size_of::<TVal>())
+                .sum(),
+        );
+        memory_bytes.insert(
+            "pq_codes",
+            self.pq_segments
+                .read()
+                .values()
+                .map(|segment| segment.read().codes.capacity())
+                .sum(),
+        );
+        memory_bytes.insert(
+            "full_precision",
+            self.full_precision
+                .as_ref()
+                .map_or(0, |store| store.memory_bytes()),
+        );
+        memory_bytes.insert(
+            "tags",
+            (vid_to_eid.capacity() + self.eid_to_vid.read().capacity())
+                * (std::mem::size_of::<usize>() + std::mem::size_of::<EId>() + 1),
+        );
+        ann::IndexStats {
+            num_live: vid_to_eid.len(),
+            num_deleted: self.delete_set.read().len(),
+            num_empty_slots: 0,
+            capacity: None,
+            graph: None,
+            memory_bytes,
+            quantizers: ann::QuantizerStats {
+                scalar_quantile: Some(self.quantizer.quantile()),
+                pq: self.params.pq,
+                pq_trained: self.pq.read().is_some(),
+                rerank_factor: self.full_precision.as_ref().map(|store| store.factor()),
+                scalar_bits: Some(self.quantizer.params().bits),
+                scalar_per_dimension: self.quantizer.params().per_dimension,
+                scalar_reconstruction_error: self.quantizer.reconstruction_error(),
+                scalar_drift: Some(self.quantizer.drift()),
+            },
+        }
+    }
+
+    // queries are scored in blocks of QUERY_BLOCK_SIZE spread over rayon,
+    // each block makes a single pass over the segments
+    pub fn search_batch(
+        &self,
+        q: ann::Points<TVal>,
+        k: usize,
+    ) -> anyhow::Result<Vec<Vec<ann::Node>>> {
+        let split = q.split(self.params.dim)?;
+        // the full precision queries to re-rank against, if any
+        let full_precision: Option<(&rerank::FullPrecisionStore, Vec<&[f32]>)> =
+            match &self.full_precision {
+                Some(store) => split
+                    .iter()
+                    .map(|q| q.full_precision())
+                    .collect::<Option<Vec<&[f32]>>>()
+                    .map(|vals| (store.as_ref(), vals)),
+                None => None,
+            };
+        // the queries are quantized with the ranges the segments hold codes of
+        let _requantize_r = self.requantize_lock.read();
+        let queries: Vec<Vec<TVal>> = split
+            .into_iter()
+            .map(|q| self.prepare_query(q))
+            .collect::<anyhow::Result<_>>()?;
+        if self.params.pq.is_some() {
+            let pq_r = self.pq.read();
+            let pq = match pq_r.as_ref() {
+                Some(pq) => pq,
+                // nothing has been inserted yet
+                None => return Ok(vec![Vec::new(); queries.len()]),
+            };
+            let tables: Vec<product_quantizer::DistanceTable> = queries
+                .iter()
+                .map(|q| pq.distance_table(&product_quantizer::to_f32(q)))
+                .collect();
+            let code_len = pq.code_len();
+            let segments = self.pq_segments.read();
+            return Ok(tables
+                .par_chunks(QUERY_BLOCK_SIZE)
+                .flat_map_iter(|block| {
+                    self.scan_batch(
+                        &segments,
+                        |segment| segment.num_vectors,
+                        |segment, i, j| {
+                            block[j].distance(&segment.codes[i * code_len..i * code_len + code_len])
+                        },
+                        block.len(),
+                        k,
+                    )
+                })
+                .collect());
+        }
+
+        let mut q_aligned: av_store::AlignedDataStore<TVal> =
+            av_store::AlignedDataStore::<TVal>::new(queries.len(), self.aligned_dim);
+        queries.iter().enumerate().for_each(|(j, q)| {
+            q_aligned.data[j * self.aligned_dim..j * self.aligned_dim + q.len()]
+                .copy_from_slice(&q[..]);
+        });
+        let segments = self.datastore.read();
+        let offsets: Vec<usize> = (0..queries.len()).collect();
+        let num_candidates = match &full_precision {
+            Some((store, _)) => store.num_candidates(k),
+            None => k,
+        };
+        let res: Vec<Vec<ann::Node>> = offsets
+            .par_chunks(QUERY_BLOCK_SIZE)
+            .flat_map_iter(|block| {
+                self.scan_batch(
+                    &segments,
+                    |segment| segment.num_vectors,
+                    |segment, i, j| {
+                        let q_idx = block[j] * self.aligned_dim;
+                        let arr_a: &[TVal] = &q_aligned.data[q_idx..q_idx + self.aligned_dim];
+                        let arr_b: &[TVal] = &segment.data
+                            [i * self.aligned_dim..(i * self.aligned_dim) + self.aligned_dim];
+                        TMetric::compare(arr_a, arr_b)
+                    },
+                    block.len(),
+                    num_candidates,
+                )
+            })
+            .collect();
+        Ok(match full_precision {
+            Some((store, vals)) => res
+                .into_iter()
+                .zip(vals)
+                .map(|(res, q)| store.rerank(q, res, k))
+                .collect(),
+            None => res,
+        })
+    }
+
+    // quantizes (if need be), pads and pre-processes a single query
+    fn prepare_query(&self, q: ann::Points<TVal>) -> anyhow::Result<Vec<TVal>> {
         let data: &[TVal];
         let quantize_result: Vec<TVal>;
         match q {
             ann::Points::QuantizerIn { vals } => {
                 let (res, _) = self.quantizer.quantize_arr(vals);
-                quantize_result = res
+                quantize_result = self
+                    .quantizer
+                    .unpack(&res, vals.len())
                     .iter()
                     .map(|x| TVal::from_u8(*x).expect("unable to coerce to u8"))
                     .collect();
@@ -276,67 +1081,190 @@ where
             );
         }
 
-        let padded_vector: Vec<TVal>;
-        let padded_points: &[TVal];
-        match ann::pad_and_preprocess::<TVal, TMetric>(data, data.len(), self.aligned_dim) {
-            Some(vec) => {
-                padded_vector = vec;
-                padded_points = &padded_vector[..]
-            }
-            None => padded_points = data,
-        }
+        Ok(
+            match ann::pad_and_preprocess::<TVal, TMetric>(data, data.len(), self.aligned_dim) {
+                Some(vec) => vec,
+                None => data.to_vec(),
+            },
+        )
+    }
 
-        // maybe we want to keep around a bunch of these in a pool we can pull from?
-        let mut res_heap: BinaryHeap<ann::Node> = BinaryHeap::with_capacity(k + 1);
-        let mut q_aligned: av_store::AlignedDataStore<TVal> =
-            av_store::AlignedDataStore::<TVal>::new(1, self.aligned_dim);
-        q_aligned.data[..padded_points.len()].copy_from_slice(&padded_points[..]);
-        // we should probably use rayon over segments and have multiple vectors
-        // in a given segment
-        self.datastore
-            .read()
-            .iter()
-            .for_each(|(segment_id, vec_store)| {
-                // we are now in a single segment!
-                let data = vec_store.read();
-                for i in 0..data.num_vectors {
-                    let eid: ann::EId;
-                    let vid = *segment_id * (self.v_per_segment as usize) + i;
-                    match self.vid_to_eid.read().get(&vid) {
-                        Some(val) => eid = val.clone(),
-                        None => {
-                            continue;
+    fn search_pq(
+        &self,
+        padded_points: &[TVal],
+        k: usize,
+        filter: Option<&ann::SearchFilter>,
+        radius: Option<f32>,
+    ) -> anyhow::Result<Vec<ann::Node>> {
+        let pq_r = self.pq.read();
+        let pq = match pq_r.as_ref() {
+            Some(pq) => pq,
+            // nothing has been inserted yet
+            None => return Ok(Vec::new()),
+        };
+        let table = pq.distance_table(&product_quantizer::to_f32(padded_points));
+        let code_len = pq.code_len();
+        Ok(self.scan(
+            &self.pq_segments.read(),
+            |segment| segment.num_vectors,
+            |segment, i| table.distance(&segment.codes[i * code_len..i * code_len + code_len]),
+            k,
+            filter,
+            radius,
+        ))
+    }
+
+    // scores the live points of segments that pass filter and keeps the k
+    // closest that are within radius - allow lists are resolved to their vids up front so that only
+    // their points get visited rather than every point of every segment
+    fn scan<S>(
+        &self,
+        segments: &HashMap<usize, RwLock<S>>,
+        num_vectors: impl Fn(&S) -> usize,
+        score: impl Fn(&S, usize) -> f32,
+        k: usize,
+        filter: Option<&ann::SearchFilter>,
+        radius: Option<f32>,
+    ) -> Vec<ann::Node> {
+        let allowed_vids: Option<Vec<usize>> = match filter.and_then(|f| f.allow.as_ref()) {
+            Some(ann::IdSet::EIds(eids)) => {
+                let eid_to_vid = self.eid_to_vid.read();
+                let mut vids: Vec<usize> = eids
+                    .iter()
+                    .filter_map(|eid| eid_to_vid.get(eid).copied())
+                    .collect();
+                vids.sort();
+                Some(vids)
+            }
+            Some(ann::IdSet::VIds(vids)) => Some(vids.iter().map(|vid| vid as usize).collect()),
+            None => None,
+        };
+        let vid_to_eid = self.vid_to_eid.read();
+        // range searches have no upper bound on how many points come back
+        let mut res_heap: BinaryHeap<ann::Node> = match radius {
+            Some(_) => BinaryHeap::new(),
+            None => BinaryHeap::with_capacity(k + 1),
+        };
+        let mut visit = |segment: &S, segment_id: usize, i: usize| {
+            let vid = segment_id * self.v_per_segment + i;
+            let eid = match vid_to_eid.get(&vid) {
+                Some(val) => *val,
+                None => return,
+            };
+            if !filter.map_or(true, |f| f.passes(vid, &eid)) {
+                return;
+            }
+            let distance = score(segment, i);
+            if radius.map_or(false, |radius| distance > radius) {
+                return;
+            }
+            res_heap.push(ann::Node {
+                vid: vid,
+                eid: eid,
+                distance: distance,
+            });
+            if res_heap.len() > k {
+                res_heap.pop().unwrap();
+            }
+        };
+        match allowed_vids {
+            Some(vids) => {
+                // the vids are sorted so each segment is locked just once
+                let mut idx: usize = 0;
+                while idx < vids.len() {
+                    let segment_id = vids[idx] / self.v_per_segment;
+                    let segment_end = (segment_id + 1) * self.v_per_segment;
+                    let segment = segments.get(&segment_id).map(|segment| segment.read());
+                    while idx < vids.len() && vids[idx] < segment_end {
+                        let i = vids[idx] % self.v_per_segment;
+                        match &segment {
+                            Some(segment) if i < num_vectors(segment) => {
+                                visit(segment, segment_id, i)
+                            }
+                            _ => {}
                         }
+                        idx += 1;
                     }
+                }
+            }
+            None => segments.iter().for_each(|(segment_id, segment)| {
+                // we are now in a single segment!
+                let segment = segment.read();
+                for i in 0..num_vectors(&segment) {
+                    visit(&segment, *segment_id, i);
+                }
+            }),
+        }
+        res_heap.into_sorted_vec()
+    }
 
-                    let arr_a: &[TVal] = &q_aligned.data[..];
-                    let arr_b: &[TVal] =
-                        &data.data[i * self.aligned_dim..(i * self.aligned_dim) + self.aligned_dim];
-                    let dist = TMetric::compare(arr_a, arr_b);
-
+    // scan(..) for a block of queries - every live point of a segment is
+    // scored against all of the queries while that segment is locked
+    fn scan_batch<S>(
+        &self,
+        segments: &HashMap<usize, RwLock<S>>,
+        num_vectors: impl Fn(&S) -> usize,
+        score: impl Fn(&S, usize, usize) -> f32,
+        num_queries: usize,
+        k: usize,
+    ) -> Vec<Vec<ann::Node>> {
+        let vid_to_eid = self.vid_to_eid.read();
+        let mut res_heaps: Vec<BinaryHeap<ann::Node>> = (0..num_queries)
+            .map(|_| BinaryHeap::with_capacity(k + 1))
+            .collect();
+        segments.iter().for_each(|(segment_id, segment)| {
+            let segment = segment.read();
+            for i in 0..num_vectors(&segment) {
+                let vid = segment_id * self.v_per_segment + i;
+                let eid = match vid_to_eid.get(&vid) {
+                    Some(val) => *val,
+                    None => continue,
+                };
+                res_heaps.iter_mut().enumerate().for_each(|(j, res_heap)| {
                     res_heap.push(ann::Node {
                         vid: vid,
                         eid: eid,
-                        distance: dist,
+                        distance: score(&segment, i, j),
                     });
                     if res_heap.len() > k {
                         res_heap.pop().unwrap();
                     }
-                }
-            });
-        let mut res_vec = Vec::with_capacity(res_heap.len());
-        while !res_heap.is_empty() {
-            let neighbor_rev = res_heap.pop().unwrap();
-            res_vec.push(neighbor_rev);
-        }
-        res_vec.reverse();
-        Ok(res_vec)
+                });
+            }
+        });
+        res_heaps
+            .into_iter()
+            .map(|res_heap| res_heap.into_sorted_vec())
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ann::ANNIndex;
+
+    // the index scores with the kernel picked at runtime, which may sum in a
+    // different order than the scalar reference - so results are checked rank
+    // by rank against the reference distances within a tolerance, near ties
+    // may swap places but a wrongly scored point may not
+    fn assert_matches_reference(
+        expected: &[(f32, ann::EId)],
+        found: &[ann::Node],
+        reference: impl Fn(&ann::EId) -> f32,
+    ) {
+        assert_eq!(expected.len(), found.len());
+        for ((dist, _), nn) in expected.iter().zip(found.iter()) {
+            let tolerance = 1e-4 * (1.0 + dist);
+            assert!(
+                (nn.distance - dist).abs() <= tolerance,
+                "{} != {}",
+                nn.distance,
+                dist
+            );
+            assert!((reference(&nn.eid) - dist).abs() <= tolerance);
+        }
+    }
 
     #[test]
     fn insert_with_quantization() {
@@ -344,8 +1272,12 @@ mod tests {
         let params = FlatParams {
             dim: dimensions,
             segment_size_kb: 512,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
         };
-        let index = FlatIndex::<metric::MetricL2, u8>::new(&params).unwrap();
+        let index = FlatIndex::<metric::MetricL2, u8>::new_core(&params).unwrap();
         let eids: Vec<ann::EId> = (0..1000)
             .map(|id| {
                 let mut eid = [0u8; 16];
@@ -393,28 +1325,276 @@ mod tests {
     }
 
     #[test]
-    fn insert_large_wpadding() {
-        let dimensions = 126;
+    fn search_with_rerank() {
+        use rand::distributions::{Distribution, Uniform};
+        let dimensions = 32;
+        let num_vectors = 2000u32;
+        let k = 10;
         let params = FlatParams {
             dim: dimensions,
-            segment_size_kb: 512,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: Some(rerank::RerankParams {
+                factor: 4,
+                mmap_dir: None,
+            }),
+            quantizer: None,
         };
-        let index = FlatIndex::<metric::MetricL2, f32>::new(&params).unwrap();
-        // insert the first 1000 vectors into the index (nb: 1000 per segment)
-        let eids: Vec<ann::EId> = (0..1000)
+        let index = FlatIndex::<metric::MetricL2, u8>::new_core(&params).unwrap();
+        let eids: Vec<ann::EId> = (0..num_vectors)
             .map(|id| {
                 let mut eid = [0u8; 16];
-                let id_str = id.clone().to_string();
-                let id_bytes = id_str.as_bytes();
-                eid[0..id_bytes.len()].copy_from_slice(&id_bytes[..]);
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
                 eid
             })
             .collect();
-        let mut points: Vec<f32> = Vec::with_capacity(dimensions * eids.len());
-        (0..1000).for_each(|factor| {
-            points.append(&mut vec![1.2 * (factor as f32); dimensions]);
-        });
-        assert_eq!(
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        index
+            .insert(&eids[..1], ann::Points::Values { vals: &[0u8; 32] })
+            .expect_err("plain values leave nothing to re-rank against");
+        index
+            .insert(&eids, ann::Points::QuantizerIn { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        assert_eq!(Some(4), index.stats().quantizers.rerank_factor);
+
+        let queries: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * 10)
+            .collect();
+        let batch = index
+            .search_batch(ann::Points::QuantizerIn { vals: &queries[..] }, k)
+            .expect("error should not be thrown on search_batch");
+        for (q, batch_res) in queries.chunks(dimensions).zip(batch) {
+            let mut expect: Vec<(f32, ann::EId)> = points
+                .chunks(dimensions)
+                .zip(eids.iter())
+                .map(|(p, eid)| {
+                    let dist = p.iter().zip(q).map(|(a, b)| (a - b) * (a - b)).sum();
+                    (dist, *eid)
+                })
+                .collect();
+            expect.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let res = index
+                .search(ann::Points::QuantizerIn { vals: q }, k)
+                .expect("error should not be thrown on search");
+            assert_eq!(
+                res.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>(),
+                batch_res.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>()
+            );
+            // the survivors carry their exact distances
+            for nn in res.iter() {
+                let (dist, _) = expect.iter().find(|(_, eid)| *eid == nn.eid).unwrap();
+                assert!((nn.distance - dist).abs() < 1e-4);
+            }
+            let hits = res
+                .iter()
+                .filter(|nn| expect[..k].iter().any(|(_, eid)| *eid == nn.eid))
+                .count();
+            assert!(hits >= k - 2, "unexpectedly low recall: {}/{}", hits, k);
+        }
+    }
+
+    #[test]
+    fn requantize() {
+        use rand::distributions::{Distribution, Uniform};
+        let dimensions = 16;
+        let num_vectors = 500u32;
+        let eids: Vec<ann::EId> = (0..2 * num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(0.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        // the second half lands well past the range fitted on the first
+        let shifted: Vec<f32> = points
+            .chunks(dimensions)
+            .enumerate()
+            .flat_map(|(i, p)| {
+                let offset = if i < num_vectors as usize { 0.0 } else { 10.0 };
+                p.iter().map(move |x| x + offset)
+            })
+            .collect();
+        let half = dimensions * num_vectors as usize;
+        let requantize_params = RequantizeParams {
+            period_millis: 10,
+            max_drift: 0.5,
+        };
+
+        for rerank in [false, true] {
+            let params = FlatParams {
+                dim: dimensions,
+                segment_size_kb: 1,
+                mmap_dir: None,
+                pq: None,
+                rerank: rerank.then_some(rerank::RerankParams {
+                    factor: 1,
+                    mmap_dir: None,
+                }),
+                quantizer: None,
+            };
+            let index = Arc::new(FlatIndex::<metric::MetricL2, u8>::new_core(&params).unwrap());
+            index
+                .insert(
+                    &eids[..num_vectors as usize],
+                    ann::Points::QuantizerIn {
+                        vals: &shifted[..half],
+                    },
+                )
+                .expect("error should not be thrown on insert");
+            index
+                .insert(
+                    &eids[num_vectors as usize..],
+                    ann::Points::QuantizerIn {
+                        vals: &shifted[half..],
+                    },
+                )
+                .expect("error should not be thrown on insert");
+            let drift = index.stats().quantizers.scalar_drift.unwrap();
+            assert!(drift > 1.0, "drift: {}", drift);
+
+            if !rerank {
+                // the clipped codes cannot be recovered, but the rest survive
+                assert_eq!(2 * num_vectors as usize, index.requantize().unwrap());
+                assert_eq!(Some(0.0), index.stats().quantizers.scalar_drift);
+                for (p, eid) in shifted[..half].chunks(dimensions).zip(eids.iter()).take(20) {
+                    let res = index
+                        .search(ann::Points::QuantizerIn { vals: p }, 1)
+                        .expect("error should not be thrown on search");
+                    assert_eq!(*eid, res[0].eid);
+                }
+                continue;
+            }
+
+            let handle = index.start_requantize(requantize_params.clone()).unwrap();
+            let start = time::Instant::now();
+            while index.stats().quantizers.scalar_drift.unwrap() > requantize_params.max_drift {
+                assert!(start.elapsed() < time::Duration::from_secs(10));
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            handle.stop();
+            // the full-precision copy brings the shifted points back apart
+            for (p, eid) in shifted[half..]
+                .chunks(dimensions)
+                .zip(eids[num_vectors as usize..].iter())
+                .take(20)
+            {
+                let res = index
+                    .search(ann::Points::QuantizerIn { vals: p }, 1)
+                    .expect("error should not be thrown on search");
+                assert_eq!(*eid, res[0].eid);
+            }
+        }
+    }
+
+    #[test]
+    fn requantize_during_search() {
+        use rand::distributions::{Distribution, Uniform};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let dimensions = 16;
+        let num_vectors = 500u32;
+        let eids: Vec<ann::EId> = (0..2 * num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        // the second half moves the ranges far off the ones fitted on the first
+        let points: Vec<f32> = Uniform::from(0.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .enumerate()
+            .map(|(i, x)| {
+                if i < dimensions * num_vectors as usize {
+                    x
+                } else {
+                    x + 10.0
+                }
+            })
+            .collect();
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = Arc::new(FlatIndex::<metric::MetricL2, u8>::new_core(&params).unwrap());
+        for (eids, vals) in eids
+            .chunks(num_vectors as usize)
+            .zip(points.chunks(dimensions * num_vectors as usize))
+        {
+            index
+                .insert(eids, ann::Points::QuantizerIn { vals })
+                .expect("error should not be thrown on insert");
+        }
+
+        // a search straddling the pass would quantize the query with the
+        // old ranges and compare it against codes of the new ones
+        let done = Arc::new(AtomicBool::new(false));
+        let searcher = {
+            let index = index.clone();
+            let done = done.clone();
+            let points = points[..20 * dimensions].to_vec();
+            let eids = eids[..20].to_vec();
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    for (p, eid) in points.chunks(dimensions).zip(eids.iter()) {
+                        let res = index
+                            .search(ann::Points::QuantizerIn { vals: p }, 1)
+                            .expect("error should not be thrown on search");
+                        assert_eq!(*eid, res[0].eid);
+                    }
+                }
+            })
+        };
+        std::thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(2 * num_vectors as usize, index.requantize().unwrap());
+        std::thread::sleep(time::Duration::from_millis(20));
+        done.store(true, Ordering::Relaxed);
+        searcher.join().expect("search failed while requantizing");
+    }
+
+    #[test]
+    fn insert_large_wpadding() {
+        let dimensions = 126;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 512,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        // insert the first 1000 vectors into the index (nb: 1000 per segment)
+        let eids: Vec<ann::EId> = (0..1000)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                let id_str = id.clone().to_string();
+                let id_bytes = id_str.as_bytes();
+                eid[0..id_bytes.len()].copy_from_slice(&id_bytes[..]);
+                eid
+            })
+            .collect();
+        let mut points: Vec<f32> = Vec::with_capacity(dimensions * eids.len());
+        (0..1000).for_each(|factor| {
+            points.append(&mut vec![1.2 * (factor as f32); dimensions]);
+        });
+        assert_eq!(
             (),
             index
                 .insert(&eids, ann::Points::Values { vals: &points[..] })
@@ -456,8 +1636,12 @@ mod tests {
         let params = FlatParams {
             dim: 32,
             segment_size_kb: 512,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
         };
-        let index = FlatIndex::<metric::MetricL2, f32>::new(&params).unwrap();
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
         for i in 0..10 {
             let mut id = [0u8; 16];
             id[0] = i;
@@ -515,8 +1699,12 @@ mod tests {
         let params = FlatParams {
             dim: 32,
             segment_size_kb: 512,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
         };
-        let index = FlatIndex::<metric::MetricL2, f32>::new(&params).unwrap();
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
         for i in 0..10 {
             let mut id = [0u8; 16];
             id[0] = i;
@@ -551,8 +1739,12 @@ mod tests {
         let params = FlatParams {
             dim: 128,
             segment_size_kb: 512,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
         };
-        let index = FlatIndex::<metric::MetricL2, f32>::new(&params).unwrap();
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
         for i in 0..10 {
             let mut id = [0u8; 16];
             id[0] = i;
@@ -582,4 +1774,667 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn save_and_load() {
+        let dimensions = 30;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, u8>::new_core(&params).unwrap();
+        // span a couple of segments (nb: 1000 per segment)
+        let eids: Vec<ann::EId> = (0..2500u32)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut points: Vec<f32> = Vec::with_capacity(dimensions * eids.len());
+        (0..eids.len()).for_each(|factor| {
+            points.append(&mut vec![0.1 * (factor as f32); dimensions]);
+        });
+        index
+            .insert(&eids, ann::Points::QuantizerIn { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        index
+            .delete(&eids[2499..2500])
+            .expect("error should not be thrown on delete");
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-flat-save-{}-{}",
+            std::process::id(),
+            dimensions
+        ));
+        index.save(&directory).expect("unable to save the index");
+        let loaded = FlatIndex::<metric::MetricL2, u8>::load(&directory).unwrap();
+        let point_search = vec![0.1 * (3000 as f32); dimensions];
+        let expected = index
+            .search(
+                ann::Points::QuantizerIn {
+                    vals: &point_search,
+                },
+                5,
+            )
+            .unwrap();
+        let found = loaded
+            .search(
+                ann::Points::QuantizerIn {
+                    vals: &point_search,
+                },
+                5,
+            )
+            .unwrap();
+        assert_eq!(
+            expected.iter().map(|x| x.eid).collect::<Vec<ann::EId>>(),
+            found.iter().map(|x| x.eid).collect::<Vec<ann::EId>>()
+        );
+        assert!(!found.iter().any(|x| x.eid == eids[2499]));
+        assert!(FlatIndex::<metric::MetricL1, u8>::load(&directory).is_err());
+        assert!(FlatIndex::<metric::MetricL2, f32>::load(&directory).is_err());
+
+        // files from before the numeric tags name the rust types instead
+        let meta = std::fs::read(directory.join(META_FILE)).unwrap();
+        let mut legacy: Vec<u8> = Vec::new();
+        persist::write_header(&mut legacy, FLAT_MAGIC, 5).unwrap();
+        persist::write_str(&mut legacy, std::any::type_name::<u8>()).unwrap();
+        persist::write_str(&mut legacy, std::any::type_name::<metric::MetricL2>()).unwrap();
+        legacy.extend_from_slice(&meta[16..]);
+        std::fs::write(directory.join(META_FILE), legacy).unwrap();
+        assert!(FlatIndex::<metric::MetricL2, u8>::load(&directory).is_ok());
+        assert!(FlatIndex::<metric::MetricL1, u8>::load(&directory).is_err());
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn mmap_segments() {
+        let dimensions = 32;
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-flat-mmap-{}-{}",
+            std::process::id(),
+            dimensions
+        ));
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 512,
+            mmap_dir: Some(directory.clone()),
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        for i in 0..10 {
+            let mut id = [0u8; 16];
+            id[0] = i;
+            let point = vec![100.0 * (i as f32); dimensions];
+            index
+                .insert(&vec![id; 1], ann::Points::Values { vals: &point[..] })
+                .expect("error should not be thrown on insert");
+        }
+        // saving into the mapped directory only flushes the segments
+        index.save(&directory).expect("unable to save the index");
+        let loaded = FlatIndex::<metric::MetricL2, f32>::load(&directory).unwrap();
+        assert_eq!(Some(directory.clone()), loaded.params.mmap_dir);
+        let point_search = vec![290.0; dimensions];
+        match loaded.search(
+            ann::Points::Values {
+                vals: &point_search,
+            },
+            1,
+        ) {
+            Ok(res) => {
+                let mut expected_id = [0u8; 16];
+                expected_id[0] = 3;
+                let result: Vec<ann::EId> = res.iter().map(|x| (x.eid)).collect();
+                assert_eq!(vec![expected_id], result);
+            }
+            Err(_) => {
+                panic!("error should not be thrown on search");
+            }
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn search_with_allow_and_deny() {
+        use rand::distributions::{Distribution, Uniform};
+        use roaring::RoaringTreemap;
+        let dimensions = 32;
+        let num_vectors = 3000u32;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        let eids: Vec<ann::EId> = (0..num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        index
+            .delete(&eids[7..8])
+            .expect("error should not be thrown on delete");
+
+        let query = vec![0.0f32; dimensions];
+        // ground truth: brute force over the points that pass the predicate
+        let expected = |passes: &dyn Fn(usize) -> bool, k: usize| -> Vec<(f32, ann::EId)> {
+            let mut dists: Vec<(f32, ann::EId)> = (0..num_vectors as usize)
+                .filter(|i| *i != 7 && passes(*i))
+                .map(|i| {
+                    let vec = &points[i * dimensions..i * dimensions + dimensions];
+                    (metric::l2_similarity(vec, &query), eids[i])
+                })
+                .collect();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            dists.truncate(k);
+            dists
+        };
+        let search = |filter: &ann::SearchFilter, k: usize| -> Vec<ann::Node> {
+            index
+                .search_filtered(ann::Points::Values { vals: &query }, k, filter)
+                .expect("error should not be thrown on search")
+        };
+        let reference = |eid: &ann::EId| {
+            let i = u32::from_le_bytes(eid[0..4].try_into().unwrap()) as usize;
+            metric::l2_similarity(&points[i * dimensions..(i + 1) * dimensions], &query)
+        };
+
+        // every 97th point across all three segments, including the deleted one
+        let allowed: Vec<usize> = (0..num_vectors as usize).step_by(97).chain([7]).collect();
+        let filter = ann::SearchFilter {
+            allow: Some(ann::IdSet::EIds(allowed.iter().map(|i| eids[*i]).collect())),
+            deny: None,
+        };
+        assert_matches_reference(
+            &expected(&|i| allowed.contains(&i), 5),
+            &search(&filter, 5),
+            reference,
+        );
+        // asking for more than the allow list holds returns all of it
+        assert_eq!(allowed.len() - 1, search(&filter, 100).len());
+
+        let mut allowed_vids = RoaringTreemap::new();
+        allowed.iter().for_each(|i| {
+            allowed_vids.insert(*i as u64);
+        });
+        let filter = ann::SearchFilter {
+            allow: Some(ann::IdSet::VIds(allowed_vids)),
+            deny: Some(ann::IdSet::EIds(
+                allowed.iter().take(3).map(|i| eids[*i]).collect(),
+            )),
+        };
+        assert_matches_reference(
+            &expected(&|i| allowed.contains(&i) && !allowed[..3].contains(&i), 5),
+            &search(&filter, 5),
+            reference,
+        );
+
+        let mut denied_vids = RoaringTreemap::new();
+        (0..1500u64).for_each(|vid| {
+            denied_vids.insert(vid);
+        });
+        let filter = ann::SearchFilter {
+            allow: None,
+            deny: Some(ann::IdSet::VIds(denied_vids)),
+        };
+        assert_matches_reference(
+            &expected(&|i| i >= 1500, 10),
+            &search(&filter, 10),
+            reference,
+        );
+        assert_matches_reference(
+            &expected(&|_| true, 10),
+            &search(&ann::SearchFilter::default(), 10),
+            reference,
+        );
+    }
+
+    #[test]
+    fn pq_search_and_persist() {
+        use rand::distributions::{Distribution, Uniform};
+        let dimensions = 64;
+        let num_vectors = 1500u32;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            // 8 bytes per vector in place of 256
+            pq: Some(product_quantizer::PQParams {
+                num_subspaces: 8,
+                bits: 8,
+            }),
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        assert_eq!(0, index.datastore.read().len());
+        let eids: Vec<ann::EId> = (0..num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        index
+            .insert(
+                &eids[..1],
+                ann::Points::Values {
+                    vals: &points[..dimensions],
+                },
+            )
+            .expect_err("the codebooks need 2^bits vectors to train on");
+        assert!(!index.contains(&eids[0]));
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        // once trained, any batch size goes
+        index
+            .insert(
+                &eids[..1],
+                ann::Points::Values {
+                    vals: &points[..dimensions],
+                },
+            )
+            .expect("error should not be thrown on insert");
+        // nothing but the codes is stored
+        assert_eq!(0, index.datastore.read().len());
+        assert_eq!(2, index.pq_segments.read().len());
+
+        let top_1 = |index: &FlatIndex<metric::MetricL2, f32>| -> Vec<ann::EId> {
+            (0..50)
+                .map(|i| {
+                    let query = &points[i * dimensions..i * dimensions + dimensions];
+                    index
+                        .search(ann::Points::Values { vals: query }, 1)
+                        .expect("error should not be thrown on search")[0]
+                        .eid
+                })
+                .collect()
+        };
+        let found = top_1(&index);
+        let hits = found
+            .iter()
+            .zip(eids.iter())
+            .filter(|(found, expected)| found == expected)
+            .count();
+        assert!(hits >= 45, "only {} of 50 queries found themselves", hits);
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-flat-pq-{}-{}",
+            std::process::id(),
+            dimensions
+        ));
+        index.save(&directory).expect("unable to save the index");
+        let loaded = FlatIndex::<metric::MetricL2, f32>::load(&directory).unwrap();
+        assert_eq!(params.pq, loaded.params.pq);
+        assert_eq!(found, top_1(&loaded));
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn pq_unsupported_metric() {
+        let params = FlatParams {
+            dim: 32,
+            segment_size_kb: 512,
+            mmap_dir: None,
+            pq: Some(product_quantizer::PQParams {
+                num_subspaces: 4,
+                bits: 8,
+            }),
+            rerank: None,
+            quantizer: None,
+        };
+        assert!(FlatIndex::<metric::Hamming, f32>::new_core(&params).is_err());
+    }
+
+    #[test]
+    fn range_search() {
+        use rand::distributions::{Distribution, Uniform};
+        let dimensions = 16;
+        let num_vectors = 2500u32;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        let eids: Vec<ann::EId> = (0..num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        index
+            .delete(&eids[0..1])
+            .expect("error should not be thrown on delete");
+
+        let query = vec![0.0f32; dimensions];
+        let mut dists: Vec<(f32, ann::EId)> = (1..num_vectors as usize)
+            .map(|i| {
+                let vec = &points[i * dimensions..i * dimensions + dimensions];
+                (metric::l2_similarity(vec, &query), eids[i])
+            })
+            .collect();
+        dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // pick a radius that takes in a few hundred points, halfway between
+        // two of them so that no point sits on the boundary
+        let radius = (dists[300].0 + dists[301].0) / 2.0;
+        let expected: Vec<(f32, ann::EId)> = dists
+            .iter()
+            .take_while(|(d, _)| *d <= radius)
+            .copied()
+            .collect();
+        let res = index
+            .range_search(ann::Points::Values { vals: &query }, radius)
+            .expect("error should not be thrown on search");
+        assert_matches_reference(&expected, &res, |eid| {
+            let i = u32::from_le_bytes(eid[0..4].try_into().unwrap()) as usize;
+            metric::l2_similarity(&points[i * dimensions..(i + 1) * dimensions], &query)
+        });
+        assert!(index
+            .range_search(ann::Points::Values { vals: &query }, -1.0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn stats() {
+        let dimensions = 16;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        let eids: Vec<ann::EId> = (0..100u32)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let points = vec![1.0f32; dimensions * eids.len()];
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        index
+            .delete(&eids[0..10])
+            .expect("error should not be thrown on delete");
+
+        let stats = index.stats();
+        assert_eq!(stats.num_live, 90);
+        assert_eq!(stats.num_deleted, 10);
+        assert_eq!(stats.capacity, None);
+        assert!(stats.graph.is_none());
+        assert!(stats.memory_bytes["vectors"] >= 100 * dimensions * std::mem::size_of::<f32>());
+        assert!(stats.quantizers.pq.is_none());
+    }
+
+    #[test]
+    fn half_precision() {
+        let dimensions = 48;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 4,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let eids: Vec<ann::EId> = (0..200u32)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let points: Vec<f32> = (0..dimensions * eids.len())
+            .map(|x| ((x * 7919) % 1009) as f32 / 1009.0)
+            .collect();
+        let half_points: Vec<ann::f16> = points.iter().map(|x| ann::f16::from_f32(*x)).collect();
+
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        let half_index = FlatIndex::<metric::MetricL2, ann::f16>::new_core(&params).unwrap();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        half_index
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &half_points[..],
+                },
+            )
+            .expect("error should not be thrown on insert");
+        let res = half_index
+            .search(
+                ann::Points::Values {
+                    vals: &half_points[7 * dimensions..8 * dimensions],
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(eids[7], res[0].eid);
+        assert_eq!(
+            index.stats().memory_bytes["vectors"],
+            2 * half_index.stats().memory_bytes["vectors"]
+        );
+    }
+
+    #[test]
+    fn binary_search() {
+        let dimensions = 4;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let eids: Vec<ann::EId> = (0..64u32)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        // point i has its low i bits set in the first word
+        let points: Vec<u64> = (0..64u32)
+            .flat_map(|i| {
+                let word = if i == 0 { 0u64 } else { u64::MAX >> (64 - i) };
+                [word, 0u64, 0u64, 0u64]
+            })
+            .collect();
+        let query: Vec<u64> = vec![u64::MAX >> 54, 0, 0, 0];
+
+        let index = FlatIndex::<metric::Hamming, u64>::new_core(&params).unwrap();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        let res = index
+            .search(ann::Points::Values { vals: &query[..] }, 3)
+            .unwrap();
+        assert_eq!(eids[10], res[0].eid);
+        assert_eq!(0.0, res[0].distance);
+        assert_eq!(1.0, res[1].distance);
+        assert_eq!(1.0, res[2].distance);
+
+        let index = FlatIndex::<metric::MetricJaccard, u64>::new_core(&params).unwrap();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        let res = index
+            .search(ann::Points::Values { vals: &query[..] }, 2)
+            .unwrap();
+        assert_eq!(eids[10], res[0].eid);
+        assert_eq!(0.0, res[0].distance);
+        // 10 shared bits out of 11 beats 9 out of 10
+        assert_eq!(eids[11], res[1].eid);
+        assert_eq!(1.0 - 10.0 / 11.0, res[1].distance);
+    }
+
+    #[test]
+    fn get_and_iter() {
+        // not a multiple of the alignment so the padding has to be stripped
+        let dimensions = 10;
+        let params = FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        };
+        let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+        let eids: Vec<ann::EId> = (0..100u32)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let points: Vec<f32> = (0..dimensions * eids.len()).map(|x| x as f32).collect();
+        index
+            .insert(&eids, ann::Points::Values { vals: &points[..] })
+            .expect("error should not be thrown on insert");
+        index
+            .delete(&eids[0..1])
+            .expect("error should not be thrown on delete");
+
+        assert!(!index.contains(&eids[0]));
+        assert!(index.contains(&eids[1]));
+        let vecs = index.get(&eids[0..3]).unwrap();
+        assert_eq!(
+            vec![
+                None,
+                Some(points[dimensions..2 * dimensions].to_vec()),
+                Some(points[2 * dimensions..3 * dimensions].to_vec()),
+            ],
+            vecs
+        );
+        let found: HashMap<ann::EId, Vec<f32>> = index
+            .iter()
+            .collect::<anyhow::Result<_>>()
+            .expect("error should not be thrown while iterating");
+        assert_eq!(99, found.len());
+        for (i, eid) in eids.iter().enumerate().skip(1) {
+            assert_eq!(
+                &points[i * dimensions..(i + 1) * dimensions],
+                &found[eid][..]
+            );
+        }
+    }
+
+    #[test]
+    fn search_batch() {
+        use rand::distributions::{Distribution, Uniform};
+        let dimensions = 32;
+        let num_vectors = 2000u32;
+        let num_queries = 70;
+        let eids: Vec<ann::EId> = (0..num_vectors)
+            .map(|id| {
+                let mut eid = [0u8; 16];
+                eid[0..4].copy_from_slice(&id.to_le_bytes());
+                eid
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let points: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dimensions * eids.len())
+            .collect();
+        let queries = &points[..num_queries * dimensions];
+        for pq in [
+            None,
+            Some(product_quantizer::PQParams {
+                num_subspaces: 8,
+                bits: 8,
+            }),
+        ] {
+            let params = FlatParams {
+                dim: dimensions,
+                segment_size_kb: 1,
+                mmap_dir: None,
+                pq: pq,
+                rerank: None,
+                quantizer: None,
+            };
+            let index = FlatIndex::<metric::MetricL2, f32>::new_core(&params).unwrap();
+            index
+                .insert(&eids, ann::Points::Values { vals: &points[..] })
+                .expect("error should not be thrown on insert");
+            index
+                .delete(&eids[1..2])
+                .expect("error should not be thrown on delete");
+            let batch = index
+                .search_batch(ann::Points::Values { vals: queries }, 10)
+                .expect("error should not be thrown on search");
+            assert_eq!(num_queries, batch.len());
+            for (i, res) in batch.iter().enumerate() {
+                let single = index
+                    .search(
+                        ann::Points::Values {
+                            vals: &queries[i * dimensions..(i + 1) * dimensions],
+                        },
+                        10,
+                    )
+                    .unwrap();
+                assert_eq!(
+                    single.iter().map(|nn| nn.eid).collect::<Vec<_>>(),
+                    res.iter().map(|nn| nn.eid).collect::<Vec<_>>()
+                );
+            }
+        }
+        assert!(FlatIndex::<metric::MetricL2, f32>::new_core(&FlatParams {
+            dim: dimensions,
+            segment_size_kb: 1,
+            mmap_dir: None,
+            pq: None,
+            rerank: None,
+            quantizer: None,
+        })
+        .unwrap()
+        .search_batch(ann::Points::Values { vals: &points[..5] }, 10)
+        .is_err());
+    }
 }
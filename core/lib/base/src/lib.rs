@@ -5,12 +5,18 @@
 
 pub mod ann;
 mod av_store;
+pub mod collection;
+pub mod diskann_ssd;
 pub mod diskannv1;
 mod errors;
 pub mod flat;
+pub mod maintenance;
 pub mod metric;
 mod nn_query_scratch;
 mod nn_queue;
+mod persist;
+pub mod product_quantizer;
+pub mod rerank;
 pub mod scalar_quantizer;
 // mod diskannv1_test;
 
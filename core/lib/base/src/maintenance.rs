@@ -0,0 +1,55 @@
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use std::{thread, time};
+
+// owns the background thread running a periodic task of an index (ie. the
+// maintenance of DiskANNV1Index or the requantization of FlatIndex), the
+// thread is stopped (and joined) when the handle is stopped or dropped. tasks
+// only hold a weak reference so they also wind down once the index is dropped
+pub struct MaintenanceHandle {
+    s_stop: Option<Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MaintenanceHandle {
+    // runs task every period on a thread of its own, for as long as the
+    // handle is around and task asks to keep going
+    pub(crate) fn spawn(
+        period: time::Duration,
+        mut task: impl FnMut() -> bool + Send + 'static,
+    ) -> MaintenanceHandle {
+        let (s_stop, r_stop) = bounded::<()>(1);
+        let thread = thread::spawn(move || loop {
+            match r_stop.recv_timeout(period) {
+                Err(RecvTimeoutError::Timeout) => {}
+                // stopped or the handle is gone
+                _ => return,
+            }
+            if !task() {
+                return;
+            }
+        });
+        MaintenanceHandle {
+            s_stop: Some(s_stop),
+            thread: Some(thread),
+        }
+    }
+
+    // waits on a run of the task that is in flight before returning
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // hanging up wakes the thread straight out of its sleep
+        self.s_stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MaintenanceHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -4,20 +4,282 @@ use crate::metric_aarch;
 #[cfg(all(target_arch = "wasm32", target_feature = "simd128",))]
 use crate::metric_wasm;
 
-#[cfg(all(target_arch = "x86_64", target_feature = "fma", target_feature = "avx",))]
+#[cfg(target_arch = "x86_64")]
 use crate::metric_avx;
 
+use crate::ann::{bf16, f16, ElementVal};
+use crate::product_quantizer::PQDistance;
+use std::fmt;
+use std::sync::OnceLock;
+
 /*
     the core metric type that we implement for everything!
     MetricHamming
     MetricL2
     MetricL1
     MetricCosine
+    MetricInnerProduct
+    MetricJaccard
 */
-pub trait Metric<T>: Sync + Send {
+// the metrics by the tags saved indexes record them under, the rust type
+// names are not stable across compilers or refactors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    L2 = 1,
+    L1 = 2,
+    Cosine = 3,
+    InnerProduct = 4,
+    Hamming = 5,
+    Jaccard = 6,
+}
+
+impl MetricType {
+    pub(crate) fn from_u32(val: u32) -> anyhow::Result<MetricType> {
+        Ok(match val {
+            1 => MetricType::L2,
+            2 => MetricType::L1,
+            3 => MetricType::Cosine,
+            4 => MetricType::InnerProduct,
+            5 => MetricType::Hamming,
+            6 => MetricType::Jaccard,
+            _ => anyhow::bail!("unknown metric type: {}", val),
+        })
+    }
+}
+
+// shared by the element types a metric is implemented over
+pub trait MetricKind {
+    const METRIC_TYPE: MetricType;
+}
+
+pub trait Metric<T>: MetricKind + Sync + Send {
     fn compare(arr_a: &[T], arr_b: &[T]) -> f32;
     fn pre_process(arr_a: &[T]) -> Option<Vec<T>>;
     fn uses_preprocessor() -> bool;
+    // how the metric decomposes over the subspaces of a product quantizer,
+    // None for metrics that cannot be approximated through one
+    fn pq_distance() -> Option<PQDistance> {
+        None
+    }
+    // how the codes of the scalar quantizer are compared, the quantizer
+    // works out the norms itself so cosine needs no normalized inputs
+    fn quantized_distance() -> Option<PQDistance> {
+        Self::pq_distance()
+    }
+    // graph pruning drops a candidate c of p once a selected neighbor n
+    // occludes it by a factor greater than alpha, dist_pc = compare(p, c)
+    // and dist_nc = compare(n, c)
+    fn occlude_factor(dist_pc: f32, dist_nc: f32) -> f32 {
+        if dist_nc == 0.0 {
+            f32::MAX
+        } else {
+            dist_pc / dist_nc
+        }
+    }
+}
+
+// the instruction set the distance kernels were picked for. x86_64 hosts are
+// probed at runtime so a single binary runs everywhere and still uses the
+// widest registers the cpu has, neon and simd128 are fixed at compile time as
+// neither platform lets us probe for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SimdLevel {
+    Avx512,
+    Avx2,
+    Sse,
+    Neon,
+    Simd128,
+    Scalar,
+}
+
+impl SimdLevel {
+    // in order of preference
+    pub const ALL: [SimdLevel; 6] = [
+        SimdLevel::Avx512,
+        SimdLevel::Avx2,
+        SimdLevel::Sse,
+        SimdLevel::Neon,
+        SimdLevel::Simd128,
+        SimdLevel::Scalar,
+    ];
+}
+
+impl fmt::Display for SimdLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SimdLevel::Avx512 => "avx512",
+            SimdLevel::Avx2 => "avx2+fma",
+            SimdLevel::Sse => "sse2",
+            SimdLevel::Neon => "neon",
+            SimdLevel::Simd128 => "simd128",
+            SimdLevel::Scalar => "scalar",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// the distance kernels every metric goes through, resolved once on first use
+#[derive(Clone, Copy)]
+pub struct Kernels {
+    pub level: SimdLevel,
+    // whether the binary metrics use the POPCNT instruction, which x86_64
+    // probes for separately from the vector extensions
+    pub popcnt: bool,
+    pub(crate) l2_f32: fn(&[f32], &[f32]) -> f32,
+    pub(crate) l1_f32: fn(&[f32], &[f32]) -> f32,
+    pub(crate) dot_f32: fn(&[f32], &[f32]) -> f32,
+    pub(crate) cosine_f32: fn(&[f32], &[f32]) -> f32,
+    // out[i] = arr_a[i] / length
+    pub(crate) normalize_f32: fn(&[f32], f32, &mut [f32]),
+    pub(crate) l2_u8: fn(&[u8], &[u8]) -> u32,
+    pub(crate) dot_u8: fn(&[u8], &[u8]) -> u32,
+    pub(crate) hamming_u64: fn(&[u64], &[u64]) -> f32,
+    pub(crate) jaccard_u64: fn(&[u64], &[u64]) -> f32,
+}
+
+impl fmt::Debug for Kernels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Kernels")
+            .field("level", &self.level)
+            .field("popcnt", &self.popcnt)
+            .finish()
+    }
+}
+
+impl Kernels {
+    fn scalar() -> Kernels {
+        Kernels {
+            level: SimdLevel::Scalar,
+            popcnt: false,
+            l2_f32: l2_similarity,
+            l1_f32: l1_similarity,
+            dot_f32: dot_product,
+            cosine_f32: |a, b| 1.0f32 - dot_product(a, b),
+            normalize_f32: normalize,
+            l2_u8: l2_similarity_u8,
+            dot_u8: dot_product_u8,
+            hamming_u64: hamming_distance,
+            jaccard_u64: jaccard_distance,
+        }
+    }
+
+    // the kernels for level, None when the cpu we are running on (or the
+    // target we were compiled for) does not support it
+    pub fn for_level(level: SimdLevel) -> Option<Kernels> {
+        let scalar = Kernels::scalar();
+        match level {
+            SimdLevel::Scalar => Some(scalar),
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx512 | SimdLevel::Avx2 | SimdLevel::Sse => {
+                let supported = match level {
+                    SimdLevel::Avx512 => {
+                        is_x86_feature_detected!("avx512f")
+                            && is_x86_feature_detected!("avx2")
+                            && is_x86_feature_detected!("fma")
+                    }
+                    SimdLevel::Avx2 => {
+                        is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+                    }
+                    _ => is_x86_feature_detected!("sse2"),
+                };
+                if !supported {
+                    return None;
+                }
+                let mut kernels = match level {
+                    SimdLevel::Avx512 => Kernels {
+                        level,
+                        l2_f32: metric_avx::l2_f32_avx512,
+                        l1_f32: metric_avx::l1_f32_avx512,
+                        dot_f32: metric_avx::dot_f32_avx512,
+                        cosine_f32: metric_avx::cosine_f32_avx512,
+                        normalize_f32: metric_avx::normalize_f32_avx512,
+                        // the byte kernels gain little from the wider registers
+                        l2_u8: metric_avx::l2_u8_avx2,
+                        dot_u8: metric_avx::dot_u8_avx2,
+                        ..scalar
+                    },
+                    SimdLevel::Avx2 => Kernels {
+                        level,
+                        l2_f32: metric_avx::l2_f32_avx2,
+                        l1_f32: metric_avx::l1_f32_avx2,
+                        dot_f32: metric_avx::dot_f32_avx2,
+                        cosine_f32: metric_avx::cosine_f32_avx2,
+                        normalize_f32: metric_avx::normalize_f32_avx2,
+                        l2_u8: metric_avx::l2_u8_avx2,
+                        dot_u8: metric_avx::dot_u8_avx2,
+                        ..scalar
+                    },
+                    _ => Kernels {
+                        level,
+                        l2_f32: metric_avx::l2_f32_sse,
+                        l1_f32: metric_avx::l1_f32_sse,
+                        dot_f32: metric_avx::dot_f32_sse,
+                        cosine_f32: metric_avx::cosine_f32_sse,
+                        normalize_f32: metric_avx::normalize_f32_sse,
+                        l2_u8: metric_avx::l2_u8_sse,
+                        dot_u8: metric_avx::dot_u8_sse,
+                        ..scalar
+                    },
+                };
+                if is_x86_feature_detected!("popcnt") {
+                    kernels.popcnt = true;
+                    kernels.hamming_u64 = metric_avx::hamming_u64_popcnt;
+                    kernels.jaccard_u64 = metric_avx::jaccard_u64_popcnt;
+                }
+                Some(kernels)
+            }
+            #[cfg(all(target_arch = "aarch64", target_feature = "neon",))]
+            SimdLevel::Neon => Some(Kernels {
+                level,
+                popcnt: true,
+                l2_f32: |a, b| unsafe { metric_aarch::l2_similarity_aarch(a, b) },
+                l1_f32: |a, b| unsafe { metric_aarch::l1_similarity_aarch(a, b) },
+                dot_f32: |a, b| unsafe { metric_aarch::dot_product_aarch(a, b) },
+                cosine_f32: |a, b| unsafe { metric_aarch::cosine_similarity_aarch(a, b) },
+                normalize_f32: |a, length, out| unsafe {
+                    metric_aarch::normalize_aarch(a, length, out)
+                },
+                l2_u8: |a, b| unsafe { metric_aarch::l2_similarity_u8_aarch(a, b) },
+                dot_u8: |a, b| unsafe { metric_aarch::dot_product_u8_aarch(a, b) },
+                hamming_u64: |a, b| unsafe { metric_aarch::hamming_distance_aarch(a, b) },
+                jaccard_u64: |a, b| unsafe { metric_aarch::jaccard_distance_aarch(a, b) },
+            }),
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128",))]
+            SimdLevel::Simd128 => Some(Kernels {
+                level,
+                l2_f32: |a, b| unsafe { metric_wasm::l2_similarity_wasm(a, b) },
+                l1_f32: |a, b| unsafe { metric_wasm::l1_similarity_wasm(a, b) },
+                dot_f32: |a, b| unsafe { metric_wasm::dot_product_wasm(a, b) },
+                cosine_f32: |a, b| unsafe { metric_wasm::cosine_similarity_wasm(a, b) },
+                normalize_f32: |a, length, out| unsafe {
+                    metric_wasm::normalize_wasm(a, length, out)
+                },
+                l2_u8: |a, b| unsafe { metric_wasm::l2_similarity_u8_wasm(a, b) },
+                dot_u8: |a, b| unsafe { metric_wasm::dot_product_u8_wasm(a, b) },
+                ..scalar
+            }),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn detect() -> Kernels {
+        SimdLevel::ALL
+            .iter()
+            .find_map(|level| Kernels::for_level(*level))
+            .unwrap_or_else(Kernels::scalar)
+    }
+}
+
+static KERNELS: OnceLock<Kernels> = OnceLock::new();
+
+// the kernels in use by this process
+pub fn kernels() -> &'static Kernels {
+    KERNELS.get_or_init(Kernels::detect)
+}
+
+pub fn simd_level() -> SimdLevel {
+    kernels().level
 }
 
 pub(crate) fn l2_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
@@ -31,10 +293,16 @@ pub(crate) fn l2_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
 // use std::marker::PhantomData;
 #[derive(Debug)]
 pub struct MetricL2 {}
+impl MetricKind for MetricL2 {
+    const METRIC_TYPE: MetricType = MetricType::L2;
+}
 impl Metric<f32> for MetricL2 {
     fn uses_preprocessor() -> bool {
         return false;
     }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L2)
+    }
     #[allow(unused_variables)]
     fn pre_process(arr_a: &[f32]) -> Option<Vec<f32>> {
         None
@@ -42,43 +310,36 @@ impl Metric<f32> for MetricL2 {
 
     #[inline(always)]
     fn compare(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-        #[cfg(all(target_feature = "fma", target_feature = "avx",))]
-        {
-            if is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma") {
-                return unsafe { metric_avx::l2_similarity_avx(arr_a, arr_b) };
-            }
-        }
-        #[cfg(all(target_arch = "aarch64", target_feature = "neon",))]
-        {
-            if std::arch::is_aarch64_feature_detected!("neon") {
-                return unsafe { metric_aarch::l2_similarity_aarch(arr_a, arr_b) };
-            }
-        }
-        #[cfg(all(target_arch = "wasm32", target_feature = "simd128",))]
-        {
-            return unsafe { metric_wasm::l2_similarity_wasm(arr_a, arr_b) };
-        }
-        l2_similarity(arr_a, arr_b)
+        (kernels().l2_f32)(arr_a, arr_b)
     }
 }
 impl Metric<u8> for MetricL2 {
     fn uses_preprocessor() -> bool {
         return false;
     }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L2)
+    }
     fn pre_process(_arr_a: &[u8]) -> Option<Vec<u8>> {
         None
     }
+    #[inline(always)]
     fn compare(arr_a: &[u8], arr_b: &[u8]) -> f32 {
-        let res: u32 = arr_a
-            .iter()
-            .copied()
-            .zip(arr_b.iter().copied())
-            .map(|(a, b)| (a as u32 - b as u32) * (a as u32 - b as u32))
-            .sum();
-        res as f32
+        (kernels().l2_u8)(arr_a, arr_b) as f32
     }
 }
 
+pub(crate) fn l2_similarity_u8(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| {
+            let diff = *a as i32 - *b as i32;
+            (diff * diff) as u32
+        })
+        .sum()
+}
+
 pub(crate) fn l1_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
     return arr_a
         .iter()
@@ -90,10 +351,16 @@ pub(crate) fn l1_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
 
 #[derive(Debug)]
 pub struct MetricL1 {}
+impl MetricKind for MetricL1 {
+    const METRIC_TYPE: MetricType = MetricType::L1;
+}
 impl Metric<f32> for MetricL1 {
     fn uses_preprocessor() -> bool {
         return false;
     }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L1)
+    }
     #[allow(unused_variables)]
     fn pre_process(arr_a: &[f32]) -> Option<Vec<f32>> {
         None
@@ -101,28 +368,39 @@ impl Metric<f32> for MetricL1 {
     #[inline(always)]
     #[allow(unused_variables)]
     fn compare(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-        #[cfg(all(target_arch = "x86_64", target_feature = "fma", target_feature = "avx",))]
-        {
-            if is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma") {
-                return unsafe { metric_avx::l1_similarity_avx(arr_a, arr_b) };
-            }
-        }
-        #[cfg(all(target_arch = "aarch64", target_feature = "neon",))]
-        {
-            if std::arch::is_aarch64_feature_detected!("neon") {
-                return unsafe { metric_aarch::l1_similarity_aarch(arr_a, arr_b) };
-            }
-        }
-        #[cfg(all(target_arch = "wasm32", target_feature = "simd128",))]
-        {
-            return unsafe { metric_wasm::l1_similarity_wasm(arr_a, arr_b) };
-        }
-        l1_similarity(arr_a, arr_b)
+        (kernels().l1_f32)(arr_a, arr_b)
     }
 }
 
+impl Metric<u8> for MetricL1 {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L1)
+    }
+    fn pre_process(_arr_a: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[u8], arr_b: &[u8]) -> f32 {
+        l1_similarity_u8(arr_a, arr_b) as f32
+    }
+}
+
+pub(crate) fn l1_similarity_u8(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| a.abs_diff(*b) as u32)
+        .sum()
+}
+
 #[derive(Debug)]
 pub struct Hamming {}
+impl MetricKind for Hamming {
+    const METRIC_TYPE: MetricType = MetricType::Hamming;
+}
 impl Metric<f32> for Hamming {
     fn uses_preprocessor() -> bool {
         return false;
@@ -144,6 +422,67 @@ impl Metric<f32> for Hamming {
     }
 }
 
+// packed binary vectors, the number of differing bits
+impl Metric<u64> for Hamming {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pre_process(_arr_a: &[u64]) -> Option<Vec<u64>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+        (kernels().hamming_u64)(arr_a, arr_b)
+    }
+}
+
+pub(crate) fn hamming_distance(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let res: u32 = arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    res as f32
+}
+
+// Jaccard / Tanimoto distance between packed binary vectors, 1 - |a & b| / |a | b|.
+// two empty sets are considered identical
+#[derive(Debug)]
+pub struct MetricJaccard {}
+impl MetricKind for MetricJaccard {
+    const METRIC_TYPE: MetricType = MetricType::Jaccard;
+}
+impl Metric<u64> for MetricJaccard {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pre_process(_arr_a: &[u64]) -> Option<Vec<u64>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+        (kernels().jaccard_u64)(arr_a, arr_b)
+    }
+}
+
+pub(crate) fn jaccard_distance(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let (intersection, union) = arr_a
+        .iter()
+        .zip(arr_b)
+        .fold((0u32, 0u32), |(i, u), (a, b)| {
+            (i + (a & b).count_ones(), u + (a | b).count_ones())
+        });
+    jaccard_from_counts(intersection, union)
+}
+
+#[inline(always)]
+pub(crate) fn jaccard_from_counts(intersection: u32, union: u32) -> f32 {
+    if union == 0 {
+        return 0.0;
+    }
+    1.0 - intersection as f32 / union as f32
+}
+
 pub(crate) fn hamming_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
     // TODO(infrawhispers) - we use the raw bitwise representations to do hamming
     // the client needs to be aware of this when creating the vectors and
@@ -159,40 +498,350 @@ pub(crate) fn hamming_similarity(arr_a: &[f32], arr_b: &[f32]) -> f32 {
 
 #[derive(Debug)]
 pub struct MetricCosine {}
+impl MetricKind for MetricCosine {
+    const METRIC_TYPE: MetricType = MetricType::Cosine;
+}
 impl Metric<f32> for MetricCosine {
     fn uses_preprocessor() -> bool {
         return true;
     }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::Cosine)
+    }
     #[allow(unused_variables)]
     fn pre_process(arr_a: &[f32]) -> Option<Vec<f32>> {
         cosine_pre_process(arr_a)
     }
     #[inline(always)]
     fn compare(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-        #[cfg(all(target_arch = "wasm32", target_feature = "simd128",))]
-        {
-            return unsafe { metric_wasm::cosine_similarity_wasm(arr_a, arr_b) };
-        }
         cosine_compare(arr_a, arr_b)
     }
 }
 
+// u8 vectors cannot be normalized in place without losing most of their
+// precision, so the norms are computed alongside the dot product instead.
+// the zero vector is treated as orthogonal to everything
+impl Metric<u8> for MetricCosine {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pre_process(_arr_a: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    fn quantized_distance() -> Option<PQDistance> {
+        Some(PQDistance::Cosine)
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[u8], arr_b: &[u8]) -> f32 {
+        let norm_a = dot_u8(arr_a, arr_a);
+        let norm_b = dot_u8(arr_b, arr_b);
+        if norm_a == 0 || norm_b == 0 {
+            return 1.0;
+        }
+        let ab = dot_u8(arr_a, arr_b);
+        (1.0f64 - ab as f64 / (norm_a as f64 * norm_b as f64).sqrt()) as f32
+    }
+}
+
+#[inline(always)]
+fn dot_u8(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    (kernels().dot_u8)(arr_a, arr_b)
+}
+
+pub(crate) fn dot_product_u8(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| *a as u32 * *b as u32)
+        .sum()
+}
+
 pub(crate) fn cosine_compare(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-    1.0f32 - arr_a.iter().zip(arr_b).map(|(a, b)| a * b).sum::<f32>()
+    (kernels().cosine_f32)(arr_a, arr_b)
 }
 
 pub(crate) fn cosine_pre_process(arr_a: &[f32]) -> Option<Vec<f32>> {
-    let mut length: f32 = arr_a.iter().map(|x| x * x).sum();
+    let kernels = kernels();
+    let mut length: f32 = (kernels.dot_f32)(arr_a, arr_a);
     if length < f32::EPSILON {
         return None;
     }
     length = length.sqrt();
-    Some(arr_a.iter().map(|x| x / length).collect())
+    let mut normalized = vec![0.0f32; arr_a.len()];
+    (kernels.normalize_f32)(arr_a, length, &mut normalized);
+    Some(normalized)
+}
+
+pub(crate) fn normalize(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    out.iter_mut().zip(arr_a).for_each(|(o, a)| *o = a / length);
+}
+
+pub(crate) fn dot_product(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    arr_a.iter().zip(arr_b).map(|(a, b)| a * b).sum::<f32>()
+}
+
+// maximum inner product search: compare(..) is the negated dot product so
+// that smaller is closer, as with every other metric. inner product is not a
+// distance, so the ratio of distances graph pruning relies on is replaced by
+// a ratio of similarities, see occlude_factor(..)
+#[derive(Debug)]
+pub struct MetricInnerProduct {}
+impl MetricKind for MetricInnerProduct {
+    const METRIC_TYPE: MetricType = MetricType::InnerProduct;
+}
+impl Metric<f32> for MetricInnerProduct {
+    fn uses_preprocessor() -> bool {
+        return false;
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::InnerProduct)
+    }
+    #[allow(unused_variables)]
+    fn pre_process(arr_a: &[f32]) -> Option<Vec<f32>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+        -inner_product(arr_a, arr_b)
+    }
+    fn occlude_factor(dist_pc: f32, dist_nc: f32) -> f32 {
+        inner_product_occlude_factor(dist_pc, dist_nc)
+    }
+}
+
+// c is occluded by n once <n, c> > alpha * <p, c>, ie. n is (alpha times)
+// more similar to c than p is. candidates pointing away from p (<p, c>
+// <= 0) are occluded by any neighbor more similar to them than p
+fn inner_product_occlude_factor(dist_pc: f32, dist_nc: f32) -> f32 {
+    let (sim_pc, sim_nc) = (-dist_pc, -dist_nc);
+    if sim_pc > 0.0 {
+        sim_nc / sim_pc
+    } else if sim_nc > sim_pc {
+        f32::MAX
+    } else {
+        0.0
+    }
+}
+
+#[inline(always)]
+fn inner_product(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    (kernels().dot_f32)(arr_a, arr_b)
+}
+
+// 16 bit floats are only a storage format, every metric widens them to f32
+// as they are read and accumulates in f32
+pub trait HalfFloat: ElementVal {
+    fn widen(self) -> f32;
+    fn narrow(val: f32) -> Self;
+}
+impl HalfFloat for f16 {
+    #[inline(always)]
+    fn widen(self) -> f32 {
+        self.to_f32()
+    }
+    #[inline(always)]
+    fn narrow(val: f32) -> Self {
+        f16::from_f32(val)
+    }
+}
+impl HalfFloat for bf16 {
+    #[inline(always)]
+    fn widen(self) -> f32 {
+        self.to_f32()
+    }
+    #[inline(always)]
+    fn narrow(val: f32) -> Self {
+        bf16::from_f32(val)
+    }
+}
+
+impl<T: HalfFloat> Metric<T> for MetricL2 {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L2)
+    }
+    fn pre_process(_arr_a: &[T]) -> Option<Vec<T>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[T], arr_b: &[T]) -> f32 {
+        arr_a
+            .iter()
+            .zip(arr_b)
+            .map(|(a, b)| {
+                let diff = a.widen() - b.widen();
+                diff * diff
+            })
+            .sum()
+    }
+}
+
+impl<T: HalfFloat> Metric<T> for MetricL1 {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L1)
+    }
+    fn pre_process(_arr_a: &[T]) -> Option<Vec<T>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[T], arr_b: &[T]) -> f32 {
+        arr_a
+            .iter()
+            .zip(arr_b)
+            .map(|(a, b)| (a.widen() - b.widen()).abs())
+            .sum()
+    }
+}
+
+// the vectors are normalized in f32 before being narrowed for storage, which
+// keeps the norms within the precision of the storage type
+impl<T: HalfFloat> Metric<T> for MetricCosine {
+    fn uses_preprocessor() -> bool {
+        true
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::Cosine)
+    }
+    fn pre_process(arr_a: &[T]) -> Option<Vec<T>> {
+        let widened: Vec<f32> = arr_a.iter().map(|x| x.widen()).collect();
+        cosine_pre_process(&widened).map(|vec| vec.into_iter().map(T::narrow).collect())
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[T], arr_b: &[T]) -> f32 {
+        1.0f32 - half_dot_product(arr_a, arr_b)
+    }
+}
+
+impl<T: HalfFloat> Metric<T> for MetricInnerProduct {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::InnerProduct)
+    }
+    fn pre_process(_arr_a: &[T]) -> Option<Vec<T>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[T], arr_b: &[T]) -> f32 {
+        -half_dot_product(arr_a, arr_b)
+    }
+    fn occlude_factor(dist_pc: f32, dist_nc: f32) -> f32 {
+        inner_product_occlude_factor(dist_pc, dist_nc)
+    }
+}
+
+#[inline(always)]
+fn half_dot_product<T: HalfFloat>(arr_a: &[T], arr_b: &[T]) -> f32 {
+    arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| a.widen() * b.widen())
+        .sum()
+}
+
+// signed bytes accumulate exactly in i32, as with u8 the vectors cannot be
+// normalized in place so cosine computes the norms alongside the dot product
+impl Metric<i8> for MetricL2 {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L2)
+    }
+    fn pre_process(_arr_a: &[i8]) -> Option<Vec<i8>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[i8], arr_b: &[i8]) -> f32 {
+        let res: i32 = arr_a
+            .iter()
+            .zip(arr_b)
+            .map(|(a, b)| {
+                let diff = *a as i32 - *b as i32;
+                diff * diff
+            })
+            .sum();
+        res as f32
+    }
+}
+
+impl Metric<i8> for MetricL1 {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::L1)
+    }
+    fn pre_process(_arr_a: &[i8]) -> Option<Vec<i8>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[i8], arr_b: &[i8]) -> f32 {
+        let res: u32 = arr_a
+            .iter()
+            .zip(arr_b)
+            .map(|(a, b)| a.abs_diff(*b) as u32)
+            .sum();
+        res as f32
+    }
+}
+
+impl Metric<i8> for MetricCosine {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pre_process(_arr_a: &[i8]) -> Option<Vec<i8>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[i8], arr_b: &[i8]) -> f32 {
+        let norm_a = dot_product_i8(arr_a, arr_a);
+        let norm_b = dot_product_i8(arr_b, arr_b);
+        if norm_a == 0 || norm_b == 0 {
+            return 1.0;
+        }
+        let ab = dot_product_i8(arr_a, arr_b);
+        (1.0f64 - ab as f64 / (norm_a as f64 * norm_b as f64).sqrt()) as f32
+    }
+}
+
+impl Metric<i8> for MetricInnerProduct {
+    fn uses_preprocessor() -> bool {
+        false
+    }
+    fn pq_distance() -> Option<PQDistance> {
+        Some(PQDistance::InnerProduct)
+    }
+    fn pre_process(_arr_a: &[i8]) -> Option<Vec<i8>> {
+        None
+    }
+    #[inline(always)]
+    fn compare(arr_a: &[i8], arr_b: &[i8]) -> f32 {
+        -dot_product_i8(arr_a, arr_b) as f32
+    }
+    fn occlude_factor(dist_pc: f32, dist_nc: f32) -> f32 {
+        inner_product_occlude_factor(dist_pc, dist_nc)
+    }
+}
+
+pub(crate) fn dot_product_i8(arr_a: &[i8], arr_b: &[i8]) -> i32 {
+    arr_a
+        .iter()
+        .zip(arr_b)
+        .map(|(a, b)| *a as i32 * *b as i32)
+        .sum()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::av_store;
     #[test]
     fn test_l2() {
         let vec1 = vec![
@@ -225,4 +874,167 @@ mod tests {
             MetricCosine::compare(&vec1_normalized, &vec2_normalized)
         );
     }
+    #[test]
+    fn test_kernels() {
+        let active = kernels();
+        assert_eq!(active.level, simd_level());
+        // detection settles on the first supported level
+        let best = SimdLevel::ALL
+            .iter()
+            .find(|level| Kernels::for_level(**level).is_some())
+            .unwrap();
+        assert_eq!(*best, active.level);
+        assert!(Kernels::for_level(SimdLevel::Scalar).is_some());
+
+        let f1: Vec<f32> = (0..37).map(|x| (x % 11) as f32 - 5.0).collect();
+        let f2: Vec<f32> = (0..37).map(|x| (x * 7 % 13) as f32).collect();
+        let u1: Vec<u8> = (0..53u32).map(|x| (x * 13 % 256) as u8).collect();
+        let u2: Vec<u8> = (0..53u32).map(|x| (255 - x * 3 % 256) as u8).collect();
+        let b1: Vec<u64> = (0..5u64)
+            .map(|x| x.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .collect();
+        let b2: Vec<u64> = (0..5u64)
+            .map(|x| (x + 3).wrapping_mul(0xff51_afd7_ed55_8ccd))
+            .collect();
+        for level in SimdLevel::ALL {
+            let Some(k) = Kernels::for_level(level) else {
+                continue;
+            };
+            assert_eq!(level, k.level);
+            assert_eq!(l2_similarity(&f1, &f2), (k.l2_f32)(&f1, &f2), "{}", level);
+            assert_eq!(l1_similarity(&f1, &f2), (k.l1_f32)(&f1, &f2), "{}", level);
+            assert_eq!(dot_product(&f1, &f2), (k.dot_f32)(&f1, &f2), "{}", level);
+            assert_eq!(1.0 - dot_product(&f1, &f2), (k.cosine_f32)(&f1, &f2));
+            let mut expected = vec![0f32; f1.len()];
+            let mut normalized = vec![0f32; f1.len()];
+            normalize(&f1, 7.3, &mut expected);
+            (k.normalize_f32)(&f1, 7.3, &mut normalized);
+            assert_eq!(expected, normalized, "{}", level);
+            assert_eq!(l2_similarity_u8(&u1, &u2), (k.l2_u8)(&u1, &u2), "{}", level);
+            assert_eq!(dot_product_u8(&u1, &u2), (k.dot_u8)(&u1, &u2), "{}", level);
+            assert_eq!(hamming_distance(&b1, &b2), (k.hamming_u64)(&b1, &b2));
+            assert_eq!(jaccard_distance(&b1, &b2), (k.jaccard_u64)(&b1, &b2));
+        }
+    }
+    #[test]
+    fn test_u8() {
+        let vec1: Vec<u8> = (0..37u32).map(|x| (x * 7 % 256) as u8).collect();
+        let vec2: Vec<u8> = (0..37u32).map(|x| (255 - x * 5 % 256) as u8).collect();
+        let to_f32 = |v: &[u8]| v.iter().map(|x| *x as f32).collect::<Vec<f32>>();
+        let (f1, f2) = (to_f32(&vec1), to_f32(&vec2));
+
+        assert_eq!(l2_similarity(&f1, &f2), MetricL2::compare(&vec1, &vec2));
+        assert_eq!(l2_similarity(&f2, &f1), MetricL2::compare(&vec2, &vec1));
+        assert_eq!(l1_similarity(&f1, &f2), MetricL1::compare(&vec1, &vec2));
+        assert_eq!(dot_product(&f1, &f2), dot_u8(&vec1, &vec2) as f32);
+        assert_eq!(dot_product_u8(&vec1, &vec2), dot_u8(&vec1, &vec2));
+
+        let expected = cosine_compare(
+            &cosine_pre_process(&f1).unwrap(),
+            &cosine_pre_process(&f2).unwrap(),
+        );
+        assert!((expected - MetricCosine::compare(&vec1, &vec2)).abs() < 1e-5);
+        assert!(MetricCosine::compare(&vec1, &vec1).abs() < 1e-6);
+        assert_eq!(1.0, MetricCosine::compare(&vec1, &[0u8; 37]));
+    }
+    #[test]
+    fn test_half_and_i8() {
+        let v1: Vec<f32> = (0..40).map(|x| (x as f32 - 20.0) / 8.0).collect();
+        let v2: Vec<f32> = (0..40)
+            .map(|x| ((x * 7 % 40) as f32 - 20.0) / 8.0)
+            .collect();
+        // multiples of 1/8 in [-2.5, 2.5] are exact in both f16 and bf16
+        let h1: Vec<f16> = v1.iter().map(|x| f16::from_f32(*x)).collect();
+        let h2: Vec<f16> = v2.iter().map(|x| f16::from_f32(*x)).collect();
+        let b1: Vec<bf16> = v1.iter().map(|x| bf16::from_f32(*x)).collect();
+        let b2: Vec<bf16> = v2.iter().map(|x| bf16::from_f32(*x)).collect();
+
+        assert_eq!(l2_similarity(&v1, &v2), MetricL2::compare(&h1, &h2));
+        assert_eq!(l2_similarity(&v1, &v2), MetricL2::compare(&b1, &b2));
+        assert_eq!(l1_similarity(&v1, &v2), MetricL1::compare(&h1, &h2));
+        assert_eq!(
+            -dot_product(&v1, &v2),
+            MetricInnerProduct::compare(&b1, &b2)
+        );
+
+        // normalization has to survive the narrowing
+        let n1 = MetricCosine::pre_process(&h1).unwrap();
+        let n2 = MetricCosine::pre_process(&h2).unwrap();
+        let expected = cosine_compare(
+            &cosine_pre_process(&v1).unwrap(),
+            &cosine_pre_process(&v2).unwrap(),
+        );
+        assert!((expected - MetricCosine::compare(&n1, &n2)).abs() < 1e-3);
+        assert!(MetricCosine::compare(&n1, &n1).abs() < 1e-3);
+
+        let i1: Vec<i8> = (0..40).map(|x| (x * 6 - 120) as i8).collect();
+        let i2: Vec<i8> = (0..40).map(|x| (127 - x * 5) as i8).collect();
+        let f1: Vec<f32> = i1.iter().map(|x| *x as f32).collect();
+        let f2: Vec<f32> = i2.iter().map(|x| *x as f32).collect();
+        assert_eq!(l2_similarity(&f1, &f2), MetricL2::compare(&i1, &i2));
+        assert_eq!(l1_similarity(&f1, &f2), MetricL1::compare(&i1, &i2));
+        assert_eq!(
+            -dot_product(&f1, &f2),
+            MetricInnerProduct::compare(&i1, &i2)
+        );
+        let expected = cosine_compare(
+            &cosine_pre_process(&f1).unwrap(),
+            &cosine_pre_process(&f2).unwrap(),
+        );
+        assert!((expected - MetricCosine::compare(&i1, &i2)).abs() < 1e-5);
+        assert_eq!(1.0, MetricCosine::compare(&i1, &[0i8; 40]));
+    }
+    #[test]
+    fn test_binary() {
+        let mut vec1 = vec![0u64; 16];
+        let mut vec2 = vec![0u64; 16];
+        assert_eq!(0.0, Hamming::compare(&vec1, &vec2));
+        assert_eq!(0.0, MetricJaccard::compare(&vec1, &vec2));
+
+        // 4 bits set in vec1, 2 of which are shared with the 3 set in vec2
+        vec1[0] = 0b1011;
+        vec1[15] = 1 << 63;
+        vec2[0] = 0b0011;
+        vec2[7] = 1;
+        assert_eq!(3.0, Hamming::compare(&vec1, &vec2));
+        assert_eq!(3.0, hamming_distance(&vec1, &vec2));
+        assert_eq!(1.0 - 2.0 / 5.0, MetricJaccard::compare(&vec1, &vec2));
+        assert_eq!(1.0 - 2.0 / 5.0, jaccard_distance(&vec1, &vec2));
+        assert_eq!(0.0, MetricJaccard::compare(&vec1, &vec1));
+        assert_eq!(1.0, MetricJaccard::compare(&vec1, &[0u64; 16]));
+    }
+    #[test]
+    fn test_inner_product() {
+        let mut store: av_store::AlignedDataStore<f32> = av_store::AlignedDataStore::new(3, 16);
+        let vecs: Vec<f32> = (0..48).map(|x| (x % 7) as f32 - 3.0).collect();
+        store.data[..].copy_from_slice(&vecs);
+        let (vec1, vec2, vec3) = (&store.data[0..16], &store.data[16..32], &store.data[32..48]);
+        assert_eq!(
+            -dot_product(vec1, vec2),
+            MetricInnerProduct::compare(vec1, vec2)
+        );
+
+        assert_eq!(
+            -dot_product(vec3, vec3),
+            MetricInnerProduct::compare(vec3, vec3)
+        );
+
+        // c is only occluded by neighbors more similar to it than p is
+        assert_eq!(
+            1.5,
+            <MetricInnerProduct as Metric<f32>>::occlude_factor(-2.0, -3.0)
+        );
+        assert_eq!(
+            0.5,
+            <MetricInnerProduct as Metric<f32>>::occlude_factor(-2.0, -1.0)
+        );
+        assert_eq!(
+            f32::MAX,
+            <MetricInnerProduct as Metric<f32>>::occlude_factor(1.0, -1.0)
+        );
+        assert_eq!(
+            0.0,
+            <MetricInnerProduct as Metric<f32>>::occlude_factor(1.0, 2.0)
+        );
+    }
 }
@@ -74,6 +74,72 @@ pub(crate) unsafe fn l1_similarity_aarch(arr_a: &[f32], arr_b: &[f32]) -> f32 {
     result
 }
 
+#[cfg(all(target_feature = "neon",))]
+#[inline(always)]
+pub(crate) unsafe fn dot_product_aarch(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len();
+    let m: isize = (n).try_into().unwrap();
+    let mut sum1: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut sum2: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut sum3: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut sum4: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut ptr_a: *const f32 = arr_a.as_ptr();
+    let mut ptr_b: *const f32 = arr_b.as_ptr();
+    let mut i: isize = 0;
+    while i < m {
+        sum1 = vfmaq_f32(sum1, vld1q_f32(ptr_a), vld1q_f32(ptr_b));
+        sum2 = vfmaq_f32(sum2, vld1q_f32(ptr_a.offset(4)), vld1q_f32(ptr_b.offset(4)));
+        sum3 = vfmaq_f32(sum3, vld1q_f32(ptr_a.offset(8)), vld1q_f32(ptr_b.offset(8)));
+        sum4 = vfmaq_f32(
+            sum4,
+            vld1q_f32(ptr_a.offset(12)),
+            vld1q_f32(ptr_b.offset(12)),
+        );
+        ptr_a = ptr_a.offset(16);
+        ptr_b = ptr_b.offset(16);
+        i += 16
+    }
+    let result = vaddvq_f32(sum1) + vaddvq_f32(sum2) + vaddvq_f32(sum3) + vaddvq_f32(sum4);
+    result
+}
+
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn cosine_similarity_aarch(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 8;
+    let mut sum1: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut sum2: float32x4_t = vdupq_n_f32(0.0f32);
+    let mut i: usize = 0;
+    while i < n {
+        let ptr_a = arr_a.as_ptr().add(i);
+        let ptr_b = arr_b.as_ptr().add(i);
+        sum1 = vfmaq_f32(sum1, vld1q_f32(ptr_a), vld1q_f32(ptr_b));
+        sum2 = vfmaq_f32(sum2, vld1q_f32(ptr_a.add(4)), vld1q_f32(ptr_b.add(4)));
+        i += 8;
+    }
+    let dot =
+        vaddvq_f32(vaddq_f32(sum1, sum2)) + crate::metric::dot_product(&arr_a[n..], &arr_b[n..]);
+    1.0f32 - dot
+}
+
+// the length is divided out, as on x86, so that every kernel normalizes a
+// vector to exactly the same values
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn normalize_aarch(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    let n = arr_a.len() - arr_a.len() % 4;
+    let div: float32x4_t = vdupq_n_f32(length);
+    let mut i: usize = 0;
+    while i < n {
+        vst1q_f32(
+            out.as_mut_ptr().add(i),
+            vdivq_f32(vld1q_f32(arr_a.as_ptr().add(i)), div),
+        );
+        i += 4;
+    }
+    crate::metric::normalize(&arr_a[n..], length, &mut out[n..]);
+}
+
 #[cfg(all(target_feature = "neon",))]
 #[inline(always)]
 unsafe fn vpadalq(sum: uint64x2_t, t: uint8x16_t) -> uint64x2_t {
@@ -159,6 +225,85 @@ pub(crate) unsafe fn hamming_similarity_aarch(arr_a: &[f32], arr_b: &[f32]) -> f
     popcnt_neon_vvnt(std::slice::from_raw_parts(ptr, temp.len() * 4))
 }
 
+// the absolute difference of two bytes squares into a u16 without overflow,
+// as does the product of two bytes, vpadalq_u16 then folds pairs into u32s
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn l2_similarity_u8_aarch(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum: uint32x4_t = vdupq_n_u32(0);
+    let mut i: usize = 0;
+    while i < n {
+        let diff = vabdq_u8(
+            vld1q_u8(arr_a.as_ptr().add(i)),
+            vld1q_u8(arr_b.as_ptr().add(i)),
+        );
+        sum = vpadalq_u16(sum, vmull_u8(vget_low_u8(diff), vget_low_u8(diff)));
+        sum = vpadalq_u16(sum, vmull_high_u8(diff, diff));
+        i += 16;
+    }
+    vaddvq_u32(sum) + crate::metric::l2_similarity_u8(&arr_a[n..], &arr_b[n..])
+}
+
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn dot_product_u8_aarch(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum: uint32x4_t = vdupq_n_u32(0);
+    let mut i: usize = 0;
+    while i < n {
+        let a = vld1q_u8(arr_a.as_ptr().add(i));
+        let b = vld1q_u8(arr_b.as_ptr().add(i));
+        sum = vpadalq_u16(sum, vmull_u8(vget_low_u8(a), vget_low_u8(b)));
+        sum = vpadalq_u16(sum, vmull_high_u8(a, b));
+        i += 16;
+    }
+    vaddvq_u32(sum) + crate::metric::dot_product_u8(&arr_a[n..], &arr_b[n..])
+}
+
+// packed binary vectors are popcounted 128 bits at a time, vcntq_u8 counts
+// per byte and the 16 byte counts (<= 128) are summed across the lanes
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn hamming_distance_aarch(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 2;
+    let mut cnt: u32 = 0;
+    let mut i: usize = 0;
+    while i < n {
+        let x = veorq_u64(
+            vld1q_u64(arr_a.as_ptr().add(i)),
+            vld1q_u64(arr_b.as_ptr().add(i)),
+        );
+        cnt += vaddlvq_u8(vcntq_u8(vreinterpretq_u8_u64(x))) as u32;
+        i += 2;
+    }
+    for idx in n..arr_a.len() {
+        cnt += (arr_a[idx] ^ arr_b[idx]).count_ones();
+    }
+    cnt as f32
+}
+
+#[cfg(target_feature = "neon")]
+#[inline(always)]
+pub(crate) unsafe fn jaccard_distance_aarch(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 2;
+    let mut intersection: u32 = 0;
+    let mut union: u32 = 0;
+    let mut i: usize = 0;
+    while i < n {
+        let a = vld1q_u64(arr_a.as_ptr().add(i));
+        let b = vld1q_u64(arr_b.as_ptr().add(i));
+        intersection += vaddlvq_u8(vcntq_u8(vreinterpretq_u8_u64(vandq_u64(a, b)))) as u32;
+        union += vaddlvq_u8(vcntq_u8(vreinterpretq_u8_u64(vorrq_u64(a, b)))) as u32;
+        i += 2;
+    }
+    for idx in n..arr_a.len() {
+        intersection += (arr_a[idx] & arr_b[idx]).count_ones();
+        union += (arr_a[idx] | arr_b[idx]).count_ones();
+    }
+    crate::metric::jaccard_from_counts(intersection, union)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,6 +381,24 @@ mod tests {
             assert_eq!(l1, l1_simd);
         }
     }
+    #[test]
+    fn test_cosine() {
+        #[cfg(all(target_arch = "aarch64", target_feature = "neon",))]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            use crate::metric::{dot_product, normalize};
+            // 19 elements leave a tail for both kernels
+            let v1: Vec<f32> = (0..19).map(|x| x as f32 - 9.0).collect();
+            let v2: Vec<f32> = (0..19).map(|x| (x * 7 % 13) as f32).collect();
+            assert_eq!(1.0 - dot_product(&v1, &v2), unsafe {
+                cosine_similarity_aarch(&v1, &v2)
+            });
+            let mut expected = vec![0f32; v1.len()];
+            let mut normalized = vec![0f32; v1.len()];
+            normalize(&v1, 3.7, &mut expected);
+            unsafe { normalize_aarch(&v1, 3.7, &mut normalized) };
+            assert_eq!(expected, normalized);
+        }
+    }
 }
 
 #[cfg(all(target_feature = "neon",))]
@@ -1,7 +1,104 @@
 use core::arch::x86_64::*;
 
-#[cfg(all(target_feature = "fma", target_feature = "avx",))]
-#[inline(always)]
+use crate::metric::{
+    dot_product, dot_product_u8, jaccard_from_counts, l1_similarity, l2_similarity,
+    l2_similarity_u8, normalize,
+};
+
+// every kernel in here is compiled for the instruction set named in its
+// target_feature attribute regardless of the flags the crate is built with.
+// metric::Kernels only hands them out once the cpu we are running on has
+// been checked for that instruction set. the kernels read arr_b (and write
+// out) for as long as arr_a goes, so the safe wrappers at the bottom of each
+// section assert the lengths match - together that is what makes them
+// sound. loads are unaligned as query vectors do not necessarily come out
+// of an AlignedDataStore, and whatever does not fill a full register is
+// handed to the scalar code
+
+// AVX-512
+
+#[target_feature(enable = "avx512f")]
+unsafe fn l2_similarity_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm512_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm512_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec = _mm512_loadu_ps(arr_b.as_ptr().add(i));
+        let tmp_vec = _mm512_sub_ps(a_vec, b_vec);
+        sum = _mm512_fmadd_ps(tmp_vec, tmp_vec, sum);
+        i += 16;
+    }
+    _mm512_reduce_add_ps(sum) + l2_similarity(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "avx512f")]
+unsafe fn l1_similarity_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm512_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm512_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec = _mm512_loadu_ps(arr_b.as_ptr().add(i));
+        sum = _mm512_add_ps(_mm512_abs_ps(_mm512_sub_ps(a_vec, b_vec)), sum);
+        i += 16;
+    }
+    _mm512_reduce_add_ps(sum) + l1_similarity(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "avx512f")]
+unsafe fn dot_product_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm512_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm512_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec = _mm512_loadu_ps(arr_b.as_ptr().add(i));
+        sum = _mm512_fmadd_ps(a_vec, b_vec, sum);
+        i += 16;
+    }
+    _mm512_reduce_add_ps(sum) + dot_product(&arr_a[n..], &arr_b[n..])
+}
+
+// the length is divided out rather than multiplied in as a reciprocal, ieee
+// division is exact per lane so every level normalizes to the same vector
+#[target_feature(enable = "avx512f")]
+unsafe fn normalize_avx512(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let div = _mm512_set1_ps(length);
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm512_loadu_ps(arr_a.as_ptr().add(i));
+        _mm512_storeu_ps(out.as_mut_ptr().add(i), _mm512_div_ps(a_vec, div));
+        i += 16;
+    }
+    normalize(&arr_a[n..], length, &mut out[n..]);
+}
+
+pub(crate) fn l2_f32_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l2_similarity_avx512(arr_a, arr_b) }
+}
+pub(crate) fn l1_f32_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l1_similarity_avx512(arr_a, arr_b) }
+}
+pub(crate) fn dot_f32_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { dot_product_avx512(arr_a, arr_b) }
+}
+pub(crate) fn cosine_f32_avx512(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    1.0f32 - unsafe { dot_product_avx512(arr_a, arr_b) }
+}
+pub(crate) fn normalize_f32_avx512(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    assert_eq!(arr_a.len(), out.len());
+    unsafe { normalize_avx512(arr_a, length, out) }
+}
+
+// AVX2 + FMA
+
+#[target_feature(enable = "avx")]
 unsafe fn _mm256_reduce_add_ps(x: __m256) -> f32 {
     // this is fine since AVX is a superset of SSE - meaning we are guaranted
     // to have the SSE instructions available to us
@@ -11,76 +108,398 @@ unsafe fn _mm256_reduce_add_ps(x: __m256) -> f32 {
     _mm_cvtss_f32(x32)
 }
 
-#[cfg(all(target_feature = "fma", target_feature = "avx",))]
-#[inline(always)]
-pub(crate) unsafe fn l2_similarity_avx(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-    let result;
-    let niters = (arr_a.len() / 8) as isize;
+#[target_feature(enable = "avx2,fma")]
+unsafe fn l2_similarity_avx(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 8;
     let mut sum = _mm256_setzero_ps();
-    let ptr_a = arr_a.as_ptr() as *mut i8;
-    let ptr_a_f = arr_a.as_ptr();
-    let ptr_b = arr_b.as_ptr() as *mut i8;
-    let ptr_b_f = arr_b.as_ptr();
-
-    for j in 0..niters {
-        if j < (niters - 1) {
-            _mm_prefetch(ptr_a.offset(8 * (j + 1)), _MM_HINT_T0);
-            _mm_prefetch(ptr_b.offset(8 * (j + 1)), _MM_HINT_T0);
-        }
-        let a_vec: __m256 = _mm256_load_ps(ptr_a_f.offset(8 * j) as *mut f32);
-        let b_vec: __m256 = _mm256_load_ps(ptr_b_f.offset(8 * j) as *mut f32);
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec: __m256 = _mm256_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec: __m256 = _mm256_loadu_ps(arr_b.as_ptr().add(i));
         let tmp_vec: __m256 = _mm256_sub_ps(a_vec, b_vec);
         sum = _mm256_fmadd_ps(tmp_vec, tmp_vec, sum);
+        i += 8;
     }
-    result = self::_mm256_reduce_add_ps(sum);
-    result
+    _mm256_reduce_add_ps(sum) + l2_similarity(&arr_a[n..], &arr_b[n..])
 }
 
-#[cfg(all(target_feature = "fma", target_feature = "avx",))]
-#[inline(always)]
-pub(crate) unsafe fn l1_similarity_avx(arr_a: &[f32], arr_b: &[f32]) -> f32 {
-    let result;
-    let niters = (arr_a.len() / 8) as isize;
+#[target_feature(enable = "avx2,fma")]
+unsafe fn l1_similarity_avx(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    // clearing the sign bit is the cheapest abs(..) there is
+    let abs_mask = _mm256_castsi256_ps(_mm256_set1_epi32(0x7fff_ffff));
+    let n = arr_a.len() - arr_a.len() % 8;
     let mut sum = _mm256_setzero_ps();
-    let ptr_a = arr_a.as_ptr() as *mut i8;
-    let ptr_a_f = arr_a.as_ptr();
-    let ptr_b = arr_b.as_ptr() as *mut i8;
-    let ptr_b_f = arr_b.as_ptr();
-
-    for j in 0..niters {
-        if j < (niters - 1) {
-            _mm_prefetch(ptr_a.offset(8 * (j + 1)), _MM_HINT_T0);
-            _mm_prefetch(ptr_b.offset(8 * (j + 1)), _MM_HINT_T0);
-        }
-        let a_vec: __m256 = _mm256_load_ps(ptr_a_f.offset(8 * j) as *mut f32);
-        let b_vec: __m256 = _mm256_load_ps(ptr_b_f.offset(8 * j) as *mut f32);
-        let tmp_vec: __m256 = _mm256_sub_ps(a_vec, b_vec);
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec: __m256 = _mm256_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec: __m256 = _mm256_loadu_ps(arr_b.as_ptr().add(i));
+        let tmp_vec: __m256 = _mm256_and_ps(_mm256_sub_ps(a_vec, b_vec), abs_mask);
         sum = _mm256_add_ps(tmp_vec, sum);
+        i += 8;
+    }
+    _mm256_reduce_add_ps(sum) + l1_similarity(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_product_avx(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 8;
+    let mut sum = _mm256_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec: __m256 = _mm256_loadu_ps(arr_a.as_ptr().add(i));
+        let b_vec: __m256 = _mm256_loadu_ps(arr_b.as_ptr().add(i));
+        sum = _mm256_fmadd_ps(a_vec, b_vec, sum);
+        i += 8;
+    }
+    _mm256_reduce_add_ps(sum) + dot_product(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "avx2,fma")]
+unsafe fn normalize_avx(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    let n = arr_a.len() - arr_a.len() % 8;
+    let div = _mm256_set1_ps(length);
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec: __m256 = _mm256_loadu_ps(arr_a.as_ptr().add(i));
+        _mm256_storeu_ps(out.as_mut_ptr().add(i), _mm256_div_ps(a_vec, div));
+        i += 8;
+    }
+    normalize(&arr_a[n..], length, &mut out[n..]);
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn _mm256_reduce_add_epi32(x: __m256i) -> u32 {
+    let x128: __m128i = _mm_add_epi32(_mm256_extracti128_si256(x, 1), _mm256_castsi256_si128(x));
+    let x64: __m128i = _mm_add_epi32(x128, _mm_unpackhi_epi64(x128, x128));
+    let x32: __m128i = _mm_add_epi32(x64, _mm_shuffle_epi32(x64, 0x55));
+    _mm_cvtsi128_si32(x32) as u32
+}
+
+// u8 kernels widen 16 bytes at a time to i16 and let madd do the multiply
+// and the first pairwise add, each i32 lane gains at most 2 * 255^2 per
+// iteration so the accumulator does not overflow below ~250k dimensions
+#[target_feature(enable = "avx2")]
+unsafe fn l2_similarity_u8_avx2(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm256_setzero_si256();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm256_cvtepu8_epi16(_mm_loadu_si128(arr_a.as_ptr().add(i) as *const __m128i));
+        let b_vec = _mm256_cvtepu8_epi16(_mm_loadu_si128(arr_b.as_ptr().add(i) as *const __m128i));
+        let diff = _mm256_sub_epi16(a_vec, b_vec);
+        sum = _mm256_add_epi32(sum, _mm256_madd_epi16(diff, diff));
+        i += 16;
+    }
+    _mm256_reduce_add_epi32(sum) + l2_similarity_u8(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn dot_product_u8_avx2(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm256_setzero_si256();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm256_cvtepu8_epi16(_mm_loadu_si128(arr_a.as_ptr().add(i) as *const __m128i));
+        let b_vec = _mm256_cvtepu8_epi16(_mm_loadu_si128(arr_b.as_ptr().add(i) as *const __m128i));
+        sum = _mm256_add_epi32(sum, _mm256_madd_epi16(a_vec, b_vec));
+        i += 16;
+    }
+    _mm256_reduce_add_epi32(sum) + dot_product_u8(&arr_a[n..], &arr_b[n..])
+}
+
+pub(crate) fn l2_f32_avx2(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l2_similarity_avx(arr_a, arr_b) }
+}
+pub(crate) fn l1_f32_avx2(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l1_similarity_avx(arr_a, arr_b) }
+}
+pub(crate) fn dot_f32_avx2(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { dot_product_avx(arr_a, arr_b) }
+}
+pub(crate) fn cosine_f32_avx2(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    1.0f32 - unsafe { dot_product_avx(arr_a, arr_b) }
+}
+pub(crate) fn normalize_f32_avx2(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    assert_eq!(arr_a.len(), out.len());
+    unsafe { normalize_avx(arr_a, length, out) }
+}
+pub(crate) fn l2_u8_avx2(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l2_similarity_u8_avx2(arr_a, arr_b) }
+}
+pub(crate) fn dot_u8_avx2(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { dot_product_u8_avx2(arr_a, arr_b) }
+}
+
+// SSE - sse2 is part of the x86_64 baseline, this is what every host we
+// could possibly run on supports
+
+#[target_feature(enable = "sse2")]
+unsafe fn _mm_reduce_add_ps(x: __m128) -> f32 {
+    let x64: __m128 = _mm_add_ps(x, _mm_movehl_ps(x, x));
+    let x32: __m128 = _mm_add_ss(x64, _mm_shuffle_ps(x64, x64, 0x55));
+    _mm_cvtss_f32(x32)
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn _mm_reduce_add_epi32(x: __m128i) -> u32 {
+    let x64: __m128i = _mm_add_epi32(x, _mm_unpackhi_epi64(x, x));
+    let x32: __m128i = _mm_add_epi32(x64, _mm_shuffle_epi32(x64, 0x55));
+    _mm_cvtsi128_si32(x32) as u32
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn l2_similarity_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 4;
+    let mut sum = _mm_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let tmp_vec = _mm_sub_ps(
+            _mm_loadu_ps(arr_a.as_ptr().add(i)),
+            _mm_loadu_ps(arr_b.as_ptr().add(i)),
+        );
+        sum = _mm_add_ps(_mm_mul_ps(tmp_vec, tmp_vec), sum);
+        i += 4;
+    }
+    _mm_reduce_add_ps(sum) + l2_similarity(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn l1_similarity_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let abs_mask = _mm_castsi128_ps(_mm_set1_epi32(0x7fff_ffff));
+    let n = arr_a.len() - arr_a.len() % 4;
+    let mut sum = _mm_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let tmp_vec = _mm_sub_ps(
+            _mm_loadu_ps(arr_a.as_ptr().add(i)),
+            _mm_loadu_ps(arr_b.as_ptr().add(i)),
+        );
+        sum = _mm_add_ps(_mm_and_ps(tmp_vec, abs_mask), sum);
+        i += 4;
+    }
+    _mm_reduce_add_ps(sum) + l1_similarity(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn dot_product_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len() - arr_a.len() % 4;
+    let mut sum = _mm_setzero_ps();
+    let mut i: usize = 0;
+    while i < n {
+        let prod = _mm_mul_ps(
+            _mm_loadu_ps(arr_a.as_ptr().add(i)),
+            _mm_loadu_ps(arr_b.as_ptr().add(i)),
+        );
+        sum = _mm_add_ps(prod, sum);
+        i += 4;
+    }
+    _mm_reduce_add_ps(sum) + dot_product(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn normalize_sse(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    let n = arr_a.len() - arr_a.len() % 4;
+    let div = _mm_set1_ps(length);
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm_loadu_ps(arr_a.as_ptr().add(i));
+        _mm_storeu_ps(out.as_mut_ptr().add(i), _mm_div_ps(a_vec, div));
+        i += 4;
+    }
+    normalize(&arr_a[n..], length, &mut out[n..]);
+}
+
+// bytes are widened by interleaving them with zeros, then handled as with
+// avx2 above, 8 at a time
+#[target_feature(enable = "sse2")]
+unsafe fn l2_similarity_u8_sse(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let zero = _mm_setzero_si128();
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm_setzero_si128();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm_loadu_si128(arr_a.as_ptr().add(i) as *const __m128i);
+        let b_vec = _mm_loadu_si128(arr_b.as_ptr().add(i) as *const __m128i);
+        let diff_lo = _mm_sub_epi16(
+            _mm_unpacklo_epi8(a_vec, zero),
+            _mm_unpacklo_epi8(b_vec, zero),
+        );
+        let diff_hi = _mm_sub_epi16(
+            _mm_unpackhi_epi8(a_vec, zero),
+            _mm_unpackhi_epi8(b_vec, zero),
+        );
+        sum = _mm_add_epi32(sum, _mm_madd_epi16(diff_lo, diff_lo));
+        sum = _mm_add_epi32(sum, _mm_madd_epi16(diff_hi, diff_hi));
+        i += 16;
+    }
+    _mm_reduce_add_epi32(sum) + l2_similarity_u8(&arr_a[n..], &arr_b[n..])
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn dot_product_u8_sse(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let zero = _mm_setzero_si128();
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum = _mm_setzero_si128();
+    let mut i: usize = 0;
+    while i < n {
+        let a_vec = _mm_loadu_si128(arr_a.as_ptr().add(i) as *const __m128i);
+        let b_vec = _mm_loadu_si128(arr_b.as_ptr().add(i) as *const __m128i);
+        sum = _mm_add_epi32(
+            sum,
+            _mm_madd_epi16(
+                _mm_unpacklo_epi8(a_vec, zero),
+                _mm_unpacklo_epi8(b_vec, zero),
+            ),
+        );
+        sum = _mm_add_epi32(
+            sum,
+            _mm_madd_epi16(
+                _mm_unpackhi_epi8(a_vec, zero),
+                _mm_unpackhi_epi8(b_vec, zero),
+            ),
+        );
+        i += 16;
+    }
+    _mm_reduce_add_epi32(sum) + dot_product_u8(&arr_a[n..], &arr_b[n..])
+}
+
+pub(crate) fn l2_f32_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l2_similarity_sse(arr_a, arr_b) }
+}
+pub(crate) fn l1_f32_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l1_similarity_sse(arr_a, arr_b) }
+}
+pub(crate) fn dot_f32_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { dot_product_sse(arr_a, arr_b) }
+}
+pub(crate) fn cosine_f32_sse(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    1.0f32 - unsafe { dot_product_sse(arr_a, arr_b) }
+}
+pub(crate) fn normalize_f32_sse(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    assert_eq!(arr_a.len(), out.len());
+    unsafe { normalize_sse(arr_a, length, out) }
+}
+pub(crate) fn l2_u8_sse(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { l2_similarity_u8_sse(arr_a, arr_b) }
+}
+pub(crate) fn dot_u8_sse(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { dot_product_u8_sse(arr_a, arr_b) }
+}
+
+// POPCNT - detected on its own, it predates avx2 but is not part of the
+// x86_64 baseline
+
+#[target_feature(enable = "popcnt")]
+unsafe fn hamming_distance_popcnt(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let mut cnt: i64 = 0;
+    for (a, b) in arr_a.iter().zip(arr_b) {
+        cnt += _popcnt64((a ^ b) as i64) as i64;
+    }
+    cnt as f32
+}
+
+#[target_feature(enable = "popcnt")]
+unsafe fn jaccard_distance_popcnt(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    let mut intersection: i32 = 0;
+    let mut union: i32 = 0;
+    for (a, b) in arr_a.iter().zip(arr_b) {
+        intersection += _popcnt64((a & b) as i64);
+        union += _popcnt64((a | b) as i64);
     }
-    result = self::_mm256_reduce_add_ps(sum);
-    result
+    jaccard_from_counts(intersection as u32, union as u32)
+}
+
+pub(crate) fn hamming_u64_popcnt(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { hamming_distance_popcnt(arr_a, arr_b) }
+}
+pub(crate) fn jaccard_u64_popcnt(arr_a: &[u64], arr_b: &[u64]) -> f32 {
+    assert_eq!(arr_a.len(), arr_b.len());
+    unsafe { jaccard_distance_popcnt(arr_a, arr_b) }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    // small integers keep every partial sum exact, whatever the order the
+    // lanes get added up in. 37 elements leave a tail at every width
+    fn vectors() -> (Vec<f32>, Vec<f32>) {
+        let v1: Vec<f32> = (0..37).map(|x| (x % 11) as f32 - 5.0).collect();
+        let v2: Vec<f32> = (0..37).map(|x| (x * 7 % 13) as f32).collect();
+        (v1, v2)
+    }
+
     #[test]
     fn test_euclid() {
-        #[cfg(all(target_arch = "avx", target_feature = "fma",))]
-        if std::arch::is_x86_feature_detected!("fma") && std::arch::is_x86_feature_detected!("avx")
+        let (v1, v2) = vectors();
+        if std::arch::is_x86_feature_detected!("avx512f") {
+            assert_eq!(l2_similarity(&v1, &v2), l2_f32_avx512(&v1, &v2));
+            assert_eq!(l1_similarity(&v1, &v2), l1_f32_avx512(&v1, &v2));
+            assert_eq!(dot_product(&v1, &v2), dot_f32_avx512(&v1, &v2));
+        }
+        if std::arch::is_x86_feature_detected!("avx2") && std::arch::is_x86_feature_detected!("fma")
         {
-            let v1: Vec<f32> = vec![
-                10., 11., 12., 13., 14., 15., 16., 17., 18., 19., 20., 21., 22., 23., 24., 25.,
-            ];
-            let v2: Vec<f32> = vec![
-                40., 41., 42., 43., 44., 45., 46., 47., 48., 49., 50., 51., 52., 53., 54., 55.,
-            ];
-            let l2 = l2_similarity(&v1, &v2);
-            let l2_simd = unsafe { l2_similarity_avx(&v1, &v2) };
-            assert_eq!(l2, l2_simd);
-
-            let l1 = l1_similarity(&v1, &v2);
-            let l1_simd = unsafe { l1_similarity_avx(&v1, &v2) };
-            assert_eq!(l1, l1_simd);
+            assert_eq!(l2_similarity(&v1, &v2), l2_f32_avx2(&v1, &v2));
+            assert_eq!(l1_similarity(&v1, &v2), l1_f32_avx2(&v1, &v2));
+            assert_eq!(dot_product(&v1, &v2), dot_f32_avx2(&v1, &v2));
         }
+        assert_eq!(l2_similarity(&v1, &v2), l2_f32_sse(&v1, &v2));
+        assert_eq!(l1_similarity(&v1, &v2), l1_f32_sse(&v1, &v2));
+        assert_eq!(dot_product(&v1, &v2), dot_f32_sse(&v1, &v2));
+    }
+
+    #[test]
+    fn test_cosine() {
+        let (v1, v2) = vectors();
+        let mut expected = vec![0f32; v1.len()];
+        normalize(&v1, 3.7, &mut expected);
+        let mut out = vec![0f32; v1.len()];
+        if std::arch::is_x86_feature_detected!("avx512f") {
+            normalize_f32_avx512(&v1, 3.7, &mut out);
+            assert_eq!(expected, out);
+            assert_eq!(1.0 - dot_product(&v1, &v2), cosine_f32_avx512(&v1, &v2));
+        }
+        if std::arch::is_x86_feature_detected!("avx2") && std::arch::is_x86_feature_detected!("fma")
+        {
+            normalize_f32_avx2(&v1, 3.7, &mut out);
+            assert_eq!(expected, out);
+            assert_eq!(1.0 - dot_product(&v1, &v2), cosine_f32_avx2(&v1, &v2));
+        }
+        normalize_f32_sse(&v1, 3.7, &mut out);
+        assert_eq!(expected, out);
+        assert_eq!(1.0 - dot_product(&v1, &v2), cosine_f32_sse(&v1, &v2));
+    }
+
+    #[test]
+    fn test_u8() {
+        // not a multiple of 16 so the scalar tail is exercised too
+        let v1: Vec<u8> = (0..53u32).map(|x| (x * 13 % 256) as u8).collect();
+        let v2: Vec<u8> = (0..53u32).map(|x| (255 - x * 3 % 256) as u8).collect();
+        if std::arch::is_x86_feature_detected!("avx2") {
+            assert_eq!(l2_similarity_u8(&v1, &v2), l2_u8_avx2(&v1, &v2));
+            assert_eq!(dot_product_u8(&v1, &v2), dot_u8_avx2(&v1, &v2));
+        }
+        assert_eq!(l2_similarity_u8(&v1, &v2), l2_u8_sse(&v1, &v2));
+        assert_eq!(dot_product_u8(&v1, &v2), dot_u8_sse(&v1, &v2));
+    }
+
+    // a shorter arr_b would be read past its end
+    #[test]
+    #[should_panic]
+    fn test_length_mismatch() {
+        l2_f32_sse(&[0.0; 8], &[0.0; 4]);
     }
 }
@@ -1,4 +1,7 @@
-use crate::metric::{cosine_compare, cosine_pre_process, l1_similarity, l2_similarity};
+use crate::metric::{
+    cosine_compare, cosine_pre_process, dot_product, dot_product_u8, l1_similarity, l2_similarity,
+    l2_similarity_u8, normalize,
+};
 use core::arch::wasm32::*;
 use wasm_bindgen_test::*;
 
@@ -84,8 +87,103 @@ pub(crate) unsafe fn l1_similarity_wasm(arr_a: &[f32], arr_b: &[f32]) -> f32 {
     result
 }
 
-pub(crate) unsafe fn cosine_pre_process_wasm(arr_a: &[f32]) -> f32 {
-    unimplemented!()
+pub(crate) unsafe fn dot_product_wasm(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let n = arr_a.len();
+    let m: isize = (n).try_into().unwrap();
+    let mut sum1: core::arch::wasm::v128 = f32x4_splat(0.0f32);
+    let mut sum2: core::arch::wasm::v128 = f32x4_splat(0.0f32);
+    let mut sum3: core::arch::wasm::v128 = f32x4_splat(0.0f32);
+    let mut sum4: core::arch::wasm::v128 = f32x4_splat(0.0f32);
+    let mut ptr_a: *const v128 = arr_a.as_ptr() as *const v128;
+    let mut ptr_b: *const v128 = arr_b.as_ptr() as *const v128;
+    let mut i: isize = 0;
+    while i < m {
+        sum1 = f32x4_add(f32x4_mul(v128_load(ptr_a), v128_load(ptr_b)), sum1);
+        sum2 = f32x4_add(
+            f32x4_mul(v128_load(ptr_a.offset(1)), v128_load(ptr_b.offset(1))),
+            sum2,
+        );
+        sum3 = f32x4_add(
+            f32x4_mul(v128_load(ptr_a.offset(2)), v128_load(ptr_b.offset(2))),
+            sum3,
+        );
+        sum4 = f32x4_add(
+            f32x4_mul(v128_load(ptr_a.offset(3)), v128_load(ptr_b.offset(3))),
+            sum4,
+        );
+
+        ptr_a = ptr_a.offset(4);
+        ptr_b = ptr_b.offset(4);
+        i += 16
+    }
+    let lane_sum = f32x4_add(f32x4_add(sum1, sum2), f32x4_add(sum3, sum4));
+    let result = f32x4_extract_lane::<0>(lane_sum)
+        + f32x4_extract_lane::<1>(lane_sum)
+        + f32x4_extract_lane::<2>(lane_sum)
+        + f32x4_extract_lane::<3>(lane_sum);
+    result
+}
+
+// bytes are widened to i16 lanes and i32x4_dot_i16x8 does the multiply and
+// the pairwise add in one go
+#[cfg(all(target_feature = "simd128",))]
+pub(crate) unsafe fn l2_similarity_u8_wasm(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum: v128 = i32x4_splat(0);
+    let mut i: usize = 0;
+    while i < n {
+        let a = v128_load(arr_a.as_ptr().add(i) as *const v128);
+        let b = v128_load(arr_b.as_ptr().add(i) as *const v128);
+        let diff_lo = i16x8_sub(u16x8_extend_low_u8x16(a), u16x8_extend_low_u8x16(b));
+        let diff_hi = i16x8_sub(u16x8_extend_high_u8x16(a), u16x8_extend_high_u8x16(b));
+        sum = i32x4_add(sum, i32x4_dot_i16x8(diff_lo, diff_lo));
+        sum = i32x4_add(sum, i32x4_dot_i16x8(diff_hi, diff_hi));
+        i += 16;
+    }
+    let result = (i32x4_extract_lane::<0>(sum)
+        + i32x4_extract_lane::<1>(sum)
+        + i32x4_extract_lane::<2>(sum)
+        + i32x4_extract_lane::<3>(sum)) as u32
+        + l2_similarity_u8(&arr_a[n..], &arr_b[n..]);
+    result
+}
+
+#[cfg(all(target_feature = "simd128",))]
+pub(crate) unsafe fn dot_product_u8_wasm(arr_a: &[u8], arr_b: &[u8]) -> u32 {
+    let n = arr_a.len() - arr_a.len() % 16;
+    let mut sum: v128 = i32x4_splat(0);
+    let mut i: usize = 0;
+    while i < n {
+        let a = v128_load(arr_a.as_ptr().add(i) as *const v128);
+        let b = v128_load(arr_b.as_ptr().add(i) as *const v128);
+        sum = i32x4_add(
+            sum,
+            i32x4_dot_i16x8(u16x8_extend_low_u8x16(a), u16x8_extend_low_u8x16(b)),
+        );
+        sum = i32x4_add(
+            sum,
+            i32x4_dot_i16x8(u16x8_extend_high_u8x16(a), u16x8_extend_high_u8x16(b)),
+        );
+        i += 16;
+    }
+    (i32x4_extract_lane::<0>(sum)
+        + i32x4_extract_lane::<1>(sum)
+        + i32x4_extract_lane::<2>(sum)
+        + i32x4_extract_lane::<3>(sum)) as u32
+        + dot_product_u8(&arr_a[n..], &arr_b[n..])
+}
+
+#[cfg(all(target_feature = "simd128",))]
+pub(crate) unsafe fn normalize_wasm(arr_a: &[f32], length: f32, out: &mut [f32]) {
+    let n = arr_a.len() - arr_a.len() % 4;
+    let div: v128 = f32x4_splat(length);
+    let mut i: usize = 0;
+    while i < n {
+        let a = v128_load(arr_a.as_ptr().add(i) as *const v128);
+        v128_store(out.as_mut_ptr().add(i) as *mut v128, f32x4_div(a, div));
+        i += 4;
+    }
+    normalize(&arr_a[n..], length, &mut out[n..]);
 }
 pub(crate) unsafe fn cosine_similarity_wasm(arr_a: &[f32], arr_b: &[f32]) -> f32 {
     let n = arr_a.len();
@@ -154,5 +252,15 @@ mod tests {
         assert_eq!(cosine_compare(&v1_preprocess, &v2_preprocess), unsafe {
             cosine_similarity_wasm(&v1_preprocess, &v2_preprocess)
         });
+        assert_eq!(dot_product(&v1, &v2), unsafe { dot_product_wasm(&v1, &v2) });
+
+        let u1: Vec<u8> = (0..53u32).map(|x| (x * 13 % 256) as u8).collect();
+        let u2: Vec<u8> = (0..53u32).map(|x| (255 - x * 3 % 256) as u8).collect();
+        assert_eq!(l2_similarity_u8(&u1, &u2), unsafe {
+            l2_similarity_u8_wasm(&u1, &u2)
+        });
+        assert_eq!(dot_product_u8(&u1, &u2), unsafe {
+            dot_product_u8_wasm(&u1, &u2)
+        });
     }
 }
@@ -23,6 +23,9 @@ pub struct InMemoryQueryScratch {
 
 impl InMemoryQueryScratch {
     pub fn new(params: &DiskANNParamsInternal) -> Self {
+        Self::with_search_list_size(params, params.params_e.indexing_queue_size)
+    }
+    pub fn with_search_list_size(params: &DiskANNParamsInternal, search_list_size: usize) -> Self {
         InMemoryQueryScratch {
             q_aligned: AlignedDataStore::new(params.aligned_dim, 1),
             occlude_factor: Vec::with_capacity(params.params_e.indexing_maxc),
@@ -34,10 +37,8 @@ impl InMemoryQueryScratch {
             dist_scratch: Vec::with_capacity(
                 (1.5 * (params.params_e.indexing_range as f32) * 1.05).ceil() as usize,
             ),
-            best_l_nodes: NNPriorityQueue::new(params.params_e.indexing_queue_size),
-            pool: Vec::with_capacity(
-                3 * params.params_e.indexing_queue_size + params.params_e.indexing_range,
-            ),
+            best_l_nodes: NNPriorityQueue::new(search_list_size),
+            pool: Vec::with_capacity(3 * search_list_size + params.params_e.indexing_range),
             // curr_l: params.params_e.indexing_queue_size,
             // curr_r: params.params_e.indexing_range,
         }
@@ -31,6 +31,9 @@ impl NNPriorityQueue {
             ],
         }
     }
+    pub fn len(&self) -> usize {
+        self.size
+    }
     pub fn has_unexpanded_node(&self) -> bool {
         return self.curr < self.size;
     }
@@ -0,0 +1,266 @@
+use crate::ann;
+use crate::ann::EId;
+use crate::metric;
+
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// every file we write starts with a 4 byte magic tag for the structure it
+// holds followed by a u32 version - bump the version whenever the layout
+// of the file changes so older builds refuse to read it
+pub(crate) fn write_header(
+    w: &mut impl Write,
+    magic: &[u8; 4],
+    version: u32,
+) -> anyhow::Result<()> {
+    w.write_all(magic)?;
+    w.write_u32::<LittleEndian>(version)?;
+    Ok(())
+}
+
+pub(crate) fn read_header(
+    r: &mut impl Read,
+    magic: &[u8; 4],
+    max_version: u32,
+) -> anyhow::Result<u32> {
+    let mut found = [0u8; 4];
+    r.read_exact(&mut found)?;
+    if &found != magic {
+        bail!(
+            "unexpected magic: {:?}, expected: {:?}",
+            String::from_utf8_lossy(&found),
+            String::from_utf8_lossy(magic)
+        );
+    }
+    let version = r.read_u32::<LittleEndian>()?;
+    if version == 0 || version > max_version {
+        bail!(
+            "unsupported version: {} (this build reads <= {})",
+            version,
+            max_version
+        );
+    }
+    Ok(version)
+}
+
+pub(crate) fn write_usize(w: &mut impl Write, val: usize) -> anyhow::Result<()> {
+    w.write_u64::<LittleEndian>(val as u64)?;
+    Ok(())
+}
+
+pub(crate) fn read_usize(r: &mut impl Read) -> anyhow::Result<usize> {
+    Ok(r.read_u64::<LittleEndian>()?.try_into()?)
+}
+
+pub(crate) fn write_str(w: &mut impl Write, val: &str) -> anyhow::Result<()> {
+    write_usize(w, val.len())?;
+    w.write_all(val.as_bytes())?;
+    Ok(())
+}
+
+// lengths read back from a file are untrusted, a corrupt or truncated file
+// must fail with an error rather than have us allocate whatever it claims.
+// strings only ever hold names so they are capped outright, element counts
+// only reserve up to MAX_PREALLOC and grow as the entries are actually read
+const MAX_STR_LEN: usize = 1 << 16;
+const MAX_PREALLOC: usize = 1 << 16;
+
+pub(crate) fn capacity_hint(len: usize) -> usize {
+    len.min(MAX_PREALLOC)
+}
+
+pub(crate) fn read_str(r: &mut impl Read) -> anyhow::Result<String> {
+    let len = read_usize(r)?;
+    if len > MAX_STR_LEN {
+        bail!("string of len: {} exceeds max: {}", len, MAX_STR_LEN);
+    }
+    let mut buf = Vec::with_capacity(len);
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        bail!("string truncated at: {} of len: {}", buf.len(), len);
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+// element types and metrics are recorded under their numeric tags, see
+// ann::ElementType and metric::MetricType. files from before those tags
+// (legacy) carry the rust type names instead, which are compared as is
+pub(crate) fn write_element_type<T: ann::ElementVal>(w: &mut impl Write) -> anyhow::Result<()> {
+    w.write_u32::<LittleEndian>(T::ELEMENT_TYPE as u32)?;
+    Ok(())
+}
+
+pub(crate) fn check_element_type<T: ann::ElementVal>(
+    r: &mut impl Read,
+    legacy: bool,
+) -> anyhow::Result<()> {
+    if legacy {
+        let val_type = read_str(r)?;
+        if val_type != std::any::type_name::<T>() {
+            bail!(
+                "index was saved with element type: {}, not: {}",
+                val_type,
+                std::any::type_name::<T>()
+            );
+        }
+        return Ok(());
+    }
+    let element = ann::ElementType::from_u32(r.read_u32::<LittleEndian>()?)?;
+    if element != T::ELEMENT_TYPE {
+        bail!(
+            "index was saved with element type: {:?}, not: {:?}",
+            element,
+            T::ELEMENT_TYPE
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn write_metric_type<M: metric::MetricKind>(w: &mut impl Write) -> anyhow::Result<()> {
+    w.write_u32::<LittleEndian>(M::METRIC_TYPE as u32)?;
+    Ok(())
+}
+
+pub(crate) fn check_metric_type<M: metric::MetricKind>(
+    r: &mut impl Read,
+    legacy: bool,
+) -> anyhow::Result<()> {
+    if legacy {
+        let metric_type = read_str(r)?;
+        if metric_type != std::any::type_name::<M>() {
+            bail!(
+                "index was saved with metric: {}, not: {}",
+                metric_type,
+                std::any::type_name::<M>()
+            );
+        }
+        return Ok(());
+    }
+    let metric = metric::MetricType::from_u32(r.read_u32::<LittleEndian>()?)?;
+    if metric != M::METRIC_TYPE {
+        bail!(
+            "index was saved with metric: {:?}, not: {:?}",
+            metric,
+            M::METRIC_TYPE
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn write_vids<'a>(
+    w: &mut impl Write,
+    len: usize,
+    vids: impl IntoIterator<Item = &'a usize>,
+) -> anyhow::Result<()> {
+    write_usize(w, len)?;
+    for vid in vids {
+        write_usize(w, *vid)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_vids(r: &mut impl Read) -> anyhow::Result<Vec<usize>> {
+    let len = read_usize(r)?;
+    let mut vids = Vec::with_capacity(capacity_hint(len));
+    for _ in 0..len {
+        vids.push(read_usize(r)?);
+    }
+    Ok(vids)
+}
+
+pub(crate) fn write_eid(w: &mut impl Write, eid: &EId) -> anyhow::Result<()> {
+    w.write_all(eid)?;
+    Ok(())
+}
+
+pub(crate) fn read_eid(r: &mut impl Read) -> anyhow::Result<EId> {
+    let mut eid: EId = [0u8; 16];
+    r.read_exact(&mut eid)?;
+    Ok(eid)
+}
+
+// vectors are dumped as their raw in-memory representation, this keeps the
+// files directly mappable but means they are only portable between hosts
+// sharing the same endianness
+pub(crate) fn write_vals<T: ann::ElementVal>(w: &mut impl Write, vals: &[T]) -> anyhow::Result<()> {
+    let bytes: &[u8] = unsafe {
+        std::slice::from_raw_parts(vals.as_ptr() as *const u8, std::mem::size_of_val(vals))
+    };
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+pub(crate) fn read_vals<T: ann::ElementVal>(
+    r: &mut impl Read,
+    vals: &mut [T],
+) -> anyhow::Result<()> {
+    let bytes: &mut [u8] = unsafe {
+        std::slice::from_raw_parts_mut(vals.as_mut_ptr() as *mut u8, std::mem::size_of_val(vals))
+    };
+    r.read_exact(bytes)?;
+    Ok(())
+}
+
+// writes go to a temporary sibling which is renamed over the target once
+// everything has been flushed, so a crash never leaves a torn file behind
+pub(crate) fn write_file(
+    path: &Path,
+    f: impl FnOnce(&mut BufWriter<fs::File>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    {
+        let mut w = BufWriter::new(fs::File::create(&tmp_path)?);
+        f(&mut w)?;
+        w.flush()?;
+        w.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub(crate) fn open_file(path: &Path) -> anyhow::Result<BufReader<fs::File>> {
+    match fs::File::open(path) {
+        Ok(f) => Ok(BufReader::new(f)),
+        Err(err) => bail!("unable to open: {:?} - {}", path, err),
+    }
+}
+
+pub(crate) fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untrusted_lengths() {
+        let mut buf = Vec::new();
+        write_str(&mut buf, "name").unwrap();
+        write_vids(&mut buf, 3, &[1, 2, 3]).unwrap();
+        let mut r = buf.as_slice();
+        assert_eq!(read_str(&mut r).unwrap(), "name");
+        assert_eq!(read_vids(&mut r).unwrap(), vec![1, 2, 3]);
+
+        // a huge length over a short buffer must error, not allocate
+        let mut buf = Vec::new();
+        write_usize(&mut buf, usize::MAX >> 1).unwrap();
+        buf.extend_from_slice(b"name");
+        assert!(read_str(&mut buf.as_slice()).is_err());
+        assert!(read_vids(&mut buf.as_slice()).is_err());
+
+        // a length within bounds but past the end of the data
+        let mut buf = Vec::new();
+        write_usize(&mut buf, 16).unwrap();
+        buf.extend_from_slice(b"name");
+        assert!(read_str(&mut buf.as_slice()).is_err());
+    }
+}
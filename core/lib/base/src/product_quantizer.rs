@@ -0,0 +1,558 @@
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use rand::Rng;
+use rayon::prelude::*;
+use std::io::{Read, Write};
+
+use crate::ann;
+use crate::metric;
+use crate::persist;
+
+const PQ_MAGIC: &[u8; 4] = b"APQZ";
+const PQ_VERSION: u32 = 1;
+// lloyd iterations run per subspace while training the codebooks
+const KMEANS_ITERATIONS: usize = 15;
+// upper bound on the number of vectors we train the codebooks on, larger
+// batches are sub-sampled down to this
+const MAX_TRAINING_POINTS: usize = 65_536;
+
+// num_subspaces * bits / 8 is the size of a single code, ie. a 768 dim f32
+// vector (3072 bytes) with 96 subspaces @ 8 bits is stored in 96 bytes (32x)
+// and with 192 subspaces @ 8 bits in 192 bytes (16x)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PQParams {
+    pub num_subspaces: usize, // M
+    pub bits: usize,          // 2^bits centroids per subspace, 1 <= bits <= 8
+}
+
+impl PQParams {
+    pub fn code_len(&self) -> usize {
+        (self.num_subspaces * self.bits + 7) / 8
+    }
+
+    pub(crate) fn validate(&self, dim: usize) -> anyhow::Result<()> {
+        if self.bits == 0 || self.bits > 8 {
+            bail!("pq bits: {} must be within [1, 8]", self.bits);
+        }
+        if self.num_subspaces == 0 || self.num_subspaces > dim {
+            bail!(
+                "pq num_subspaces: {} must be within [1, dim: {}]",
+                self.num_subspaces,
+                dim
+            );
+        }
+        Ok(())
+    }
+}
+
+// the codebooks of an index are trained on the first batch inserted into it,
+// which has to hold enough vectors for k-means to place every centroid
+pub(crate) fn check_first_batch(
+    params: &PQParams,
+    trained: bool,
+    num_vectors: usize,
+) -> anyhow::Result<()> {
+    let num_centroids = 1usize << params.bits;
+    if !trained && num_vectors < num_centroids {
+        bail!(
+            "the pq codebooks are trained on the first insert, which needs at least 2^bits: {} vectors - got: {}",
+            num_centroids,
+            num_vectors
+        );
+    }
+    Ok(())
+}
+
+// how a metric decomposes over the subspaces of the quantizer - the distance
+// between a query and a code is the sum of the per subspace distances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PQDistance {
+    L2,
+    L1,
+    // 1 - <a, b>, the vectors are expected to be normalized already
+    Cosine,
+    // -<a, b>
+    InnerProduct,
+}
+
+impl PQDistance {
+    fn to_u8(self) -> u8 {
+        match self {
+            PQDistance::L2 => 0,
+            PQDistance::L1 => 1,
+            PQDistance::Cosine => 2,
+            PQDistance::InnerProduct => 3,
+        }
+    }
+
+    fn from_u8(val: u8) -> anyhow::Result<PQDistance> {
+        match val {
+            0 => Ok(PQDistance::L2),
+            1 => Ok(PQDistance::L1),
+            2 => Ok(PQDistance::Cosine),
+            3 => Ok(PQDistance::InnerProduct),
+            _ => bail!("unknown pq distance: {}", val),
+        }
+    }
+}
+
+// the decomposition an index built over TMetric should use, indexes call
+// this up front so that unsupported metrics are refused at construction
+pub(crate) fn distance_for<TVal, TMetric: metric::Metric<TVal>>() -> anyhow::Result<PQDistance> {
+    match TMetric::pq_distance() {
+        Some(distance) => Ok(distance),
+        None => bail!(
+            "metric: {} cannot be used with product quantization",
+            std::any::type_name::<TMetric>()
+        ),
+    }
+}
+
+// codebooks are always trained and queried in f32
+pub(crate) fn to_f32<TVal: ann::ElementVal>(vals: &[TVal]) -> Vec<f32> {
+    vals.iter()
+        .map(|x| x.to_f32().expect("unable to coerce to f32"))
+        .collect()
+}
+
+fn l2_partial(arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    arr_a
+        .iter()
+        .zip(arr_b.iter())
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum()
+}
+
+// per-query lookup table for asymmetric distance computation (ADC): the
+// query stays at full precision and its distance to every centroid of every
+// subspace is computed once, after which scoring a code is M table lookups
+#[derive(Debug)]
+pub struct DistanceTable {
+    num_centroids: usize,
+    num_subspaces: usize,
+    bits: usize,
+    bias: f32,
+    table: Vec<f32>,
+}
+
+impl DistanceTable {
+    #[inline(always)]
+    pub fn distance(&self, code: &[u8]) -> f32 {
+        let mut dist = self.bias;
+        if self.bits == 8 {
+            for (i, c) in code[..self.num_subspaces].iter().enumerate() {
+                dist += self.table[i * self.num_centroids + *c as usize];
+            }
+        } else {
+            for i in 0..self.num_subspaces {
+                dist += self.table[i * self.num_centroids + unpack(code, i, self.bits)];
+            }
+        }
+        dist
+    }
+}
+
+#[inline(always)]
+fn unpack(code: &[u8], idx: usize, bits: usize) -> usize {
+    let bit_s = idx * bits;
+    let mut val: usize = code[bit_s / 8] as usize >> (bit_s % 8);
+    if bit_s % 8 + bits > 8 {
+        val |= (code[bit_s / 8 + 1] as usize) << (8 - bit_s % 8);
+    }
+    val & ((1 << bits) - 1)
+}
+
+#[inline(always)]
+fn pack(code: &mut [u8], idx: usize, bits: usize, val: usize) {
+    let bit_s = idx * bits;
+    code[bit_s / 8] |= (val << (bit_s % 8)) as u8;
+    if bit_s % 8 + bits > 8 {
+        code[bit_s / 8 + 1] |= (val >> (8 - bit_s % 8)) as u8;
+    }
+}
+
+#[derive(Debug)]
+pub struct ProductQuantizer {
+    dim: usize,
+    params: PQParams,
+    distance: PQDistance,
+    num_centroids: usize,
+    // subspace i covers dims [bounds[i], bounds[i+1]), the first dim % M
+    // subspaces pick up one extra dim when M does not divide dim
+    bounds: Vec<usize>,
+    // the centroids of subspace i live at [bounds[i] * K, bounds[i+1] * K)
+    codebooks: Vec<f32>,
+}
+
+impl ProductQuantizer {
+    fn subspace_bounds(dim: usize, num_subspaces: usize) -> Vec<usize> {
+        let mut bounds = Vec::with_capacity(num_subspaces + 1);
+        bounds.push(0);
+        for i in 0..num_subspaces {
+            let sub_dim = dim / num_subspaces + (i < dim % num_subspaces) as usize;
+            bounds.push(bounds[i] + sub_dim);
+        }
+        bounds
+    }
+
+    // trains the codebooks with k-means on the supplied vectors, every
+    // subspace is clustered independently (and in parallel)
+    pub fn train(
+        params: &PQParams,
+        dim: usize,
+        distance: PQDistance,
+        vectors: &[f32],
+    ) -> anyhow::Result<ProductQuantizer> {
+        params.validate(dim)?;
+        if vectors.is_empty() || vectors.len() % dim != 0 {
+            bail!(
+                "training set of len: {} is not a multiple of dim: {}",
+                vectors.len(),
+                dim
+            );
+        }
+        let num_centroids = 1usize << params.bits;
+        // fewer points than centroids leaves most of every codebook as
+        // copies of the same few points
+        if vectors.len() / dim < num_centroids {
+            bail!(
+                "training the codebooks takes at least 2^bits: {} vectors - got: {}",
+                num_centroids,
+                vectors.len() / dim
+            );
+        }
+        let bounds = ProductQuantizer::subspace_bounds(dim, params.num_subspaces);
+        let mut rng = thread_rng();
+        let mut sample: Vec<usize> = (0..vectors.len() / dim).collect();
+        if sample.len() > MAX_TRAINING_POINTS {
+            sample.shuffle(&mut rng);
+            sample.truncate(MAX_TRAINING_POINTS);
+        }
+
+        let codebooks: Vec<Vec<f32>> = (0..params.num_subspaces)
+            .into_par_iter()
+            .map(|i| {
+                let (s, e) = (bounds[i], bounds[i + 1]);
+                let points: Vec<f32> = sample
+                    .iter()
+                    .flat_map(|vid| vectors[vid * dim + s..vid * dim + e].iter().copied())
+                    .collect();
+                ProductQuantizer::kmeans(&points, e - s, num_centroids)
+            })
+            .collect();
+        Ok(ProductQuantizer {
+            dim,
+            params: *params,
+            distance,
+            num_centroids,
+            bounds,
+            codebooks: codebooks.concat(),
+        })
+    }
+
+    fn kmeans(points: &[f32], sub_dim: usize, num_centroids: usize) -> Vec<f32> {
+        let num_points = points.len() / sub_dim;
+        let mut rng = thread_rng();
+        // seed with distinct points where we can, small training sets end
+        // up with duplicate centroids which simply never get assigned
+        let mut seeds: Vec<usize> = (0..num_points).collect();
+        seeds.shuffle(&mut rng);
+        let mut centroids: Vec<f32> = Vec::with_capacity(num_centroids * sub_dim);
+        for c in 0..num_centroids {
+            let p = seeds[c % num_points];
+            centroids.extend_from_slice(&points[p * sub_dim..p * sub_dim + sub_dim]);
+        }
+
+        let mut assignments: Vec<usize> = vec![0; num_points];
+        let mut sums: Vec<f32> = vec![0.0; num_centroids * sub_dim];
+        let mut counts: Vec<usize> = vec![0; num_centroids];
+        for _ in 0..KMEANS_ITERATIONS {
+            assignments.par_iter_mut().enumerate().for_each(|(p, a)| {
+                *a = ProductQuantizer::nearest(
+                    &centroids,
+                    &points[p * sub_dim..p * sub_dim + sub_dim],
+                    sub_dim,
+                );
+            });
+            sums.iter_mut().for_each(|x| *x = 0.0);
+            counts.iter_mut().for_each(|x| *x = 0);
+            for (p, c) in assignments.iter().enumerate() {
+                counts[*c] += 1;
+                for j in 0..sub_dim {
+                    sums[c * sub_dim + j] += points[p * sub_dim + j];
+                }
+            }
+            for c in 0..num_centroids {
+                if counts[c] == 0 {
+                    // re-seed empty clusters at a random point
+                    let p = rng.gen_range(0..num_points);
+                    centroids[c * sub_dim..c * sub_dim + sub_dim]
+                        .copy_from_slice(&points[p * sub_dim..p * sub_dim + sub_dim]);
+                    continue;
+                }
+                for j in 0..sub_dim {
+                    centroids[c * sub_dim + j] = sums[c * sub_dim + j] / counts[c] as f32;
+                }
+            }
+        }
+        centroids
+    }
+
+    #[inline(always)]
+    fn nearest(centroids: &[f32], point: &[f32], sub_dim: usize) -> usize {
+        let mut best: usize = 0;
+        let mut best_dist: f32 = f32::MAX;
+        for (c, centroid) in centroids.chunks_exact(sub_dim).enumerate() {
+            let dist = l2_partial(centroid, point);
+            if dist < best_dist {
+                best = c;
+                best_dist = dist;
+            }
+        }
+        best
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.params.code_len()
+    }
+
+    pub fn params(&self) -> &PQParams {
+        &self.params
+    }
+
+    fn codebook(&self, subspace: usize) -> &[f32] {
+        &self.codebooks[self.bounds[subspace] * self.num_centroids
+            ..self.bounds[subspace + 1] * self.num_centroids]
+    }
+
+    // writes the code of vec into code, which must be code_len() bytes long
+    pub fn encode(&self, vec: &[f32], code: &mut [u8]) {
+        debug_assert!(vec.len() == self.dim && code.len() == self.code_len());
+        code.iter_mut().for_each(|x| *x = 0);
+        for i in 0..self.params.num_subspaces {
+            let (s, e) = (self.bounds[i], self.bounds[i + 1]);
+            let c = ProductQuantizer::nearest(self.codebook(i), &vec[s..e], e - s);
+            pack(code, i, self.params.bits, c);
+        }
+    }
+
+    // reconstructs the approximation of the vector a code stands for
+    pub fn decode(&self, code: &[u8], vec: &mut [f32]) {
+        for i in 0..self.params.num_subspaces {
+            let (s, e) = (self.bounds[i], self.bounds[i + 1]);
+            let c = unpack(code, i, self.params.bits);
+            vec[s..e].copy_from_slice(&self.codebook(i)[c * (e - s)..(c + 1) * (e - s)]);
+        }
+    }
+
+    pub fn distance_table(&self, query: &[f32]) -> DistanceTable {
+        let mut table: Vec<f32> = vec![0.0; self.params.num_subspaces * self.num_centroids];
+        for i in 0..self.params.num_subspaces {
+            let (s, e) = (self.bounds[i], self.bounds[i + 1]);
+            let q = &query[s..e];
+            for (c, centroid) in self.codebook(i).chunks_exact(e - s).enumerate() {
+                table[i * self.num_centroids + c] = match self.distance {
+                    PQDistance::L2 => l2_partial(q, centroid),
+                    PQDistance::L1 => q.iter().zip(centroid).map(|(a, b)| (a - b).abs()).sum(),
+                    PQDistance::Cosine | PQDistance::InnerProduct => {
+                        -q.iter().zip(centroid).map(|(a, b)| a * b).sum::<f32>()
+                    }
+                };
+            }
+        }
+        DistanceTable {
+            num_centroids: self.num_centroids,
+            num_subspaces: self.params.num_subspaces,
+            bits: self.params.bits,
+            bias: if self.distance == PQDistance::Cosine {
+                1.0
+            } else {
+                0.0
+            },
+            table,
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        persist::write_header(w, PQ_MAGIC, PQ_VERSION)?;
+        persist::write_usize(w, self.dim)?;
+        persist::write_usize(w, self.params.num_subspaces)?;
+        persist::write_usize(w, self.params.bits)?;
+        w.write_u8(self.distance.to_u8())?;
+        persist::write_usize(w, self.codebooks.len())?;
+        for val in self.codebooks.iter() {
+            w.write_f32::<LittleEndian>(*val)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load(r: &mut impl Read) -> anyhow::Result<ProductQuantizer> {
+        persist::read_header(r, PQ_MAGIC, PQ_VERSION)?;
+        let dim = persist::read_usize(r)?;
+        let params = PQParams {
+            num_subspaces: persist::read_usize(r)?,
+            bits: persist::read_usize(r)?,
+        };
+        params.validate(dim)?;
+        let distance = PQDistance::from_u8(r.read_u8()?)?;
+        let num_centroids = 1usize << params.bits;
+        let len = persist::read_usize(r)?;
+        if len != dim * num_centroids {
+            bail!(
+                "codebooks of len: {} do not match dim: {} * centroids: {}",
+                len,
+                dim,
+                num_centroids
+            );
+        }
+        let mut codebooks: Vec<f32> = Vec::with_capacity(len);
+        for _ in 0..len {
+            codebooks.push(r.read_f32::<LittleEndian>()?);
+        }
+        Ok(ProductQuantizer {
+            dim,
+            params,
+            distance,
+            num_centroids,
+            bounds: ProductQuantizer::subspace_bounds(dim, params.num_subspaces),
+            codebooks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::{Distribution, Uniform};
+
+    fn random_vectors(num_vectors: usize, dim: usize) -> Vec<f32> {
+        let mut rng = thread_rng();
+        Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(num_vectors * dim)
+            .collect()
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        for bits in 1..=8 {
+            let num_subspaces = 13;
+            let mut code = vec![0u8; (num_subspaces * bits + 7) / 8];
+            let vals: Vec<usize> = (0..num_subspaces).map(|i| (i * 7) % (1 << bits)).collect();
+            vals.iter()
+                .enumerate()
+                .for_each(|(i, val)| pack(&mut code, i, bits, *val));
+            for (i, val) in vals.iter().enumerate() {
+                assert_eq!(*val, unpack(&code, i, bits));
+            }
+        }
+    }
+
+    #[test]
+    fn adc_matches_decoded_distance() {
+        let dim = 48;
+        let vectors = random_vectors(500, dim);
+        for (distance, bits) in [
+            (PQDistance::L2, 8),
+            (PQDistance::L1, 4),
+            (PQDistance::Cosine, 5),
+            (PQDistance::InnerProduct, 8),
+        ] {
+            let params = PQParams {
+                num_subspaces: 10,
+                bits,
+            };
+            let pq = ProductQuantizer::train(&params, dim, distance, &vectors)
+                .expect("unable to train the quantizer");
+            let query = &vectors[0..dim];
+            let table = pq.distance_table(query);
+            let mut code = vec![0u8; pq.code_len()];
+            let mut decoded = vec![0.0f32; dim];
+            for vid in 0..20 {
+                pq.encode(&vectors[vid * dim..vid * dim + dim], &mut code);
+                pq.decode(&code, &mut decoded);
+                let expected: f32 = match distance {
+                    PQDistance::L2 => l2_partial(query, &decoded),
+                    PQDistance::L1 => query.iter().zip(&decoded).map(|(a, b)| (a - b).abs()).sum(),
+                    PQDistance::Cosine => {
+                        1.0 - query.iter().zip(&decoded).map(|(a, b)| a * b).sum::<f32>()
+                    }
+                    PQDistance::InnerProduct => {
+                        -query.iter().zip(&decoded).map(|(a, b)| a * b).sum::<f32>()
+                    }
+                };
+                assert!((expected - table.distance(&code)).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn quantization_error_shrinks_with_bits() {
+        let dim = 32;
+        let vectors = random_vectors(2000, dim);
+        let error = |bits: usize| -> f32 {
+            let params = PQParams {
+                num_subspaces: 8,
+                bits,
+            };
+            let pq = ProductQuantizer::train(&params, dim, PQDistance::L2, &vectors)
+                .expect("unable to train the quantizer");
+            let mut code = vec![0u8; pq.code_len()];
+            let mut decoded = vec![0.0f32; dim];
+            (0..200)
+                .map(|vid| {
+                    pq.encode(&vectors[vid * dim..vid * dim + dim], &mut code);
+                    pq.decode(&code, &mut decoded);
+                    l2_partial(&vectors[vid * dim..vid * dim + dim], &decoded)
+                })
+                .sum()
+        };
+        assert!(error(8) < error(2));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dim = 20;
+        let vectors = random_vectors(300, dim);
+        let params = PQParams {
+            num_subspaces: 6,
+            bits: 6,
+        };
+        let pq = ProductQuantizer::train(&params, dim, PQDistance::L2, &vectors)
+            .expect("unable to train the quantizer");
+        let mut buf: Vec<u8> = Vec::new();
+        pq.save(&mut buf).expect("unable to save the quantizer");
+        let loaded = ProductQuantizer::load(&mut &buf[..]).expect("unable to load the quantizer");
+        assert_eq!(pq.bounds, loaded.bounds);
+        assert_eq!(pq.codebooks, loaded.codebooks);
+
+        let mut code = vec![0u8; pq.code_len()];
+        let mut code_loaded = vec![0u8; pq.code_len()];
+        pq.encode(&vectors[0..dim], &mut code);
+        loaded.encode(&vectors[0..dim], &mut code_loaded);
+        assert_eq!(code, code_loaded);
+    }
+
+    #[test]
+    fn invalid_params() {
+        let vectors = random_vectors(10, 16);
+        for (num_subspaces, bits) in [(0, 8), (17, 8), (4, 0), (4, 9)] {
+            let params = PQParams {
+                num_subspaces,
+                bits,
+            };
+            assert!(ProductQuantizer::train(&params, 16, PQDistance::L2, &vectors).is_err());
+        }
+        // too few vectors to place 2^bits centroids
+        let params = PQParams {
+            num_subspaces: 4,
+            bits: 4,
+        };
+        assert!(ProductQuantizer::train(&params, 16, PQDistance::L2, &vectors).is_err());
+        assert!(check_first_batch(&params, false, 10).is_err());
+        assert!(check_first_batch(&params, false, 16).is_ok());
+        assert!(check_first_batch(&params, true, 1).is_ok());
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::bail;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use parking_lot::RwLock;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ann;
+use crate::av_store::AlignedDataStore;
+use crate::metric;
+use crate::persist;
+use crate::product_quantizer::PQDistance;
+
+const FULL_PRECISION_FILE: &str = "full_precision";
+// number of vectors the store starts out with, it doubles whenever a vid
+// lands past the end of it
+const INITIAL_CAPACITY: usize = 1024;
+
+// searches over the codes of QuantizerIn points are ranked by approximate
+// distances. with re-ranking the full precision vectors are retained next
+// to the codes, a search then fetches k * factor candidates from the codes
+// and re-scores them so that the distances handed back are exact
+#[derive(Debug, Clone)]
+pub struct RerankParams {
+    pub factor: usize,
+    // when set, the full precision vectors live in a memory-mapped file
+    // inside this directory rather than on the heap
+    pub mmap_dir: Option<PathBuf>,
+}
+
+impl RerankParams {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.factor == 0 {
+            bail!("rerank factor must be >= 1");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save(params: &Option<RerankParams>, w: &mut impl Write) -> anyhow::Result<()> {
+        w.write_u8(params.is_some() as u8)?;
+        if let Some(params) = params {
+            persist::write_usize(w, params.factor)?;
+            w.write_u8(params.mmap_dir.is_some() as u8)?;
+        }
+        Ok(())
+    }
+
+    // a store that was mapped is mapped again from the saved directory
+    pub(crate) fn load(r: &mut impl Read, path: &Path) -> anyhow::Result<Option<RerankParams>> {
+        if r.read_u8()? == 0 {
+            return Ok(None);
+        }
+        let factor = persist::read_usize(r)?;
+        let mmap_dir = match r.read_u8()? != 0 {
+            true => Some(path.to_path_buf()),
+            false => None,
+        };
+        Ok(Some(RerankParams { factor, mmap_dir }))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FullPrecisionStore {
+    params: RerankParams,
+    distance: PQDistance,
+    aligned_dim: usize,
+    vectors: RwLock<AlignedDataStore<f32>>,
+}
+
+impl FullPrecisionStore {
+    pub(crate) fn new(
+        params: &RerankParams,
+        distance: PQDistance,
+        aligned_dim: usize,
+    ) -> anyhow::Result<FullPrecisionStore> {
+        params.validate()?;
+        let vectors = match &params.mmap_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                AlignedDataStore::new_mmap(
+                    &dir.join(FULL_PRECISION_FILE),
+                    INITIAL_CAPACITY,
+                    aligned_dim,
+                )?
+            }
+            None => AlignedDataStore::new(INITIAL_CAPACITY, aligned_dim),
+        };
+        Ok(FullPrecisionStore {
+            params: params.clone(),
+            distance,
+            aligned_dim,
+            vectors: RwLock::new(vectors),
+        })
+    }
+
+    pub(crate) fn factor(&self) -> usize {
+        self.params.factor
+    }
+
+    // how many candidates to fetch from the codes for a search after k
+    pub(crate) fn num_candidates(&self, k: usize) -> usize {
+        k.saturating_mul(self.params.factor)
+    }
+
+    // vals holds the points of vids back to back, each at most aligned_dim
+    // long - shorter points are padded out with zeros
+    pub(crate) fn insert(&self, vids: &[usize], vals: &[f32]) -> anyhow::Result<()> {
+        if vids.is_empty() {
+            return Ok(());
+        }
+        let dim = vals.len() / vids.len();
+        if vals.len() % vids.len() != 0 || dim > self.aligned_dim {
+            bail!(
+                "points.len: {} does not hold {} points of dim <= {}",
+                vals.len(),
+                vids.len(),
+                self.aligned_dim
+            );
+        }
+        let aligned_dim = self.aligned_dim;
+        let mut vectors = self.vectors.write();
+        let capacity = vectors.data.len() / aligned_dim;
+        let needed = vids.iter().max().map_or(0, |vid| vid + 1);
+        if needed > capacity {
+            vectors.grow(needed.max(2 * capacity), aligned_dim)?;
+        }
+        for (idx, vid) in vids.iter().enumerate() {
+            let slot = &mut vectors.data[vid * aligned_dim..vid * aligned_dim + aligned_dim];
+            slot[..dim].copy_from_slice(&vals[idx * dim..idx * dim + dim]);
+            slot[dim..].fill(0.0);
+        }
+        Ok(())
+    }
+
+    // re-scores the candidates against q and keeps the k closest of them
+    pub(crate) fn rerank(&self, q: &[f32], candidates: Vec<ann::Node>, k: usize) -> Vec<ann::Node> {
+        let aligned_dim = self.aligned_dim;
+        let mut q_aligned: Vec<f32> = vec![0.0; aligned_dim];
+        q_aligned[..q.len()].copy_from_slice(q);
+        let vectors = self.vectors.read();
+        let mut reranked: Vec<ann::Node> = candidates
+            .into_iter()
+            .filter(|nn| (nn.vid + 1) * aligned_dim <= vectors.data.len())
+            .map(|nn| ann::Node {
+                distance: exact_distance(
+                    self.distance,
+                    &q_aligned,
+                    &vectors.data[nn.vid * aligned_dim..nn.vid * aligned_dim + aligned_dim],
+                ),
+                ..nn
+            })
+            .collect();
+        reranked.sort();
+        reranked.truncate(k);
+        reranked
+    }
+
+    // the retained values of vid, padded out to aligned_dim
+    pub(crate) fn vector(&self, vid: usize) -> Option<Vec<f32>> {
+        let vectors = self.vectors.read();
+        vectors
+            .data
+            .get(vid * self.aligned_dim..(vid + 1) * self.aligned_dim)
+            .map(|vals| vals.to_vec())
+    }
+
+    pub(crate) fn memory_bytes(&self) -> usize {
+        self.vectors.read().data.len() * std::mem::size_of::<f32>()
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let vectors = self.vectors.read();
+        let vectors_path = path.join(FULL_PRECISION_FILE);
+        match vectors.mapped_path() {
+            // renaming over the file we are mapped onto would detach the
+            // mapping from it - so just flush our dirty pages instead
+            Some(mapped) if persist::same_file(mapped, &vectors_path) => vectors.flush(),
+            _ => persist::write_file(&vectors_path, |w| persist::write_vals(w, &vectors.data[..])),
+        }
+    }
+
+    pub(crate) fn load(
+        params: &RerankParams,
+        distance: PQDistance,
+        aligned_dim: usize,
+        path: &Path,
+    ) -> anyhow::Result<FullPrecisionStore> {
+        params.validate()?;
+        let vectors_path = path.join(FULL_PRECISION_FILE);
+        let n_bytes = fs::metadata(&vectors_path)?.len() as usize;
+        let capacity = n_bytes / (aligned_dim * std::mem::size_of::<f32>());
+        let vectors = match &params.mmap_dir {
+            Some(_) => AlignedDataStore::open_mmap(&vectors_path, capacity, aligned_dim)?,
+            None => {
+                let mut vectors = AlignedDataStore::new(capacity, aligned_dim);
+                let mut r = persist::open_file(&vectors_path)?;
+                persist::read_vals(&mut r, &mut vectors.data[..])?;
+                vectors
+            }
+        };
+        Ok(FullPrecisionStore {
+            params: params.clone(),
+            distance,
+            aligned_dim,
+            vectors: RwLock::new(vectors),
+        })
+    }
+}
+
+// the distance re-ranking scores with, the one the codes of the scalar
+// quantizer approximate for TMetric
+pub(crate) fn distance_for<TVal, TMetric: metric::Metric<TVal>>() -> anyhow::Result<PQDistance> {
+    match TMetric::quantized_distance() {
+        Some(distance) => Ok(distance),
+        None => bail!(
+            "metric: {} cannot be used with re-ranking",
+            std::any::type_name::<TMetric>()
+        ),
+    }
+}
+
+// the exact counterpart of the distances the codes of the scalar quantizer
+// approximate, see scalar_quantizer::CodeSpace
+pub(crate) fn exact_distance(distance: PQDistance, arr_a: &[f32], arr_b: &[f32]) -> f32 {
+    let kernels = metric::kernels();
+    match distance {
+        PQDistance::L2 => (kernels.l2_f32)(arr_a, arr_b),
+        PQDistance::L1 => (kernels.l1_f32)(arr_a, arr_b),
+        PQDistance::InnerProduct => -(kernels.dot_f32)(arr_a, arr_b),
+        PQDistance::Cosine => {
+            let norm_a = (kernels.dot_f32)(arr_a, arr_a);
+            let norm_b = (kernels.dot_f32)(arr_b, arr_b);
+            if norm_a <= 0.0 || norm_b <= 0.0 {
+                return 1.0;
+            }
+            1.0 - (kernels.dot_f32)(arr_a, arr_b) / (norm_a * norm_b).sqrt()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eid_for(id: usize) -> ann::EId {
+        let mut eid = [0u8; 16];
+        eid[0..8].copy_from_slice(&id.to_le_bytes());
+        eid
+    }
+
+    #[test]
+    fn rerank() {
+        let params = RerankParams {
+            factor: 4,
+            mmap_dir: None,
+        };
+        let store = FullPrecisionStore::new(&params, PQDistance::L2, 16).unwrap();
+        assert_eq!(store.num_candidates(10), 40);
+        // a vid past the initial capacity grows the store
+        let vids: Vec<usize> = vec![0, 1, 2, INITIAL_CAPACITY + 5];
+        let vals: Vec<f32> = vids.iter().flat_map(|vid| vec![*vid as f32; 8]).collect();
+        store.insert(&vids, &vals).unwrap();
+        assert!(store.memory_bytes() >= (INITIAL_CAPACITY + 6) * 16 * 4);
+
+        // the approximate distances come back in the wrong order
+        let candidates: Vec<ann::Node> = vids
+            .iter()
+            .rev()
+            .map(|vid| ann::Node {
+                vid: *vid,
+                eid: eid_for(*vid),
+                distance: 0.0,
+            })
+            .collect();
+        let res = store.rerank(&[0.75f32; 8], candidates, 2);
+        assert_eq!(
+            vec![1, 0],
+            res.iter().map(|nn| nn.vid).collect::<Vec<usize>>()
+        );
+        assert_eq!(
+            vec![0.5, 4.5],
+            res.iter().map(|nn| nn.distance).collect::<Vec<f32>>()
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let root = std::env::temp_dir().join(format!("anansi-rerank-{}", std::process::id()));
+        for mapped in [false, true] {
+            // a store is not mapped over the file of an earlier save
+            let directory = root.join(if mapped { "mapped" } else { "heap" });
+            let params = RerankParams {
+                factor: 2,
+                mmap_dir: mapped.then(|| directory.clone()),
+            };
+            let store = FullPrecisionStore::new(&params, PQDistance::Cosine, 16).unwrap();
+            let vals: Vec<f32> = (0..32).map(|x| x as f32).collect();
+            store.insert(&[3, 7], &vals).unwrap();
+            fs::create_dir_all(&directory).unwrap();
+            store.save(&directory).unwrap();
+
+            let mut buf: Vec<u8> = Vec::new();
+            RerankParams::save(&Some(params.clone()), &mut buf).unwrap();
+            let loaded_params = RerankParams::load(&mut &buf[..], &directory)
+                .unwrap()
+                .unwrap();
+            assert_eq!(mapped, loaded_params.mmap_dir.is_some());
+            let loaded =
+                FullPrecisionStore::load(&loaded_params, PQDistance::Cosine, 16, &directory)
+                    .unwrap();
+            assert_eq!(
+                store.vectors.read().data[..],
+                loaded.vectors.read().data[..]
+            );
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,108 +1,1017 @@
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use anyhow::bail;
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::io::{Read, Write};
 use std::sync::Arc;
 
-use tdigest::TDigest;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tdigest::{Centroid, TDigest};
+
+use crate::ann;
+use crate::metric;
+use crate::persist;
+use crate::product_quantizer::PQDistance;
+
+const QUANTIZER_MAGIC: &[u8; 4] = b"ASQZ";
+const QUANTIZER_VERSION: u32 = 3;
+// the digest is persisted as a fixed number of evenly spaced quantiles which
+// are re-inflated into equally weighted centroids on load
+const DIGEST_QUANTILES: usize = 101;
+const DIGEST_SIZE: usize = 100;
+
+// how QuantizerIn points are mapped onto codes: every coordinate becomes a
+// code of `bits` bits and the range of the codes spans the minimum up to
+// `quantile` of the values seen. codes of fewer than 8 bits are packed
+// several to a byte, see pack(..)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScalarQuantizerParams {
+    pub quantile: f32,
+    pub bits: usize, // 2^bits levels per coordinate, one of 1, 2, 4 or 8
+    // fits a range to every dimension on its own rather than a single one to
+    // all of them, so that a few outlier dimensions do not stretch the
+    // buckets of every other dimension
+    pub per_dimension: bool,
+}
+
+impl Default for ScalarQuantizerParams {
+    fn default() -> Self {
+        ScalarQuantizerParams {
+            quantile: 0.99,
+            bits: 8,
+            per_dimension: false,
+        }
+    }
+}
+
+impl ScalarQuantizerParams {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if !matches!(self.bits, 1 | 2 | 4 | 8) {
+            bail!(
+                "scalar quantizer bits: {} must be one of 1, 2, 4 or 8",
+                self.bits
+            );
+        }
+        if !(self.quantile > 0.0 && self.quantile <= 1.0) {
+            bail!(
+                "scalar quantizer quantile: {} must be within (0, 1]",
+                self.quantile
+            );
+        }
+        Ok(())
+    }
+
+    fn write(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        w.write_f32::<LittleEndian>(self.quantile)?;
+        persist::write_usize(w, self.bits)?;
+        w.write_u8(self.per_dimension as u8)?;
+        Ok(())
+    }
+
+    fn read(r: &mut impl Read) -> anyhow::Result<ScalarQuantizerParams> {
+        Ok(ScalarQuantizerParams {
+            quantile: r.read_f32::<LittleEndian>()?,
+            bits: persist::read_usize(r)?,
+            per_dimension: r.read_u8()? != 0,
+        })
+    }
+
+    pub(crate) fn save(
+        params: &Option<ScalarQuantizerParams>,
+        w: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        w.write_u8(params.is_some() as u8)?;
+        if let Some(params) = params {
+            params.write(w)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load(r: &mut impl Read) -> anyhow::Result<Option<ScalarQuantizerParams>> {
+        match r.read_u8()? != 0 {
+            true => Ok(Some(ScalarQuantizerParams::read(r)?)),
+            false => Ok(None),
+        }
+    }
+
+    fn max_code(&self) -> f32 {
+        ((1usize << self.bits) - 1) as f32
+    }
+}
+
+// how far the reconstructed coordinates land from the values they encode
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReconstructionError {
+    // mean squared error per coordinate
+    pub mse: f32,
+    pub max_abs: f32,
+    pub num_values: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ErrorStats {
+    sum_sq: f64,
+    max_abs: f32,
+    count: u64,
+}
+
+impl ErrorStats {
+    fn add(&mut self, x: f32, reconstructed: f32) {
+        let err = (x - reconstructed).abs();
+        self.sum_sq += (err as f64) * (err as f64);
+        self.max_abs = self.max_abs.max(err);
+        self.count += 1;
+    }
+
+    fn report(&self) -> Option<ReconstructionError> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(ReconstructionError {
+            mse: (self.sum_sq / self.count as f64) as f32,
+            max_abs: self.max_abs,
+            num_values: self.count,
+        })
+    }
+}
+
+// values within [offset, offset + alpha * 2^bits) are split into 2^bits
+// buckets of width alpha, anything outside is clipped to the first or last
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    offset: f32,
+    alpha: f32,
+}
+
+impl Range {
+    fn fit(digest: &TDigest, quantile: f32, bits: usize) -> Range {
+        let p9x = digest.estimate_quantile(quantile as f64);
+        let p0 = digest.estimate_quantile(0.0f64);
+        Range {
+            offset: p0 as f32,
+            alpha: ((p9x - p0) / (1usize << bits) as f64) as f32,
+        }
+    }
+
+    #[inline(always)]
+    fn encode(&self, x: f32, max_code: f32) -> u8 {
+        // a range fit to a single value only tells apart what lies above it
+        if self.alpha <= 0.0 {
+            return if x > self.offset { max_code as u8 } else { 0 };
+        }
+        ((x - self.offset) / self.alpha).clamp(0.0, max_code) as u8
+    }
+
+    #[inline(always)]
+    fn decode(&self, c: u8) -> f32 {
+        self.offset + self.alpha * (c as f32 + 0.5)
+    }
+}
 
 #[derive(Debug)]
 struct QuantizerSettings {
     pub updated: bool,
-    pub offset: f32,
-    pub alpha: f32,
-    pub tdigest: TDigest,
+    // a single range shared by every coordinate or one per dimension, the
+    // ranges are cycled through for the coordinates of each point
+    pub ranges: Vec<Range>,
+    pub tdigests: Vec<TDigest>,
+    pub scale: Scale,
+    // of the points quantized since the ranges were last fit
+    pub error: ErrorStats,
+}
+
+impl QuantizerSettings {
+    fn set_ranges(&mut self, ranges: Vec<Range>) {
+        self.scale = Scale::from_ranges(&ranges);
+        self.ranges = ranges;
+        self.error = ErrorStats::default();
+        self.updated = true;
+    }
+
+    // folds the points into the running digests, which keep following the
+    // values after the ranges are fit so that drift can be told apart
+    fn observe(&mut self, arr_a: &[f32], num_ranges: usize) {
+        if self.tdigests.len() != num_ranges {
+            self.tdigests = (0..num_ranges)
+                .map(|_| TDigest::new_with_size(DIGEST_SIZE))
+                .collect();
+        }
+        self.tdigests
+            .iter_mut()
+            .enumerate()
+            .for_each(|(d, digest)| {
+                let vals: Vec<f64> = arr_a
+                    .iter()
+                    .skip(d)
+                    .step_by(num_ranges)
+                    .map(|x| *x as f64)
+                    .collect();
+                if !vals.is_empty() {
+                    *digest = digest.merge_unsorted(vals);
+                }
+            });
+    }
 }
+
 #[derive(Debug)]
 pub struct ScalarQuantizer {
-    quantile: f32,
+    params: ScalarQuantizerParams,
     settings: Arc<RwLock<QuantizerSettings>>,
-    pre_compute_by_vid: Arc<RwLock<HashMap<usize, f32>>>,
+    // indexed by vid, NaN for the vids that never went through quantize(..)
+    pre_compute_by_vid: Arc<RwLock<Vec<f32>>>,
 }
 
 impl ScalarQuantizer {
-    fn gen_quantize_params(&self, arr_a: &[f32]) -> (f32, f32) {
-        let mut vals = Vec::with_capacity(arr_a.len());
-        arr_a.iter().for_each(|x| {
-            vals.push_within_capacity(*x as f64);
-        });
+    fn num_ranges(&self, dim: usize) -> usize {
+        match self.params.per_dimension {
+            true => dim.max(1),
+            false => 1,
+        }
+    }
 
-        let mut settings_w = self.settings.write();
-        let t = settings_w.tdigest.merge_unsorted(vals);
-        let p9x = t.estimate_quantile(self.quantile as f64);
-        let p0 = t.estimate_quantile(0.0f64);
-        let offset = p0 as f32;
-        let alpha = ((p9x - p0) / 255.0f64) as f32;
+    fn gen_quantize_params(&self, settings: &QuantizerSettings) -> Vec<Range> {
+        settings
+            .tdigests
+            .iter()
+            .map(|digest| Range::fit(digest, self.params.quantile, self.params.bits))
+            .collect()
+    }
 
-        settings_w.offset = offset;
-        settings_w.alpha = alpha;
-        settings_w.tdigest = t;
-        settings_w.updated = true;
-        (offset, alpha)
+    fn encode(&self, ranges: &[Range], arr_a: &[f32]) -> Vec<u8> {
+        let max_code = self.params.max_code();
+        arr_a
+            .iter()
+            .zip(ranges.iter().cycle())
+            .map(|(x, range)| range.encode(*x, max_code))
+            .collect()
     }
 
     pub fn quantize_arr(&self, arr_a: &[f32]) -> (Vec<u8>, f32) {
-        let offset: f32;
-        let alpha: f32;
-        {
-            let settings_r = self.settings.read();
-            offset = settings_r.offset;
-            alpha = settings_r.alpha;
+        let settings_r = self.settings.read();
+        let result = pack(
+            &self.encode(&settings_r.ranges, arr_a),
+            self.params.bits,
+            arr_a.len(),
+        );
+        let pre_compute = CodeSpace {
+            distance: PQDistance::L2,
+            scale: settings_r.scale.clone(),
+            bits: self.params.bits,
+            dim: arr_a.len(),
         }
-        let result: Vec<u8> = arr_a.iter().map(|x| ((x - offset) / alpha) as u8).collect();
-        let sum: f32 = arr_a[..].iter().sum();
-        return (result, sum * offset * alpha);
+        .pre_compute(&result);
+        return (result, pre_compute);
     }
 
     pub fn quantize(&self, vids: &[usize], arr_a: &[f32], requantize: Option<bool>) -> Vec<u8> {
-        let offset: f32;
-        let alpha: f32;
-        let gen_params: bool;
-        match requantize {
-            Some(x) => gen_params = x,
-            None => gen_params = !self.settings.read().updated,
-        }
-        if gen_params {
-            (offset, alpha) = self.gen_quantize_params(arr_a);
-        } else {
-            let settings_r = self.settings.read();
-            offset = settings_r.offset;
-            alpha = settings_r.alpha;
-        }
+        let dim = arr_a.len() / vids.len();
+        let num_ranges = self.num_ranges(dim);
+        let ranges: Vec<Range> = {
+            let mut settings_w = self.settings.write();
+            settings_w.observe(arr_a, num_ranges);
+            let gen_params = match requantize {
+                Some(x) => x,
+                // per dimension ranges are fit again once the points change shape
+                None => !settings_w.updated || settings_w.ranges.len() != num_ranges,
+            };
+            if gen_params {
+                let ranges = self.gen_quantize_params(&settings_w);
+                settings_w.set_ranges(ranges);
+            }
+            settings_w.ranges.clone()
+        };
 
         // now go through the vectors and re-calculate them!
         // TODO(infrawhipsers) - this could be done using SIMD?
-        let result: Vec<u8> = arr_a.iter().map(|x| ((x - offset) / alpha) as u8).collect();
-        let mut precompute: Vec<f32> = Vec::with_capacity(vids.len());
-        let dims = arr_a.len() / vids.len();
-        for i in 0..vids.len() {
-            let sum: f32 = arr_a[i * dims..i * dims + dims].iter().sum();
-            precompute.push(sum * offset * alpha)
-        }
+        let codes: Vec<u8> = self.encode(&ranges, arr_a);
+        let mut error = ErrorStats::default();
+        arr_a
+            .iter()
+            .zip(codes.iter())
+            .zip(ranges.iter().cycle())
+            .for_each(|((x, c), range)| error.add(*x, range.decode(*c)));
+        let space = {
+            let mut settings_w = self.settings.write();
+            let total = &mut settings_w.error;
+            total.sum_sq += error.sum_sq;
+            total.max_abs = total.max_abs.max(error.max_abs);
+            total.count += error.count;
+            CodeSpace {
+                distance: PQDistance::L2,
+                scale: Scale::from_ranges(&ranges),
+                bits: self.params.bits,
+                dim,
+            }
+        };
+        let result = pack(&codes, self.params.bits, dim);
+        let precompute: Vec<f32> = result
+            .chunks(self.code_len(dim).max(1))
+            .map(|codes| space.pre_compute(codes))
+            .collect();
         let mut mappings = self.pre_compute_by_vid.write();
         precompute
             .iter()
             .copied()
             .zip(vids.iter().copied())
-            .for_each(|(res, vid)| {
-                mappings.insert(vid, res);
-            });
+            .for_each(|(res, vid)| set_pre_compute(&mut mappings, vid, res));
 
         return result;
     }
 
+    // reconstructs approximate values from the packed codes of points of
+    // dim coordinates, each code stands for the middle of its bucket
+    pub fn dequantize(&self, codes: &[u8], dim: usize) -> Vec<f32> {
+        let settings_r = self.settings.read();
+        unpack(codes, self.params.bits, dim)
+            .iter()
+            .zip(settings_r.ranges.iter().cycle())
+            .map(|(c, range)| range.decode(*c))
+            .collect()
+    }
+
+    // the error arr_a would be quantized with, nothing is recorded
+    pub fn measure_reconstruction_error(&self, arr_a: &[f32]) -> ReconstructionError {
+        let settings_r = self.settings.read();
+        let max_code = self.params.max_code();
+        let mut error = ErrorStats::default();
+        arr_a
+            .iter()
+            .zip(settings_r.ranges.iter().cycle())
+            .for_each(|(x, range)| error.add(*x, range.decode(range.encode(*x, max_code))));
+        error.report().unwrap_or_default()
+    }
+
+    // over every point quantized since the ranges were last fit
+    pub fn reconstruction_error(&self) -> Option<ReconstructionError> {
+        self.settings.read().error.report()
+    }
+
+    // how far the ranges the running digests call for have moved away from
+    // the fitted ones, as a fraction of the fitted width and the largest
+    // over all the ranges. past a point codes quantized before and after
+    // the shift are better off re-encoded with fresh ranges, see refit(..)
+    pub fn drift(&self) -> f32 {
+        let settings_r = self.settings.read();
+        if !settings_r.updated || settings_r.tdigests.len() != settings_r.ranges.len() {
+            return 0.0;
+        }
+        let levels = (1usize << self.params.bits) as f32;
+        settings_r
+            .ranges
+            .iter()
+            .zip(settings_r.tdigests.iter())
+            .map(|(fitted, digest)| {
+                let current = Range::fit(digest, self.params.quantile, self.params.bits);
+                let width = fitted.alpha * levels;
+                let shift = (current.offset - fitted.offset)
+                    .abs()
+                    .max((current.offset + current.alpha * levels - fitted.offset - width).abs());
+                match (width > 0.0, shift > 0.0) {
+                    (true, _) => shift / width,
+                    // a range fit to a single value that no longer holds
+                    (false, true) => 1.0,
+                    (false, false) => 0.0,
+                }
+            })
+            .fold(0.0f32, f32::max)
+    }
+
+    // fits the ranges to the running digests, the codes quantized so far are
+    // left for the caller to re-encode through the returned Recoder
+    pub(crate) fn refit(&self) -> Recoder {
+        let mut settings_w = self.settings.write();
+        let old = settings_w.ranges.clone();
+        let ranges = self.gen_quantize_params(&settings_w);
+        settings_w.set_ranges(ranges);
+        Recoder {
+            old,
+            new: settings_w.ranges.clone(),
+            max_code: self.params.max_code(),
+            bits: self.params.bits,
+        }
+    }
+
+    // the vids of every point that went through quantize(..)
+    pub(crate) fn quantized_vids(&self) -> Vec<usize> {
+        self.pre_compute_by_vid
+            .read()
+            .iter()
+            .enumerate()
+            .filter(|(_, pre)| !pre.is_nan())
+            .map(|(vid, _)| vid)
+            .collect()
+    }
+
+    // keeps the per-vid term in step with codes re-encoded by a Recoder
+    pub(crate) fn recoded(&self, vid: usize, codes: &[u8], dim: usize) {
+        let pre_compute = self.code_space(PQDistance::L2, dim).pre_compute(codes);
+        set_pre_compute(&mut self.pre_compute_by_vid.write(), vid, pre_compute);
+    }
+
+    pub(crate) fn save(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        let settings_r = self.settings.read();
+        persist::write_header(w, QUANTIZER_MAGIC, QUANTIZER_VERSION)?;
+        self.params.write(w)?;
+        w.write_u8(settings_r.updated as u8)?;
+        persist::write_usize(w, settings_r.ranges.len())?;
+        for range in settings_r.ranges.iter() {
+            w.write_f32::<LittleEndian>(range.offset)?;
+            w.write_f32::<LittleEndian>(range.alpha)?;
+        }
+        persist::write_usize(w, settings_r.tdigests.len())?;
+        for tdigest in settings_r.tdigests.iter() {
+            write_digest(w, tdigest)?;
+        }
+
+        let pre_compute_r = self.pre_compute_by_vid.read();
+        let quantized = || {
+            pre_compute_r
+                .iter()
+                .enumerate()
+                .filter(|(_, pre)| !pre.is_nan())
+        };
+        persist::write_usize(w, quantized().count())?;
+        for (vid, val) in quantized() {
+            persist::write_usize(w, vid)?;
+            w.write_f32::<LittleEndian>(*val)?;
+        }
+
+        w.write_f64::<LittleEndian>(settings_r.error.sum_sq)?;
+        w.write_f32::<LittleEndian>(settings_r.error.max_abs)?;
+        w.write_u64::<LittleEndian>(settings_r.error.count)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(r: &mut impl Read) -> anyhow::Result<ScalarQuantizer> {
+        let version = persist::read_header(r, QUANTIZER_MAGIC, QUANTIZER_VERSION)?;
+        let params: ScalarQuantizerParams;
+        let updated: bool;
+        let mut ranges: Vec<Range> = Vec::new();
+        let mut tdigests: Vec<TDigest> = Vec::new();
+        if version >= 2 {
+            params = ScalarQuantizerParams::read(r)?;
+            updated = r.read_u8()? != 0;
+            for _ in 0..persist::read_usize(r)? {
+                ranges.push(Range {
+                    offset: r.read_f32::<LittleEndian>()?,
+                    alpha: r.read_f32::<LittleEndian>()?,
+                });
+            }
+            for _ in 0..persist::read_usize(r)? {
+                tdigests.push(read_digest(r)?);
+            }
+        } else {
+            // v1: a single 8 bit range
+            params = ScalarQuantizerParams {
+                quantile: r.read_f32::<LittleEndian>()?,
+                ..Default::default()
+            };
+            updated = r.read_u8()? != 0;
+            ranges.push(Range {
+                offset: r.read_f32::<LittleEndian>()?,
+                alpha: r.read_f32::<LittleEndian>()?,
+            });
+            tdigests.push(read_digest(r)?);
+        }
+        params.validate()?;
+        if version < 3 && params.bits != 8 {
+            bail!(
+                "scalar quantizer with bits: {} was saved before codes were packed",
+                params.bits
+            );
+        }
+        if ranges.is_empty() {
+            bail!("scalar quantizer was saved without a range");
+        }
+
+        let num_pre_compute = persist::read_usize(r)?;
+        let mut pre_compute_by_vid: Vec<f32> =
+            Vec::with_capacity(persist::capacity_hint(num_pre_compute));
+        for _ in 0..num_pre_compute {
+            let vid = persist::read_usize(r)?;
+            set_pre_compute(&mut pre_compute_by_vid, vid, r.read_f32::<LittleEndian>()?);
+        }
+        let mut error = ErrorStats::default();
+        if version >= 2 {
+            error.sum_sq = r.read_f64::<LittleEndian>()?;
+            error.max_abs = r.read_f32::<LittleEndian>()?;
+            error.count = r.read_u64::<LittleEndian>()?;
+        }
+        Ok(ScalarQuantizer {
+            params: params,
+            settings: Arc::new(RwLock::new(QuantizerSettings {
+                scale: Scale::from_ranges(&ranges),
+                ranges: ranges,
+                tdigests: tdigests,
+                updated: updated,
+                error: error,
+            })),
+            pre_compute_by_vid: Arc::new(RwLock::new(pre_compute_by_vid)),
+        })
+    }
+
     pub fn new(quantile: f32) -> anyhow::Result<ScalarQuantizer> {
-        return Ok(ScalarQuantizer {
+        ScalarQuantizer::with_params(&ScalarQuantizerParams {
             quantile: quantile,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_params(params: &ScalarQuantizerParams) -> anyhow::Result<ScalarQuantizer> {
+        params.validate()?;
+        let ranges = vec![Range {
+            offset: 0.0f32,
+            alpha: 0.0f32,
+        }];
+        return Ok(ScalarQuantizer {
+            params: *params,
             settings: Arc::new(RwLock::new(QuantizerSettings {
-                offset: 0.0f32,
-                alpha: 0.0f32,
-                tdigest: TDigest::new_with_size(100),
+                scale: Scale::from_ranges(&ranges),
+                ranges: ranges,
+                tdigests: vec![TDigest::new_with_size(DIGEST_SIZE)],
                 updated: false,
+                error: ErrorStats::default(),
             })),
-            pre_compute_by_vid: Arc::new(RwLock::new(HashMap::new())),
+            pre_compute_by_vid: Arc::new(RwLock::new(Vec::new())),
+        });
+    }
+
+    pub fn quantile(&self) -> f32 {
+        self.params.quantile
+    }
+
+    pub fn params(&self) -> &ScalarQuantizerParams {
+        &self.params
+    }
+
+    // the bytes the packed codes of a point of dim coordinates take up
+    pub fn code_len(&self, dim: usize) -> usize {
+        code_len(self.params.bits, dim)
+    }
+
+    // one code per byte, for the indexes that compare codes as they are
+    pub(crate) fn unpack(&self, codes: &[u8], dim: usize) -> Vec<u8> {
+        unpack(codes, self.params.bits, dim)
+    }
+
+    pub(crate) fn pack(&self, codes: &[u8], dim: usize) -> Vec<u8> {
+        pack(codes, self.params.bits, dim)
+    }
+
+    // the fitted parameters, paired with the distance the packed codes of
+    // points of dim coordinates are compared by
+    pub(crate) fn code_space(&self, distance: PQDistance, dim: usize) -> CodeSpace {
+        CodeSpace {
+            distance,
+            scale: self.settings.read().scale.clone(),
+            bits: self.params.bits,
+            dim,
+        }
+    }
+
+    // the code space and the per-vid terms, held for the length of a search
+    // or a prune so that its comparisons leave the quantizer alone
+    pub(crate) fn view(&self, distance: PQDistance, dim: usize) -> CodeView<'_> {
+        CodeView {
+            space: self.code_space(distance, dim),
+            pre_compute_by_vid: self.pre_compute_by_vid.read(),
+        }
+    }
+
+    #[cfg(test)]
+    fn pre_compute(&self, vid: usize) -> Option<f32> {
+        self.pre_compute_by_vid
+            .read()
+            .get(vid)
+            .copied()
+            .filter(|pre| !pre.is_nan())
+    }
+}
+
+fn code_len(bits: usize, dim: usize) -> usize {
+    (dim * bits + 7) / 8
+}
+
+// codes of fewer than 8 bits are packed several to a byte, the first in the
+// lowest bits. bits divides 8 so no code straddles two bytes, and every
+// point starts on a byte of its own
+fn pack(codes: &[u8], bits: usize, dim: usize) -> Vec<u8> {
+    if bits == 8 {
+        return codes.to_vec();
+    }
+    codes
+        .chunks(dim.max(1))
+        .flat_map(|point| point.chunks(8 / bits))
+        .map(|byte| {
+            byte.iter()
+                .enumerate()
+                .fold(0u8, |packed, (i, c)| packed | (c << (i * bits)))
+        })
+        .collect()
+}
+
+fn unpack(packed: &[u8], bits: usize, dim: usize) -> Vec<u8> {
+    if bits == 8 {
+        return packed.to_vec();
+    }
+    let mask = ((1u16 << bits) - 1) as u8;
+    packed
+        .chunks(code_len(bits, dim).max(1))
+        .flat_map(|point| (0..dim).map(move |i| (point[i * bits / 8] >> (i * bits % 8)) & mask))
+        .collect()
+}
+
+fn set_pre_compute(pre_compute_by_vid: &mut Vec<f32>, vid: usize, pre_compute: f32) {
+    if pre_compute_by_vid.len() <= vid {
+        pre_compute_by_vid.resize(vid + 1, f32::NAN);
+    }
+    pre_compute_by_vid[vid] = pre_compute;
+}
+
+pub(crate) struct CodeView<'a> {
+    space: CodeSpace,
+    pre_compute_by_vid: RwLockReadGuard<'a, Vec<f32>>,
+}
+
+impl<'a> CodeView<'a> {
+    pub(crate) fn space(&self) -> &CodeSpace {
+        &self.space
+    }
+
+    // points that were never handed to the quantizer (the start point of a
+    // graph) have their term worked out from their codes instead
+    #[inline(always)]
+    pub(crate) fn pre_compute<T: ann::ElementVal>(&self, vid: usize, codes: &[T]) -> f32 {
+        match self.pre_compute_by_vid.get(vid) {
+            Some(pre) if !pre.is_nan() => *pre,
+            _ => self.space.pre_compute(codes),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn between<T: ann::ElementVal>(
+        &self,
+        vid_a: usize,
+        arr_a: &[T],
+        vid_b: usize,
+        arr_b: &[T],
+    ) -> f32 {
+        self.space.between(
+            arr_a,
+            self.pre_compute(vid_a, arr_a),
+            arr_b,
+            self.pre_compute(vid_b, arr_b),
+        )
+    }
+}
+
+// moves codes from the ranges they were quantized with onto refit ones
+#[derive(Debug)]
+pub(crate) struct Recoder {
+    old: Vec<Range>,
+    new: Vec<Range>,
+    max_code: f32,
+    bits: usize,
+}
+
+impl Recoder {
+    // without the original values the codes are decoded to the middle of
+    // their old buckets first
+    pub(crate) fn recode(&self, codes: &[u8], dim: usize) -> Vec<u8> {
+        let recoded: Vec<u8> = unpack(codes, self.bits, dim)
+            .iter()
+            .zip(self.old.iter().cycle())
+            .zip(self.new.iter().cycle())
+            .map(|((c, old), new)| new.encode(old.decode(*c), self.max_code))
+            .collect();
+        pack(&recoded, self.bits, dim)
+    }
+
+    pub(crate) fn encode(&self, arr_a: &[f32]) -> Vec<u8> {
+        let codes: Vec<u8> = arr_a
+            .iter()
+            .zip(self.new.iter().cycle())
+            .map(|(x, range)| range.encode(*x, self.max_code))
+            .collect();
+        pack(&codes, self.bits, arr_a.len())
+    }
+}
+
+fn write_digest(w: &mut impl Write, tdigest: &TDigest) -> anyhow::Result<()> {
+    persist::write_usize(w, tdigest.max_size())?;
+    w.write_f64::<LittleEndian>(tdigest.count())?;
+    if tdigest.count() > 0.0 {
+        w.write_f64::<LittleEndian>(tdigest.sum())?;
+        w.write_f64::<LittleEndian>(tdigest.min())?;
+        w.write_f64::<LittleEndian>(tdigest.max())?;
+        for i in 0..DIGEST_QUANTILES {
+            let q = (i as f64) / ((DIGEST_QUANTILES - 1) as f64);
+            w.write_f64::<LittleEndian>(tdigest.estimate_quantile(q))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_digest(r: &mut impl Read) -> anyhow::Result<TDigest> {
+    let max_size = persist::read_usize(r)?;
+    let count = r.read_f64::<LittleEndian>()?;
+    if count <= 0.0 {
+        return Ok(TDigest::new_with_size(max_size));
+    }
+    let sum = r.read_f64::<LittleEndian>()?;
+    let min = r.read_f64::<LittleEndian>()?;
+    let max = r.read_f64::<LittleEndian>()?;
+    let weight = count / (DIGEST_QUANTILES as f64);
+    let mut centroids: Vec<Centroid> = Vec::with_capacity(DIGEST_QUANTILES);
+    for _ in 0..DIGEST_QUANTILES {
+        centroids.push(Centroid::new(r.read_f64::<LittleEndian>()?, weight));
+    }
+    Ok(TDigest::new(centroids, sum, count, max, min, max_size))
+}
+
+// the (center, alpha) of the coordinates, center = offset + alpha / 2
+#[derive(Debug, Clone)]
+enum Scale {
+    Global { center: f32, alpha: f32 },
+    PerDimension(Arc<[(f32, f32)]>),
+}
+
+impl Scale {
+    fn from_ranges(ranges: &[Range]) -> Scale {
+        let scale = |range: &Range| (range.offset + range.alpha / 2.0, range.alpha);
+        match ranges {
+            [range] => {
+                let (center, alpha) = scale(range);
+                Scale::Global { center, alpha }
+            }
+            _ => Scale::PerDimension(ranges.iter().map(scale).collect()),
+        }
+    }
+}
+
+// a code c of dimension i stands for the middle of its bucket,
+// x_i ≈ center_i + alpha_i * c_i, which for a pair of points expands into
+//   <x, y> ≈ sum(alpha_i² * c_x_i * c_y_i) + pre(x) + pre(y)
+// where pre(x) = sum(center_i * alpha_i * c_x_i + center_i² / 2) is the
+// per-vid term held in pre_compute_by_vid. the norm of a point follows as
+// |x|² ≈ sum(alpha_i² * c_x_i²) + 2 * pre(x). with a single range center_i
+// and alpha_i are the same for every dimension
+#[derive(Debug, Clone)]
+pub(crate) struct CodeSpace {
+    distance: PQDistance,
+    scale: Scale,
+    // the codes are packed bits to a code, dim codes to a point
+    bits: usize,
+    dim: usize,
+}
+
+impl CodeSpace {
+    // the dim codes packed into the front of arr_a
+    #[inline(always)]
+    fn codes<'b, T: ann::ElementVal>(&self, arr_a: &'b [T]) -> impl Iterator<Item = f32> + 'b {
+        let bits = self.bits;
+        let mask = (1u32 << bits) - 1;
+        (0..self.dim).map(move |i| {
+            let byte = code_val(&arr_a[i * bits / 8]) as u32;
+            ((byte >> (i * bits % 8)) & mask) as f32
+        })
+    }
+
+    // only unpacked codes of a u8 index go through the byte kernels
+    #[inline(always)]
+    fn code_bytes<'b, T: ann::ElementVal>(&self, arr_a: &'b [T]) -> Option<&'b [u8]> {
+        match self.bits {
+            8 => code_bytes(&arr_a[..self.dim]),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn pre_compute<T: ann::ElementVal>(&self, codes: &[T]) -> f32 {
+        match &self.scale {
+            Scale::Global { center, alpha } => {
+                let sum: f32 = self.codes(codes).sum();
+                center * alpha * sum + (self.dim as f32) * center * center / 2.0
+            }
+            Scale::PerDimension(scales) => self
+                .codes(codes)
+                .zip(scales.iter().cycle())
+                .map(|(c, (center, alpha))| center * alpha * c + center * center / 2.0)
+                .sum(),
+        }
+    }
+
+    // the distance between two quantized points, both held as codes
+    pub(crate) fn between<T: ann::ElementVal>(
+        &self,
+        arr_a: &[T],
+        pre_a: f32,
+        arr_b: &[T],
+        pre_b: f32,
+    ) -> f32 {
+        match &self.scale {
+            Scale::Global { center, alpha } => {
+                match (self.code_bytes(arr_a), self.code_bytes(arr_b)) {
+                    (Some(a), Some(b)) if self.distance != PQDistance::L1 => {
+                        self.between_bytes(*alpha, a, pre_a, b, pre_b)
+                    }
+                    _ => self.between_with(
+                        std::iter::repeat((*center, *alpha)),
+                        arr_a,
+                        pre_a,
+                        arr_b,
+                        pre_b,
+                    ),
+                }
+            }
+            Scale::PerDimension(scales) => {
+                self.between_with(scales.iter().copied().cycle(), arr_a, pre_a, arr_b, pre_b)
+            }
+        }
+    }
+
+    // with a single range the codes go through the byte kernels as they are
+    // and alpha² is applied to the sums
+    #[inline(always)]
+    fn between_bytes(&self, alpha: f32, arr_a: &[u8], pre_a: f32, arr_b: &[u8], pre_b: f32) -> f32 {
+        let kernels = metric::kernels();
+        let alpha_sq = alpha * alpha;
+        if self.distance == PQDistance::L2 {
+            return alpha_sq * (kernels.l2_u8)(arr_a, arr_b) as f32;
+        }
+        let dot = alpha_sq * (kernels.dot_u8)(arr_a, arr_b) as f32 + pre_a + pre_b;
+        match self.distance {
+            PQDistance::InnerProduct => -dot,
+            _ => cosine_distance(
+                dot,
+                alpha_sq * (kernels.dot_u8)(arr_a, arr_a) as f32 + 2.0 * pre_a,
+                alpha_sq * (kernels.dot_u8)(arr_b, arr_b) as f32 + 2.0 * pre_b,
+            ),
+        }
+    }
+
+    #[inline(always)]
+    fn between_with<T: ann::ElementVal>(
+        &self,
+        scales: impl Iterator<Item = (f32, f32)>,
+        arr_a: &[T],
+        pre_a: f32,
+        arr_b: &[T],
+        pre_b: f32,
+    ) -> f32 {
+        // the centers cancel out of differences
+        let pairs = self
+            .codes(arr_a)
+            .zip(self.codes(arr_b))
+            .zip(scales)
+            .map(|((a, b), (_, alpha))| (alpha * a, alpha * b));
+        match self.distance {
+            PQDistance::L2 => pairs.map(|(a, b)| (a - b) * (a - b)).sum(),
+            PQDistance::L1 => pairs.map(|(a, b)| (a - b).abs()).sum(),
+            PQDistance::InnerProduct | PQDistance::Cosine => {
+                let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+                pairs.for_each(|(a, b)| {
+                    dot += a * b;
+                    norm_a += a * a;
+                    norm_b += b * b;
+                });
+                let dot = dot + pre_a + pre_b;
+                match self.distance {
+                    PQDistance::InnerProduct => -dot,
+                    _ => cosine_distance(dot, norm_a + 2.0 * pre_a, norm_b + 2.0 * pre_b),
+                }
+            }
+        }
+    }
+
+    // the query stays at full precision and is compared against the codes
+    // as they are (asymmetric distance computation)
+    pub(crate) fn query(&self, vals: &[f32]) -> QuantizedQuery {
+        let center_sum = match &self.scale {
+            Scale::Global { center, .. } => center * vals.iter().sum::<f32>(),
+            Scale::PerDimension(scales) => vals
+                .iter()
+                .zip(scales.iter().cycle())
+                .map(|(q, (center, _))| q * center)
+                .sum(),
+        };
+        let scaled = match &self.scale {
+            Scale::Global { alpha, .. } => vals.iter().map(|q| q * alpha).collect(),
+            Scale::PerDimension(scales) => vals
+                .iter()
+                .zip(scales.iter().cycle())
+                .map(|(q, (_, alpha))| q * alpha)
+                .collect(),
+        };
+        QuantizedQuery {
+            space: self.clone(),
+            center_sum,
+            norm: vals.iter().map(|x| x * x).sum(),
+            vals: vals.to_vec(),
+            scaled,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct QuantizedQuery {
+    space: CodeSpace,
+    vals: Vec<f32>,
+    // alpha_i * q_i
+    scaled: Vec<f32>,
+    // sum(center_i * q_i) and |q|²
+    center_sum: f32,
+    norm: f32,
+}
+
+impl QuantizedQuery {
+    #[inline(always)]
+    pub(crate) fn distance<T: ann::ElementVal>(&self, codes: &[T], pre: f32) -> f32 {
+        if let (Some(codes), Scale::Global { alpha, .. }) =
+            (self.space.code_bytes(codes), &self.space.scale)
+        {
+            if self.space.distance != PQDistance::L1 {
+                let dot: f32 = self
+                    .scaled
+                    .iter()
+                    .zip(codes.iter())
+                    .map(|(q, c)| q * *c as f32)
+                    .sum();
+                let code_norm = alpha * alpha * (metric::kernels().dot_u8)(codes, codes) as f32;
+                return self.combine(dot, code_norm, pre);
+            }
+        }
+        match &self.space.scale {
+            Scale::Global { center, alpha } => {
+                self.distance_with(std::iter::repeat((*center, *alpha)), codes, pre)
+            }
+            Scale::PerDimension(scales) => {
+                self.distance_with(scales.iter().copied().cycle(), codes, pre)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn distance_with<T: ann::ElementVal>(
+        &self,
+        scales: impl Iterator<Item = (f32, f32)>,
+        codes: &[T],
+        pre: f32,
+    ) -> f32 {
+        let triples = self
+            .vals
+            .iter()
+            .copied()
+            .zip(self.space.codes(codes))
+            .zip(scales);
+        if self.space.distance == PQDistance::L1 {
+            return triples
+                .map(|((q, c), (center, alpha))| (q - center - alpha * c).abs())
+                .sum();
+        }
+        let (mut dot, mut code_norm) = (0.0f32, 0.0f32);
+        triples.for_each(|((q, c), (_, alpha))| {
+            let c = alpha * c;
+            dot += q * c;
+            code_norm += c * c;
         });
+        self.combine(dot, code_norm, pre)
+    }
+
+    // dot = sum(q_i * alpha_i * c_i), code_norm = sum(alpha_i² * c_i²)
+    #[inline(always)]
+    fn combine(&self, dot: f32, code_norm: f32, pre: f32) -> f32 {
+        // <q, x> ≈ sum(q_i * alpha_i * c_i) + sum(center_i * q_i)
+        let dot = dot + self.center_sum;
+        match self.space.distance {
+            PQDistance::L2 => self.norm - 2.0 * dot + code_norm + 2.0 * pre,
+            PQDistance::InnerProduct => -dot,
+            _ => cosine_distance(dot, self.norm, code_norm + 2.0 * pre),
+        }
+    }
+}
+
+// the codes of a u8 index are handed to the byte kernels as they are
+#[inline(always)]
+fn code_bytes<T: ann::ElementVal>(codes: &[T]) -> Option<&[u8]> {
+    match T::ELEMENT_TYPE {
+        // safety: T is u8
+        ann::ElementType::U8 => {
+            Some(unsafe { std::slice::from_raw_parts(codes.as_ptr() as *const u8, codes.len()) })
+        }
+        _ => None,
     }
 }
 
+#[inline(always)]
+fn code_val<T: ann::ElementVal>(c: &T) -> f32 {
+    c.to_f32().expect("unable to coerce to f32")
+}
+
+fn cosine_distance(dot: f32, norm_a: f32, norm_b: f32) -> f32 {
+    if norm_a <= 0.0 || norm_b <= 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / (norm_a * norm_b).sqrt()
+}
+
 #[allow(unused_imports)]
 mod tests {
     use super::*;
@@ -120,7 +1029,374 @@ mod tests {
             .map(|x| x as f32)
             .collect();
         let vids: Vec<usize> = (0..num_vectors).collect();
-        assert_eq!(arr.len(), obj.quantize(&vids, &arr, None).len())
-        // TODO(infrawhispers) - verify that we can reconstruct the vectors!
+        let codes = obj.quantize(&vids, &arr, None);
+        assert_eq!(arr.len(), codes.len());
+        // the values within the fitted range come back within half a bucket
+        let alpha = obj.settings.read().ranges[0].alpha;
+        let upper = obj.settings.read().ranges[0].offset + 256.0 * alpha;
+        let reconstructed = obj.dequantize(&codes, dim);
+        arr.iter()
+            .zip(reconstructed.iter())
+            .filter(|(x, _)| **x < upper)
+            .for_each(|(x, y)| assert!((x - y).abs() <= alpha / 2.0 + 1.0, "{} vs {}", x, y));
+        let error = obj.reconstruction_error().unwrap();
+        assert_eq!(arr.len() as u64, error.num_values);
+        assert_eq!(error, obj.measure_reconstruction_error(&arr));
+    }
+
+    #[test]
+    fn test_quantizer_levels() {
+        let dim = 64;
+        let mut rng = rand::thread_rng();
+        let arr: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * 100)
+            .collect();
+        let vids: Vec<usize> = (0..100).collect();
+        let mut last_mse = 0.0f32;
+        for bits in [8, 4, 1] {
+            let obj = ScalarQuantizer::with_params(&ScalarQuantizerParams {
+                quantile: 1.0,
+                bits,
+                per_dimension: false,
+            })
+            .expect("unable to create the quantizer");
+            let codes = obj.quantize(&vids, &arr, None);
+            // packed 8 / bits codes to a byte
+            assert_eq!(100 * dim * bits / 8, codes.len());
+            let unpacked = obj.unpack(&codes, dim);
+            assert_eq!(arr.len(), unpacked.len());
+            assert!(unpacked.iter().all(|c| (*c as usize) < (1 << bits)));
+            assert_eq!(codes, obj.pack(&unpacked, dim));
+            // a uniform bucket of width w has an mse of w² / 12
+            let width = 2.0 / (1 << bits) as f32;
+            let mse = obj.reconstruction_error().unwrap().mse;
+            assert!(
+                mse < 1.5 * width * width / 12.0,
+                "bits: {} mse: {}",
+                bits,
+                mse
+            );
+            assert!(mse > last_mse);
+            last_mse = mse;
+            let reconstructed = obj.dequantize(&codes, dim);
+            let dequantized_mse = arr
+                .iter()
+                .zip(reconstructed.iter())
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                / arr.len() as f32;
+            assert!((dequantized_mse - mse).abs() < 1e-3 * mse.max(1e-6));
+        }
+        // 1 bit splits every coordinate around the middle of the range, a
+        // point of 5 coordinates still takes a byte of its own
+        let obj = ScalarQuantizer::with_params(&ScalarQuantizerParams {
+            quantile: 1.0,
+            bits: 1,
+            per_dimension: false,
+        })
+        .unwrap();
+        let codes = obj.quantize(&[0], &[-1.0, -0.5, 0.5, 1.0], None);
+        assert_eq!(vec![0b1100], codes);
+        assert_eq!(vec![-0.5, -0.5, 0.5, 0.5], obj.dequantize(&codes, 4));
+        let codes = obj.quantize(
+            &[1, 2],
+            &[-1.0, 1.0, -1.0, 1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0],
+            None,
+        );
+        assert_eq!(vec![0b11010, 0b00011], codes);
+        for bits in [0, 3, 9] {
+            assert!(ScalarQuantizer::with_params(&ScalarQuantizerParams {
+                bits,
+                ..Default::default()
+            })
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_quantizer_per_dimension() {
+        let dim = 32;
+        let num_vectors = 200;
+        let mut rng = rand::thread_rng();
+        // a couple of dimensions span a range a thousand times wider
+        let arr: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * num_vectors)
+            .enumerate()
+            .map(|(i, x)| if i % dim < 2 { 1000.0 * x } else { x })
+            .collect();
+        let vids: Vec<usize> = (0..num_vectors).collect();
+        let mse_of = |per_dimension: bool| -> (f32, ScalarQuantizer) {
+            let obj = ScalarQuantizer::with_params(&ScalarQuantizerParams {
+                quantile: 1.0,
+                bits: 8,
+                per_dimension,
+            })
+            .unwrap();
+            obj.quantize(&vids, &arr, None);
+            // the error of the regular dimensions alone
+            let reconstructed = obj.dequantize(&obj.quantize_arr(&arr).0, arr.len());
+            let mse = arr
+                .iter()
+                .zip(reconstructed.iter())
+                .enumerate()
+                .filter(|(i, _)| i % dim >= 2)
+                .map(|(_, (x, y))| (x - y) * (x - y))
+                .sum::<f32>()
+                / ((dim - 2) * num_vectors) as f32;
+            (mse, obj)
+        };
+        let (global_mse, _) = mse_of(false);
+        let (per_dimension_mse, obj) = mse_of(true);
+        assert!(
+            per_dimension_mse * 1000.0 < global_mse,
+            "per dimension: {} global: {}",
+            per_dimension_mse,
+            global_mse
+        );
+
+        // the code space follows the ranges of every dimension
+        let (x, y) = (&arr[0..dim], &arr[dim..2 * dim]);
+        let codes = obj.quantize(&[0, 1], &arr[0..2 * dim], Some(false));
+        let (pre_x, pre_y) = (obj.pre_compute(0).unwrap(), obj.pre_compute(1).unwrap());
+        let dot: f32 = x.iter().zip(y).map(|(a, b)| a * b).sum();
+        let l2: f32 = x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum();
+        let (codes_x, codes_y) = (&codes[0..dim], &codes[dim..]);
+        // every coordinate is off by at most half a bucket of its dimension,
+        // which bounds the error of the distances (plus some f32 slack)
+        let alphas: Vec<f32> = obj.settings.read().ranges.iter().map(|r| r.alpha).collect();
+        let slack = 1e-4 * (dot.abs() + l2);
+        let bound = |f: &dyn Fn(f32, f32, f32) -> f32| -> f32 {
+            slack + (0..dim).map(|i| f(x[i], y[i], alphas[i])).sum::<f32>()
+        };
+        let space = obj.code_space(PQDistance::InnerProduct, dim);
+        assert!(
+            (space.between(codes_x, pre_x, codes_y, pre_y) + dot).abs()
+                <= bound(&|x, y, a| (x.abs() + y.abs()) * a / 2.0 + a * a / 4.0)
+        );
+        assert!(
+            (space.query(x).distance(codes_y, pre_y) + dot).abs()
+                <= bound(&|x, _, a| x.abs() * a / 2.0)
+        );
+        let space = obj.code_space(PQDistance::L2, dim);
+        assert!(
+            (space.between(codes_x, pre_x, codes_y, pre_y) - l2).abs()
+                <= bound(&|x, y, a| 2.0 * (x - y).abs() * a + a * a)
+        );
+        assert!(
+            (space.query(x).distance(codes_y, pre_y) - l2).abs()
+                <= bound(&|x, y, a| (x - y).abs() * a + a * a / 4.0)
+        );
+        assert_eq!(pre_y, space.pre_compute(codes_y));
+    }
+
+    #[test]
+    fn test_drift() {
+        let dim = 16;
+        let mut rng = rand::thread_rng();
+        let arr: Vec<f32> = Uniform::from(0.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * 100)
+            .collect();
+        let vids: Vec<usize> = (0..100).collect();
+        for per_dimension in [false, true] {
+            let obj = ScalarQuantizer::with_params(&ScalarQuantizerParams {
+                quantile: 1.0,
+                bits: 8,
+                per_dimension,
+            })
+            .unwrap();
+            assert_eq!(0.0, obj.drift());
+            let codes = obj.quantize(&vids, &arr, None);
+            assert_eq!(0.0, obj.drift());
+            // more of the same barely moves the ranges
+            let same: Vec<f32> = Uniform::from(0.0f32..1.0f32)
+                .sample_iter(&mut rng)
+                .take(dim * 100)
+                .collect();
+            obj.quantize(&vids, &same, None);
+            assert!(obj.drift() < 0.1, "drift: {}", obj.drift());
+            // while shifted points are clipped to the fitted range
+            let shifted: Vec<f32> = arr.iter().map(|x| x + 2.0).collect();
+            let shifted_codes = obj.quantize(&(100..200).collect::<Vec<usize>>(), &shifted, None);
+            assert!(shifted_codes.iter().all(|c| *c == 255));
+            assert!(obj.drift() > 1.0, "drift: {}", obj.drift());
+
+            let recoder = obj.refit();
+            assert_eq!(0.0, obj.drift());
+            // the old codes land within a bucket of where the values would
+            let recoded = recoder.recode(&codes, dim);
+            let expected = recoder.encode(&arr);
+            assert_eq!(expected, obj.quantize_arr(&arr).0);
+            recoded
+                .iter()
+                .zip(expected.iter())
+                .for_each(|(a, b)| assert!((*a as i32 - *b as i32).abs() <= 1));
+            let reconstructed = obj.dequantize(&recoder.encode(&shifted), shifted.len());
+            assert!(shifted
+                .iter()
+                .zip(reconstructed.iter())
+                .all(|(x, y)| (x - y).abs() < 0.01));
+            obj.recoded(0, &recoded[..dim], dim);
+            assert_eq!(
+                obj.code_space(PQDistance::L2, dim).pre_compute(&recoded),
+                obj.pre_compute(0).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantizer_save_load() {
+        for params in [
+            ScalarQuantizerParams::default(),
+            ScalarQuantizerParams {
+                quantile: 0.95,
+                bits: 4,
+                per_dimension: true,
+            },
+        ] {
+            let obj =
+                ScalarQuantizer::with_params(&params).expect("unable to create the quantizer");
+            let arr: Vec<f32> = (0..1024).map(|x| x as f32).collect();
+            let vids: Vec<usize> = (0..4).collect();
+            let quantized = obj.quantize(&vids, &arr, None);
+
+            let mut buf: Vec<u8> = Vec::new();
+            obj.save(&mut buf).expect("unable to save the quantizer");
+            let loaded =
+                ScalarQuantizer::load(&mut &buf[..]).expect("unable to load the quantizer");
+            assert_eq!(params, *loaded.params());
+            assert_eq!(obj.reconstruction_error(), loaded.reconstruction_error());
+            assert_eq!(
+                obj.dequantize(&quantized, 256),
+                loaded.dequantize(&quantized, 256)
+            );
+            assert_eq!(quantized, loaded.quantize(&vids, &arr, None));
+            assert_eq!(
+                obj.pre_compute_by_vid.read().clone(),
+                loaded.pre_compute_by_vid.read().clone()
+            );
+        }
+    }
+
+    #[test]
+    fn test_code_space() {
+        let obj = ScalarQuantizer::new(1.0).expect("unable to create the quantizer");
+        let dim = 64;
+        let mut rng = rand::thread_rng();
+        let arr: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * 2)
+            .collect();
+        let codes = obj.quantize(&[0, 1], &arr, None);
+        let (x, y) = (&arr[0..dim], &arr[dim..]);
+        let dot: f32 = x.iter().zip(y).map(|(a, b)| a * b).sum();
+        let l2: f32 = x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum();
+        let norm_x: f32 = x.iter().map(|a| a * a).sum::<f32>().sqrt();
+        let norm_y: f32 = y.iter().map(|a| a * a).sum::<f32>().sqrt();
+        let (pre_x, pre_y) = (obj.pre_compute(0).unwrap(), obj.pre_compute(1).unwrap());
+        let (codes_x, codes_y) = (&codes[0..dim], &codes[dim..]);
+
+        // every coordinate is off by at most half a bucket
+        let tolerance = 0.1;
+        let space = obj.code_space(PQDistance::InnerProduct, dim);
+        assert!((space.between(codes_x, pre_x, codes_y, pre_y) + dot).abs() < tolerance);
+        assert!((space.query(x).distance(codes_y, pre_y) + dot).abs() < tolerance);
+        let space = obj.code_space(PQDistance::L2, dim);
+        assert!((space.between(codes_x, pre_x, codes_y, pre_y) - l2).abs() < tolerance);
+        assert!((space.query(x).distance(codes_y, pre_y) - l2).abs() < tolerance);
+        // against itself only the quantization error remains
+        assert!(space.query(x).distance(codes_x, pre_x) < tolerance);
+        let space = obj.code_space(PQDistance::Cosine, dim);
+        let cosine = 1.0 - dot / (norm_x * norm_y);
+        assert!((space.query(x).distance(codes_y, pre_y) - cosine).abs() < tolerance);
+        assert_eq!(pre_y, space.pre_compute(codes_y));
+    }
+
+    #[test]
+    fn test_code_space_packed() {
+        // an odd dim leaves the last byte of every point half used
+        let dim = 63;
+        let mut rng = rand::thread_rng();
+        let arr: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * 2)
+            .collect();
+        for bits in [4, 2, 1] {
+            let obj = ScalarQuantizer::with_params(&ScalarQuantizerParams {
+                quantile: 1.0,
+                bits,
+                per_dimension: false,
+            })
+            .expect("unable to create the quantizer");
+            let codes = obj.quantize(&[0, 1], &arr, None);
+            let code_len = obj.code_len(dim);
+            let (codes_x, codes_y) = (&codes[0..code_len], &codes[code_len..]);
+            // the packed codes stand for the dequantized points
+            let deq = obj.dequantize(&codes, dim);
+            let (deq_x, deq_y) = (&deq[0..dim], &deq[dim..]);
+            let x = &arr[0..dim];
+            let (pre_x, pre_y) = (obj.pre_compute(0).unwrap(), obj.pre_compute(1).unwrap());
+            let l2 = |a: &[f32], b: &[f32]| -> f32 {
+                a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+            };
+            let dot = |a: &[f32], b: &[f32]| -> f32 { a.iter().zip(b).map(|(a, b)| a * b).sum() };
+            let close = |a: f32, b: f32| (a - b).abs() <= 1e-3 * (1.0 + b.abs());
+            let space = obj.code_space(PQDistance::L2, dim);
+            assert!(close(
+                space.between(codes_x, pre_x, codes_y, pre_y),
+                l2(deq_x, deq_y)
+            ));
+            assert!(close(space.query(x).distance(codes_y, pre_y), l2(x, deq_y)));
+            let space = obj.code_space(PQDistance::InnerProduct, dim);
+            assert!(close(
+                space.between(codes_x, pre_x, codes_y, pre_y),
+                -dot(deq_x, deq_y)
+            ));
+            assert!(close(
+                space.query(x).distance(codes_y, pre_y),
+                -dot(x, deq_y)
+            ));
+            // codes held by an f32 index read the same
+            let vals_y: Vec<f32> = codes_y.iter().map(|c| *c as f32).collect();
+            assert!(close(
+                space.query(x).distance(&vals_y[..], pre_y),
+                -dot(x, deq_y)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_code_view() {
+        let obj = ScalarQuantizer::new(1.0).expect("unable to create the quantizer");
+        let dim = 64;
+        let mut rng = rand::thread_rng();
+        let arr: Vec<f32> = Uniform::from(-1.0f32..1.0f32)
+            .sample_iter(&mut rng)
+            .take(dim * 2)
+            .collect();
+        let codes = obj.quantize(&[0, 2], &arr, None);
+        let (codes_x, codes_y) = (&codes[0..dim], &codes[dim..]);
+        // codes held by an f32 index skip the byte kernels
+        let vals_x: Vec<f32> = codes_x.iter().map(|c| *c as f32).collect();
+        let vals_y: Vec<f32> = codes_y.iter().map(|c| *c as f32).collect();
+        for distance in [PQDistance::L2, PQDistance::InnerProduct, PQDistance::Cosine] {
+            let view = obj.view(distance, dim);
+            let kernel = view.between(0, codes_x, 2, codes_y);
+            let generic = view.between(0, &vals_x[..], 2, &vals_y[..]);
+            assert!((kernel - generic).abs() <= 1e-4 * (1.0 + generic.abs()));
+            let query = view.space().query(&arr[0..dim]);
+            let pre_y = view.pre_compute(2, codes_y);
+            let kernel = query.distance(codes_y, pre_y);
+            let generic = query.distance(&vals_y[..], pre_y);
+            assert!((kernel - generic).abs() <= 1e-4 * (1.0 + generic.abs()));
+            // vid 1 never went through quantize(..), its term comes from its codes
+            assert_eq!(
+                view.space().pre_compute(codes_y),
+                view.pre_compute(1, codes_y)
+            );
+        }
+        assert_eq!(vec![0, 2], obj.quantized_vids());
     }
 }
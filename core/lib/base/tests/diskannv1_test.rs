@@ -9,8 +9,10 @@ use std::sync::Arc;
 
 use base::ann;
 use base::ann::ANNIndex;
+use base::diskann_ssd;
 use base::diskannv1;
 use base::metric;
+use base::product_quantizer;
 
 struct SIFT<'a> {
     directory: &'a Path,
@@ -103,9 +105,1500 @@ impl SIFT<'_> {
     }
 }
 
+fn eid_for(id: usize) -> base::ann::EId {
+    let mut eid: base::ann::EId = [0u8; 16];
+    BigEndian::write_uint(
+        &mut eid,
+        id.try_into().unwrap(),
+        std::mem::size_of::<usize>(),
+    );
+    eid
+}
+
+fn random_vectors(num_vectors: usize, dims: usize) -> Vec<f32> {
+    use rand::distributions::{Distribution, Uniform};
+    let mut rng = rand::thread_rng();
+    Uniform::from(-1.0f32..1.0f32)
+        .sample_iter(&mut rng)
+        .take(num_vectors * dims)
+        .collect()
+}
+
+fn small_params(dims: usize, max_points: usize) -> ann::ANNParams {
+    ann::ANNParams::DiskANN {
+        params: diskannv1::DiskANNParams {
+            dim: dims,
+            max_points: max_points,
+            indexing_threads: None,
+            indexing_range: 32,      // R
+            indexing_queue_size: 64, // L
+            indexing_maxc: 100,      // C
+            indexing_alpha: 1.2,     // alpha
+            maintenance_period_millis: 500,
+            mmap_dir: None,
+            pq: None,
+            growth_factor: None,
+            rerank: None,
+            quantizer: None,
+        },
+    }
+}
+
+// the points a recall fixture hands to the index, narrowed from the
+// reference points or left to the scalar quantizer
+enum Encoded<TVal> {
+    Values(Vec<TVal>),
+    QuantizerIn(Vec<f32>),
+}
+
+impl<TVal> Encoded<TVal> {
+    fn points(&self) -> ann::Points<'_, TVal> {
+        match self {
+            Encoded::Values(vals) => ann::Points::Values { vals },
+            Encoded::QuantizerIn(vals) => ann::Points::QuantizerIn { vals },
+        }
+    }
+}
+
+fn values<TRef: Copy, TVal>(narrow: fn(TRef) -> TVal) -> impl Fn(&[TRef]) -> Encoded<TVal> {
+    move |vals| Encoded::Values(vals.iter().map(|x| narrow(*x)).collect())
+}
+
+fn quantized(vals: &[f32]) -> Encoded<u8> {
+    Encoded::QuantizerIn(vals.to_vec())
+}
+
+// uniform words, the bits of binary vectors
+fn random_words(num_vectors: usize, dims: usize) -> Vec<u64> {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..num_vectors * dims).map(|_| rng.gen()).collect()
+}
+
+// scalar references the ground truth is worked out with
+fn l2_reference(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn dot_reference(a: &[f32], b: &[f32]) -> f32 {
+    -a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>()
+}
+
+fn cosine_reference(a: &[f32], b: &[f32]) -> f32 {
+    let norm_a: f32 = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    1.0 + dot_reference(a, b) / (norm_a * norm_b)
+}
+
+fn hamming_reference(a: &[u64], b: &[u64]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x ^ y).count_ones())
+        .sum::<u32>() as f32
+}
+
+fn jaccard_reference(a: &[u64], b: &[u64]) -> f32 {
+    let inter: u32 = a.iter().zip(b).map(|(x, y)| (x & y).count_ones()).sum();
+    let union: u32 = a.iter().zip(b).map(|(x, y)| (x | y).count_ones()).sum();
+    1.0 - inter as f32 / union as f32
+}
+
+// fills an index with 1000 points drawn from random and checks the 10
+// nearest neighbors it finds for 20 more against a brute force search over
+// the reference points - anything as close as the 10th counts, so ties at
+// the boundary are fine. the index is handed back for further checks
+fn check_recall<TMetric, TVal, TRef>(
+    dims: usize,
+    random: fn(usize, usize) -> Vec<TRef>,
+    encode: impl Fn(&[TRef]) -> Encoded<TVal>,
+    reference: fn(&[TRef], &[TRef]) -> f32,
+    min_recall: f32,
+) -> diskannv1::DiskANNV1Index<TMetric, TVal>
+where
+    TMetric: metric::Metric<TVal>,
+    TVal: ann::ElementVal,
+    TRef: Copy + Default,
+{
+    let num_vectors: usize = 1000;
+    let num_queries: usize = 20;
+    let k: usize = 10;
+    // points are handed over padded out to the aligned dimension
+    let aligned_dim = ann::round_up(dims as u32) as usize;
+    let pad = |vals: Vec<TRef>| -> Vec<TRef> {
+        vals.chunks(dims)
+            .flat_map(|vec| {
+                let mut padded = vec![TRef::default(); aligned_dim];
+                padded[0..dims].copy_from_slice(vec);
+                padded
+            })
+            .collect()
+    };
+    let base_vectors = pad(random(num_vectors, dims));
+    let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+    let ann_idx: diskannv1::DiskANNV1Index<TMetric, TVal> =
+        ann::ANNIndex::new(&small_params(dims, num_vectors))
+            .expect("error creating diskannv1 index");
+    ann_idx
+        .insert(&eids, encode(&base_vectors).points())
+        .expect("unexpected err on insert");
+
+    let query_vectors = pad(random(num_queries, dims));
+    let mut total_intersection_count: usize = 0;
+    for query_vec in query_vectors.chunks(aligned_dim) {
+        let mut dists: Vec<(f32, usize)> = base_vectors
+            .chunks(aligned_dim)
+            .enumerate()
+            .map(|(i, vec)| (reference(query_vec, vec), i))
+            .collect();
+        dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let kth_dist = dists[k - 1].0;
+        let expected: HashSet<ann::EId> = dists
+            .iter()
+            .take_while(|(d, _)| *d <= kth_dist)
+            .map(|(_, i)| eids[*i])
+            .collect();
+        let query = encode(query_vec);
+        let found = ann_idx
+            .search(query.points(), k)
+            .expect("unexpected error on search");
+        assert_eq!(k, found.len());
+        if let Encoded::QuantizerIn(_) = query {
+            // the asymmetric distances track the full precision ones
+            let (closest_dist, closest) = dists[0];
+            if let Some(nn) = found.iter().find(|nn| nn.eid == eids[closest]) {
+                assert!(
+                    (nn.distance - closest_dist).abs() < 0.1 * closest_dist.abs().max(1.0),
+                    "distance: {} too far from: {}",
+                    nn.distance,
+                    closest_dist
+                );
+            }
+        }
+        total_intersection_count += found.iter().filter(|nn| expected.contains(&nn.eid)).count();
+    }
+    assert!(
+        (total_intersection_count as f32 / (k * num_queries) as f32) > min_recall,
+        "unexpectedly low recall: {}/{}",
+        total_intersection_count,
+        k * num_queries
+    );
+    ann_idx
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let dims: usize = 32;
+        let num_vectors: usize = 500;
+        let k: usize = 10;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        ann_idx
+            .delete(&eids[0..5])
+            .expect("unexpected err on delete");
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-save-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        for i in 0..10 {
+            let query_vec = &base_vectors[i * dims..i * dims + dims];
+            let expected: Vec<ann::EId> = ann_idx
+                .search(ann::Points::Values { vals: query_vec }, k)
+                .expect("unexpected error on search")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            let found: Vec<ann::EId> = loaded
+                .search(ann::Points::Values { vals: query_vec }, k)
+                .expect("unexpected error on search of loaded index")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            assert_eq!(expected, found);
+            assert!(!found.iter().any(|eid| eids[0..5].contains(eid)));
+        }
+        // the loaded index must also accept new writes straight away
+        let extra = random_vectors(1, dims);
+        loaded
+            .insert(
+                &[eid_for(num_vectors + 1)],
+                ann::Points::Values { vals: &extra },
+            )
+            .expect_err("index is at max_points and should reject the insert");
+        loaded
+            .delete(&eids[5..6])
+            .expect("unexpected err on delete of loaded index");
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+    #[test]
+    fn save_and_load_mmap() {
+        let dims: usize = 32;
+        let num_vectors: usize = 300;
+        let k: usize = 5;
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-mmap-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        let mut params = small_params(dims, num_vectors);
+        match &mut params {
+            ann::ANNParams::DiskANN { params } => params.mmap_dir = Some(directory.clone()),
+            _ => unreachable!(),
+        }
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        // a new index would otherwise map over the saved vectors
+        ann::ANNIndex::new(&params)
+            .map(|_: diskannv1::DiskANNV1Index<metric::MetricL2, f32>| ())
+            .expect_err("the mapped file of the saved index is in the way");
+
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        for i in 0..10 {
+            let query_vec = &base_vectors[i * dims..i * dims + dims];
+            let nns = loaded
+                .search(ann::Points::Values { vals: query_vec }, k)
+                .expect("unexpected error on search of loaded index");
+            assert_eq!(eids[i], nns[0].eid);
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn pq_search() {
+        let dims: usize = 64;
+        let num_vectors: usize = 1000;
+        let mut params = small_params(dims, num_vectors);
+        match &mut params {
+            // 16 bytes per vector in place of 256
+            ann::ANNParams::DiskANN { params } => {
+                params.pq = Some(product_quantizer::PQParams {
+                    num_subspaces: 16,
+                    bits: 8,
+                })
+            }
+            _ => unreachable!(),
+        }
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+
+        let top_1 = |idx: &diskannv1::DiskANNV1Index<metric::MetricL2, f32>| -> Vec<ann::EId> {
+            (0..50)
+                .map(|i| {
+                    let query_vec = &base_vectors[i * dims..i * dims + dims];
+                    idx.search(ann::Points::Values { vals: query_vec }, 1)
+                        .expect("unexpected error on search")[0]
+                        .eid
+                })
+                .collect()
+        };
+        let found = top_1(&ann_idx);
+        let hits = found
+            .iter()
+            .zip(eids.iter())
+            .filter(|(found, expected)| found == expected)
+            .count();
+        assert!(hits >= 45, "only {} of 50 queries found themselves", hits);
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-pq-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        assert_eq!(found, top_1(&loaded));
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn filtered_search() {
+        let dims: usize = 32;
+        let num_vectors: usize = 2000;
+        let k: usize = 10;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        // a tenant (0..10) and a language (100..103) label for every point
+        let labels_for = |i: usize| -> Vec<ann::Label> {
+            vec![(i % 10) as ann::Label, (100 + i % 3) as ann::Label]
+        };
+        let labels: Vec<Vec<ann::Label>> = (0..num_vectors).map(labels_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert_with_labels(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+                &labels,
+            )
+            .expect("unexpected err on insert");
+
+        let ground_truth = |query_vec: &[f32], filter: &ann::LabelFilter| -> HashSet<ann::EId> {
+            let mut dists: Vec<(f32, usize)> = (0..num_vectors)
+                .filter(|i| filter.matches(&labels[*i]))
+                .map(|i| {
+                    let vec = &base_vectors[i * dims..i * dims + dims];
+                    let dist: f32 = vec
+                        .iter()
+                        .zip(query_vec)
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    (dist, i)
+                })
+                .collect();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            dists.iter().take(k).map(|(_, i)| eids[*i]).collect()
+        };
+        let filters = [
+            ann::LabelFilter::Any(vec![3]),
+            ann::LabelFilter::Any(vec![3, 7]),
+            ann::LabelFilter::All(vec![3, 101]),
+        ];
+        for filter in filters.iter() {
+            let mut total_intersection_count: usize = 0;
+            for i in 0..20 {
+                let query_vec = &base_vectors[i * dims..i * dims + dims];
+                let found = ann_idx
+                    .search_filtered(ann::Points::Values { vals: query_vec }, k, filter)
+                    .expect("unexpected error on search");
+                assert_eq!(k, found.len(), "filter: {:?}", filter);
+                for nn in found.iter() {
+                    assert!(filter.matches(&labels[nn.vid]));
+                }
+                let found: HashSet<ann::EId> = found.iter().map(|nn| nn.eid).collect();
+                total_intersection_count +=
+                    ground_truth(query_vec, filter).intersection(&found).count();
+            }
+            let recall = total_intersection_count as f32 / (k * 20) as f32;
+            assert!(recall > 0.9, "recall: {} for filter: {:?}", recall, filter);
+        }
+        // labels the index has never seen match nothing
+        let query_vec = &base_vectors[0..dims];
+        assert!(ann_idx
+            .search_filtered(
+                ann::Points::Values { vals: query_vec },
+                k,
+                &ann::LabelFilter::Any(vec![55]),
+            )
+            .expect("unexpected error on search")
+            .is_empty());
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-labels-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        for filter in filters.iter() {
+            let expected: Vec<ann::EId> = ann_idx
+                .search_filtered(ann::Points::Values { vals: query_vec }, k, filter)
+                .expect("unexpected error on search")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            let found: Vec<ann::EId> = loaded
+                .search_filtered(ann::Points::Values { vals: query_vec }, k, filter)
+                .expect("unexpected error on search of loaded index")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            assert_eq!(expected, found);
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn range_search() {
+        let dims: usize = 16;
+        let num_vectors: usize = 2000;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-range-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        ann_idx
+            .save_ssd(&directory)
+            .expect("unexpected err writing the ssd layout");
+        let ssd_idx: diskann_ssd::DiskANNSSDIndex<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&ann::ANNParams::DiskANNSSD {
+                params: diskann_ssd::DiskANNSSDParams {
+                    path: directory.clone(),
+                    search_list_size: 64,
+                    beam_width: 4,
+                },
+            })
+            .expect("unexpected err opening the ssd index");
+
+        for i in 0..5 {
+            let query_vec = &base_vectors[i * dims..i * dims + dims];
+            let mut dists: Vec<(f32, usize)> = (0..num_vectors)
+                .map(|j| {
+                    let vec = &base_vectors[j * dims..j * dims + dims];
+                    let distance: f32 = vec
+                        .iter()
+                        .zip(query_vec.iter())
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    (distance, j)
+                })
+                .collect();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            // more points fall within the radius than the default search
+            // list holds so the list has to grow to find all of them
+            let radius = dists[200].0;
+            let expected: HashSet<ann::EId> = dists
+                .iter()
+                .take_while(|(d, _)| *d <= radius)
+                .map(|(_, j)| eids[*j])
+                .collect();
+            for found in [
+                ann_idx.range_search(ann::Points::Values { vals: query_vec }, radius),
+                ssd_idx.range_search(ann::Points::Values { vals: query_vec }, radius),
+            ] {
+                let found = found.expect("unexpected error on range search");
+                assert!(found.iter().all(|nn| nn.distance <= radius));
+                let found: HashSet<ann::EId> = found.iter().map(|nn| nn.eid).collect();
+                assert!(
+                    (expected.intersection(&found).count() as f32 / expected.len() as f32) > 0.9,
+                    "unexpectedly low recall for range search"
+                );
+            }
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn search_batch() {
+        let dims: usize = 32;
+        let num_vectors: usize = 1000;
+        let num_queries: usize = 100;
+        let k: usize = 10;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+
+        let queries = &base_vectors[..num_queries * dims];
+        let batch = ann_idx
+            .search_batch(ann::Points::Values { vals: queries }, k)
+            .expect("unexpected error on batch search");
+        assert_eq!(num_queries, batch.len());
+        for (i, found) in batch.iter().enumerate() {
+            let expected: Vec<ann::EId> = ann_idx
+                .search(
+                    ann::Points::Values {
+                        vals: &queries[i * dims..i * dims + dims],
+                    },
+                    k,
+                )
+                .expect("unexpected error on search")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            assert_eq!(eids[i], found[0].eid);
+            assert_eq!(
+                expected,
+                found.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>()
+            );
+        }
+        assert!(ann_idx
+            .search_batch(
+                ann::Points::Values {
+                    vals: &queries[..dims + 1]
+                },
+                k
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn resize_and_grow() {
+        let dims: usize = 32;
+        let num_vectors: usize = 600;
+        let batch_size: usize = 100;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-grow-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        let insert_batch = |ann_idx: &diskannv1::DiskANNV1Index<metric::MetricL2, f32>,
+                            batch: usize|
+         -> anyhow::Result<()> {
+            let range = batch * batch_size..(batch + 1) * batch_size;
+            ann_idx.insert(
+                &eids[range.clone()],
+                ann::Points::Values {
+                    vals: &base_vectors[range.start * dims..range.end * dims],
+                },
+            )
+        };
+        let assert_found = |ann_idx: &diskannv1::DiskANNV1Index<metric::MetricL2, f32>,
+                            upto: usize| {
+            let mut found_count: usize = 0;
+            for i in (0..upto).step_by(7) {
+                let query_vec = &base_vectors[i * dims..i * dims + dims];
+                let found = ann_idx
+                    .search(ann::Points::Values { vals: query_vec }, 5)
+                    .expect("unexpected error on search");
+                if found[0].eid == eids[i] {
+                    found_count += 1;
+                }
+            }
+            assert!(found_count as f32 / ((upto + 6) / 7) as f32 > 0.95);
+        };
+
+        // explicit resize of a memory-mapped index
+        let mut params = small_params(dims, batch_size);
+        match &mut params {
+            ann::ANNParams::DiskANN { params } => params.mmap_dir = Some(directory.clone()),
+            _ => unreachable!(),
+        }
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        insert_batch(&ann_idx, 0).expect("unexpected err on insert");
+        insert_batch(&ann_idx, 1).expect_err("index is at max_points and should reject the insert");
+        ann_idx
+            .resize(batch_size / 2)
+            .expect_err("shrinking should be rejected");
+        ann_idx
+            .resize(3 * batch_size)
+            .expect("unexpected err on resize");
+        assert_found(&ann_idx, batch_size);
+        insert_batch(&ann_idx, 1).expect("unexpected err on insert after resize");
+        insert_batch(&ann_idx, 2).expect("unexpected err on insert after resize");
+        assert_found(&ann_idx, 3 * batch_size);
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        assert_found(&loaded, 3 * batch_size);
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+
+        // automatic growth of a heap backed index
+        let mut params = small_params(dims, batch_size);
+        match &mut params {
+            ann::ANNParams::DiskANN { params } => params.growth_factor = Some(1.5),
+            _ => unreachable!(),
+        }
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        for batch in 0..num_vectors / batch_size {
+            insert_batch(&ann_idx, batch).expect("unexpected err on insert");
+        }
+        assert_found(&ann_idx, num_vectors);
+    }
+
+    #[test]
+    fn maintenance() {
+        let dims: usize = 32;
+        let num_vectors: usize = 500;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let mut params = small_params(dims, num_vectors);
+        match &mut params {
+            ann::ANNParams::DiskANN { params } => params.maintenance_period_millis = 10,
+            _ => unreachable!(),
+        }
+        let ann_idx: Arc<diskannv1::DiskANNV1Index<metric::MetricL2, f32>> =
+            Arc::new(ann::ANNIndex::new(&params).expect("error creating diskannv1 index"));
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        let deleted_slots_reused = |ann_idx: &diskannv1::DiskANNV1Index<metric::MetricL2, f32>| {
+            // the index is full so only consolidated locations can be reused
+            let extra = random_vectors(1, dims);
+            ann_idx
+                .insert(
+                    &[eid_for(num_vectors + 1)],
+                    ann::Points::Values { vals: &extra },
+                )
+                .is_ok()
+        };
+
+        // below both thresholds nothing gets consolidated
+        let maintenance = ann_idx.start_maintenance(diskannv1::MaintenanceParams {
+            min_deletes: 10,
+            min_delete_fraction: 0.5,
+        });
+        ann_idx
+            .delete(&eids[0..5])
+            .expect("unexpected err on delete");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!deleted_slots_reused(&ann_idx));
+        maintenance.stop();
+
+        // crossing the count threshold triggers a consolidation
+        let maintenance = ann_idx.start_maintenance(diskannv1::MaintenanceParams {
+            min_deletes: 5,
+            min_delete_fraction: 0.5,
+        });
+        let mut consolidated = false;
+        for _ in 0..100 {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            if deleted_slots_reused(&ann_idx) {
+                consolidated = true;
+                break;
+            }
+        }
+        assert!(consolidated, "deletes were never consolidated");
+
+        // consolidate_now ignores the thresholds
+        ann_idx
+            .delete(&eids[5..6])
+            .expect("unexpected err on delete");
+        ann_idx.consolidate_now();
+        let found = ann_idx
+            .search(
+                ann::Points::Values {
+                    vals: &base_vectors[5 * dims..6 * dims],
+                },
+                5,
+            )
+            .expect("unexpected error on search");
+        assert!(!found.iter().any(|nn| nn.eid == eids[5]));
+
+        // the thread only holds a weak reference to the index, stop it first
+        // as it upgrades that reference for the duration of every tick
+        maintenance.stop();
+        let weak = Arc::downgrade(&ann_idx);
+        drop(ann_idx);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn stats() {
+        let dims: usize = 32;
+        let num_vectors: usize = 300;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let params = small_params(dims, 500);
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        ann_idx
+            .delete(&eids[0..20])
+            .expect("unexpected err on delete");
+
+        let stats = ann::ANNIndex::stats(&ann_idx);
+        assert_eq!(stats.num_live, num_vectors - 20);
+        assert_eq!(stats.num_deleted, 20);
+        assert_eq!(stats.capacity, Some(500));
+        assert!(stats.memory_bytes["vectors"] >= 500 * dims * std::mem::size_of::<f32>());
+        assert!(stats.memory_bytes["final_graph"] > 0);
+        assert!(!stats.quantizers.pq_trained);
+        let graph = stats.graph.expect("diskann should report graph stats");
+        assert_eq!(
+            graph.degree_histogram.iter().sum::<usize>(),
+            num_vectors - 20
+        );
+        assert!(graph.min_degree <= graph.max_degree);
+        // out-degrees are allowed some slack over R before being pruned
+        assert!(graph.max_degree <= (32.0f64 * 1.3 * 1.05).ceil() as usize);
+        assert!(graph.avg_degree >= graph.min_degree as f32);
+        assert!(graph.avg_degree <= graph.max_degree as f32);
+        assert!(graph.in_graph_edges > 0);
+
+        ann_idx.consolidate_now();
+        let stats = ann::ANNIndex::stats(&ann_idx);
+        assert_eq!(stats.num_live, num_vectors - 20);
+        assert_eq!(stats.num_deleted, 0);
+        assert_eq!(stats.num_empty_slots, 20);
+    }
+
+    #[test]
+    fn stats_during_deletes() {
+        let dims: usize = 16;
+        let num_vectors: usize = 1000;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: Arc<diskannv1::DiskANNV1Index<metric::MetricL2, f32>> = Arc::new(
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index"),
+        );
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+
+        let (done_s, done_r) = crossbeam_channel::unbounded::<()>();
+        let deleter = {
+            let ann_idx = ann_idx.clone();
+            let done_s = done_s.clone();
+            std::thread::spawn(move || {
+                for batch in eids.chunks(10) {
+                    ann_idx.delete(batch).expect("unexpected err on delete");
+                    if batch[0] == eids[500] {
+                        ann_idx.consolidate_now();
+                    }
+                }
+                done_s.send(()).unwrap();
+            })
+        };
+        let reader = {
+            let ann_idx = ann_idx.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    let stats = ann::ANNIndex::stats(ann_idx.as_ref());
+                    assert!(stats.num_live <= num_vectors);
+                }
+                done_s.send(()).unwrap();
+            })
+        };
+        // a lock order inversion shows up as a hang rather than a panic
+        for _ in 0..2 {
+            done_r
+                .recv_timeout(std::time::Duration::from_secs(60))
+                .expect("stats and delete deadlocked");
+        }
+        deleter.join().unwrap();
+        reader.join().unwrap();
+        assert_eq!(ann::ANNIndex::stats(ann_idx.as_ref()).num_live, 0);
+    }
+
+    #[test]
+    fn labels_during_consolidate() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let dims: usize = 16;
+        let num_vectors: usize = 1000;
+        let num_rounds: usize = 10;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        // room for every round, the deleted points need not be freed in time
+        let ann_idx: Arc<diskannv1::DiskANNV1Index<metric::MetricL2, f32>> = Arc::new(
+            ann::ANNIndex::new(&small_params(dims, num_vectors * num_rounds))
+                .expect("error creating diskannv1 index"),
+        );
+        let inserted = Arc::new(AtomicBool::new(false));
+        let (done_s, done_r) = crossbeam_channel::unbounded::<()>();
+        let inserter = {
+            let ann_idx = ann_idx.clone();
+            let inserted = inserted.clone();
+            let done_s = done_s.clone();
+            std::thread::spawn(move || {
+                // every batch but the very last is deleted again so that the
+                // consolidations have labelled points to free
+                let batches: Vec<(&[ann::EId], &[f32])> = eids
+                    .chunks(10)
+                    .zip(base_vectors.chunks(10 * dims))
+                    .collect();
+                let num_batches = batches.len() * num_rounds;
+                for (i, (batch, vals)) in batches.iter().cycle().take(num_batches).enumerate() {
+                    let labels: Vec<Vec<ann::Label>> = (0..batch.len())
+                        .map(|j| vec![(j % 3) as ann::Label])
+                        .collect();
+                    ann_idx
+                        .insert_with_labels(batch, ann::Points::Values { vals }, &labels)
+                        .expect("unexpected err on insert");
+                    if i + 1 < num_batches {
+                        ann_idx.delete(batch).expect("unexpected err on delete");
+                    }
+                }
+                inserted.store(true, Ordering::SeqCst);
+                done_s.send(()).unwrap();
+            })
+        };
+        let consolidator = {
+            let ann_idx = ann_idx.clone();
+            std::thread::spawn(move || {
+                while !inserted.load(Ordering::SeqCst) {
+                    ann_idx.consolidate_now();
+                }
+                done_s.send(()).unwrap();
+            })
+        };
+        // a lock order inversion shows up as a hang rather than a panic
+        for _ in 0..2 {
+            done_r
+                .recv_timeout(std::time::Duration::from_secs(120))
+                .expect("labelled inserts and consolidation deadlocked");
+        }
+        inserter.join().unwrap();
+        consolidator.join().unwrap();
+        ann_idx.consolidate_now();
+        let stats = ann::ANNIndex::stats(ann_idx.as_ref());
+        assert_eq!(10, stats.num_live);
+        assert_eq!(0, stats.num_deleted);
+    }
+
+    #[test]
+    fn inner_product() {
+        // spread the norms out, otherwise this is just cosine
+        check_recall::<metric::MetricInnerProduct, f32, f32>(
+            32,
+            |num_vectors, dims| {
+                use rand::distributions::{Distribution, Uniform};
+                let mut rng = rand::thread_rng();
+                let scales = Uniform::from(0.2f32..2.0f32);
+                let mut vectors = random_vectors(num_vectors, dims);
+                vectors.chunks_mut(dims).for_each(|vec| {
+                    let scale = scales.sample(&mut rng);
+                    vec.iter_mut().for_each(|x| *x *= scale);
+                });
+                vectors
+            },
+            values(|x| x),
+            dot_reference,
+            0.9,
+        );
+    }
+
+    #[test]
+    fn half_precision() {
+        let f16_idx = check_recall::<metric::MetricL2, ann::f16, f32>(
+            64,
+            random_vectors,
+            values(ann::f16::from_f32),
+            l2_reference,
+            0.85,
+        );
+        check_recall::<metric::MetricL2, ann::bf16, f32>(
+            64,
+            random_vectors,
+            values(ann::bf16::from_f32),
+            l2_reference,
+            0.85,
+        );
+        let f32_idx = check_recall::<metric::MetricL2, f32, f32>(
+            64,
+            random_vectors,
+            values(|x| x),
+            l2_reference,
+            0.85,
+        );
+        assert_eq!(
+            f32_idx.stats().memory_bytes["vectors"],
+            2 * f16_idx.stats().memory_bytes["vectors"]
+        );
+    }
+
+    #[test]
+    fn signed_bytes() {
+        // [-1, 1) scaled onto the full i8 range
+        check_recall::<metric::MetricL2, i8, f32>(
+            64,
+            random_vectors,
+            values(|x: f32| (x * 127.0).round() as i8),
+            l2_reference,
+            0.85,
+        );
+    }
+
+    #[test]
+    fn quantized_l2() {
+        let ann_idx = check_recall::<metric::MetricL2, u8, f32>(
+            64,
+            random_vectors,
+            quantized,
+            l2_reference,
+            0.8,
+        );
+        let f32_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(64, 1000)).expect("error creating diskannv1 index");
+        assert_eq!(
+            f32_idx.stats().memory_bytes["vectors"],
+            4 * ann_idx.stats().memory_bytes["vectors"]
+        );
+        // codes and values do not mix
+        ann_idx
+            .insert(
+                &[eid_for(0)],
+                ann::Points::Values {
+                    vals: &vec![0u8; 64],
+                },
+            )
+            .expect_err("plain values should be refused by a quantized index");
+        f32_idx
+            .search(
+                ann::Points::QuantizerIn {
+                    vals: &random_vectors(1, 64),
+                },
+                10,
+            )
+            .expect_err("QuantizerIn queries need a quantized index");
+
+        let directory =
+            std::env::temp_dir().join(format!("anansi-diskann-quantized-{}", std::process::id()));
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, u8> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        for query_vec in random_vectors(10, 64).chunks(64) {
+            let expected = ann_idx
+                .search(ann::Points::QuantizerIn { vals: query_vec }, 10)
+                .expect("unexpected error on search");
+            let found = loaded
+                .search(ann::Points::QuantizerIn { vals: query_vec }, 10)
+                .expect("unexpected error on search of loaded index");
+            assert_eq!(
+                expected.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>(),
+                found.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>()
+            );
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn quantized_cosine() {
+        check_recall::<metric::MetricCosine, u8, f32>(
+            64,
+            random_vectors,
+            quantized,
+            cosine_reference,
+            0.8,
+        );
+    }
+
+    #[test]
+    fn quantized_packed() {
+        let dims: usize = 64;
+        let num_vectors: usize = 1000;
+        let k: usize = 10;
+        let directory =
+            std::env::temp_dir().join(format!("anansi-diskann-packed-{}", std::process::id()));
+        let mut params = small_params(dims, num_vectors);
+        if let ann::ANNParams::DiskANN { params } = &mut params {
+            params.quantizer = Some(base::scalar_quantizer::ScalarQuantizerParams {
+                quantile: 1.0,
+                bits: 4,
+                per_dimension: false,
+            });
+        }
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, u8> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::QuantizerIn {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        assert_eq!(Some(4), ann_idx.stats().quantizers.scalar_bits);
+
+        // 16 levels per coordinate still find most of the neighbors
+        let query_vectors = random_vectors(20, dims);
+        let mut total_intersection_count: usize = 0;
+        for query_vec in query_vectors.chunks(dims) {
+            let mut dists: Vec<(f32, usize)> = base_vectors
+                .chunks(dims)
+                .enumerate()
+                .map(|(i, vec)| (l2_reference(query_vec, vec), i))
+                .collect();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let expected: HashSet<ann::EId> = dists[0..k].iter().map(|(_, i)| eids[*i]).collect();
+            let found = ann_idx
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search");
+            assert_eq!(k, found.len());
+            total_intersection_count +=
+                found.iter().filter(|nn| expected.contains(&nn.eid)).count();
+        }
+        assert!(
+            (total_intersection_count as f32 / (k * 20) as f32) > 0.5f32,
+            "unexpectedly low recall: {}/{}",
+            total_intersection_count,
+            k * 20
+        );
+
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, u8> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        for query_vec in query_vectors.chunks(dims) {
+            let expected = ann_idx
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search");
+            let found = loaded
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search of loaded index");
+            assert_eq!(
+                expected.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>(),
+                found.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>()
+            );
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn quantized_rerank() {
+        let dims: usize = 64;
+        let num_vectors: usize = 1000;
+        let k: usize = 20;
+        let directory =
+            std::env::temp_dir().join(format!("anansi-diskann-rerank-{}", std::process::id()));
+        let mut params = small_params(dims, num_vectors);
+        if let ann::ANNParams::DiskANN { params } = &mut params {
+            params.rerank = Some(base::rerank::RerankParams {
+                factor: 4,
+                mmap_dir: Some(directory.clone()),
+            });
+        }
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, u8> =
+            ann::ANNIndex::new(&params).expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &[eid_for(0)],
+                ann::Points::Values {
+                    vals: &vec![0u8; dims],
+                },
+            )
+            .expect_err("plain values leave nothing to re-rank against");
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::QuantizerIn {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        let stats = ann_idx.stats();
+        assert_eq!(Some(4), stats.quantizers.rerank_factor);
+        assert!(stats.memory_bytes["full_precision"] >= num_vectors * dims * 4);
+
+        // k * factor candidates outnumber the search list, the scratch
+        // spaces are sized up to hold them
+        let query_vectors = random_vectors(20, dims);
+        let batch = ann_idx
+            .search_batch(
+                ann::Points::QuantizerIn {
+                    vals: &query_vectors,
+                },
+                k,
+            )
+            .expect("unexpected error on batch search");
+        let mut total_intersection_count: usize = 0;
+        for (query_vec, batch_found) in query_vectors.chunks(dims).zip(batch) {
+            let mut dists: Vec<(f32, usize)> = base_vectors
+                .chunks(dims)
+                .enumerate()
+                .map(|(i, vec)| {
+                    let dist = query_vec
+                        .iter()
+                        .zip(vec)
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    (dist, i)
+                })
+                .collect();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let found = ann_idx
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search");
+            assert_eq!(k, found.len());
+            assert_eq!(
+                found.iter().map(|nn| nn.eid).collect::<Vec<ann::EId>>(),
+                batch_found
+                    .iter()
+                    .map(|nn| nn.eid)
+                    .collect::<Vec<ann::EId>>()
+            );
+            // the distances handed back are exact
+            let dist_by_eid: HashMap<ann::EId, f32> =
+                dists.iter().map(|(dist, i)| (eids[*i], *dist)).collect();
+            for nn in found.iter() {
+                let expected = dist_by_eid[&nn.eid];
+                assert!(
+                    (nn.distance - expected).abs() < 1e-3 * expected.max(1.0),
+                    "distance: {} != exact distance: {}",
+                    nn.distance,
+                    expected
+                );
+            }
+            let expected: HashSet<ann::EId> = dists[0..k].iter().map(|(_, i)| eids[*i]).collect();
+            let found: HashSet<ann::EId> = found.iter().map(|nn| nn.eid).collect();
+            total_intersection_count += expected.intersection(&found).count();
+        }
+        assert!(
+            (total_intersection_count as f32 / (k * 20) as f32) > 0.9f32,
+            "unexpectedly low recall: {}/{}",
+            total_intersection_count,
+            k * 20
+        );
+
+        ann_idx
+            .save(&directory)
+            .expect("unexpected err saving the index");
+        let loaded: diskannv1::DiskANNV1Index<metric::MetricL2, u8> =
+            ann::ANNIndex::load(&directory).expect("unexpected err loading the index");
+        assert_eq!(Some(4), loaded.stats().quantizers.rerank_factor);
+        for query_vec in query_vectors.chunks(dims) {
+            let expected = ann_idx
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search");
+            let found = loaded
+                .search(ann::Points::QuantizerIn { vals: query_vec }, k)
+                .expect("unexpected error on search of loaded index");
+            assert_eq!(
+                expected
+                    .iter()
+                    .map(|nn| (nn.eid, nn.distance))
+                    .collect::<Vec<(ann::EId, f32)>>(),
+                found
+                    .iter()
+                    .map(|nn| (nn.eid, nn.distance))
+                    .collect::<Vec<(ann::EId, f32)>>()
+            );
+        }
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
+    #[test]
+    fn binary_hamming() {
+        // 512 bit fingerprints
+        check_recall::<metric::Hamming, u64, u64>(
+            8,
+            random_words,
+            values(|x| x),
+            hamming_reference,
+            0.9,
+        );
+    }
+
+    #[test]
+    fn binary_jaccard() {
+        check_recall::<metric::MetricJaccard, u64, u64>(
+            8,
+            random_words,
+            values(|x| x),
+            jaccard_reference,
+            0.9,
+        );
+    }
+
+    #[test]
+    fn upsert() {
+        let dims: usize = 32;
+        let num_vectors: usize = 500;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, 2 * num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+
+        // re-embed the first 50 points, eids[0] shows up twice in the batch
+        // and the last copy wins
+        let num_updated: usize = 50;
+        let updated_vectors = random_vectors(num_updated + 1, dims);
+        let mut updated_eids: Vec<base::ann::EId> = eids[0..num_updated].to_vec();
+        updated_eids.push(eids[0]);
+        ann_idx
+            .insert(
+                &updated_eids,
+                ann::Points::Values {
+                    vals: &updated_vectors,
+                },
+            )
+            .expect("unexpected err on upsert");
+
+        let stats = ann::ANNIndex::stats(&ann_idx);
+        assert_eq!(num_vectors, stats.num_live);
+        assert_eq!(num_updated + 1, stats.num_deleted);
+        let check = |ann_idx: &diskannv1::DiskANNV1Index<metric::MetricL2, f32>| {
+            for i in 0..num_updated {
+                let idx = if i == 0 { num_updated } else { i };
+                let updated = &updated_vectors[idx * dims..idx * dims + dims];
+                assert_eq!(Some(updated.to_vec()), ann_idx.get(&eids[i..i + 1])[0]);
+                let found = ann_idx
+                    .search(ann::Points::Values { vals: updated }, 5)
+                    .expect("unexpected error on search");
+                assert_eq!(eids[i], found[0].eid);
+                assert_eq!(0.0, found[0].distance);
+                // the stale vector must never come back under the eid
+                let found = ann_idx
+                    .search(
+                        ann::Points::Values {
+                            vals: &base_vectors[i * dims..i * dims + dims],
+                        },
+                        5,
+                    )
+                    .expect("unexpected error on search");
+                assert!(!found
+                    .iter()
+                    .any(|nn| nn.eid == eids[i] && nn.distance == 0.0));
+            }
+        };
+        check(&ann_idx);
+
+        ann_idx.consolidate_now();
+        let stats = ann::ANNIndex::stats(&ann_idx);
+        assert_eq!(num_vectors, stats.num_live);
+        assert_eq!(0, stats.num_deleted);
+        assert_eq!(num_updated + 1, stats.num_empty_slots);
+        check(&ann_idx);
+    }
+
+    #[test]
+    fn get_and_iter() {
+        // not a multiple of the alignment so the padding has to be stripped
+        let dims: usize = 30;
+        let aligned_dims: usize = 32;
+        let num_vectors: usize = 200;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let padded_vectors: Vec<f32> = base_vectors
+            .chunks(dims)
+            .flat_map(|vec| vec.iter().copied().chain(std::iter::repeat(0.0).take(2)))
+            .collect();
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &padded_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+        ann_idx
+            .delete(&eids[0..10])
+            .expect("unexpected err on delete");
+
+        assert!(!ann::ANNIndex::stores_normalized(&ann_idx));
+        assert!(!ann_idx.contains(&eids[0]));
+        assert!(ann_idx.contains(&eids[10]));
+        let vecs = ann_idx.get(&[eids[0], eids[10], eid_for(num_vectors)]);
+        assert_eq!(
+            vec![
+                None,
+                Some(base_vectors[10 * dims..11 * dims].to_vec()),
+                None
+            ],
+            vecs
+        );
+
+        let mut found: Vec<(ann::EId, Vec<f32>)> = ann_idx
+            .iter()
+            .collect::<anyhow::Result<_>>()
+            .expect("unexpected err iterating the index");
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(num_vectors - 10, found.len());
+        for (i, (eid, vec)) in found.iter().enumerate() {
+            let id = i + 10;
+            assert_eq!(eids[id], *eid);
+            assert_eq!(&base_vectors[id * dims..id * dims + dims], &vec[..]);
+        }
+
+        // cosine keeps the normalized form of each point
+        let cos_idx: diskannv1::DiskANNV1Index<metric::MetricCosine, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        cos_idx
+            .insert(
+                &eids[0..1],
+                ann::Points::Values {
+                    vals: &padded_vectors[0..aligned_dims],
+                },
+            )
+            .expect("unexpected err on insert");
+        assert!(ann::ANNIndex::stores_normalized(&cos_idx));
+        let vec = cos_idx.get(&eids[0..1])[0].clone().unwrap();
+        assert_eq!(dims, vec.len());
+        let norm: f32 = vec.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ssd_search() {
+        let dims: usize = 64;
+        let num_vectors: usize = 1000;
+        let k: usize = 10;
+        let base_vectors = random_vectors(num_vectors, dims);
+        let eids: Vec<base::ann::EId> = (0..num_vectors).map(eid_for).collect();
+        let ann_idx: diskannv1::DiskANNV1Index<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&small_params(dims, num_vectors))
+                .expect("error creating diskannv1 index");
+        ann_idx
+            .insert(
+                &eids,
+                ann::Points::Values {
+                    vals: &base_vectors,
+                },
+            )
+            .expect("unexpected err on insert");
+
+        let directory = std::env::temp_dir().join(format!(
+            "anansi-diskann-ssd-{}-{}",
+            std::process::id(),
+            num_vectors
+        ));
+        ann_idx
+            .save_ssd(&directory)
+            .expect("unexpected err writing the ssd layout");
+        let ssd_idx: diskann_ssd::DiskANNSSDIndex<metric::MetricL2, f32> =
+            ann::ANNIndex::new(&ann::ANNParams::DiskANNSSD {
+                params: diskann_ssd::DiskANNSSDParams {
+                    path: directory.clone(),
+                    search_list_size: 64,
+                    beam_width: 4,
+                },
+            })
+            .expect("unexpected err opening the ssd index");
+        let (in_memory, on_disk) = ssd_idx.footprint();
+        assert!(in_memory * 4 <= on_disk);
+
+        let mut total_intersection_count: usize = 0;
+        for i in 0..20 {
+            let query_vec = &base_vectors[i * dims..i * dims + dims];
+            let expected: HashSet<ann::EId> = ann_idx
+                .search(ann::Points::Values { vals: query_vec }, k)
+                .expect("unexpected error on search")
+                .iter()
+                .map(|nn| nn.eid)
+                .collect();
+            let found = ssd_idx
+                .search(ann::Points::Values { vals: query_vec }, k)
+                .expect("unexpected error on search of the ssd index");
+            // distances are exact so the point itself must come back first
+            assert_eq!(eids[i], found[0].eid);
+            assert_eq!(0.0, found[0].distance);
+            let found: HashSet<ann::EId> = found.iter().map(|nn| nn.eid).collect();
+            total_intersection_count += expected.intersection(&found).count();
+        }
+        assert!(
+            (total_intersection_count as f32 / (k * 20) as f32) > 0.9f32,
+            "unexpectedly low overlap with the in-memory index"
+        );
+
+        ssd_idx
+            .delete(&eids[0..1])
+            .expect("unexpected err on delete");
+        let found = ssd_idx
+            .search(
+                ann::Points::Values {
+                    vals: &base_vectors[0..dims],
+                },
+                k,
+            )
+            .expect("unexpected error on search of the ssd index");
+        assert!(!found.iter().any(|nn| nn.eid == eids[0]));
+        let vecs = ssd_idx
+            .get(&eids[0..2])
+            .expect("unexpected err reading vectors back from disk");
+        assert!(vecs[0].is_none());
+        assert_eq!(Some(base_vectors[dims..2 * dims].to_vec()), vecs[1]);
+
+        // a loaded index searches with the params it was saved with
+        let saved = directory.join("saved");
+        ann::ANNIndex::save(&ssd_idx, &saved).expect("unexpected err saving the ssd index");
+        let loaded: diskann_ssd::DiskANNSSDIndex<metric::MetricL2, f32> =
+            ann::ANNIndex::load(&saved).expect("unexpected err loading the ssd index");
+        assert_eq!(64, loaded.params().search_list_size);
+        assert_eq!(4, loaded.params().beam_width);
+        assert!(!ann::ANNIndex::contains(&loaded, &eids[0]));
+        std::fs::remove_dir_all(&directory).expect("unable to clean up the saved index");
+    }
+
     #[test]
     fn sift_small_deletes() {
         // let directory = Path::new("../../../../eval/data/siftsmall/");
@@ -147,13 +1640,18 @@ mod test {
                 indexing_maxc: 140,       // C
                 indexing_alpha: 1.2,      // alpha
                 maintenance_period_millis: 500,
+                mmap_dir: None,
+                pq: None,
+                growth_factor: None,
+                rerank: None,
+                quantizer: None,
             },
         };
         let ann_idx: Arc<diskannv1::DiskANNV1Index<metric::MetricL2, f32>> =
             Arc::new(ann::ANNIndex::new(&params).expect("error creating diskannv1 index"));
-        let maintain_arc = ann_idx.clone();
-        let _t = std::thread::spawn(move || {
-            maintain_arc.maintain();
+        let _maintenance = ann_idx.start_maintenance(diskannv1::MaintenanceParams {
+            min_deletes: 1,
+            min_delete_fraction: 0.0,
         });
         assert!(
             ann_idx
@@ -254,13 +1752,19 @@ mod test {
                 indexing_maxc: 140,       // C
                 indexing_alpha: 1.2,      // alpha
                 maintenance_period_millis: 500,
+                mmap_dir: None,
+                pq: None,
+                growth_factor: None,
+                rerank: None,
+                quantizer: None,
             },
         };
         let ann_idx: Arc<diskannv1::DiskANNV1Index<metric::MetricL2, f32>> =
             Arc::new(ann::ANNIndex::new(&params).expect("error creating diskannv1 index"));
-        let _t_ann_idx = ann_idx.clone();
-        let _t = std::thread::spawn(move || {
-            _t_ann_idx.maintain();
+        // consolidate on every tick, as the unconditional maintenance loop did
+        let _maintenance = ann_idx.start_maintenance(diskannv1::MaintenanceParams {
+            min_deletes: 1,
+            min_delete_fraction: 0.0,
         });
 
         assert!(
@@ -49,6 +49,11 @@ impl Index {
                 indexing_maxc: 140,
                 indexing_alpha: 1.2,
                 maintenance_period_millis: 500,
+                mmap_dir: None,
+                pq: None,
+                growth_factor: None,
+                rerank: None,
+                quantizer: None,
             },
         };
         Index {